@@ -2,6 +2,7 @@
 #define ARTI_H
 
 #include <stdint.h>
+#include <stdbool.h>
 
 #ifdef __cplusplus
 extern "C" {
@@ -10,23 +11,48 @@ extern "C" {
 /**
  * Start Arti with a SOCKS5 proxy.
  *
+ * Listeners are bound before this returns, so with socks_port 0 the
+ * chosen port is available from arti_socks_port() immediately.
+ *
  * @param data_dir Path to data directory for Tor state (C string)
- * @param socks_port Port for SOCKS5 proxy (e.g., 39050)
+ * @param socks_port Port for SOCKS5 proxy (e.g., 39050), or 0 for any free port
  * @return 0 on success, negative on error:
- *         -1: already running
+ *         -1: already running, still finishing a stop that ran out of
+ *             time, or arti_prefetch() is in progress
  *         -2: invalid data_dir
  *         -3: runtime initialization failed
  *         -4: bootstrap failed
+ *         -5: a SOCKS listener could not be bound
+ *         -6: the audit log could not be opened
  */
 int32_t arti_start(const char *data_dir, uint16_t socks_port);
 
 /**
  * Stop Arti gracefully.
  *
+ * Open connections are asked to close and given up to shutdown.drain_ms
+ * before being cut; the outcome is reported by arti_status. Blocks until the
+ * proxy has stopped.
+ *
  * @return 0 on success, -1 if not running
  */
 int32_t arti_stop(void);
 
+/**
+ * Stop Arti within a deadline, for an app about to be suspended or killed
+ * (e.g. from a background task's expiration handler).
+ *
+ * Like arti_stop(), but open connections get only part of the deadline to
+ * close, leaving the rest for Arti to save its guard and circuit timing
+ * state and close its channels.
+ *
+ * @param deadline_ms Time the app can wait for this call to return
+ * @return 0 if stopped within the deadline, -1 if not running, -2 if the
+ *         deadline passed first (stopping carries on in the background,
+ *         and arti_start() fails until it is done)
+ */
+int32_t arti_prepare_for_termination(uint32_t deadline_ms);
+
 /**
  * Check if Arti is currently running.
  *
@@ -51,7 +77,8 @@ int32_t arti_bootstrap_progress(void);
 int32_t arti_bootstrap_summary(char *buf, int32_t len);
 
 /**
- * Signal Arti to go dormant (reduce resource usage).
+ * Signal Arti to go dormant (reduce resource usage). Connection padding
+ * drops to the padding.dormant level until arti_wake().
  *
  * @return 0 on success, -1 if not running
  */
@@ -64,6 +91,1380 @@ int32_t arti_go_dormant(void);
  */
 int32_t arti_wake(void);
 
+/**
+ * Correct a wrong device clock by an offset the host worked out elsewhere,
+ * e.g. from NTP or the cell network.
+ *
+ * Arti checks the directory and relays' certificates against the current
+ * time, so a skewed clock blocks bootstrap (see clock_skew_detected). The
+ * corrected time applies at once, including to a running instance; Arti's
+ * tolerance for directory documents that look stale or not yet valid is
+ * widened by the offset at the next start. The offset is kept across stops;
+ * pass 0 to go back to the device clock.
+ *
+ * @param offset_ms The right time minus the device's, in milliseconds
+ */
+void arti_set_clock_offset(int64_t offset_ms);
+
+/**
+ * Tell Arti the device moved to another network interface, e.g. from Wi-Fi
+ * to cellular.
+ *
+ * Streams being relayed are closed and the circuits they were on torn down,
+ * since they ride connections over the old interface that Arti would only
+ * notice were gone once they time out; apps reconnect at once instead.
+ * Favorite and pinned peers are reached again straight away. An
+ * interface_changed event lists the streams dropped and the peers migrated.
+ * Interface names are the host's own, e.g. "wifi" or "en0".
+ *
+ * @param from Interface moved off, or NULL if there was none
+ * @param to Interface now in use, or NULL if there is none
+ * @return 0 on success, -1 if not running, -3 if from or to is not valid
+ *         UTF-8
+ */
+int32_t arti_interface_changed(const char *from, const char *to);
+
+/**
+ * Get a JSON status snapshot (bootstrap, guard, circuits, streams, traffic,
+ * dormancy, versions) for the app's Tor status sheet.
+ *
+ * "storage" is "ephemeral" while running with storage.ephemeral set, else
+ * "persistent". An ephemeral session downloads the full directory on every
+ * start, so it bootstraps more slowly and uses more data, and it picks new
+ * guards each time, which gives an observer more chances to be chosen as the
+ * entry point.
+ *
+ * "loopback" is true in builds with the loopback feature, whose streams are
+ * connected directly instead of over Tor; show the user a banner.
+ *
+ * "padding" is the connection padding level in use while running. "quota"
+ * carries "today_bytes", "month_bytes", "daily_limit" and "monthly_limit"
+ * (null without a quota) and "exceeded" (daily, monthly or null).
+ *
+ * "circuit_failures" counts circuit builds that failed behind failed connects
+ * since launch, "by_reason" (timeout, destroyed, path_selection, channel,
+ * protocol) and "by_hop" (first, later, unknown). Builds Arti retried
+ * successfully are not seen.
+ *
+ * "connect_latency" has histograms of successful connects since launch:
+ * "total" from the SOCKS request to the stream opening, and its final
+ * attempt split into "circuit_attach" (until Arti has a circuit) and
+ * "stream_open" (until the exit confirms). Each has "bounds_ms", "counts"
+ * (one more than the bounds, for slower connects), "count" and "sum_ms".
+ *
+ * "favorites" lists the services in onion.favorites and those pinned with
+ * arti_pin_peer() with "address", "port", "pinned", "reachable" (the last
+ * keepalive got through), "last_reached" (Unix seconds, or null) and
+ * "failures" (keepalives failed in a row).
+ *
+ * "network_tuning" is null until tuning.adaptive has rated the network, then
+ * has "quality" (good, fair or poor), "reason" (the measurements behind it),
+ * and the circuit settings applied: "request_timeout_ms",
+ * "request_max_retries" and "prebuilt_exit_circuits" for each predicted
+ * port. A change in rating must hold for two assessments, 30 s apart.
+ *
+ * "circuit_timeouts" is null while stopped, else has "learned_ms", the build
+ * timeout Arti has learned for a 3-hop circuit so far, the limits
+ * "interactive_ms" and "bulk_ms" connects of each class get (null to wait as
+ * Arti does; see circuit.interactive_timeout), and "failovers", the connects
+ * moved to a new circuit since launch.
+ *
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @param redact_guard Report the guard only by a short fingerprint prefix
+ * @return Number of bytes written, -1 if buf is null, -2 if buf is too small
+ */
+int32_t arti_status(char *buf, int32_t len, bool redact_guard);
+
+/**
+ * Set a configuration option, applied the next time Arti starts.
+ *
+ * An environment variable named after the key, upper-cased with dots as
+ * underscores and prefixed with BITCHAT_TOR_ (e.g. BITCHAT_TOR_SOCKS_LISTEN),
+ * overrides the value set here. Variables are read once, on first use;
+ * invalid ones are logged and ignored. arti_options() shows the result.
+ *
+ * Supported keys:
+ *   socks.listen  Comma-separated SOCKS listen addresses, e.g. "127.0.0.1,::1".
+ *                 Addresses without a port use the port passed to arti_start.
+ *   socks.handshake_timeout_ms  Time a client may take to send its SOCKS
+ *                 greeting and request (default 10000).
+ *   socks.handshake_max_bytes  Bytes a client may send before its request
+ *                 is complete (default 2048).
+ *   socks.failure_limit  Failed handshakes from one source address before
+ *                 its connections are refused; 0 disables (default 10).
+ *   socks.failure_window_ms  Window over which failures are counted
+ *                 (default 60000).
+ *   socks.failure_block_ms  How long an offending source is refused
+ *                 (default 300000).
+ *   audit.path    Append a JSON line per connection decision to this file,
+ *                 relative to the data directory; empty disables (default).
+ *   audit.max_bytes  Rotate the audit log to <path>.1 beyond this size;
+ *                 0 never rotates (default 1048576).
+ *   audit.redact  "none" records everything, "host" replaces destination
+ *                 hosts with their kind, "all" also omits the source
+ *                 address (default "host").
+ *   socks.coalesce_ms  How long less than a cell's worth of data written
+ *                 towards Tor waits for more before being sent, so bursts
+ *                 of tiny writes share cells; also applies to hosted onion
+ *                 services; 0 sends each write at once (default 0).
+ *   socks.write_timeout_ms  How long a write towards a SOCKS client may
+ *                 block before its stream is closed, so a client that
+ *                 stopped reading, e.g. a suspended app still holding its
+ *                 socket, does not hold Tor data buffered; 0 waits forever
+ *                 (default 60000).
+ *   socks.max_lifetime_ms  How long a stream may stay open before it is
+ *                 closed, e.g. to move long-lived relay connections to new
+ *                 circuits now and then; 0 for no limit (default). Arti
+ *                 puts no new streams on a circuit in use for over ten
+ *                 minutes, so the replacement of a stream older than that
+ *                 gets a new circuit.
+ *   socks.lifetime_notice_ms  How long before socks.max_lifetime_ms closes
+ *                 a stream stream_expiring is emitted, so the app can open
+ *                 the replacement and switch over first (default 60000).
+ *   socks.resolve_retries  When an exit fails to resolve a hostname, retry
+ *                 on up to this many other exits before failing (default 0).
+ *   socks.exit_hostnames  "reject" (default) refuses legacy host.relay.exit
+ *                 names; "strip" connects to host through an exit Arti
+ *                 chooses, since a specific exit cannot be requested.
+ *                 host is then checked like any other request.
+ *                 .noconnect names are always refused.
+ *   socks.reject_replies  Comma-separated rule=reply entries choosing how
+ *                 requests refused under noconnect_hostname, exit_hostname
+ *                 or v2_onion are answered: "refuse" sends the rule's SOCKS
+ *                 error at once (default); "blackhole" sends nothing and
+ *                 closes after socks.blackhole_ms, so a client probing the
+ *                 policy cannot tell a refusal from a destination that does
+ *                 not answer; "redirect:host:port" connects to that
+ *                 destination instead, without checking it against the
+ *                 policy again. Refusals other than redirects still emit
+ *                 stream_rejected.
+ *   socks.blackhole_ms  How long a blackholed request is held open
+ *                 (default 30000).
+ *   socks.pipelining  What to do when a client sends a SOCKS5 greeting or
+ *                 request again as the first data of its stream, as some
+ *                 libraries do: "tunnel" relays it to the destination as
+ *                 data (default), "reject" refuses the request, or closes
+ *                 the stream if it is already connected. Either way
+ *                 stream_pipelined is emitted.
+ *   socks.record_path  Append a replayable recording of each SOCKS session
+ *                 to this file, relative to the data directory: handshake
+ *                 bytes and replies exactly, payloads only by length. For
+ *                 debugging client compatibility; the destination is
+ *                 recorded. Empty disables (default).
+ *   socks.require_token  "true" makes clients authenticate with
+ *                 username/password, giving the token from
+ *                 arti_socks_token() as the username, so other apps on the
+ *                 device cannot use the proxy (default "false"). The
+ *                 password then names the client's isolation group.
+ *   socks.max_connecting  Tor connects allowed at once; more wait, and
+ *                 free slots go to the waiting isolation groups in turn.
+ *                 0 is unlimited (default).
+ *   isolation.max_streams  Streams one isolation group may have open; more
+ *                 are refused. 0 is unlimited (default).
+ *   isolation.max_new_per_second  Streams one isolation group may open per
+ *                 second, in bursts of up to as many; more are refused. 0
+ *                 is unlimited (default).
+ *   isolation.circuits  How streams of one isolation group share circuits:
+ *                 "token" lets any of them share (default), "destination"
+ *                 only those to the same host and port, and "stream" gives
+ *                 each stream its own circuit. Sharing saves circuit
+ *                 builds for pools of relay connections; separate circuits
+ *                 keep an exit from linking them.
+ *   isolation.circuits_by_host  Comma-separated "host=mode" entries
+ *                 overriding isolation.circuits for streams to that host,
+ *                 e.g. "*.example.com=stream"; a leading "*." matches any
+ *                 subdomain. The first matching entry applies. Empty by
+ *                 default.
+ *
+ *                 Clients that authenticate with username/password are
+ *                 isolated by their credentials, as with Tor's
+ *                 IsolateSOCKSAuth: streams of different credentials never
+ *                 share a circuit, and each set of credentials is a group
+ *                 the options above apply to. Clients that do not
+ *                 authenticate share one group.
+ *   socket.bulk_ports  Comma-separated destination ports whose clients
+ *                 are bulk transfers, e.g. media: their sockets get
+ *                 TCP_NODELAY off and 256 KiB buffers. Other clients are
+ *                 interactive, with TCP_NODELAY on and the system's
+ *                 buffers. Empty by default.
+ *   socket.nodelay  "true" or "false" overrides TCP_NODELAY for every
+ *                 client; "auto" decides by class (default).
+ *   socket.keepalive_ms  Idle time before TCP keepalive probes on client
+ *                 sockets, for clients on other machines; 0 disables
+ *                 (default).
+ *   socket.send_buffer_bytes, socket.recv_buffer_bytes  Client socket
+ *                 buffer sizes for every class; 0 uses the class default
+ *                 (default).
+ *   circuit.interactive_timeout, circuit.bulk_timeout  How long a SOCKS
+ *                 connect of the class waits for a circuit before it is
+ *                 moved to a new one, so a chat send fails over quickly
+ *                 instead of waiting out Arti's retries: "auto" for the
+ *                 build timeout Arti learned, as in arti_status(),
+ *                 "<min>-<max>" for that clamped to a range of
+ *                 milliseconds, or a fixed number of milliseconds. After
+ *                 two failovers a connect waits as Arti would. 0 always
+ *                 does (default).
+ *   bootstrap.max_attempts  Failed bootstraps before giving up; 0 retries
+ *                 until stopped (default 0). Configuration errors are
+ *                 never retried.
+ *   bootstrap.backoff_initial_ms  Delay before the first retry, doubled
+ *                 with jitter after each failure (default 1000).
+ *   bootstrap.backoff_max_ms  Cap on the retry delay (default 300000).
+ *   bootstrap.stall_timeout_ms  An attempt whose progress does not move
+ *                 for this long has hung, and the client is replaced by a
+ *                 new one; time dormant or blocked by clock skew does not
+ *                 count. 0 disables (default 120000).
+ *   bootstrap.max_restarts  New clients to try for hung bootstraps per
+ *                 start; after that bootstrap carries on with the last one
+ *                 (default 2).
+ *   probe.url     HTTPS URL fetched directly (not over Tor) before
+ *                 bootstrap; if it is intercepted, bootstrap waits until the
+ *                 user signs in to the network. Empty disables (default).
+ *   probe.timeout_ms  Time allowed for the probe (default 5000).
+ *   probe.retry_ms  How often to re-probe while a captive portal is present
+ *                 (default 15000).
+ *   bridges       Newline-separated bridge lines to connect through instead
+ *                 of connecting to the Tor network directly; empty disables
+ *                 (default). Only direct bridges are supported. Check lines
+ *                 first with arti_parse_bridge_line().
+ *   firewall.reachable_addresses  Comma-separated addr:port patterns Tor
+ *                 may connect to directly, for networks that block most
+ *                 ports; a bare port means any address, so "80,443" allows
+ *                 only web ports. Ranges ("*:8000-8080") and networks
+ *                 ("192.0.2.0/24:*") are accepted. Empty allows all (default).
+ *   moat.url      Where arti_request_bridges() asks for bridges
+ *                 (default "https://bridges.torproject.org/moat").
+ *   moat.front    Domain to connect to instead of the moat host, which is
+ *                 then only named inside TLS; empty disables (default).
+ *   testnet.authorities  Newline-separated DirAuthority lines of a private
+ *                 test network, such as chutney's, to use instead of the
+ *                 public Tor network; relays there may share a subnet and
+ *                 streams to local addresses are allowed. Needs the testnet
+ *                 feature; empty uses the public network (default).
+ *   testnet.fallbacks  Newline-separated "addr:orport rsa_id ed25519_id"
+ *                 lines of the test network's relays to bootstrap from
+ *                 (default empty).
+ *   loopback.upstream  addr:port a loopback build connects every stream to,
+ *                 e.g. a local echo server; empty connects to each
+ *                 requested destination directly (default). Needs the
+ *                 loopback feature.
+ *   runtime.host_driven  "true" to give Arti no threads of its own, so it
+ *                 only runs within arti_run_for() and other blocking calls,
+ *                 for hosts that ration background CPU time. Read once, when
+ *                 the runtime is created by the first start or prefetch
+ *                 (default "false").
+ *   storage.ephemeral  "true" to keep Tor state and the directory cache only
+ *                 for the session, in a temporary directory deleted on stop;
+ *                 see arti_status() for the tradeoffs (default "false").
+ *   storage.microdescs_max_age_ms  Arti's directory cache is shrunk at
+ *                 each start; with this set, cached microdescriptors no
+ *                 consensus has listed for this long are deleted first. 0
+ *                 leaves them to Arti, which keeps them 7 days (default 0);
+ *                 at least a day otherwise.
+ *   storage.consensuses_max_age_ms  The same for consensuses expired this
+ *                 long; Arti keeps them 2 days (default 0). See
+ *                 arti_storage_report().
+ *   prefetch.onions  Comma-separated name.onion:port services of pinned
+ *                 peers for arti_prefetch() to look up; empty disables
+ *                 (default).
+ *   onion.favorites  Comma-separated name.onion:port services whose
+ *                 rendezvous circuits are kept open while running;
+ *                 needs the onion-service-client feature; empty disables
+ *                 (default).
+ *   onion.keepalive_ms  How often a stream is opened to each favorite to
+ *                 keep its circuit open; Arti closes it after 10 minutes
+ *                 unused (default 300000, minimum 1000).
+ *   onion.refresh_ms  How often a stream is opened to each peer pinned
+ *                 with arti_pin_peer(), keeping its descriptor fresh
+ *                 (default 1800000, minimum 1000).
+ *   onion.race  Comma-separated host=name.onion destinations reachable at
+ *                 both addresses. A SOCKS request for either connects to
+ *                 the onion first and to the host once
+ *                 onion.race_head_start_ms passes or the onion fails, on
+ *                 the requested port, keeping whichever connects first and
+ *                 emitting stream_raced. Needs the onion-service-client
+ *                 feature; empty disables (default).
+ *   onion.race_head_start_ms  How long a raced onion connects alone
+ *                 (default 3000).
+ *   service.client_streams_per_minute  Streams a client of a hosted onion
+ *                 service may open a minute over one rendezvous circuit
+ *                 before the circuit is closed; 0 for no limit (default 0).
+ *   service.client_max_bytes  Bytes a client of a hosted onion service may
+ *                 send towards the app over one rendezvous circuit before
+ *                 it is closed; 0 for no limit (default 0).
+ *   padding.foreground  Connection padding while in use: "normal",
+ *                 "reduced" or "off" (default "normal").
+ *   padding.dormant  Connection padding between arti_go_dormant() and
+ *                 arti_wake(), with the same values (default "reduced").
+ *                 Less padding saves battery but makes traffic timing
+ *                 easier to analyse.
+ *   quota.daily_bytes  Bytes of SOCKS traffic, both directions, allowed per
+ *                 UTC day before new connections are refused; 0 for no
+ *                 quota (default).
+ *   quota.monthly_bytes  The same per UTC calendar month (default 0).
+ *                 Totals are kept in traffic.json in the data directory
+ *                 across restarts.
+ *   memory.report_interval_ms  How often to emit a memory_usage event while
+ *                 running; 0 never does (default 0).
+ *   budget.connects_per_minute  SOCKS connects over the last minute beyond
+ *                 which budget_exceeded is emitted, e.g. to hold back
+ *                 background fetches on battery; 0 for no budget (default).
+ *                 Nothing is refused.
+ *   budget.circuits  Circuits carrying streams beyond which
+ *                 budget_exceeded is emitted; 0 for no budget (default).
+ *   tuning.adaptive  "true" to adjust circuit timeouts, retries and
+ *                 prebuilt exit circuits to the network as measured from
+ *                 bootstrap time and recent connects, e.g. giving circuits
+ *                 longer on a slow cellular link; decisions are announced
+ *                 with network_tuned (default "false").
+ *   exits.refresh_ms  Fetch the Tor Project's list of exit addresses over
+ *                 Tor once bootstrapped, and again when it is this old, for
+ *                 arti_is_tor_exit(); 0 never fetches it (default 0).
+ *                 3600000 (an hour) follows the list's own update rate
+ *                 closely enough.
+ *   exits.country  Two-letter code of the country every SOCKS stream's exit
+ *                 must be in, e.g. "DE"; empty allows any (default).
+ *                 Connects fail if no usable exit is in the country.
+ *                 Ignored without the geoip feature.
+ *   status.http_listen  Loopback "address:port" serving a status page
+ *                 while running, for debugging: "/" shows status, streams
+ *                 and recent errors, also at /status.json, /streams.json and
+ *                 /errors.json. Everything is redacted as by arti_status()
+ *                 and arti_streams(). Empty disables (default).
+ *   events.streams  "true" emits progress events for each SOCKS stream,
+ *                 see arti_set_event_callback() (default "false").
+ *   shutdown.drain_ms  Time open connections get to close on arti_stop
+ *                 before being cut (default 2000).
+ *
+ * @param key Option name (C string)
+ * @param value Option value (C string)
+ * @return 0 on success, -1 unknown key, -2 invalid value, -3 invalid string
+ */
+int32_t arti_set_option(const char *key, const char *value);
+
+/**
+ * List every option with its effective value and where it comes from.
+ *
+ * Writes {"options":[{"key":...,"value":...,"origin":...}]}, where value is
+ * in the form arti_set_option() takes and origin is "default", "api" or
+ * "environment".
+ *
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if buf is null, -2 if buf is too small
+ */
+int32_t arti_options(char *buf, int32_t len);
+
+/**
+ * Export the options Arti is running with, with secrets redacted.
+ *
+ * Writes {"running":...,"options":[{"key":...,"value":...,"origin":...}]}
+ * as arti_options() does, but with the options the running instance started
+ * with, or those the next start will use when running is false. Values that
+ * are secret (bridges, prefetch.onions, onion.favorites and onion.race) are
+ * written as "<redacted:digest>": the digest differs when the value does,
+ * but is keyed anew in every process and reveals nothing of the value.
+ * Suitable for a debug screen or a bug report.
+ *
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if buf is null, -2 if buf is too small
+ */
+int32_t arti_export_config(char *buf, int32_t len);
+
+/**
+ * Compare two sets of options, e.g. to show what pending changes would
+ * alter once Arti restarts.
+ *
+ * Both are JSON in the form arti_export_config() writes, of which only each
+ * option's "key" and "value" are read; options left out take their defaults
+ * and unknown ones are ignored. A NULL old_options stands for the running
+ * instance's options (the defaults when stopped), a NULL new_options for
+ * those the next start will use, so two NULLs show the changes made since
+ * the running instance started.
+ *
+ * Writes {"changes":[{"key":...,"old":...,"new":...}]}, in the order options
+ * are documented, with secrets redacted as in arti_export_config(). Secret
+ * values from an export of an earlier process never match.
+ *
+ * @param old_options Options before (JSON C string), or NULL
+ * @param new_options Options after (JSON C string), or NULL
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if buf is null, -2 if buf is too small,
+ *         -3 if either is not valid UTF-8 or not an export
+ */
+int32_t arti_diff_config(const char *old_options, const char *new_options, char *buf, int32_t len);
+
+/**
+ * Get the port the SOCKS proxy is bound to.
+ *
+ * Valid as soon as arti_start returns; changes if the proxy is restarted
+ * with an ephemeral port. With several listeners, this is the first one's port.
+ *
+ * @return The bound port, -1 if no listener is bound
+ */
+int32_t arti_socks_port(void);
+
+/**
+ * Stop accepting SOCKS connections while keeping the listeners bound.
+ *
+ * Intended for identity switches and dormancy, so clients never see the
+ * port disappear. Resumes automatically on the next start.
+ *
+ * @param refuse If true, keep accepting but fail each request with a SOCKS
+ *               error; otherwise leave new connections waiting in the backlog
+ * @return 0 on success, -1 if not running
+ */
+int32_t arti_pause_listener(bool refuse);
+
+/**
+ * Resume accepting SOCKS connections after arti_pause_listener().
+ *
+ * @return 0 on success, -1 if not running
+ */
+int32_t arti_resume_listener(void);
+
+/**
+ * Callback receiving data the SOCKS handler sent on a pipe, valid only for
+ * the duration of the call. A len of 0 means the pipe is closed.
+ */
+typedef void (*ArtiPipeCallback)(int32_t pipe, const uint8_t *data, int32_t len, void *context);
+
+/**
+ * Open an in-memory SOCKS connection, for a client the host holds itself.
+ *
+ * Serves the pipe as the listeners serve a connection they accept, without
+ * a local socket: what the host's client sends goes in with
+ * arti_pipe_write(), and what the SOCKS handler sends back is passed to
+ * callback, which gets a len of 0 once the pipe is closed. Pipes count as
+ * connections from 0.0.0.0:0 and are reported with client port 0, but
+ * their failed handshakes are not rate limited.
+ *
+ * The callback runs on an Arti worker thread, must return quickly, and must
+ * not call arti_pipe_write() or arti_pipe_close() itself.
+ *
+ * @param callback Function to pass the handler's output to
+ * @param context Opaque pointer passed back to the callback
+ * @return The pipe's id, which is positive, -1 if callback is NULL, -2 if
+ *         not serving (not running, or not bootstrapped yet)
+ */
+int32_t arti_pipe_open(ArtiPipeCallback callback, void *context);
+
+/**
+ * Pass data from the host's client to the SOCKS handler of a pipe.
+ *
+ * Blocks until the pipe's buffer has taken all of data, which holds the
+ * client back while the handler is connecting or Tor is slow.
+ *
+ * @param pipe Id from arti_pipe_open()
+ * @param data Bytes to pass
+ * @param len Number of bytes
+ * @return 0 on success, -1 if data is NULL or len is negative, -2 if there
+ *         is no such pipe or it has closed, -3 if called on Arti's own
+ *         thread, e.g. from a callback
+ */
+int32_t arti_pipe_write(int32_t pipe, const uint8_t *data, int32_t len);
+
+/**
+ * Tell a pipe's SOCKS handler that the host's client is done sending.
+ *
+ * The handler finishes as it would when a socket client shuts down its
+ * side; the callback gets a len of 0 once it is done.
+ *
+ * @param pipe Id from arti_pipe_open()
+ * @return 0 on success, -2 if there is no such pipe or it has closed, -3 if
+ *         called on Arti's own thread, e.g. from a callback
+ */
+int32_t arti_pipe_close(int32_t pipe);
+
+/**
+ * Give a SOCKS connection a deadline, after which it is stopped wherever it
+ * has got to.
+ *
+ * A handshake or connect still running then fails, the latter with SOCKS
+ * reply 0x06 (TTL expired), and a stream still relaying is closed with
+ * reason deadline. Either way stream_deadline reports how far it got. The
+ * connection is named by the local address of the client's socket, so the
+ * deadline can be set before connecting to the SOCKS port; it then applies
+ * to the next connection from that address, if that comes before the
+ * deadline. Setting it again moves it. Pipes cannot be given a deadline.
+ *
+ * @param client_addr Local address of the client's connection to the SOCKS
+ *                    port, e.g. "127.0.0.1:50123" or "[::1]:50123" (C string)
+ * @param deadline_ms Milliseconds from now; 0 clears the deadline
+ * @return 0 on success, -1 if client_addr is NULL, -2 if not running, -3 if
+ *         client_addr is not an address with a port other than 0
+ */
+int32_t arti_set_stream_deadline(const char *client_addr, uint32_t deadline_ms);
+
+/**
+ * Callback receiving one event as a null-terminated JSON string, valid only
+ * for the duration of the call.
+ */
+typedef void (*ArtiEventCallback)(const char *event_json, void *context);
+
+/**
+ * Register a callback for events, replacing any previous one.
+ *
+ * Each event is a JSON object with a "type" field:
+ *   loopback_mode  On every start of a loopback build, which never
+ *                  bootstraps and connects streams without Tor; carries
+ *                  "upstream" (loopback.upstream, or null).
+ *   bootstrap_attempt  A bootstrap attempt is starting; carries "attempt".
+ *   bootstrap_failed  An attempt failed; carries "attempt", "error", "fatal"
+ *                  and "retry_in_ms" (null when giving up).
+ *   bootstrap_succeeded  Carries "attempt" and "elapsed_ms".
+ *   bootstrap_stalled  An attempt hung; carries "diagnostics" ("attempt",
+ *                  "percent", "status", "blocked", "stalled_ms",
+ *                  "elapsed_ms") and "restart", the number of the client
+ *                  restart begun, or null if none are left. The last
+ *                  diagnostics are also in arti_status.
+ *   clock_skew_detected  The device clock is wrong enough to block
+ *                  bootstrap; carries "message". The host can correct it
+ *                  with arti_set_clock_offset().
+ *   clock_skew_cleared  The skew no longer blocks bootstrap.
+ *   captive_portal_detected  The pre-bootstrap probe was intercepted;
+ *                  carries "reason".
+ *   captive_portal_cleared  The probe got through again.
+ *   stream_failed  A SOCKS request could not be connected; carries
+ *                  "stream", "client_port", "destination", "reason"
+ *                  (host_not_found, resolve_failed, timeout, refused,
+ *                  exit_policy, network_failed or other), "detail" and
+ *                  "attempts"; arti_diagnose_failure() explains it.
+ *   stream_rejected  A SOCKS request was refused by local policy; carries
+ *                  "stream", "client_port", "destination" and "rule"
+ *                  (noconnect_hostname, exit_hostname, v2_onion,
+ *                  isolation_streams or isolation_rate). v2 onion addresses
+ *                  get SOCKS reply 0xF6 (onion address invalid), the others
+ *                  0x02.
+ *   stream_pipelined  A SOCKS client sent its handshake again as stream
+ *                  data; carries "stream", "client_port", "destination"
+ *                  and "action" (tunneled, refused or closed, see
+ *                  socks.pipelining).
+ *   stream_expiring  A stream will be closed at socks.max_lifetime_ms;
+ *                  carries "stream", "client_port", "destination" and
+ *                  "closes_in_ms".
+ *   stream_deadline  A connection was stopped at the deadline set with
+ *                  arti_set_stream_deadline(); carries "stream" and
+ *                  "destination" (null if it was still in its handshake),
+ *                  "client_port", "phase" (handshake, connect or relay),
+ *                  "sent", "received" and "elapsed_ms" since the connection
+ *                  was accepted.
+ *   stream_raced  A SOCKS request listed in onion.race connected;
+ *                  carries "stream", "client_port", "destination" (the
+ *                  address that connected), "route" (onion or clearnet)
+ *                  and "elapsed_ms".
+ *   bridge_challenge  A CAPTCHA to show for arti_request_bridges(); carries
+ *                  "image" (base64) and "mime_type".
+ *   bridges_received  Bridges were stored in the bridges option; carries
+ *                  "count".
+ *   bridge_fetch_failed  Carries "stage" (fetch or check), "kind" (network,
+ *                  server, captcha_incorrect or no_bridges) and "error".
+ *   quota_exceeded Carries "period" (daily or monthly), "used" and "limit"
+ *                  in bytes; new SOCKS connections are refused from now on.
+ *   quota_cleared  The quota period rolled over; connections are accepted
+ *                  again.
+ *   budget_exceeded  SOCKS connects or circuits went over their budget;
+ *                  carries "budget" (connects_per_minute or circuits),
+ *                  "value" and "limit".
+ *   budget_cleared  The same budget is back down to three quarters of its
+ *                  limit, checked every 5 seconds; carries the same fields.
+ *   memory_usage  Every memory.report_interval_ms; carries the fields
+ *                  described at arti_memory_usage().
+ *   network_tuned  With tuning.adaptive, circuit settings were changed;
+ *                  carries the fields described for "network_tuning" at
+ *                  arti_status().
+ *   isolation_not_honored  arti_check_isolation() found two isolated
+ *                  streams on one circuit; carries "circuit".
+ *   interface_changed  arti_interface_changed() moved Arti off the old
+ *                  interface; carries "old", "new", "dropped" (the
+ *                  "stream", "client_port" and "destination" of each
+ *                  stream closed), "circuits_closed" and "migrated" (the
+ *                  favorite and pinned peers being reached again).
+ *   onion_service_state  A hosted onion service changed state; carries
+ *                  "nickname", "state" and "descriptor" (as in
+ *                  arti_onion_services()) and "problem" (fatal,
+ *                  descriptor_upload, introduction_points, other, or null
+ *                  if none). Only sent by builds with onion service
+ *                  hosting.
+ *   onion_service_client_limited  A client of a hosted onion service went
+ *                  over service.client_streams_per_minute or
+ *                  service.client_max_bytes and its circuit was closed;
+ *                  carries "nickname", "client" (numbered per service) and
+ *                  "limit" (streams or bytes). Only sent by builds with
+ *                  onion service hosting.
+ *   onion_service_expired  A service from
+ *                  arti_onion_service_create_expiring() stopped; carries
+ *                  "nickname", "reason" (ttl, connections, or stopped if
+ *                  stopped by the app), "streams" (forwarded to the app)
+ *                  and "key_wiped" (false if the keystore failed and its
+ *                  keys remain).
+ *
+ * With the events.streams option, each SOCKS stream also reports its
+ * progress. These carry "stream", the id arti_streams() lists it by,
+ * "client_port", the port of the app's end of the SOCKS connection, and
+ * "elapsed_ms" since the request was read:
+ *   stream_circuit_attached  A connect attempt got its circuit; not sent if
+ *                  the host app installed its own tracing subscriber.
+ *   stream_connected  The remote end accepted the stream; also carries
+ *                  "destination" and "circuit".
+ *   stream_first_byte  The first data came back.
+ *   stream_closed  The stream ended; carries "reason" (client, remote,
+ *                  client_error, client_stalled (see
+ *                  socks.write_timeout_ms), pipelined_request (see
+ *                  socks.pipelining), max_lifetime (see
+ *                  socks.max_lifetime_ms), deadline (see
+ *                  arti_set_stream_deadline()), tor_error, shutdown or
+ *                  interface_changed), "sent" and "received". Streams that
+ *                  never connect end with stream_failed or stream_rejected
+ *                  instead.
+ *
+ * The callback runs on an Arti worker thread and must return quickly.
+ *
+ * @param callback Function to call, or NULL to unregister
+ * @param context Opaque pointer passed back to the callback
+ */
+void arti_set_event_callback(ArtiEventCallback callback, void *context);
+
+/**
+ * Queue events for arti_poll_events(), alongside or instead of a callback,
+ * for hosts that cannot take calls on arbitrary threads.
+ *
+ * The queue holds up to 256 events; when full, the oldest is dropped and
+ * counted. Disabling it discards any events still queued.
+ */
+void arti_set_event_queue(bool enabled);
+
+/**
+ * Take queued events, waiting up to timeout_ms for one if none is queued.
+ *
+ * Writes {"events":[...],"dropped":N} with events as described at
+ * arti_set_event_callback(), oldest first; dropped counts events lost to
+ * overflow since the previous poll. Events that do not fit stay queued.
+ *
+ * @param max Most events to take (at least 1)
+ * @param timeout_ms Time to wait for an event, or 0 to return at once
+ * @param buf Buffer to write JSON into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if buf is null, len is not positive
+ *         or max is less than 1, -2 if buf is too small for the oldest event
+ */
+int32_t arti_poll_events(int32_t max, uint32_t timeout_ms, char *buf, int32_t len);
+
+/**
+ * Check a bridge line the user entered, without storing it.
+ *
+ * Writes {"valid":true,"bridge":{"addrs":[...],"rsa_id":...,"ed_id":...}}
+ * or {"valid":false,"error":{"kind":...,"message":...}}, where kind is one
+ * of empty, invalid_address, invalid_identity, duplicate_identity,
+ * unsupported_identity, missing_rsa_identity, unexpected_parameters,
+ * transport_unsupported or invalid.
+ *
+ * @param line Bridge line (C string), with or without the leading "Bridge"
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if a pointer is null, -2 if buf is too
+ *         small, -3 if line is not valid UTF-8
+ */
+int32_t arti_parse_bridge_line(const char *line, char *buf, int32_t len);
+
+/**
+ * Check that a bridge is reachable from this network.
+ *
+ * Connects directly (not over Tor) to the bridge address and reports the
+ * latency; the bridge is not authenticated. Blocks for up to timeout_ms per
+ * address, so call it off the main thread.
+ *
+ * Writes a JSON object with "valid", "reachable", and when known "address",
+ * "latency_ms" and "error" ({"kind":...,"message":...}; kinds as for
+ * arti_parse_bridge_line(), plus timeout, unreachable and no_address).
+ *
+ * @param line Bridge line (C string)
+ * @param timeout_ms Connect timeout per address
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if a pointer is null, -2 if buf is too
+ *         small, -3 if line is not valid UTF-8
+ */
+int32_t arti_test_bridge(const char *line, uint32_t timeout_ms, char *buf, int32_t len);
+
+/**
+ * Ask moat for bridges, for users who cannot reach the Tor network directly.
+ *
+ * Runs in the background and works while Arti is stopped. The CAPTCHA to
+ * show arrives as a bridge_challenge event; pass the answer to
+ * arti_solve_bridge_challenge(). Failures arrive as bridge_fetch_failed.
+ *
+ * @return 0 if started, -1 if a bridge request is already in progress
+ */
+int32_t arti_request_bridges(void);
+
+/**
+ * Answer the CAPTCHA from the last bridge_challenge event.
+ *
+ * Runs in the background. On success the bridges are stored in the bridges
+ * option, used from the next arti_start(), and a bridges_received event is
+ * sent. Each CAPTCHA can be answered once; after a captcha_incorrect failure
+ * call arti_request_bridges() again.
+ *
+ * @param solution The text the user read from the image (C string)
+ * @return 0 if submitted, -1 if there is no CAPTCHA to answer, -2 if a bridge
+ *         request is in progress, -3 if solution is null or invalid UTF-8
+ */
+int32_t arti_solve_bridge_challenge(const char *solution);
+
+/**
+ * List the entry guards Arti has sampled, as JSON.
+ *
+ * Writes {"guards":[...],"pinned":...,"rotate_all_pending":...}. Each guard
+ * carries "fingerprint", "ed_id", "addrs", "sample", "added_at", and the
+ * flags "confirmed", "disabled", "unlisted", "current", "pinned" and
+ * "rotation_pending". Works whether or not Arti is running.
+ *
+ * @param data_dir Data directory passed to arti_start (C string)
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @param redact Shorten fingerprints to a prefix and omit addresses and ed25519 ids
+ * @return Number of bytes written, -1 if a pointer is null, -2 if buf is too
+ *         small, -3 if data_dir is not valid UTF-8
+ */
+int32_t arti_guards(const char *data_dir, char *buf, int32_t len, bool redact);
+
+/**
+ * Always start circuits at one guard, or stop doing so.
+ *
+ * The pin is kept in the data directory and applies from the next
+ * arti_start(). The guard is used like a bridge, so it stays in use even if
+ * it loses its Guard flag; configured bridges take precedence over it.
+ *
+ * @param data_dir Data directory passed to arti_start (C string)
+ * @param fingerprint Guard fingerprint, or a prefix of at least 8 hex digits
+ *                    as shown when redacted; NULL unpins
+ * @return 0 on success, -1 if data_dir is null, -2 if no sampled guard (or
+ *         more than one) matches, -3 invalid string, -4 could not be saved
+ */
+int32_t arti_pin_guard(const char *data_dir, const char *fingerprint);
+
+/**
+ * Replace a guard, or every guard, with freshly chosen ones.
+ *
+ * Applied at the next arti_start(), and remembered until then even if the
+ * app exits.
+ *
+ * @param data_dir Data directory passed to arti_start (C string)
+ * @param fingerprint Guard fingerprint or prefix as for arti_pin_guard();
+ *                    NULL rotates all guards
+ * @return 0 on success, -1 if data_dir is null, -2 if no sampled guard (or
+ *         more than one) matches, -3 invalid string, -4 could not be saved
+ */
+int32_t arti_rotate_guards(const char *data_dir, const char *fingerprint);
+
+/**
+ * Refresh directory information, and the onion services in prefetch.onions,
+ * within a time budget.
+ *
+ * Intended for background refresh tasks, so that the next foreground start
+ * connects quickly. Uses the running instance if there is one; otherwise a
+ * temporary client works on the same state, and arti_start() fails with -1
+ * until the prefetch returns. Blocks for up to budget_ms.
+ *
+ * Writes a JSON report: "complete", "directory_percent", "directory_ready",
+ * "onions_fetched", "onions_remaining", "onion_support" (onion services are
+ * only fetched in builds with the onion-service-client feature),
+ * "elapsed_ms" and "error".
+ *
+ * @param data_dir Data directory passed to arti_start (C string)
+ * @param budget_ms Time the prefetch may take
+ * @param buf Buffer to write the report into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if a pointer is null, -2 if buf is too
+ *         small, -3 if data_dir is not valid UTF-8, -4 runtime failure,
+ *         -5 if another prefetch is in progress
+ */
+int32_t arti_prefetch(const char *data_dir, uint32_t budget_ms, char *buf, int32_t len);
+
+/**
+ * Report how much storage a data directory takes, and on what.
+ *
+ * Writes {"total_bytes":...,"state_bytes":...,"cache_bytes":...,
+ * "consensuses":{"count":...,"bytes":...},"microdescriptors":...,
+ * "router_descriptors":...,"bridge_descriptors":...,
+ * "authority_certificates":...,"unused_bytes":...}. "state_bytes" covers
+ * guards, keys, statistics and the rest of Arti's state. "unused_bytes" is
+ * space in the directory cache holding nothing, which the next start gives
+ * back; see storage.microdescs_max_age_ms for pruning more. Onion service
+ * descriptors are kept in memory only and take no storage. Works whether or
+ * not Arti is running; while an ephemeral session runs on data_dir, its
+ * temporary directory is reported.
+ *
+ * @param data_dir Data directory passed to arti_start (C string)
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if a pointer is null, -2 if buf is too
+ *         small, -3 if data_dir is not valid UTF-8
+ */
+int32_t arti_storage_report(const char *data_dir, char *buf, int32_t len);
+
+/**
+ * Lend the calling thread to a host-driven runtime for budget_ms.
+ *
+ * With runtime.host_driven set, Arti runs nothing on threads of its own:
+ * directory maintenance, descriptor refreshes, timeouts and SOCKS traffic
+ * all wait until the host calls this, so work can be fitted into the short
+ * bursts of CPU time hosts such as watchOS hand out. Work that came due
+ * meanwhile is caught up. Blocks for budget_ms; several threads may call it
+ * at once, but only one runs Arti at a time.
+ *
+ * @param budget_ms Time to run for
+ * @return 0 once the budget is used up, -1 if the runtime is not host-driven
+ *         or not created yet, -2 if called on Arti's own thread, e.g. from an
+ *         event callback
+ */
+int32_t arti_run_for(uint32_t budget_ms);
+
+/**
+ * List active streams with the circuit path each one takes, as JSON.
+ *
+ * Writes {"streams":[{"id":...,"destination":"host:port","circuit":...,
+ * "hops":[{"role":...,"fingerprint":...,"nickname":...,"country":...,
+ * "addrs":[...]}]}]} for a "your traffic takes this path" view. "role" is
+ * guard, middle, exit, or rendezvous for the last hop to an onion service.
+ * "nickname" is missing for bridges; "country" is null unless the library
+ * was built with the geoip feature, and is looked up in the database from
+ * arti_geoip_update() if one was installed.
+ *
+ * Each stream also has "multipath", true when its traffic is split over
+ * several circuits (conflux), in which case "hops" shows the first. Arti 0.38
+ * does not yet build multipath circuits for client streams, so this is
+ * currently always false.
+ *
+ * Debug builds of the library add "relay", with "client_to_tor" and
+ * "tor_to_client" each counting the "reads", "writes" and "flushes" the
+ * relay loop made, "avg_read_bytes" and "avg_write_bytes", and the time it
+ * waited for data to read ("read_wait_ms") and for room to write it
+ * ("write_wait_ms"), for tuning buffer sizes.
+ *
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @param redact Report destinations only by kind (<domain>, <onion>, <ip>)
+ *               and relays by a short fingerprint prefix and country
+ * @return Number of bytes written, -1 if buf is null, -2 if buf is too small
+ */
+int32_t arti_streams(char *buf, int32_t len, bool redact);
+
+/**
+ * Explain why a SOCKS request failed to connect, as JSON.
+ *
+ * Writes {"stream":...,"client_port":...,"destination":"host:port",
+ * "failed_at":...,"elapsed_ms":...,"reason":...,"socks_reply":...,
+ * "retryable":...,"kind":...,"errors":[...],"attempts":...,
+ * "circuit_failures":[{"reason":...,"hop":...}],"ready_for_traffic":...,
+ * "log":[{"at_ms":...,"level":...,"target":...,"message":...}]}, for the app
+ * to show or attach to a bug report. "reason" is as in the stream_failed
+ * event and "kind" is Arti's error kind; "errors" is the error followed by
+ * each one it was caused by. "circuit_failures" are those counted in
+ * arti_status(). "log" has the lines logged while connecting, at debug
+ * level whatever the log filter, with "at_ms" counted from the request. The
+ * last 16 failures are kept, while running and after.
+ *
+ * @param stream Id of the stream, as in stream_failed, or 0 for the latest
+ *               failure
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @param redact Report the destination only by kind (<domain>, <onion>,
+ *               <ip>), also where errors and log lines name it
+ * @return Number of bytes written, -1 if buf is null, -2 if buf is too
+ *         small, -4 if no such failure is kept
+ */
+int32_t arti_diagnose_failure(uint64_t stream, char *buf, int32_t len, bool redact);
+
+/**
+ * Report approximate memory use by subsystem, as JSON.
+ *
+ * Writes {"total_bytes":...,"directory_bytes":...,"circuit_bytes":...,
+ * "stream_buffer_bytes":...,"event_queue_bytes":...}. "total_bytes" is the
+ * process's physical footprint, the figure iOS limits a network extension
+ * by, or null where the OS does not report it. "directory_bytes" and
+ * "circuit_bytes" are estimates from relay and circuit counts; the others
+ * are exact.
+ *
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if buf is null, -2 if buf is too small
+ */
+int32_t arti_memory_usage(char *buf, int32_t len);
+
+/**
+ * Host an onion service that forwards streams for port to
+ * 127.0.0.1:target_port, where the app listens.
+ *
+ * The identity key is kept in the keystore under nickname, so creating the
+ * same nickname on a later start gives the same address. Pass key from
+ * arti_export_onion_service_key() to move an address from another install,
+ * then wipe it from your own memory. The service stops with
+ * arti_onion_service_stop() or arti_stop(). Requires the
+ * onion-service-service feature.
+ *
+ * @param nickname Local name for the service
+ * @param port Virtual port clients connect to
+ * @param target_port Local port streams are forwarded to
+ * @param key Hex identity key to import, or NULL to use or generate the
+ *            stored one
+ * @param buf Buffer to write the .onion address into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if not running or an argument is null,
+ *         -2 if buf is too small, -3 if a string, the nickname or the key is
+ *         invalid, -4 if the service could not be launched (e.g. key was
+ *         given but the nickname already has one), -5 if a service with this
+ *         nickname is already running, -6 if built without onion service
+ *         support
+ */
+int32_t arti_onion_service_create(const char *nickname,
+                                  uint16_t port,
+                                  uint16_t target_port,
+                                  const char *key,
+                                  char *buf,
+                                  int32_t len);
+
+/**
+ * Host an onion service like arti_onion_service_create() that stops by
+ * itself, for one-shot handoffs between contacts.
+ *
+ * The service stops once ttl_ms has passed or it has forwarded
+ * max_connections streams to the app, whichever comes first; streams past
+ * the last are refused, and the service stops when the last one closes. A
+ * stream counts only once the app's port accepts it.
+ * However it stops, including by arti_onion_service_stop() or arti_stop(),
+ * every key stored under nickname is deleted, so the address is never
+ * served again, and an onion_service_expired event is sent. The key is
+ * always new, so nickname must not have one stored. If the process dies
+ * before the service stops its keys remain until arti_wipe_all_keys().
+ *
+ * @param nickname Local name for the service
+ * @param port Virtual port clients connect to
+ * @param target_port Local port streams are forwarded to
+ * @param ttl_ms How long the service runs, or 0 for no limit
+ * @param max_connections Streams it forwards, or 0 for no limit
+ * @param buf Buffer to write the .onion address into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if not running or an argument is null,
+ *         -2 if buf is too small, -3 if the nickname is invalid or both
+ *         limits are 0, -4 if the service could not be launched (e.g. the
+ *         nickname already has a key), -5 if a service with this nickname is
+ *         already running, -6 if built without onion service support
+ */
+int32_t arti_onion_service_create_expiring(const char *nickname,
+                                           uint16_t port,
+                                           uint16_t target_port,
+                                           uint32_t ttl_ms,
+                                           uint32_t max_connections,
+                                           char *buf,
+                                           int32_t len);
+
+/**
+ * List hosted onion services as JSON.
+ *
+ * Writes {"services":[{"nickname":...,"address":...,"port":...,
+ * "target_port":...,"state":...,"descriptor":...,"descriptor_ms":...,
+ * "clients":...,"counts":{...}}]}, where "state" is Arti's view of the
+ * service, e.g. Bootstrapping, Running or Broken. "descriptor" is "pending"
+ * until the descriptor is first published, "fresh" while Arti believes the
+ * service reachable and "stale" otherwise, which it has been for
+ * "descriptor_ms"; Arti republishes on its own when the network or the
+ * introduction points change. "clients" counts open rendezvous circuits.
+ * "counts" totals what the service has handled since it was launched:
+ * "introductions", "rendezvous", "rendezvous_failed", "streams",
+ * "streams_refused" (for another port, or not accepted by the app),
+ * "clients_limited", "bytes_sent" and "bytes_received". Services from
+ * arti_onion_service_create_expiring() also carry "expires_ms", the time
+ * left of their TTL, and "connections_left", the streams they have left,
+ * each null if not limited. "self_test" is the latest result of
+ * arti_onion_service_self_test(), or null if none has run.
+ *
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if buf is null, -2 if buf is too small
+ */
+int32_t arti_onion_services(char *buf, int32_t len);
+
+/**
+ * Stop a hosted onion service. Its key stays in the keystore, unless it was
+ * created with arti_onion_service_create_expiring().
+ *
+ * Arti only releases the service's state once it has had a directory, so a
+ * service stopped before bootstrap completes cannot be created again under
+ * the same nickname until the process exits.
+ *
+ * @param nickname Service nickname
+ * @return 0 on success, -1 if nickname is null or no such service is
+ *         running, -3 if nickname is not valid UTF-8, -6 if built without
+ *         onion service support
+ */
+int32_t arti_onion_service_stop(const char *nickname);
+
+/**
+ * Check that a hosted onion service can be reached, the way a contact would
+ * reach it.
+ *
+ * Looks the service up and connects to it from new circuits, isolated from
+ * everything else, so the descriptor is fetched from the network and the
+ * introduction and rendezvous are done in full. The stream asks for a port
+ * the service does not serve, so the service itself turns it away, which
+ * proves the whole path without the app seeing a connection; it shows in
+ * "counts" as a refused stream. Blocks for up to timeout_ms; call it off
+ * the main thread.
+ *
+ * Writes {"reachable":...,"latency_ms":...,"error":...}, where "latency_ms"
+ * is the time from starting the lookup until the service answered, and
+ * "error" says why it was not reached. The latest result is also listed by
+ * arti_onion_services() as "self_test".
+ *
+ * @param nickname Service nickname
+ * @param timeout_ms Time the test may take
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if not running, not bootstrapped or
+ *         an argument is null, -2 if buf is too small, -3 if nickname is not
+ *         valid UTF-8, -4 if no such service is running, -6 if built without
+ *         onion service support
+ */
+int32_t arti_onion_service_self_test(const char *nickname,
+                                     uint32_t timeout_ms,
+                                     char *buf,
+                                     int32_t len);
+
+/**
+ * Export the identity key of an onion service as hex, for
+ * arti_onion_service_create() on another install.
+ *
+ * Anyone holding the key can impersonate the service; treat it like a
+ * password, and overwrite buf once it has been used.
+ *
+ * @param nickname Service nickname
+ * @param buf Buffer to write the key into (at least 129 bytes)
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if not running or an argument is null,
+ *         -2 if buf is too small, -3 if nickname is invalid, -4 if there is
+ *         no key for the nickname or the keystore failed, -6 if built without
+ *         onion service support
+ */
+int32_t arti_export_onion_service_key(const char *nickname, char *buf, int32_t len);
+
+/**
+ * Generate a key for reaching an onion service in restricted discovery mode,
+ * and get the public part to give to the service's operator.
+ *
+ * @param onion The service's .onion address
+ * @param replace Replace an existing key instead of failing
+ * @param buf Buffer to write the "descriptor:x25519:..." public key into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if not running or an argument is null,
+ *         -2 if buf is too small, -3 if onion is not a valid v3 onion
+ *         address, -4 if a key exists and replace is false, -5 if the
+ *         keystore failed, -6 if built without onion service support
+ */
+int32_t arti_generate_client_auth_key(const char *onion, bool replace, char *buf, int32_t len);
+
+/**
+ * Get the public part of the key held for a restricted discovery service.
+ *
+ * @return As arti_generate_client_auth_key(), with -4 if no key is held
+ */
+int32_t arti_client_auth_key(const char *onion, char *buf, int32_t len);
+
+/**
+ * Delete the key held for a restricted discovery service.
+ *
+ * @param onion The service's .onion address
+ * @return 0 on success, -1 if not running or onion is null, -3 if onion is
+ *         not a valid v3 onion address, -4 if no key is held, -5 if the
+ *         keystore failed, -6 if built without onion service support
+ */
+int32_t arti_remove_client_auth_key(const char *onion);
+
+/**
+ * Set which logs are written to stderr, without restarting.
+ *
+ * @param filter Comma-separated "target=level" directives with an optional
+ *        bare level for other targets, e.g. "arti=warn,arti_bitchat=debug".
+ *        A target covers every target it is a prefix of; the longest match
+ *        wins; "" turns logging off. The default is "warn".
+ * @return 0 on success, -1 if filter is null or not valid UTF-8, -2 if
+ *         filter is invalid, -3 if the host process installed its own
+ *         tracing subscriber
+ */
+int32_t arti_set_log_filter(const char *filter);
+
+/**
+ * Look up an onion service ahead of connecting to it.
+ *
+ * Fetches the descriptor and builds introduction circuits on the running
+ * client, so the connect that follows skips the lookup. Blocks for up to
+ * timeout_ms. Descriptors are kept in memory only, not across a stop; use
+ * prefetch.onions and arti_prefetch() to look peers up after launch.
+ *
+ * @param onion The service's .onion address
+ * @param port A port the service accepts streams on
+ * @param timeout_ms Time the lookup may take
+ * @return 0 if the service accepted a stream, -1 if not running, not
+ *         bootstrapped, or onion is null, -3 if onion is not a valid v3
+ *         onion address, -4 if the service could not be reached, -5 on
+ *         timeout, -6 if built without onion service support
+ */
+int32_t arti_warm_onion(const char *onion, uint16_t port, uint32_t timeout_ms);
+
+/**
+ * Pin a peer's onion service, so sending to it stays fast.
+ *
+ * While running, a stream is opened to the service and closed again every
+ * onion.refresh_ms, like the onion.favorites are every onion.keepalive_ms,
+ * which keeps its descriptor fresh. Arti cannot fetch a descriptor alone,
+ * so each refresh builds a rendezvous circuit, and the peer's app sees the
+ * stream. The first refresh starts straight away. Pinning a peer again
+ * replaces its port. Pins are kept until unpinned, across arti_stop(), and
+ * are listed under "favorites" in arti_status().
+ *
+ * @param onion The service's .onion address
+ * @param port A port the service accepts streams on
+ * @return 0 if pinned, -1 if onion is null, -3 if onion is not a valid v3
+ *         onion address, -4 if 64 peers are pinned already, -6 if built
+ *         without onion service support
+ */
+int32_t arti_pin_peer(const char *onion, uint16_t port);
+
+/**
+ * Unpin a peer pinned with arti_pin_peer(), or every pinned peer.
+ *
+ * @param onion The service's .onion address, or NULL for all
+ * @return 0 if unpinned, -2 if it was not pinned, -6 if built without onion
+ *         service support
+ */
+int32_t arti_unpin_peer(const char *onion);
+
+/**
+ * Create a signed payload introducing this device to a peer, e.g. as a QR
+ * code.
+ *
+ * The payload carries noise_key, the address of the service launched as
+ * nickname, client_auth_key if given, and expires, and is signed with the
+ * service's identity key. It is unpadded base32, so it fits a QR code's
+ * alphanumeric mode. The peer checks it with arti_contact_payload_verify().
+ *
+ * @param nickname Nickname the service was launched under
+ * @param noise_key Noise static public key as 64 hex digits
+ * @param client_auth_key A "descriptor:x25519:..." key to pass on, or NULL
+ * @param expires Unix time in seconds after which the payload is refused
+ * @param buf Buffer to write the payload into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if not running or an argument is
+ *         null, -2 if buf is too small, -3 if an argument is malformed or
+ *         expires has passed, -4 if no identity key is stored for nickname
+ *         or the keystore failed, -6 if built without onion service support
+ */
+int32_t arti_contact_payload_create(const char *nickname,
+                                    const char *noise_key,
+                                    const char *client_auth_key,
+                                    uint64_t expires,
+                                    char *buf,
+                                    int32_t len);
+
+/**
+ * Check a payload from arti_contact_payload_create() and decode it as JSON.
+ *
+ * Writes {"noise_key":..,"onion":..,"client_auth_key":..,"expires":..},
+ * with noise_key as hex and client_auth_key null if none was included. A
+ * valid signature proves the payload was made by whoever controls onion.
+ * Works whether or not Arti is running.
+ *
+ * @param payload The payload
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if a pointer is null, -2 if buf is
+ *         too small, -3 if payload is not a contact payload, -4 if the
+ *         signature is not valid, -5 if it has expired, -6 if built without
+ *         onion service support
+ */
+int32_t arti_contact_payload_verify(const char *payload, char *buf, int32_t len);
+
+/**
+ * Daily usage statistics, as JSON, for charting trends.
+ *
+ * Writes {"days":[...]}, oldest first, with an entry for each UTC day with
+ * activity: "date" (start of the day, Unix seconds), "bytes_sent",
+ * "bytes_received", "connects_succeeded", "connects_failed",
+ * "connect_success_rate" and "average_connect_ms" (null without connects)
+ * and "connected_secs" (time Tor was ready for traffic). Statistics are
+ * kept in the data directory for 90 days, and can be read while stopped.
+ *
+ * @param data_dir Data directory passed to arti_start (C string)
+ * @param days Number of days to include, counting today
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if a pointer is null, -2 if buf is
+ *         too small, -3 if data_dir is not valid UTF-8
+ */
+int32_t arti_stats(const char *data_dir, uint32_t days, char *buf, int32_t len);
+
+/**
+ * Create a profile, a separate data directory for one identity.
+ *
+ * Profiles live under <root>/profiles/<name>, each with its own guards,
+ * state and directory cache, keys, statistics and audit log, so nothing Tor
+ * keeps links one identity to another. Start one with arti_profile_switch().
+ *
+ * @param root Directory holding the profiles (C string)
+ * @param name Profile name: 1 to 64 ASCII letters, digits, "-" and "_"
+ * @return 0 on success, -1 if a pointer is null, -3 if root or name is not
+ *         valid, -4 if the profile exists, -5 if the directory could not be
+ *         created
+ */
+int32_t arti_profile_create(const char *root, const char *name);
+
+/**
+ * Run Arti on a profile, stopping it first if it runs on another one.
+ *
+ * The new session gets a new client on the profile's own data directory,
+ * so it shares no guards, circuits or streams with the one before. Does
+ * nothing if Arti already runs on the profile.
+ *
+ * @param root Directory holding the profiles (C string)
+ * @param name Profile to run on (C string)
+ * @param socks_port As for arti_start()
+ * @return As arti_start(), with -2 also if root or name is null or not
+ *         valid, -7 if there is no such profile, and -8 if the session
+ *         on the other profile did not stop in time (nothing runs until it
+ *         has; retry the switch then)
+ */
+int32_t arti_profile_switch(const char *root, const char *name, uint16_t socks_port);
+
+/**
+ * Delete a profile and everything Tor kept for it, e.g. when its identity
+ * is wiped. Arti is stopped first if it runs on the profile.
+ *
+ * @param root Directory holding the profiles (C string)
+ * @param name Profile to delete (C string)
+ * @return 0 on success, -1 if a pointer is null, -3 if root or name is not
+ *         valid, -4 if there is no such profile, -5 if the directory could
+ *         not be removed
+ */
+int32_t arti_profile_delete(const char *root, const char *name);
+
+/**
+ * List the profiles, as JSON.
+ *
+ * Writes {"profiles":[{"name":...,"active":...}]} sorted by name, where
+ * "active" marks the profile Arti is running on.
+ *
+ * @param root Directory holding the profiles (C string)
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if a pointer is null, -2 if buf is
+ *         too small, -3 if root is not valid UTF-8
+ */
+int32_t arti_profiles(const char *root, char *buf, int32_t len);
+
+/**
+ * Wipe every key Tor keeps, for panic mode.
+ *
+ * Stops Arti if it is running, then overwrites and deletes the keystore of
+ * data_dir and of every profile under it: hosted onion services' identity
+ * keys and the keys for reaching services in restricted discovery mode.
+ * The services get new addresses if they are launched again. Ephemeral
+ * state is deleted as well. Guards, statistics and other state are kept;
+ * delete profiles with arti_profile_delete() to remove those too.
+ *
+ * @param data_dir Data directory given to arti_start(), also the root of the
+ *        profiles (C string)
+ * @return 0 on success, -1 if data_dir is null, -3 if data_dir is not valid
+ *         UTF-8, -5 if a key file could not be overwritten or deleted (the
+ *         rest are still wiped)
+ */
+int32_t arti_wipe_all_keys(const char *data_dir);
+
+/**
+ * Get the token SOCKS clients must give as their username while
+ * socks.require_token is set.
+ *
+ * A new random token is made at each start, so fetch it after arti_start()
+ * and hand it only to the code that should use the proxy.
+ *
+ * @param buf Buffer to write the token into (at least 33 bytes)
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if not running or buf is null, -2 if
+ *         buf is too small, -3 if no token is required
+ */
+int32_t arti_socks_token(char *buf, int32_t len);
+
+/**
+ * Check that streams with different isolation tokens get different circuits.
+ *
+ * Opens two streams to host:port, the second while the first is still
+ * open, each under its own isolation token, and compares their circuits.
+ * Writes {"isolated":...,"circuits":[...,...]}, where "isolated" is null if
+ * a circuit could not be inspected. If they share a circuit, an
+ * isolation_not_honored event is emitted too. Blocks for up to timeout_ms;
+ * call it off the main thread.
+ *
+ * @param host Destination to connect to, e.g. a hostname the app uses (C string)
+ * @param port Destination port
+ * @param timeout_ms Time both connects may take
+ * @param buf Buffer to write the JSON into
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if not running, not bootstrapped or
+ *         an argument is null, -2 if buf is too small, -3 if host is not a
+ *         valid destination, -4 if a stream could not be opened, -5 if the
+ *         check did not finish within timeout_ms
+ */
+int32_t arti_check_isolation(const char *host, uint16_t port, uint32_t timeout_ms, char *buf, int32_t len);
+
+/**
+ * Check whether an IP address belongs to a Tor exit.
+ *
+ * Looks the address up in the exit list fetched under exits.refresh_ms,
+ * e.g. to label a peer whose connection arrives from Tor. Works after Arti
+ * stops, with the list last fetched.
+ *
+ * @param ip IPv4 or IPv6 address (C string)
+ * @return 1 if it is an exit address, 0 if it is not, -1 if ip is null, -3
+ *         if ip is not an IP address, -4 if no exit list has been fetched yet
+ */
+int32_t arti_is_tor_exit(const char *ip);
+
+/**
+ * Look up the country of an IP address, e.g. a destination or a relay.
+ *
+ * Uses the embedded GeoIP database, or the one installed with
+ * arti_geoip_update(). Works whether or not Arti is running.
+ *
+ * @param ip IPv4 or IPv6 address (C string)
+ * @param buf Buffer to write the two-letter country code into (at least 3 bytes)
+ * @param len Length of the buffer
+ * @return Number of bytes written, -1 if a pointer is null, -2 if buf is
+ *         too small, -3 if ip is not an IP address, -4 if the database does
+ *         not place the address, -6 if built without the geoip feature
+ */
+int32_t arti_geoip_country(const char *ip, char *buf, int32_t len);
+
+/**
+ * Replace the GeoIP database with a newer one, e.g. downloaded by the app.
+ *
+ * The files are in C Tor's geoip and geoip6 formats. They are checked,
+ * copied into <data_dir>/geoip so later starts load them too, and used for
+ * lookups at once. Arti still chooses exits for exits.country from its
+ * embedded copy. Works whether or not Arti is running.
+ *
+ * @param data_dir Data directory passed to arti_start() (C string)
+ * @param v4_path IPv4 database file (C string)
+ * @param v6_path IPv6 database file (C string)
+ * @return 0 on success, -1 if a pointer is null, -3 if a path is not valid
+ *         UTF-8, -4 if a file cannot be read or is not a GeoIP database, -5
+ *         if the database could not be stored in the data directory, -6 if
+ *         built without the geoip feature
+ */
+int32_t arti_geoip_update(const char *data_dir, const char *v4_path, const char *v6_path);
+
 #ifdef __cplusplus
 }
 #endif
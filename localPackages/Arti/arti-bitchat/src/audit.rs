@@ -0,0 +1,221 @@
+//! Optional append-only audit log of SOCKS connection decisions
+//!
+//! Each connection gets one JSON line recording whether it was allowed or
+//! blocked, which rule decided, and for allowed connections how it ended,
+//! how long it lasted and how many bytes it carried. The file is rotated to
+//! `<path>.1` once it exceeds the configured size.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::config::Config;
+
+static AUDIT_LOG: Mutex<Option<AuditLog>> = Mutex::new(None);
+
+/// How much of each connection the audit log reveals
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Redaction {
+    /// Record source and destination in full
+    None,
+    /// Replace destination hosts with their kind (`<onion>`, `<domain>`, `<ip>`)
+    Host,
+    /// Additionally omit the source address
+    All,
+}
+
+impl Redaction {
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        match s {
+            "none" => Some(Redaction::None),
+            "host" => Some(Redaction::Host),
+            "all" => Some(Redaction::All),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Redaction::None => "none",
+            Redaction::Host => "host",
+            Redaction::All => "all",
+        }
+    }
+}
+
+/// Decision taken for a connection
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Verdict {
+    Allowed,
+    Blocked,
+    /// The client never completed a valid handshake
+    Failed,
+}
+
+/// One line of the audit log
+#[derive(Serialize)]
+pub(crate) struct AuditRecord {
+    /// Seconds since the Unix epoch
+    ts: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<String>,
+    verdict: Verdict,
+    /// Rule that produced the verdict
+    rule: &'static str,
+    /// Isolation applied to the stream
+    isolation: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outcome: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_sent: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_received: Option<u64>,
+}
+
+impl AuditRecord {
+    pub(crate) fn new(source: SocketAddr, verdict: Verdict, rule: &'static str) -> Self {
+        AuditRecord {
+            ts: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            source: Some(source.to_string()),
+            destination: None,
+            verdict,
+            rule,
+            isolation: "default",
+            outcome: None,
+            duration_ms: None,
+            bytes_sent: None,
+            bytes_received: None,
+        }
+    }
+
+    pub(crate) fn destination(mut self, host: &str, port: u16) -> Self {
+        self.destination = Some(format!("{}:{}", host, port));
+        self
+    }
+
+    pub(crate) fn outcome(mut self, outcome: impl Into<String>) -> Self {
+        self.outcome = Some(outcome.into());
+        self
+    }
+
+    pub(crate) fn traffic(mut self, duration: Duration, sent: u64, received: u64) -> Self {
+        self.duration_ms = Some(duration.as_millis() as u64);
+        self.bytes_sent = Some(sent);
+        self.bytes_received = Some(received);
+        self
+    }
+
+    fn redact(&mut self, redaction: Redaction) {
+        if redaction == Redaction::None {
+            return;
+        }
+        if let Some(dest) = self.destination.as_mut() {
+            let (host, port) = dest.rsplit_once(':').unwrap_or((dest.as_str(), ""));
+            *dest = format!("{}:{}", host_kind(host), port);
+        }
+        if redaction == Redaction::All {
+            self.source = None;
+        }
+    }
+}
+
+pub(crate) fn host_kind(host: &str) -> &'static str {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.parse::<IpAddr>().is_ok() {
+        "<ip>"
+    } else if host.to_ascii_lowercase().ends_with(".onion") {
+        "<onion>"
+    } else {
+        "<domain>"
+    }
+}
+
+struct AuditLog {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    redaction: Redaction,
+}
+
+impl AuditLog {
+    fn write(&mut self, mut record: AuditRecord) -> io::Result<()> {
+        record.redact(self.redaction);
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        if self.max_bytes > 0 && self.written + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(&line)?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        self.file = open_append(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Open the audit log if one is configured; relative paths are under `data_dir`
+pub(crate) fn open(config: &Config, data_dir: &Path) -> io::Result<()> {
+    let log = match config.audit_path.as_ref() {
+        Some(path) => {
+            let path = data_dir.join(path);
+            let file = open_append(&path)?;
+            let written = file.metadata()?.len();
+            Some(AuditLog {
+                path,
+                file,
+                written,
+                max_bytes: config.audit_max_bytes,
+                redaction: config.audit_redact,
+            })
+        }
+        None => None,
+    };
+    if let Ok(mut current) = AUDIT_LOG.lock() {
+        *current = log;
+    }
+    Ok(())
+}
+
+/// Stop writing the audit log
+pub(crate) fn close() {
+    if let Ok(mut current) = AUDIT_LOG.lock() {
+        *current = None;
+    }
+}
+
+/// Append a record if the audit log is open
+pub(crate) fn record(record: AuditRecord) {
+    let Ok(mut current) = AUDIT_LOG.lock() else {
+        return;
+    };
+    if let Some(log) = current.as_mut() {
+        if let Err(e) = log.write(record) {
+            tracing::warn!("Failed to write audit log: {}", e);
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Bootstrap supervisor
+//!
+//! Retries failed bootstraps with jittered exponential backoff so the host
+//! app does not have to poll and restart. Errors that retrying cannot fix,
+//! such as invalid configuration or an unusable state directory, end the
+//! attempt loop immediately. Attempts that hang instead of failing are
+//! caught by the [`watchdog`](crate::watchdog).
+
+use std::time::{Duration, Instant};
+
+use arti_client::{ErrorKind, HasKind, TorClient};
+
+use crate::clock::TorRuntime;
+use crate::config::Config;
+use crate::events::{self, Event};
+use crate::probe::{self, ProbeResult};
+use crate::shutdown::ShutdownController;
+use crate::watchdog;
+use crate::{tuning, update_summary};
+
+/// How supervised bootstrapping ended
+pub(crate) enum Outcome {
+    Bootstrapped,
+    /// Shutdown was requested first
+    Cancelled,
+    /// An attempt hung and the client is to be replaced
+    Stalled,
+    /// A fatal error, or the last error once attempts ran out
+    Failed(arti_client::Error),
+}
+
+/// Probe for a captive portal and, while one is present, wait and re-probe
+/// instead of bootstrapping. Returns false if shutdown was requested.
+///
+/// Does nothing unless `probe.url` is set. An unreachable endpoint is not
+/// treated as a portal; bootstrap goes ahead and reports its own errors.
+pub(crate) async fn wait_for_open_network(config: &Config, shutdown: &ShutdownController) -> bool {
+    let Some(url) = config.probe_url.clone() else {
+        return true;
+    };
+    loop {
+        let (url, timeout) = (url.clone(), config.probe_timeout);
+        let result = tokio::select! {
+            result = tokio::task::spawn_blocking(move || probe::probe(&url, timeout)) => result,
+            _ = shutdown.cancelled() => return false,
+        };
+        match result {
+            Ok(ProbeResult::CaptivePortal(reason)) => {
+                if !probe::captive_portal() {
+                    tracing::warn!("Captive portal detected: {}", reason);
+                    probe::set_captive_portal(true);
+                    events::emit(Event::CaptivePortalDetected { reason });
+                }
+                update_summary("Sign in to the network to continue");
+            }
+            other => {
+                if let Ok(ProbeResult::Offline(reason)) = &other {
+                    tracing::debug!("Captive portal probe could not reach endpoint: {}", reason);
+                }
+                if probe::captive_portal() {
+                    probe::set_captive_portal(false);
+                    events::emit(Event::CaptivePortalCleared);
+                }
+                return true;
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(config.probe_retry) => {}
+            _ = shutdown.cancelled() => return false,
+        }
+    }
+}
+
+/// Whether retrying could plausibly fix this error
+pub(crate) fn is_fatal(error: &arti_client::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::InvalidConfig
+            | ErrorKind::InvalidConfigTransition
+            | ErrorKind::FeatureDisabled
+            | ErrorKind::NotImplemented
+            | ErrorKind::BadApiUsage
+            | ErrorKind::FsPermissions
+            | ErrorKind::NoHomeDirectory
+            | ErrorKind::LocalResourceAlreadyInUse
+            | ErrorKind::SoftwareDeprecated
+    )
+}
+
+/// Bootstrap `client`, retrying transient failures until success, a fatal
+/// error, `bootstrap.max_attempts` attempts, or shutdown.
+///
+/// An attempt that hangs is reported as the client `restart` about to
+/// begin, and ends supervision; with `restart` unset, none are left and the
+/// attempt carries on.
+pub(crate) async fn supervise(
+    client: &TorClient<TorRuntime>,
+    config: &Config,
+    restart: Option<u32>,
+    shutdown: &ShutdownController,
+) -> Outcome {
+    let started = Instant::now();
+    let mut delay = config.bootstrap_backoff_initial;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        events::emit(Event::BootstrapAttempt { attempt });
+        update_summary(&if attempt == 1 {
+            "Bootstrapping...".to_string()
+        } else {
+            format!("Bootstrapping (attempt {})...", attempt)
+        });
+
+        // Timed but never entered, so tasks Arti spawns while bootstrapping
+        // do not inherit the span and keep it open after the attempt
+        let span = tracing::info_span!("bootstrap", attempt);
+        let bootstrap = client.bootstrap();
+        let watch = watchdog::watch(client, attempt, config.bootstrap_stall_timeout);
+        tokio::pin!(bootstrap, watch);
+        let mut watching = true;
+        let error = loop {
+            tokio::select! {
+                result = &mut bootstrap => match result {
+                    Ok(()) => {
+                        tuning::note_bootstrap(started.elapsed());
+                        events::emit(Event::BootstrapSucceeded {
+                            attempt,
+                            elapsed_ms: started.elapsed().as_millis() as u64,
+                        });
+                        return Outcome::Bootstrapped;
+                    }
+                    Err(e) => break e,
+                },
+                diagnostics = &mut watch, if watching => {
+                    events::emit(Event::BootstrapStalled { diagnostics, restart });
+                    if restart.is_some() {
+                        return Outcome::Stalled;
+                    }
+                    // A cancelled bootstrap cannot be resumed, so let it run
+                    update_summary("Bootstrap stalled");
+                    watching = false;
+                }
+                _ = shutdown.cancelled() => return Outcome::Cancelled,
+            }
+        };
+        drop(span);
+
+        let fatal = is_fatal(&error);
+        let exhausted =
+            config.bootstrap_max_attempts != 0 && attempt >= config.bootstrap_max_attempts;
+        let retry_in = (!fatal && !exhausted).then(|| jitter(delay));
+        tracing::warn!("Bootstrap attempt {} failed: {}", attempt, error);
+        events::emit(Event::BootstrapFailed {
+            attempt,
+            error: error.to_string(),
+            fatal,
+            retry_in_ms: retry_in.map(|d| d.as_millis() as u64),
+        });
+
+        let Some(retry_in) = retry_in else {
+            return Outcome::Failed(error);
+        };
+        update_summary(&format!(
+            "Retrying bootstrap in {}s",
+            retry_in.as_secs().max(1)
+        ));
+        tokio::select! {
+            _ = tokio::time::sleep(retry_in) => {}
+            _ = shutdown.cancelled() => return Outcome::Cancelled,
+        }
+        delay = (delay * 2).min(config.bootstrap_backoff_max);
+    }
+}
+
+/// Spread `delay` over 50%-150% so many devices do not retry in lockstep
+fn jitter(delay: Duration) -> Duration {
+    delay.mul_f64(rand::random_range(0.5..1.5))
+}
@@ -0,0 +1,214 @@
+//! Bridge line validation and reachability testing
+//!
+//! Lets the app's bridge settings screen check what the user pasted before
+//! it is stored with the `bridges` option. Only direct bridges are usable:
+//! this build has no pluggable transport support.
+
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+use arti_client::config::{BridgeConfigBuilder, BridgeParseError};
+use serde::Serialize;
+use tor_linkspec::{HasAddrs, HasRelayIds};
+
+/// A parsed bridge line
+#[derive(Serialize)]
+pub(crate) struct BridgeInfo {
+    addrs: Vec<String>,
+    /// RSA identity fingerprint, upper-case hex as in bridge lines
+    rsa_id: Option<String>,
+    ed_id: Option<String>,
+}
+
+/// Why a bridge line was rejected
+#[derive(Debug, Serialize)]
+pub(crate) struct BridgeLineError {
+    /// Machine-readable category, e.g. `invalid_address`
+    kind: &'static str,
+    pub(crate) message: String,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum ParseReport {
+    Valid { valid: bool, bridge: BridgeInfo },
+    Invalid { valid: bool, error: BridgeLineError },
+}
+
+/// Result of trying to reach a bridge
+#[derive(Serialize)]
+pub(crate) struct TestReport {
+    valid: bool,
+    reachable: bool,
+    /// Address that answered, or the last one tried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<BridgeLineError>,
+}
+
+/// Parse a bridge line into the builder Arti's config takes
+pub(crate) fn parse_line(line: &str) -> Result<(BridgeConfigBuilder, BridgeInfo), BridgeLineError> {
+    let builder: BridgeConfigBuilder =
+        line.parse()
+            .map_err(|e: BridgeParseError| BridgeLineError {
+                kind: error_kind(&e),
+                message: e.to_string(),
+            })?;
+    let bridge = builder.build().map_err(|e| BridgeLineError {
+        kind: "invalid",
+        message: e.to_string(),
+    })?;
+    let info = BridgeInfo {
+        addrs: bridge.addrs().map(|a| a.to_string()).collect(),
+        rsa_id: bridge
+            .rsa_identity()
+            .map(|id| id.to_string().trim_start_matches('$').to_uppercase()),
+        ed_id: bridge.ed_identity().map(|id| id.to_string()),
+    };
+    Ok((builder, info))
+}
+
+fn error_kind(e: &BridgeParseError) -> &'static str {
+    match e {
+        BridgeParseError::Empty => "empty",
+        BridgeParseError::InvalidPtOrAddr { .. } | BridgeParseError::InvalidIpAddrOrPt { .. } => {
+            "invalid_address"
+        }
+        BridgeParseError::InvalidIdentityOrParameter { .. } => "invalid_identity",
+        BridgeParseError::MultipleIdentitiesOfSameType { .. } => "duplicate_identity",
+        BridgeParseError::UnsupportedIdentityType { .. } => "unsupported_identity",
+        BridgeParseError::NoRsaIdentity => "missing_rsa_identity",
+        BridgeParseError::DirectParametersNotAllowed => "unexpected_parameters",
+        BridgeParseError::PluggableTransportsNotSupported { .. } => "transport_unsupported",
+        _ => "invalid",
+    }
+}
+
+pub(crate) fn parse_report(line: &str) -> ParseReport {
+    match parse_line(line) {
+        Ok((_, bridge)) => ParseReport::Valid {
+            valid: true,
+            bridge,
+        },
+        Err(error) => ParseReport::Invalid {
+            valid: false,
+            error,
+        },
+    }
+}
+
+/// Check that the bridge's ORPort accepts TCP connections, trying each
+/// address in turn. Blocks for up to `timeout` per address.
+///
+/// This shows the bridge is reachable from this network; it does not
+/// authenticate the bridge's identity.
+pub(crate) fn test(line: &str, timeout: Duration) -> TestReport {
+    let info = match parse_line(line) {
+        Ok((_, info)) => info,
+        Err(error) => {
+            return TestReport {
+                valid: false,
+                reachable: false,
+                address: None,
+                latency_ms: None,
+                error: Some(error),
+            }
+        }
+    };
+
+    let mut last = None;
+    for addr in info
+        .addrs
+        .iter()
+        .filter_map(|a| a.parse::<SocketAddr>().ok())
+    {
+        let started = Instant::now();
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(_) => {
+                return TestReport {
+                    valid: true,
+                    reachable: true,
+                    address: Some(addr.to_string()),
+                    latency_ms: Some(started.elapsed().as_millis() as u64),
+                    error: None,
+                }
+            }
+            Err(e) => last = Some((addr, e)),
+        }
+    }
+
+    let (address, error) = match last {
+        Some((addr, e)) => (
+            Some(addr.to_string()),
+            BridgeLineError {
+                kind: if e.kind() == std::io::ErrorKind::TimedOut {
+                    "timeout"
+                } else {
+                    "unreachable"
+                },
+                message: e.to_string(),
+            },
+        ),
+        None => (
+            None,
+            BridgeLineError {
+                kind: "no_address",
+                message: "bridge line has no address to test".into(),
+            },
+        ),
+    };
+    TestReport {
+        valid: true,
+        reachable: false,
+        address,
+        latency_ms: None,
+        error: Some(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINGERPRINT: &str = "4352E58420E68F5E40BF7C74FADDCCD9D1349413";
+
+    #[test]
+    fn direct_bridges() {
+        let (_, info) = parse_line(&format!("192.0.2.1:443 {}", FINGERPRINT)).unwrap();
+        assert_eq!(info.addrs, ["192.0.2.1:443"]);
+        assert_eq!(info.rsa_id.as_deref(), Some(FINGERPRINT));
+        assert!(info.ed_id.is_none());
+
+        let line = format!("Bridge [2001:db8::1]:9001 {}", FINGERPRINT.to_lowercase());
+        let (_, info) = parse_line(&line).unwrap();
+        assert_eq!(info.addrs, ["[2001:db8::1]:9001"]);
+        assert_eq!(info.rsa_id.as_deref(), Some(FINGERPRINT));
+    }
+
+    #[test]
+    fn bad_lines() {
+        for (line, kind) in [
+            ("", "empty"),
+            ("192.0.2.1:99999", "invalid_address"),
+            ("obfs4 192.0.2.1:443", "transport_unsupported"),
+            ("192.0.2.1:443", "missing_rsa_identity"),
+            ("192.0.2.1:443 nothex", "invalid_identity"),
+            (
+                &*format!("192.0.2.1:443 {} {}", FINGERPRINT, FINGERPRINT),
+                "duplicate_identity",
+            ),
+            (
+                &*format!("192.0.2.1:443 {} key=value", FINGERPRINT),
+                "unexpected_parameters",
+            ),
+        ] {
+            let Err(error) = parse_line(line) else {
+                panic!("{:?} parsed", line);
+            };
+            assert_eq!(error.kind, kind, "{:?}: {}", line, error.message);
+        }
+    }
+}
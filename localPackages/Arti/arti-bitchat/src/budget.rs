@@ -0,0 +1,132 @@
+//! Outbound connection budgets, for battery awareness
+//!
+//! Every connect and circuit costs radio time. With `budget.connects_per_minute`
+//! or `budget.circuits` set, `budget_exceeded` is emitted when SOCKS connects
+//! over the last minute, or circuits carrying streams, go over the limit, and
+//! `budget_cleared` once they are back down to three quarters of it. The app
+//! can then hold back background work such as link previews and avatar
+//! fetches while on battery. Nothing is refused; the budgets only inform.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::events::{self, Event};
+use crate::metrics;
+
+/// Window connects are counted over
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// How often budgets are checked for having cleared
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Budget {
+    /// `budget.connects_per_minute`
+    ConnectsPerMinute,
+    /// `budget.circuits`
+    Circuits,
+}
+
+struct State {
+    /// When each connect of the last minute started
+    connects: VecDeque<Instant>,
+    connects_exceeded: bool,
+    circuits_exceeded: bool,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    connects: VecDeque::new(),
+    connects_exceeded: false,
+    circuits_exceeded: false,
+});
+
+/// Count a SOCKS connect starting now
+pub(crate) fn note_connect(config: &Config) {
+    if config.budget_connects_per_minute == 0 && config.budget_circuits == 0 {
+        return;
+    }
+    if let Ok(mut state) = STATE.lock() {
+        state.connects.push_back(Instant::now());
+    }
+    check(config);
+}
+
+/// Check the budgets every few seconds, so a cleared one is reported while
+/// nothing connects; runs until aborted
+pub(crate) async fn watch(config: Config) {
+    let mut ticks = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        ticks.tick().await;
+        check(&config);
+    }
+}
+
+/// Forget the connects counted so far
+pub(crate) fn clear() {
+    if let Ok(mut state) = STATE.lock() {
+        state.connects.clear();
+        state.connects_exceeded = false;
+        state.circuits_exceeded = false;
+    }
+}
+
+fn check(config: &Config) {
+    let circuits = metrics::active_circuits() as u64;
+    let changes = {
+        let Ok(mut state) = STATE.lock() else {
+            return;
+        };
+        let State {
+            connects,
+            connects_exceeded,
+            circuits_exceeded,
+        } = &mut *state;
+        while connects.front().is_some_and(|at| at.elapsed() >= WINDOW) {
+            connects.pop_front();
+        }
+        [
+            update(
+                Budget::ConnectsPerMinute,
+                connects.len() as u64,
+                config.budget_connects_per_minute,
+                connects_exceeded,
+            ),
+            update(
+                Budget::Circuits,
+                circuits,
+                config.budget_circuits,
+                circuits_exceeded,
+            ),
+        ]
+    };
+    for event in changes.into_iter().flatten() {
+        events::emit(event);
+    }
+}
+
+/// The event for `budget` if `value` crossed `limit` (0 for none) either way
+fn update(budget: Budget, value: u64, limit: u64, exceeded: &mut bool) -> Option<Event> {
+    if !*exceeded && limit != 0 && value > limit {
+        *exceeded = true;
+        tracing::debug!("Over the {:?} budget: {} > {}", budget, value, limit);
+        Some(Event::BudgetExceeded {
+            budget,
+            value,
+            limit,
+        })
+    } else if *exceeded && (limit == 0 || value <= limit * 3 / 4) {
+        *exceeded = false;
+        Some(Event::BudgetCleared {
+            budget,
+            value,
+            limit,
+        })
+    } else {
+        None
+    }
+}
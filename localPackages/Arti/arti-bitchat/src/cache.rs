@@ -0,0 +1,240 @@
+//! Pruning and sizing of the directory cache
+//!
+//! Arti drops directory documents on its own schedule, but never gives the
+//! space back: its SQLite database only grows, and blobs left behind by a
+//! killed session stay in `dir_blobs`. On iOS the whole container is evicted
+//! once it gets too big. At each start, before Arti opens the cache,
+//! microdescriptors and consensuses older than `storage.microdescs_max_age_ms`
+//! and `storage.consensuses_max_age_ms` are deleted, orphaned blobs removed,
+//! and the database vacuumed once enough of it is unused. `arti_storage_report`
+//! shows where the bytes go.
+//!
+//! Onion service descriptors are kept in memory only by Arti 0.38, so there
+//! are none on disk to prune.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+
+use crate::config::Config;
+
+/// Arti's directory database, blob directory and lock, within the cache directory
+const DATABASE: &str = "dir.sqlite3";
+const BLOBS: &str = "dir_blobs";
+const LOCK: &str = "dir.lock";
+
+/// Unused space in the database worth a vacuum
+const VACUUM_THRESHOLD: u64 = 4 << 20;
+
+/// Consensus blobs, as Arti tells them apart from other kinds of blob
+const CONSENSUS_BLOBS: &str = "(type LIKE 'con_%' OR type = 'sha3-256')";
+
+/// Documents of one kind in the cache
+#[derive(Default, Serialize)]
+pub(crate) struct Documents {
+    count: u64,
+    bytes: u64,
+}
+
+/// Where the bytes of a data directory go
+#[derive(Default, Serialize)]
+pub(crate) struct Report {
+    total_bytes: u64,
+    /// Guards, keys, statistics and the rest of Arti's state
+    state_bytes: u64,
+    cache_bytes: u64,
+    consensuses: Documents,
+    microdescriptors: Documents,
+    router_descriptors: Documents,
+    bridge_descriptors: Documents,
+    authority_certificates: Documents,
+    /// Free pages in the database and orphaned blobs, which pruning gives back
+    unused_bytes: u64,
+}
+
+/// Prune the cache in `dir`, unless something else holds it
+pub(crate) fn prune(dir: &Path, config: &Config) {
+    if !dir.join(DATABASE).exists() {
+        return;
+    }
+    let mut lock = match fslock::LockFile::open(&dir.join(LOCK)) {
+        Ok(lock) => lock,
+        Err(e) => {
+            tracing::warn!("Not pruning the directory cache: {}", e);
+            return;
+        }
+    };
+    if !lock.try_lock().unwrap_or(false) {
+        tracing::debug!("Not pruning the directory cache: it is in use");
+        return;
+    }
+    match prune_locked(dir, config) {
+        Ok(0) => {}
+        Ok(freed) => tracing::info!("Pruned {} bytes from the directory cache", freed),
+        Err(e) => tracing::warn!("Failed to prune the directory cache: {}", e),
+    }
+}
+
+/// Returns roughly how many bytes were freed
+fn prune_locked(
+    dir: &Path,
+    config: &Config,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let before = dir_size(dir);
+    let mut conn = Connection::open(dir.join(DATABASE))?;
+    let tx = conn.transaction()?;
+    if let Some(age) = config.microdescs_max_age {
+        tx.execute(
+            "DELETE FROM Microdescs WHERE last_listed < datetime('now', ?1)",
+            [ago(age)],
+        )?;
+    }
+    if let Some(age) = config.consensuses_max_age {
+        tx.execute(
+            "DELETE FROM Consensuses WHERE valid_until < datetime('now', ?1)",
+            [ago(age)],
+        )?;
+    }
+    let unreferenced: Vec<String> = tx
+        .prepare(&format!(
+            "SELECT filename FROM ExtDocs WHERE {} AND NOT EXISTS \
+             (SELECT digest FROM Consensuses WHERE Consensuses.digest = ExtDocs.digest)",
+            CONSENSUS_BLOBS
+        ))?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    for filename in &unreferenced {
+        tx.execute("DELETE FROM ExtDocs WHERE filename = ?1", [filename])?;
+    }
+    tx.commit()?;
+
+    // Including those removed above
+    for blob in orphaned_blobs(&conn, dir)? {
+        fs::remove_file(blob)?;
+    }
+
+    if free_bytes(&conn)? >= VACUUM_THRESHOLD {
+        conn.execute_batch("VACUUM")?;
+    }
+    drop(conn);
+    Ok(before.saturating_sub(dir_size(dir)))
+}
+
+/// `age` as an SQLite date modifier
+fn ago(age: Duration) -> String {
+    format!("-{} seconds", age.as_secs())
+}
+
+fn free_bytes(conn: &Connection) -> rusqlite::Result<u64> {
+    let pages: u64 = conn.query_row("PRAGMA freelist_count", [], |row| row.get(0))?;
+    let page_size: u64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+    Ok(pages * page_size)
+}
+
+/// Size up the state directory `state` and the cache directory `cache`
+pub(crate) fn report(state: &Path, cache: &Path) -> Report {
+    let mut report = Report {
+        state_bytes: dir_size(state),
+        cache_bytes: dir_size(cache),
+        ..Report::default()
+    };
+    report.total_bytes = report.state_bytes + report.cache_bytes;
+    if let Err(e) = count_documents(cache, &mut report) {
+        tracing::debug!("Could not read the directory cache: {}", e);
+    }
+    report
+}
+
+fn count_documents(
+    cache: &Path,
+    report: &mut Report,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let path = cache.join(DATABASE);
+    if !path.exists() {
+        return Ok(());
+    }
+    // Read-only, so a running Arti is not disturbed
+    let conn = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let count = |table: &str| -> rusqlite::Result<Documents> {
+        conn.query_row(
+            &format!(
+                "SELECT COUNT(*), COALESCE(SUM(LENGTH(contents)), 0) FROM {}",
+                table
+            ),
+            [],
+            |row| {
+                Ok(Documents {
+                    count: row.get(0)?,
+                    bytes: row.get(1)?,
+                })
+            },
+        )
+    };
+    report.microdescriptors = count("Microdescs")?;
+    report.router_descriptors = count("RouterDescs")?;
+    report.bridge_descriptors = count("BridgeDescs")?;
+    report.authority_certificates = count("Authcerts")?;
+
+    let blobs: Vec<String> = conn
+        .prepare(&format!(
+            "SELECT filename FROM ExtDocs WHERE {}",
+            CONSENSUS_BLOBS
+        ))?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    report.consensuses = Documents {
+        count: blobs.len() as u64,
+        bytes: blobs
+            .iter()
+            .map(|f| file_size(&cache.join(BLOBS).join(f)))
+            .sum(),
+    };
+    let orphaned: u64 = orphaned_blobs(&conn, cache)?
+        .iter()
+        .map(|p| file_size(p))
+        .sum();
+    report.unused_bytes = free_bytes(&conn)? + orphaned;
+    Ok(())
+}
+
+/// Files in the blob directory of the cache in `dir` that Arti does not list
+fn orphaned_blobs(conn: &Connection, dir: &Path) -> rusqlite::Result<Vec<PathBuf>> {
+    let listed: HashSet<String> = conn
+        .prepare("SELECT filename FROM ExtDocs")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    let Ok(entries) = fs::read_dir(dir.join(BLOBS)) else {
+        return Ok(Vec::new());
+    };
+    Ok(entries
+        .flatten()
+        .filter(|e| !listed.contains(&*e.file_name().to_string_lossy()))
+        .map(|e| e.path())
+        .collect())
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Bytes of the files under `dir`
+fn dir_size(dir: &Path) -> u64 {
+    fn walk(dir: &Path) -> io::Result<u64> {
+        let mut total = 0;
+        for entry in fs::read_dir(dir)?.flatten() {
+            let meta = entry.metadata()?;
+            total += if meta.is_dir() {
+                walk(&entry.path()).unwrap_or(0)
+            } else {
+                meta.len()
+            };
+        }
+        Ok(total)
+    }
+    walk(dir).unwrap_or(0)
+}
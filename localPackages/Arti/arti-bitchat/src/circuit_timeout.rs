@@ -0,0 +1,165 @@
+//! Circuit timeouts per QoS class
+//!
+//! Arti learns how long circuits take to build and gives each build about
+//! that long, but a stream waits for its circuit through Arti's retries too,
+//! which on a bad network adds up to far longer than a chat send should
+//! hang. With `circuit.interactive_timeout` or `circuit.bulk_timeout` set, a
+//! SOCKS connect of that class (see [`sockopt`](crate::sockopt)) that has no
+//! circuit within the limit is moved to a new one, up to [`FAILOVERS`]
+//! times; the last attempt waits as long as Arti would. The limit is either
+//! fixed or the timeout Arti learned, optionally clamped to a range.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use arti_client::TorClient;
+use serde::Serialize;
+use tor_circmgr::timeouts::Action;
+
+use crate::clock::TorRuntime;
+use crate::config::Config;
+use crate::sockopt::QosClass;
+
+/// Times a connect may move to a new circuit before waiting as Arti would
+pub(crate) const FAILOVERS: u32 = 2;
+
+/// Hops in the circuits whose learned build timeout is used
+const CIRCUIT_LENGTH: usize = 3;
+
+static FAILOVER_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// How long a connect of one class waits for a circuit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CircuitTimeout {
+    /// As long as Arti does, retries included
+    Arti,
+    /// The build timeout Arti learned, clamped to `min..=max`
+    Learned {
+        min: Duration,
+        max: Duration,
+    },
+    Fixed(Duration),
+}
+
+impl CircuitTimeout {
+    /// Parse `0`, `auto`, `<ms>` or `<min ms>-<max ms>`
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        let ms = |s: &str| s.trim().parse().ok().map(Duration::from_millis);
+        match s {
+            "0" => Some(CircuitTimeout::Arti),
+            "auto" => Some(CircuitTimeout::Learned {
+                min: Duration::ZERO,
+                max: Duration::MAX,
+            }),
+            _ => match s.split_once('-') {
+                Some((min, max)) => {
+                    let (min, max) = (ms(min)?, ms(max)?);
+                    (min <= max && !max.is_zero()).then_some(CircuitTimeout::Learned { min, max })
+                }
+                None => ms(s).map(CircuitTimeout::Fixed),
+            },
+        }
+    }
+
+    /// The limit, given the build timeout Arti learned; `None` waits as Arti does
+    fn limit(self, learned: Duration) -> Option<Duration> {
+        match self {
+            CircuitTimeout::Arti => None,
+            CircuitTimeout::Learned { min, max } => Some(learned.clamp(min, max)),
+            CircuitTimeout::Fixed(limit) => Some(limit),
+        }
+    }
+}
+
+impl fmt::Display for CircuitTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CircuitTimeout::Arti => f.write_str("0"),
+            CircuitTimeout::Learned { min, max } if min.is_zero() && *max == Duration::MAX => {
+                f.write_str("auto")
+            }
+            CircuitTimeout::Learned { min, max } => {
+                write!(f, "{}-{}", min.as_millis(), max.as_millis())
+            }
+            CircuitTimeout::Fixed(limit) => write!(f, "{}", limit.as_millis()),
+        }
+    }
+}
+
+/// Circuit timeouts, for the status sheet
+#[derive(Serialize)]
+pub(crate) struct Timeouts {
+    /// Build timeout Arti learned for a 3-hop circuit
+    learned_ms: u64,
+    /// Limits connects of each class get, null to wait as Arti does
+    interactive_ms: Option<u64>,
+    bulk_ms: Option<u64>,
+    /// Connects moved to a new circuit since launch
+    failovers: u64,
+}
+
+/// The build timeout Arti has learned so far
+fn learned(client: &TorClient<TorRuntime>) -> Duration {
+    client.circmgr().estimate_timeout(&Action::BuildCircuit {
+        length: CIRCUIT_LENGTH,
+    })
+}
+
+/// How long a connect to `port` may wait for a circuit before failing over
+pub(crate) fn limit(
+    client: &TorClient<TorRuntime>,
+    port: u16,
+    config: &Config,
+) -> Option<Duration> {
+    let timeout = match QosClass::of(port, config) {
+        QosClass::Interactive => config.interactive_circuit_timeout,
+        QosClass::Bulk => config.bulk_circuit_timeout,
+    };
+    // Arti's estimate is only taken when needed
+    if timeout == CircuitTimeout::Arti {
+        return None;
+    }
+    timeout.limit(learned(client))
+}
+
+/// Run a connect attempt, giving it up if it has not `attached` to a
+/// circuit within `limit`
+pub(crate) async fn within<F: Future + Unpin>(
+    limit: Option<Duration>,
+    attached: &AtomicBool,
+    mut attempt: F,
+) -> Option<F::Output> {
+    let Some(limit) = limit else {
+        return Some(attempt.await);
+    };
+    tokio::select! {
+        output = &mut attempt => return Some(output),
+        _ = tokio::time::sleep(limit) => {}
+    }
+    if !attached.load(Ordering::SeqCst) {
+        note_failover(limit);
+        return None;
+    }
+    Some(attempt.await)
+}
+
+fn note_failover(limit: Duration) {
+    FAILOVER_COUNT.fetch_add(1, Ordering::Relaxed);
+    tracing::debug!(
+        "No circuit within {}ms, failing over to a new one",
+        limit.as_millis()
+    );
+}
+
+pub(crate) fn snapshot(client: &TorClient<TorRuntime>, config: &Config) -> Timeouts {
+    let learned = learned(client);
+    let ms = |timeout: CircuitTimeout| timeout.limit(learned).map(|d| d.as_millis() as u64);
+    Timeouts {
+        learned_ms: learned.as_millis() as u64,
+        interactive_ms: ms(config.interactive_circuit_timeout),
+        bulk_ms: ms(config.bulk_circuit_timeout),
+        failovers: FAILOVER_COUNT.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,68 @@
+//! The near end of a SOCKS stream
+//!
+//! Normally a TCP connection accepted by a listener; for hosts that would
+//! rather not open a local socket, an in-memory pipe fed by the host (see
+//! [`pipe`](crate::pipe)). The SOCKS handler works on either alike, and
+//! socket options and the bound address only apply where there is a socket.
+
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadHalf, WriteHalf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+use crate::sockopt;
+
+/// A client connection the SOCKS handler can serve
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    type Reader: AsyncRead + Unpin + Send;
+    type Writer: AsyncWrite + Unpin + Send;
+
+    /// Whether failed handshakes count against the source address, see
+    /// [`ratelimit`](crate::ratelimit)
+    const RATE_LIMITED: bool = true;
+
+    fn into_split(self) -> (Self::Reader, Self::Writer);
+
+    /// Tune the connection for a stream to `port`
+    fn apply_options(&self, _port: u16, _config: &Config) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Address reported to the client as bound, if there is one
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl ClientStream for TcpStream {
+    type Reader = OwnedReadHalf;
+    type Writer = OwnedWriteHalf;
+
+    fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        TcpStream::into_split(self)
+    }
+
+    fn apply_options(&self, port: u16, config: &Config) -> io::Result<()> {
+        sockopt::apply(self, port, config)
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+}
+
+impl ClientStream for DuplexStream {
+    type Reader = ReadHalf<DuplexStream>;
+    type Writer = WriteHalf<DuplexStream>;
+
+    // Pipes all come from the host under one address, so one client failing
+    // its handshakes would block every pipe
+    const RATE_LIMITED: bool = false;
+
+    fn into_split(self) -> (Self::Reader, Self::Writer) {
+        tokio::io::split(self)
+    }
+}
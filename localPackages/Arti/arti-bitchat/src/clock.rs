@@ -0,0 +1,96 @@
+//! Clock correction for devices whose clock is wrong
+//!
+//! Tor checks the consensus and relays' certificates against the current
+//! time, so a device clock off by more than a day or so keeps Arti from
+//! bootstrapping (see the `clock_skew_detected` event). The host can learn
+//! the right time elsewhere, e.g. from NTP or the cell network, and hand the
+//! difference to `arti_set_clock_offset`. Arti then reads the time from a
+//! clock corrected by it.
+//!
+//! Arti also checks the freshness of the directory it builds circuits from
+//! against the system clock directly, so the directory tolerances are
+//! widened by the offset as well, when a client is created.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use arti_client::config::TorClientConfigBuilder;
+use tor_rtcompat::{CompoundRuntime, PreferredRuntime, RuntimeSubstExt, SleepProvider};
+
+/// Arti's runtime: the preferred one, with the corrected clock
+pub(crate) type TorRuntime = CompoundRuntime<
+    PreferredRuntime,
+    CorrectedClock,
+    PreferredRuntime,
+    PreferredRuntime,
+    PreferredRuntime,
+    PreferredRuntime,
+    PreferredRuntime,
+>;
+
+/// Arti's defaults for how long before and after its validity a directory
+/// document is accepted
+const PRE_VALID_TOLERANCE: Duration = Duration::from_secs(24 * 60 * 60);
+const POST_VALID_TOLERANCE: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+
+/// The right time minus the device's, in milliseconds
+static OFFSET_MS: AtomicI64 = AtomicI64::new(0);
+
+/// Sleeps as the preferred runtime does, but tells the time corrected by
+/// the offset
+#[derive(Clone)]
+pub(crate) struct CorrectedClock(PreferredRuntime);
+
+impl SleepProvider for CorrectedClock {
+    type SleepFuture = <PreferredRuntime as SleepProvider>::SleepFuture;
+
+    fn sleep(&self, duration: Duration) -> Self::SleepFuture {
+        self.0.sleep(duration)
+    }
+
+    fn wallclock(&self) -> SystemTime {
+        now()
+    }
+}
+
+/// Build Arti's runtime on the current Tokio runtime.
+///
+/// Must be called within the Tokio runtime context.
+pub(crate) fn runtime() -> std::io::Result<TorRuntime> {
+    let base = PreferredRuntime::current()?;
+    Ok(base.with_sleep_provider(CorrectedClock(base.clone())))
+}
+
+pub(crate) fn set_offset(offset_ms: i64) {
+    OFFSET_MS.store(offset_ms, Ordering::SeqCst);
+}
+
+pub(crate) fn offset_ms() -> i64 {
+    OFFSET_MS.load(Ordering::SeqCst)
+}
+
+/// The current time, corrected
+pub(crate) fn now() -> SystemTime {
+    let offset = offset_ms();
+    let system = SystemTime::now();
+    let corrected = if offset >= 0 {
+        system.checked_add(Duration::from_millis(offset as u64))
+    } else {
+        system.checked_sub(Duration::from_millis(offset.unsigned_abs()))
+    };
+    corrected.unwrap_or(system)
+}
+
+/// Widen the directory tolerances so documents that are timely by the
+/// corrected clock pass Arti's checks against the system clock
+pub(crate) fn widen_tolerance(builder: &mut TorClientConfigBuilder) {
+    let offset = offset_ms();
+    let skew = Duration::from_millis(offset.unsigned_abs());
+    let tolerance = builder.directory_tolerance();
+    if offset > 0 {
+        // The device is behind, so documents look not yet valid
+        tolerance.pre_valid_tolerance(PRE_VALID_TOLERANCE + skew);
+    } else if offset < 0 {
+        tolerance.post_valid_tolerance(POST_VALID_TOLERANCE + skew);
+    }
+}
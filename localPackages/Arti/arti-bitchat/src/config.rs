@@ -0,0 +1,1369 @@
+//! Runtime options for the SOCKS proxy and Tor client
+//!
+//! Options are set by key from the host app before `arti_start` and take
+//! effect the next time Arti starts. `BITCHAT_TOR_*` environment variables,
+//! read once on first use, override options set by the app: the key in upper
+//! case with dots as underscores, e.g. `BITCHAT_TOR_SOCKS_LISTEN` for
+//! `socks.listen`. This suits the CLI, tests and desktop packaging.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tor_config::PaddingLevel;
+use tor_netdoc::types::policy::AddrPortPattern;
+
+use crate::audit::Redaction;
+use crate::bridges;
+use crate::circuit_timeout::CircuitTimeout;
+use crate::groups::{CircuitReuse, ReuseRule};
+use crate::loopback;
+use crate::padding;
+use crate::policy::{ExitHostnames, Rejection, Reply, ReplyRule};
+use crate::race::RacePair;
+use crate::socks::Pipelining;
+use crate::testnet;
+
+static CONFIG: Lazy<Mutex<Options>> = Lazy::new(|| Mutex::new(Options::default()));
+
+/// Overrides from the environment, as option key and value
+static ENV_OVERRIDES: Lazy<Vec<(&'static str, String)>> = Lazy::new(env_overrides);
+
+const ENV_PREFIX: &str = "BITCHAT_TOR_";
+
+/// Every option, in the order they are documented
+const KEYS: &[&str] = &[
+    "socks.listen",
+    "socks.handshake_timeout_ms",
+    "socks.handshake_max_bytes",
+    "socks.failure_limit",
+    "socks.failure_window_ms",
+    "socks.failure_block_ms",
+    "audit.path",
+    "audit.max_bytes",
+    "audit.redact",
+    "socks.coalesce_ms",
+    "socks.write_timeout_ms",
+    "socks.max_lifetime_ms",
+    "socks.lifetime_notice_ms",
+    "socks.resolve_retries",
+    "socks.exit_hostnames",
+    "socks.reject_replies",
+    "socks.blackhole_ms",
+    "socks.pipelining",
+    "socks.record_path",
+    "socks.require_token",
+    "socks.max_connecting",
+    "isolation.max_streams",
+    "isolation.max_new_per_second",
+    "isolation.circuits",
+    "isolation.circuits_by_host",
+    "socket.bulk_ports",
+    "socket.nodelay",
+    "socket.keepalive_ms",
+    "socket.send_buffer_bytes",
+    "socket.recv_buffer_bytes",
+    "circuit.interactive_timeout",
+    "circuit.bulk_timeout",
+    "bootstrap.max_attempts",
+    "bootstrap.backoff_initial_ms",
+    "bootstrap.backoff_max_ms",
+    "bootstrap.stall_timeout_ms",
+    "bootstrap.max_restarts",
+    "probe.url",
+    "probe.timeout_ms",
+    "probe.retry_ms",
+    "bridges",
+    "moat.url",
+    "moat.front",
+    "firewall.reachable_addresses",
+    "testnet.authorities",
+    "testnet.fallbacks",
+    "loopback.upstream",
+    "runtime.host_driven",
+    "storage.ephemeral",
+    "storage.microdescs_max_age_ms",
+    "storage.consensuses_max_age_ms",
+    "prefetch.onions",
+    "onion.favorites",
+    "onion.keepalive_ms",
+    "onion.refresh_ms",
+    "onion.race",
+    "onion.race_head_start_ms",
+    "service.client_streams_per_minute",
+    "service.client_max_bytes",
+    "padding.foreground",
+    "padding.dormant",
+    "quota.daily_bytes",
+    "quota.monthly_bytes",
+    "memory.report_interval_ms",
+    "budget.connects_per_minute",
+    "budget.circuits",
+    "tuning.adaptive",
+    "exits.refresh_ms",
+    "exits.country",
+    "status.http_listen",
+    "events.streams",
+    "shutdown.drain_ms",
+];
+
+/// Options as set by the app
+#[derive(Default)]
+struct Options {
+    config: Config,
+    /// Keys the app has set
+    set: HashSet<&'static str>,
+}
+
+/// Where an option's effective value comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Origin {
+    Default,
+    /// `arti_set_option`
+    Api,
+    Environment,
+}
+
+/// An option's effective value
+#[derive(Clone, Serialize)]
+pub(crate) struct Effective {
+    key: &'static str,
+    value: String,
+    origin: Origin,
+}
+
+/// Options whose values are secret: bridge lines, and the onion services of
+/// the user's contacts
+const SECRET_KEYS: &[&str] = &[
+    "bridges",
+    "prefetch.onions",
+    "onion.favorites",
+    "onion.race",
+];
+
+/// Keys the digests of secret values, new in every process so digests cannot
+/// be matched across runs
+static REDACTION_KEY: Lazy<RandomState> = Lazy::new(RandomState::new);
+
+/// Options the running instance started with
+static RUNNING: Mutex<Option<Vec<Effective>>> = Mutex::new(None);
+
+/// Every option, with secrets redacted, for a debug screen
+#[derive(Serialize)]
+pub(crate) struct Export {
+    /// Whether these are the running instance's options, rather than the
+    /// next start's
+    running: bool,
+    options: Vec<Effective>,
+}
+
+/// An option two sets of options disagree on, in exported form
+#[derive(Serialize)]
+pub(crate) struct Change {
+    key: &'static str,
+    old: String,
+    new: String,
+}
+
+/// An export passed back by the app, e.g. one saved earlier
+#[derive(Deserialize)]
+struct Document {
+    options: Vec<DocumentOption>,
+}
+
+#[derive(Deserialize)]
+struct DocumentOption {
+    key: String,
+    value: String,
+}
+
+/// Shortest pruning age, so documents still in use are kept
+const MIN_MAX_AGE_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Smallest byte budget that still fits a minimal greeting and request
+const MIN_HANDSHAKE_BYTES: usize = 16;
+
+/// Error from setting an option
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    UnknownKey,
+    InvalidValue(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::UnknownKey => write!(f, "unknown option"),
+            ConfigError::InvalidValue(reason) => write!(f, "invalid value: {}", reason),
+        }
+    }
+}
+
+/// Address the SOCKS server listens on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ListenSpec {
+    /// Bind this IP on the port passed to `arti_start`
+    Ip(IpAddr),
+    /// Bind this exact address
+    Socket(SocketAddr),
+}
+
+impl ListenSpec {
+    /// Resolve to a concrete address given the port passed to `arti_start`
+    pub(crate) fn resolve(&self, default_port: u16) -> SocketAddr {
+        match self {
+            ListenSpec::Ip(ip) => SocketAddr::new(*ip, default_port),
+            ListenSpec::Socket(addr) => *addr,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    /// `socks.listen`: comma-separated addresses; bare IPs use the start port
+    pub(crate) socks_listen: Vec<ListenSpec>,
+    /// `socks.handshake_timeout_ms`: time allowed for greeting and request
+    pub(crate) handshake_timeout: Duration,
+    /// `socks.handshake_max_bytes`: bytes a client may send before CONNECT completes
+    pub(crate) handshake_max_bytes: usize,
+    /// `socks.failure_limit`: failed handshakes from one source before it is refused; 0 disables
+    pub(crate) failure_limit: u32,
+    /// `socks.failure_window_ms`: window over which failures are counted
+    pub(crate) failure_window: Duration,
+    /// `socks.failure_block_ms`: how long a source over the limit is refused
+    pub(crate) failure_block: Duration,
+    /// `audit.path`: audit log file, relative to the data directory; empty disables
+    pub(crate) audit_path: Option<PathBuf>,
+    /// `audit.max_bytes`: size at which the audit log is rotated; 0 never rotates
+    pub(crate) audit_max_bytes: u64,
+    /// `audit.redact`: `none`, `host` or `all`
+    pub(crate) audit_redact: Redaction,
+    /// `shutdown.drain_ms`: time open connections get to close before being cut
+    pub(crate) shutdown_drain: Duration,
+    /// `socks.coalesce_ms`: how long small writes towards Tor wait to fill a cell; zero flushes at once
+    pub(crate) coalesce_window: Duration,
+    /// `socks.write_timeout_ms`: time a write towards a SOCKS client may block before the stream is closed; zero waits forever
+    pub(crate) client_write_timeout: Duration,
+    /// `socks.max_lifetime_ms`: how long a stream may stay open; zero for no limit
+    pub(crate) max_stream_lifetime: Duration,
+    /// `socks.lifetime_notice_ms`: how long before its lifetime ends a stream announces it
+    pub(crate) lifetime_notice: Duration,
+    /// `socks.resolve_retries`: extra exits to try when one fails to resolve a hostname
+    pub(crate) resolve_retries: u32,
+    /// `socks.exit_hostnames`: `reject` or `strip` legacy `.exit` names
+    pub(crate) exit_hostnames: ExitHostnames,
+    /// `socks.reject_replies`: `rule=reply` overrides of how refused requests are answered
+    pub(crate) reject_replies: Vec<ReplyRule>,
+    /// `socks.blackhole_ms`: how long a blackholed request is held before being closed
+    pub(crate) blackhole_delay: Duration,
+    /// `socks.pipelining`: `tunnel` or `reject` a SOCKS handshake a client repeats as stream data
+    pub(crate) pipelining: Pipelining,
+    /// `socks.record_path`: SOCKS session recording, relative to the data directory; empty disables
+    pub(crate) record_path: Option<PathBuf>,
+    /// `socks.require_token`: clients must give the per-start token as their username
+    pub(crate) require_token: bool,
+    /// `socks.max_connecting`: Tor connects in progress at once, shared fairly between groups; 0 is unlimited
+    pub(crate) max_connecting: usize,
+    /// `isolation.max_streams`: open streams per isolation group; 0 is unlimited
+    pub(crate) group_max_streams: usize,
+    /// `isolation.max_new_per_second`: new streams per second per isolation group; 0 is unlimited
+    pub(crate) group_max_new_per_second: u32,
+    /// `isolation.circuits`: `token`, `destination` or `stream`, how far streams of a group share circuits
+    pub(crate) circuit_reuse: CircuitReuse,
+    /// `isolation.circuits_by_host`: `host=mode` overrides of `isolation.circuits`
+    pub(crate) circuit_reuse_by_host: Vec<ReuseRule>,
+    /// `socket.bulk_ports`: destination ports whose clients get bulk socket options
+    pub(crate) bulk_ports: Vec<u16>,
+    /// `socket.nodelay`: TCP_NODELAY on client sockets; `None` (auto) decides by class
+    pub(crate) nodelay: Option<bool>,
+    /// `socket.keepalive_ms`: idle time before keepalive probes on client sockets; zero disables
+    pub(crate) keepalive: Duration,
+    /// `socket.send_buffer_bytes`: client socket send buffer; `None` for the class default
+    pub(crate) send_buffer_bytes: Option<usize>,
+    /// `socket.recv_buffer_bytes`: client socket receive buffer; `None` for the class default
+    pub(crate) recv_buffer_bytes: Option<usize>,
+    /// `circuit.interactive_timeout`: how long interactive connects wait for a circuit before failing over
+    pub(crate) interactive_circuit_timeout: CircuitTimeout,
+    /// `circuit.bulk_timeout`: the same for bulk connects
+    pub(crate) bulk_circuit_timeout: CircuitTimeout,
+    /// `bootstrap.max_attempts`: give up after this many failed bootstraps; 0 retries forever
+    pub(crate) bootstrap_max_attempts: u32,
+    /// `bootstrap.backoff_initial_ms`: delay before the first retry
+    pub(crate) bootstrap_backoff_initial: Duration,
+    /// `bootstrap.backoff_max_ms`: cap on the doubling retry delay
+    pub(crate) bootstrap_backoff_max: Duration,
+    /// `bootstrap.stall_timeout_ms`: progress stuck this long counts as a hang; zero never does
+    pub(crate) bootstrap_stall_timeout: Duration,
+    /// `bootstrap.max_restarts`: new clients created for hung bootstraps per start
+    pub(crate) bootstrap_max_restarts: u32,
+    /// `probe.url`: HTTPS URL fetched directly before bootstrap to detect captive portals
+    pub(crate) probe_url: Option<String>,
+    /// `probe.timeout_ms`: time allowed for the probe
+    pub(crate) probe_timeout: Duration,
+    /// `probe.retry_ms`: how often to re-probe while a portal is present
+    pub(crate) probe_retry: Duration,
+    /// `bridges`: newline-separated bridge lines; empty connects directly
+    pub(crate) bridges: Vec<String>,
+    /// `moat.url`: moat endpoint bridges are requested from
+    pub(crate) moat_url: String,
+    /// `moat.front`: domain to connect to instead of the moat host, for domain fronting
+    pub(crate) moat_front: Option<String>,
+    /// `firewall.reachable_addresses`: addresses Tor may connect to directly; empty allows all
+    pub(crate) reachable_addresses: Vec<AddrPortPattern>,
+    /// `testnet.authorities`: `DirAuthority` lines of a test network to use instead of the public one
+    pub(crate) testnet_authorities: Vec<String>,
+    /// `testnet.fallbacks`: `addr:orport rsa_id ed25519_id` lines of the test network's relays
+    pub(crate) testnet_fallbacks: Vec<String>,
+    /// `loopback.upstream`: where a `loopback` build sends every stream; unset connects to each destination
+    pub(crate) loopback_upstream: Option<SocketAddr>,
+    /// `runtime.host_driven`: run Arti only within `arti_run_for`; read when the runtime is created
+    pub(crate) host_driven: bool,
+    /// `storage.ephemeral`: keep Tor state only for the session
+    pub(crate) ephemeral: bool,
+    /// `storage.microdescs_max_age_ms`: prune microdescriptors unlisted this long; `None` leaves it to Arti
+    pub(crate) microdescs_max_age: Option<Duration>,
+    /// `storage.consensuses_max_age_ms`: prune consensuses expired this long; `None` leaves it to Arti
+    pub(crate) consensuses_max_age: Option<Duration>,
+    /// `prefetch.onions`: onion services `arti_prefetch` looks up, as host and port
+    pub(crate) prefetch_onions: Vec<(String, u16)>,
+    /// `onion.favorites`: onion services whose circuits are kept open, as host and port
+    pub(crate) favorite_onions: Vec<(String, u16)>,
+    /// `onion.keepalive_ms`: how often a stream is opened to each favorite
+    pub(crate) favorite_keepalive: Duration,
+    /// `onion.refresh_ms`: how often a stream is opened to each pinned peer
+    pub(crate) pin_refresh: Duration,
+    /// `onion.race`: destinations whose onion and clearnet addresses are raced
+    pub(crate) race_pairs: Vec<RacePair>,
+    /// `onion.race_head_start_ms`: how long a raced onion connects alone
+    pub(crate) race_head_start: Duration,
+    /// `service.client_streams_per_minute`: streams a hosted service's client may open a minute; zero for no limit
+    pub(crate) service_client_streams_per_minute: u64,
+    /// `service.client_max_bytes`: bytes a hosted service's client may send over one circuit; zero for no limit
+    pub(crate) service_client_max_bytes: u64,
+    /// `padding.foreground`: connection padding while the app is in use
+    pub(crate) padding_foreground: PaddingLevel,
+    /// `padding.dormant`: connection padding after `arti_go_dormant`
+    pub(crate) padding_dormant: PaddingLevel,
+    /// `quota.daily_bytes`: bytes per UTC day before new connections are refused; 0 for no quota
+    pub(crate) quota_daily_bytes: u64,
+    /// `quota.monthly_bytes`: bytes per UTC calendar month; 0 for no quota
+    pub(crate) quota_monthly_bytes: u64,
+    /// `memory.report_interval_ms`: how often to emit `memory_usage`; zero for never
+    pub(crate) memory_report_interval: Duration,
+    /// `budget.connects_per_minute`: SOCKS connects a minute beyond which `budget_exceeded` is emitted; zero for no budget
+    pub(crate) budget_connects_per_minute: u64,
+    /// `budget.circuits`: circuits carrying streams beyond which `budget_exceeded` is emitted; zero for no budget
+    pub(crate) budget_circuits: u64,
+    /// `tuning.adaptive`: adjust circuit settings to measured network quality
+    pub(crate) adaptive_tuning: bool,
+    /// `exits.refresh_ms`: how old the exit list may get before it is fetched again; zero never fetches
+    pub(crate) exit_list_refresh: Duration,
+    /// `exits.country`: two-letter code of the country exits must be in; empty for any
+    pub(crate) exit_country: Option<String>,
+    /// `status.http_listen`: loopback address to serve the status page on; empty disables
+    pub(crate) status_page_listen: Option<SocketAddr>,
+    /// `events.streams`: emit progress events for each SOCKS stream
+    pub(crate) stream_events: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            socks_listen: vec![ListenSpec::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST))],
+            handshake_timeout: Duration::from_secs(10),
+            handshake_max_bytes: 2048,
+            failure_limit: 10,
+            failure_window: Duration::from_secs(60),
+            failure_block: Duration::from_secs(300),
+            audit_path: None,
+            record_path: None,
+            require_token: false,
+            max_connecting: 0,
+            group_max_streams: 0,
+            group_max_new_per_second: 0,
+            circuit_reuse: CircuitReuse::Token,
+            circuit_reuse_by_host: Vec::new(),
+            bulk_ports: Vec::new(),
+            nodelay: None,
+            keepalive: Duration::ZERO,
+            send_buffer_bytes: None,
+            recv_buffer_bytes: None,
+            interactive_circuit_timeout: CircuitTimeout::Arti,
+            bulk_circuit_timeout: CircuitTimeout::Arti,
+            audit_max_bytes: 1024 * 1024,
+            audit_redact: Redaction::Host,
+            shutdown_drain: Duration::from_secs(2),
+            coalesce_window: Duration::ZERO,
+            client_write_timeout: Duration::from_secs(60),
+            max_stream_lifetime: Duration::ZERO,
+            lifetime_notice: Duration::from_secs(60),
+            resolve_retries: 0,
+            exit_hostnames: ExitHostnames::Reject,
+            reject_replies: Vec::new(),
+            blackhole_delay: Duration::from_secs(30),
+            pipelining: Pipelining::Tunnel,
+            bootstrap_max_attempts: 0,
+            bootstrap_backoff_initial: Duration::from_secs(1),
+            bootstrap_backoff_max: Duration::from_secs(300),
+            bootstrap_stall_timeout: Duration::from_secs(120),
+            bootstrap_max_restarts: 2,
+            probe_url: None,
+            probe_timeout: Duration::from_secs(5),
+            probe_retry: Duration::from_secs(15),
+            bridges: Vec::new(),
+            moat_url: "https://bridges.torproject.org/moat".into(),
+            moat_front: None,
+            reachable_addresses: Vec::new(),
+            testnet_authorities: Vec::new(),
+            testnet_fallbacks: Vec::new(),
+            loopback_upstream: None,
+            host_driven: false,
+            ephemeral: false,
+            microdescs_max_age: None,
+            consensuses_max_age: None,
+            prefetch_onions: Vec::new(),
+            favorite_onions: Vec::new(),
+            favorite_keepalive: Duration::from_secs(300),
+            pin_refresh: Duration::from_secs(1800),
+            race_pairs: Vec::new(),
+            race_head_start: Duration::from_secs(3),
+            service_client_streams_per_minute: 0,
+            service_client_max_bytes: 0,
+            padding_foreground: PaddingLevel::Normal,
+            padding_dormant: PaddingLevel::Reduced,
+            quota_daily_bytes: 0,
+            quota_monthly_bytes: 0,
+            memory_report_interval: Duration::ZERO,
+            budget_connects_per_minute: 0,
+            budget_circuits: 0,
+            adaptive_tuning: false,
+            exit_list_refresh: Duration::ZERO,
+            exit_country: None,
+            status_page_listen: None,
+            stream_events: false,
+        }
+    }
+}
+
+impl Config {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            "socks.listen" => self.socks_listen = parse_listen_list(value)?,
+            "socks.handshake_timeout_ms" => {
+                self.handshake_timeout = Duration::from_millis(parse_number(value, 1)?)
+            }
+            "socks.handshake_max_bytes" => {
+                self.handshake_max_bytes = parse_number(value, MIN_HANDSHAKE_BYTES as u64)? as usize
+            }
+            "socks.failure_limit" => {
+                self.failure_limit = parse_number(value, 0)?
+                    .try_into()
+                    .map_err(|_| ConfigError::InvalidValue("limit too large".into()))?
+            }
+            "socks.failure_window_ms" => {
+                self.failure_window = Duration::from_millis(parse_number(value, 1)?)
+            }
+            "socks.failure_block_ms" => {
+                self.failure_block = Duration::from_millis(parse_number(value, 1)?)
+            }
+            "audit.path" => {
+                self.audit_path = Some(value).filter(|v| !v.is_empty()).map(PathBuf::from)
+            }
+            "socks.record_path" => {
+                self.record_path = Some(value).filter(|v| !v.is_empty()).map(PathBuf::from)
+            }
+            "socks.require_token" => self.require_token = parse_bool(value)?,
+            "audit.max_bytes" => self.audit_max_bytes = parse_number(value, 0)?,
+            "audit.redact" => {
+                self.audit_redact = Redaction::parse(value)
+                    .ok_or_else(|| ConfigError::InvalidValue("expected none, host or all".into()))?
+            }
+            "socks.coalesce_ms" => {
+                self.coalesce_window = Duration::from_millis(parse_number(value, 0)?)
+            }
+            "socks.write_timeout_ms" => {
+                self.client_write_timeout = Duration::from_millis(parse_number(value, 0)?)
+            }
+            "socks.max_lifetime_ms" => {
+                self.max_stream_lifetime = Duration::from_millis(parse_number(value, 0)?)
+            }
+            "socks.lifetime_notice_ms" => {
+                self.lifetime_notice = Duration::from_millis(parse_number(value, 0)?)
+            }
+            "socks.resolve_retries" => {
+                self.resolve_retries = parse_number(value, 0)?
+                    .try_into()
+                    .map_err(|_| ConfigError::InvalidValue("too many retries".into()))?
+            }
+            "socks.exit_hostnames" => {
+                self.exit_hostnames = ExitHostnames::parse(value)
+                    .ok_or_else(|| ConfigError::InvalidValue("expected reject or strip".into()))?
+            }
+            "socks.reject_replies" => self.reject_replies = parse_reply_rules(value)?,
+            "socks.blackhole_ms" => {
+                self.blackhole_delay = Duration::from_millis(parse_number(value, 0)?)
+            }
+            "socks.pipelining" => {
+                self.pipelining = Pipelining::parse(value)
+                    .ok_or_else(|| ConfigError::InvalidValue("expected tunnel or reject".into()))?
+            }
+            "socks.max_connecting" => self.max_connecting = parse_number(value, 0)? as usize,
+            "isolation.max_streams" => self.group_max_streams = parse_number(value, 0)? as usize,
+            "isolation.max_new_per_second" => {
+                self.group_max_new_per_second = parse_number(value, 0)?
+                    .try_into()
+                    .map_err(|_| ConfigError::InvalidValue("rate too high".into()))?
+            }
+            "isolation.circuits" => self.circuit_reuse = parse_reuse(value)?,
+            "isolation.circuits_by_host" => self.circuit_reuse_by_host = parse_reuse_rules(value)?,
+            "socket.bulk_ports" => self.bulk_ports = parse_port_list(value)?,
+            "socket.nodelay" if value == "auto" => self.nodelay = None,
+            "socket.nodelay" => self.nodelay = Some(parse_bool(value)?),
+            "socket.keepalive_ms" => {
+                self.keepalive = Duration::from_millis(parse_number(value, 0)?)
+            }
+            "socket.send_buffer_bytes" => self.send_buffer_bytes = parse_buffer_size(value)?,
+            "socket.recv_buffer_bytes" => self.recv_buffer_bytes = parse_buffer_size(value)?,
+            "circuit.interactive_timeout" => {
+                self.interactive_circuit_timeout = parse_circuit_timeout(value)?
+            }
+            "circuit.bulk_timeout" => self.bulk_circuit_timeout = parse_circuit_timeout(value)?,
+            "bootstrap.max_attempts" => {
+                self.bootstrap_max_attempts = parse_number(value, 0)?
+                    .try_into()
+                    .map_err(|_| ConfigError::InvalidValue("too many attempts".into()))?
+            }
+            "bootstrap.backoff_initial_ms" => {
+                self.bootstrap_backoff_initial = Duration::from_millis(parse_number(value, 1)?)
+            }
+            "bootstrap.backoff_max_ms" => {
+                self.bootstrap_backoff_max = Duration::from_millis(parse_number(value, 1)?)
+            }
+            "bootstrap.stall_timeout_ms" => {
+                self.bootstrap_stall_timeout = Duration::from_millis(parse_number(value, 0)?)
+            }
+            "bootstrap.max_restarts" => {
+                self.bootstrap_max_restarts = parse_number(value, 0)?
+                    .try_into()
+                    .map_err(|_| ConfigError::InvalidValue("too many restarts".into()))?
+            }
+            "probe.url" if value.is_empty() => self.probe_url = None,
+            "probe.url" if value.starts_with("https://") => {
+                self.probe_url = Some(value.to_string())
+            }
+            "probe.url" => {
+                return Err(ConfigError::InvalidValue("expected an https:// URL".into()))
+            }
+            "probe.timeout_ms" => {
+                self.probe_timeout = Duration::from_millis(parse_number(value, 1)?)
+            }
+            "probe.retry_ms" => self.probe_retry = Duration::from_millis(parse_number(value, 1)?),
+            "bridges" => self.bridges = parse_bridge_lines(value)?,
+            "moat.url" if value.starts_with("https://") => self.moat_url = value.to_string(),
+            "moat.url" => return Err(ConfigError::InvalidValue("expected an https:// URL".into())),
+            "moat.front" if value.is_empty() => self.moat_front = None,
+            "moat.front" => self.moat_front = Some(value.to_string()),
+            "firewall.reachable_addresses" => self.reachable_addresses = parse_reachable(value)?,
+            "testnet.authorities" | "testnet.fallbacks"
+                if !testnet::ENABLED && !value.trim().is_empty() =>
+            {
+                return Err(ConfigError::InvalidValue(
+                    "built without the testnet feature".into(),
+                ))
+            }
+            "testnet.authorities" => {
+                self.testnet_authorities = parse_lines(value, testnet::parse_authority)?
+            }
+            "testnet.fallbacks" => {
+                self.testnet_fallbacks = parse_lines(value, testnet::parse_fallback)?
+            }
+            "loopback.upstream" if value.is_empty() => self.loopback_upstream = None,
+            "loopback.upstream" if !loopback::ENABLED => {
+                return Err(ConfigError::InvalidValue(
+                    "built without the loopback feature".into(),
+                ))
+            }
+            "loopback.upstream" => match value.parse::<SocketAddr>() {
+                Ok(addr) => self.loopback_upstream = Some(addr),
+                Err(_) => return Err(ConfigError::InvalidValue("expected addr:port".into())),
+            },
+            "runtime.host_driven" => self.host_driven = parse_bool(value)?,
+            "storage.ephemeral" => self.ephemeral = parse_bool(value)?,
+            "storage.microdescs_max_age_ms" => self.microdescs_max_age = parse_max_age(value)?,
+            "storage.consensuses_max_age_ms" => self.consensuses_max_age = parse_max_age(value)?,
+            "prefetch.onions" => self.prefetch_onions = parse_onion_list(value)?,
+            "onion.favorites" => self.favorite_onions = parse_onion_list(value)?,
+            "onion.keepalive_ms" => {
+                self.favorite_keepalive = Duration::from_millis(parse_number(value, 1000)?)
+            }
+            "onion.refresh_ms" => {
+                self.pin_refresh = Duration::from_millis(parse_number(value, 1000)?)
+            }
+            "onion.race" => self.race_pairs = parse_race_pairs(value)?,
+            "onion.race_head_start_ms" => {
+                self.race_head_start = Duration::from_millis(parse_number(value, 0)?)
+            }
+            "service.client_streams_per_minute" => {
+                self.service_client_streams_per_minute = parse_number(value, 0)?
+            }
+            "service.client_max_bytes" => self.service_client_max_bytes = parse_number(value, 0)?,
+            "padding.foreground" => self.padding_foreground = parse_padding(value)?,
+            "padding.dormant" => self.padding_dormant = parse_padding(value)?,
+            "quota.daily_bytes" => self.quota_daily_bytes = parse_number(value, 0)?,
+            "quota.monthly_bytes" => self.quota_monthly_bytes = parse_number(value, 0)?,
+            "memory.report_interval_ms" => {
+                self.memory_report_interval = Duration::from_millis(parse_number(value, 0)?)
+            }
+            "budget.connects_per_minute" => {
+                self.budget_connects_per_minute = parse_number(value, 0)?
+            }
+            "budget.circuits" => self.budget_circuits = parse_number(value, 0)?,
+            "tuning.adaptive" => self.adaptive_tuning = parse_bool(value)?,
+            "exits.refresh_ms" => {
+                self.exit_list_refresh = Duration::from_millis(parse_number(value, 0)?)
+            }
+            "exits.country" => {
+                let valid = value.is_empty()
+                    || (value.len() == 2 && value.bytes().all(|b| b.is_ascii_alphabetic()));
+                if !valid {
+                    return Err(ConfigError::InvalidValue(
+                        "expected a two-letter country code".into(),
+                    ));
+                }
+                self.exit_country = Some(value.to_ascii_uppercase()).filter(|v| !v.is_empty())
+            }
+            "status.http_listen" if value.is_empty() => self.status_page_listen = None,
+            "status.http_listen" => match value.parse::<SocketAddr>() {
+                Ok(addr) if addr.ip().is_loopback() => self.status_page_listen = Some(addr),
+                _ => {
+                    return Err(ConfigError::InvalidValue(
+                        "expected a loopback address:port".into(),
+                    ))
+                }
+            },
+            "events.streams" => self.stream_events = parse_bool(value)?,
+            "shutdown.drain_ms" => {
+                self.shutdown_drain = Duration::from_millis(parse_number(value, 0)?)
+            }
+            _ => return Err(ConfigError::UnknownKey),
+        }
+        Ok(())
+    }
+
+    /// The value of `key` in the form `set` takes; "" for an unknown key
+    fn get(&self, key: &str) -> String {
+        fn ms(d: Duration) -> String {
+            d.as_millis().to_string()
+        }
+        fn join<T>(items: &[T], sep: &str, f: impl Fn(&T) -> String) -> String {
+            items.iter().map(f).collect::<Vec<_>>().join(sep)
+        }
+        fn onion(&(ref host, port): &(String, u16)) -> String {
+            format!("{}:{}", host, port)
+        }
+        match key {
+            "socks.listen" => join(&self.socks_listen, ",", |spec| match spec {
+                ListenSpec::Ip(ip) => ip.to_string(),
+                ListenSpec::Socket(addr) => addr.to_string(),
+            }),
+            "socks.handshake_timeout_ms" => ms(self.handshake_timeout),
+            "socks.handshake_max_bytes" => self.handshake_max_bytes.to_string(),
+            "socks.failure_limit" => self.failure_limit.to_string(),
+            "socks.failure_window_ms" => ms(self.failure_window),
+            "socks.failure_block_ms" => ms(self.failure_block),
+            "audit.path" => self
+                .audit_path
+                .as_ref()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+            "socks.record_path" => self
+                .record_path
+                .as_ref()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+            "socks.require_token" => self.require_token.to_string(),
+            "socks.max_connecting" => self.max_connecting.to_string(),
+            "isolation.max_streams" => self.group_max_streams.to_string(),
+            "isolation.max_new_per_second" => self.group_max_new_per_second.to_string(),
+            "isolation.circuits" => self.circuit_reuse.as_str().to_string(),
+            "isolation.circuits_by_host" => join(&self.circuit_reuse_by_host, ",", |r| {
+                format!("{}={}", r.host, r.reuse.as_str())
+            }),
+            "socket.bulk_ports" => join(&self.bulk_ports, ",", |p| p.to_string()),
+            "socket.nodelay" => self.nodelay.map_or("auto".to_string(), |n| n.to_string()),
+            "socket.keepalive_ms" => ms(self.keepalive),
+            "socket.send_buffer_bytes" => self.send_buffer_bytes.unwrap_or(0).to_string(),
+            "socket.recv_buffer_bytes" => self.recv_buffer_bytes.unwrap_or(0).to_string(),
+            "circuit.interactive_timeout" => self.interactive_circuit_timeout.to_string(),
+            "circuit.bulk_timeout" => self.bulk_circuit_timeout.to_string(),
+            "audit.max_bytes" => self.audit_max_bytes.to_string(),
+            "audit.redact" => self.audit_redact.as_str().to_string(),
+            "socks.coalesce_ms" => ms(self.coalesce_window),
+            "socks.write_timeout_ms" => ms(self.client_write_timeout),
+            "socks.max_lifetime_ms" => ms(self.max_stream_lifetime),
+            "socks.lifetime_notice_ms" => ms(self.lifetime_notice),
+            "socks.resolve_retries" => self.resolve_retries.to_string(),
+            "socks.exit_hostnames" => self.exit_hostnames.as_str().to_string(),
+            "socks.reject_replies" => join(&self.reject_replies, ",", |r| {
+                format!("{}={}", r.rule.as_str(), r.reply.render())
+            }),
+            "socks.blackhole_ms" => ms(self.blackhole_delay),
+            "socks.pipelining" => self.pipelining.as_str().to_string(),
+            "bootstrap.max_attempts" => self.bootstrap_max_attempts.to_string(),
+            "bootstrap.backoff_initial_ms" => ms(self.bootstrap_backoff_initial),
+            "bootstrap.backoff_max_ms" => ms(self.bootstrap_backoff_max),
+            "bootstrap.stall_timeout_ms" => ms(self.bootstrap_stall_timeout),
+            "bootstrap.max_restarts" => self.bootstrap_max_restarts.to_string(),
+            "probe.url" => self.probe_url.clone().unwrap_or_default(),
+            "probe.timeout_ms" => ms(self.probe_timeout),
+            "probe.retry_ms" => ms(self.probe_retry),
+            "bridges" => self.bridges.join("\n"),
+            "moat.url" => self.moat_url.clone(),
+            "moat.front" => self.moat_front.clone().unwrap_or_default(),
+            "firewall.reachable_addresses" => {
+                join(&self.reachable_addresses, ",", |p| p.to_string())
+            }
+            "testnet.authorities" => self.testnet_authorities.join("\n"),
+            "testnet.fallbacks" => self.testnet_fallbacks.join("\n"),
+            "loopback.upstream" => self
+                .loopback_upstream
+                .map(|a| a.to_string())
+                .unwrap_or_default(),
+            "runtime.host_driven" => self.host_driven.to_string(),
+            "storage.ephemeral" => self.ephemeral.to_string(),
+            "storage.microdescs_max_age_ms" => ms(self.microdescs_max_age.unwrap_or_default()),
+            "storage.consensuses_max_age_ms" => ms(self.consensuses_max_age.unwrap_or_default()),
+            "prefetch.onions" => join(&self.prefetch_onions, ",", onion),
+            "onion.favorites" => join(&self.favorite_onions, ",", onion),
+            "onion.keepalive_ms" => ms(self.favorite_keepalive),
+            "onion.refresh_ms" => ms(self.pin_refresh),
+            "onion.race" => join(&self.race_pairs, ",", |p| {
+                format!("{}={}", p.clearnet, p.onion)
+            }),
+            "onion.race_head_start_ms" => ms(self.race_head_start),
+            "service.client_streams_per_minute" => {
+                self.service_client_streams_per_minute.to_string()
+            }
+            "service.client_max_bytes" => self.service_client_max_bytes.to_string(),
+            "padding.foreground" => padding::as_str(self.padding_foreground).to_string(),
+            "padding.dormant" => padding::as_str(self.padding_dormant).to_string(),
+            "quota.daily_bytes" => self.quota_daily_bytes.to_string(),
+            "quota.monthly_bytes" => self.quota_monthly_bytes.to_string(),
+            "memory.report_interval_ms" => ms(self.memory_report_interval),
+            "budget.connects_per_minute" => self.budget_connects_per_minute.to_string(),
+            "budget.circuits" => self.budget_circuits.to_string(),
+            "tuning.adaptive" => self.adaptive_tuning.to_string(),
+            "exits.refresh_ms" => ms(self.exit_list_refresh),
+            "exits.country" => self.exit_country.clone().unwrap_or_default(),
+            "status.http_listen" => self
+                .status_page_listen
+                .map(|a| a.to_string())
+                .unwrap_or_default(),
+            "events.streams" => self.stream_events.to_string(),
+            "shutdown.drain_ms" => ms(self.shutdown_drain),
+            _ => String::new(),
+        }
+    }
+}
+
+/// Set an option by key; applied on the next start
+pub(crate) fn set_option(key: &str, value: &str) -> Result<(), ConfigError> {
+    let key = KEYS
+        .iter()
+        .find(|k| **k == key.trim())
+        .ok_or(ConfigError::UnknownKey)?;
+    let mut options = CONFIG
+        .lock()
+        .map_err(|_| ConfigError::InvalidValue("config lock poisoned".into()))?;
+    options.config.set(key, value.trim())?;
+    options.set.insert(key);
+    Ok(())
+}
+
+/// The options the next start will use
+pub(crate) fn current() -> Config {
+    let mut config = CONFIG.lock().map(|o| o.config.clone()).unwrap_or_default();
+    for (key, value) in ENV_OVERRIDES.iter() {
+        // Validated when read
+        let _ = config.set(key, value);
+    }
+    config
+}
+
+/// Every option's effective value and where it comes from
+pub(crate) fn effective() -> Vec<Effective> {
+    let config = current();
+    let set = CONFIG.lock().map(|o| o.set.clone()).unwrap_or_default();
+    KEYS.iter()
+        .map(|key| Effective {
+            key,
+            value: config.get(key),
+            origin: if ENV_OVERRIDES.iter().any(|(k, _)| k == key) {
+                Origin::Environment
+            } else if set.contains(key) {
+                Origin::Api
+            } else {
+                Origin::Default
+            },
+        })
+        .collect()
+}
+
+/// Remember the options of the instance starting now, or forget them on stop
+pub(crate) fn note_running(running: bool) {
+    if let Ok(mut snapshot) = RUNNING.lock() {
+        *snapshot = running.then(effective);
+    }
+}
+
+/// The running instance's options, or the next start's when stopped
+pub(crate) fn export() -> Export {
+    let (running, options) = match RUNNING.lock().ok().and_then(|s| s.clone()) {
+        Some(options) => (true, options),
+        None => (false, effective()),
+    };
+    let options = options
+        .into_iter()
+        .map(|o| Effective {
+            value: redact(o.key, o.value),
+            ..o
+        })
+        .collect();
+    Export { running, options }
+}
+
+/// Compare two sets of options: `old` and `new` are exports, or `None` for
+/// the running instance's options (defaults when stopped) and the next
+/// start's respectively
+pub(crate) fn diff(old: Option<&str>, new: Option<&str>) -> Result<Vec<Change>, serde_json::Error> {
+    let old = match old {
+        Some(json) => from_document(json)?,
+        None => match RUNNING.lock().ok().and_then(|s| s.clone()) {
+            Some(options) => options
+                .into_iter()
+                .map(|o| (o.key, redact(o.key, o.value)))
+                .collect(),
+            None => from_config(&Config::default()),
+        },
+    };
+    let new = match new {
+        Some(json) => from_document(json)?,
+        None => from_config(&current()),
+    };
+    Ok(KEYS
+        .iter()
+        .filter(|key| old[*key] != new[*key])
+        .map(|key| Change {
+            key,
+            old: old[key].clone(),
+            new: new[key].clone(),
+        })
+        .collect())
+}
+
+/// Every option of `config`, in exported form
+fn from_config(config: &Config) -> HashMap<&'static str, String> {
+    KEYS.iter()
+        .map(|key| (*key, redact(key, config.get(key))))
+        .collect()
+}
+
+/// Every option of an export; options it leaves out take their defaults,
+/// and ones this build does not know are ignored
+fn from_document(json: &str) -> Result<HashMap<&'static str, String>, serde_json::Error> {
+    let document: Document = serde_json::from_str(json)?;
+    let mut options = from_config(&Config::default());
+    for option in document.options {
+        if let Some(key) = KEYS.iter().find(|k| **k == option.key) {
+            options.insert(key, redact(key, option.value));
+        }
+    }
+    Ok(options)
+}
+
+/// `value` of `key` as exported: secret values become `<redacted:digest>`,
+/// which tells changes apart without revealing what changed
+fn redact(key: &str, value: String) -> String {
+    if value.is_empty() || value.starts_with("<redacted:") || !SECRET_KEYS.contains(&key) {
+        return value;
+    }
+    format!("<redacted:{:08x}>", REDACTION_KEY.hash_one(&value) as u32)
+}
+
+/// Read `BITCHAT_TOR_*` variables, warning about any that are not valid
+fn env_overrides() -> Vec<(&'static str, String)> {
+    let mut overrides = Vec::new();
+    for (name, value) in std::env::vars() {
+        let Some(suffix) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let Some(key) = KEYS
+            .iter()
+            .find(|k| k.to_ascii_uppercase().replace('.', "_") == suffix)
+        else {
+            tracing::warn!("Ignoring {}: unknown option", name);
+            continue;
+        };
+        match Config::default().set(key, value.trim()) {
+            Ok(()) => overrides.push((*key, value.trim().to_string())),
+            Err(e) => tracing::warn!("Ignoring {}: {}", name, e),
+        }
+    }
+    overrides
+}
+
+fn parse_number(value: &str, min: u64) -> Result<u64, ConfigError> {
+    let n: u64 = value
+        .parse()
+        .map_err(|_| ConfigError::InvalidValue(format!("{:?} is not a number", value)))?;
+    if n < min {
+        return Err(ConfigError::InvalidValue(format!(
+            "must be at least {}",
+            min
+        )));
+    }
+    Ok(n)
+}
+
+fn parse_bool(value: &str) -> Result<bool, ConfigError> {
+    match value {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(ConfigError::InvalidValue(format!(
+            "{:?} is not true or false",
+            value
+        ))),
+    }
+}
+
+/// Parse comma-separated port numbers
+fn parse_port_list(value: &str) -> Result<Vec<u16>, ConfigError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse::<u16>()
+                .ok()
+                .filter(|p| *p != 0)
+                .ok_or_else(|| ConfigError::InvalidValue(format!("{:?} is not a port", s)))
+        })
+        .collect()
+}
+
+/// Parse a socket buffer size; 0 means the default
+fn parse_buffer_size(value: &str) -> Result<Option<usize>, ConfigError> {
+    let size = parse_number(value, 0)?;
+    let size: usize = size
+        .try_into()
+        .ok()
+        .filter(|s| *s <= i32::MAX as usize)
+        .ok_or_else(|| ConfigError::InvalidValue("buffer too large".into()))?;
+    Ok(Some(size).filter(|s| *s != 0))
+}
+
+/// Parse a cache pruning age; 0 means Arti's own
+fn parse_max_age(value: &str) -> Result<Option<Duration>, ConfigError> {
+    match parse_number(value, 0)? {
+        0 => Ok(None),
+        ms if ms < MIN_MAX_AGE_MS => Err(ConfigError::InvalidValue(
+            "must be 0 or at least a day".into(),
+        )),
+        ms => Ok(Some(Duration::from_millis(ms))),
+    }
+}
+
+fn parse_circuit_timeout(value: &str) -> Result<CircuitTimeout, ConfigError> {
+    CircuitTimeout::parse(value).ok_or_else(|| {
+        ConfigError::InvalidValue("expected 0, auto, milliseconds or min-max".into())
+    })
+}
+
+fn parse_reuse(value: &str) -> Result<CircuitReuse, ConfigError> {
+    CircuitReuse::parse(value)
+        .ok_or_else(|| ConfigError::InvalidValue("expected token, destination or stream".into()))
+}
+
+/// Parse comma-separated `host=mode` entries
+fn parse_reuse_rules(value: &str) -> Result<Vec<ReuseRule>, ConfigError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let (host, reuse) = s.split_once('=').ok_or_else(|| {
+                ConfigError::InvalidValue(format!("expected host=mode, got {:?}", s))
+            })?;
+            let host = host.trim().to_ascii_lowercase();
+            if host.is_empty() || host == "*." {
+                return Err(ConfigError::InvalidValue(format!("no host in {:?}", s)));
+            }
+            Ok(ReuseRule {
+                host,
+                reuse: parse_reuse(reuse.trim())?,
+            })
+        })
+        .collect()
+}
+
+/// Parse comma-separated `rule=reply` entries
+fn parse_reply_rules(value: &str) -> Result<Vec<ReplyRule>, ConfigError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let (rule, reply) = s.split_once('=').ok_or_else(|| {
+                ConfigError::InvalidValue(format!("expected rule=reply, got {:?}", s))
+            })?;
+            let rule = Rejection::parse(rule.trim()).ok_or_else(|| {
+                ConfigError::InvalidValue(format!(
+                    "unknown rule {:?}; expected noconnect_hostname, exit_hostname or v2_onion",
+                    rule.trim()
+                ))
+            })?;
+            let reply = Reply::parse(reply.trim()).ok_or_else(|| {
+                ConfigError::InvalidValue(format!(
+                    "expected refuse, blackhole or redirect:host:port, got {:?}",
+                    reply.trim()
+                ))
+            })?;
+            Ok(ReplyRule { rule, reply })
+        })
+        .collect()
+}
+
+fn parse_padding(value: &str) -> Result<PaddingLevel, ConfigError> {
+    padding::parse(value)
+        .ok_or_else(|| ConfigError::InvalidValue("expected normal, reduced or off".into()))
+}
+
+/// Length of the base32 label of a v3 onion address
+const V3_ONION_LEN: usize = 56;
+
+/// Parse comma-separated `name.onion:port` entries
+fn parse_onion_list(value: &str) -> Result<Vec<(String, u16)>, ConfigError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let invalid =
+                || ConfigError::InvalidValue(format!("expected name.onion:port, got {:?}", s));
+            let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+            let port: u16 = port.parse().ok().filter(|p| *p != 0).ok_or_else(invalid)?;
+            let host = host.to_ascii_lowercase();
+            let label = host.strip_suffix(".onion").ok_or_else(invalid)?;
+            if label.rsplit('.').next().map(str::len) != Some(V3_ONION_LEN) {
+                return Err(invalid());
+            }
+            Ok((host, port))
+        })
+        .collect()
+}
+
+/// Parse comma-separated `host=name.onion` entries
+fn parse_race_pairs(value: &str) -> Result<Vec<RacePair>, ConfigError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let invalid =
+                || ConfigError::InvalidValue(format!("expected host=name.onion, got {:?}", s));
+            let (clearnet, onion) = s.split_once('=').ok_or_else(invalid)?;
+            let (clearnet, onion) = (
+                clearnet.trim().to_ascii_lowercase(),
+                onion.trim().to_ascii_lowercase(),
+            );
+            let label = onion.strip_suffix(".onion").ok_or_else(invalid)?;
+            if label.rsplit('.').next().map(str::len) != Some(V3_ONION_LEN)
+                || clearnet.is_empty()
+                || clearnet.ends_with(".onion")
+                || (clearnet.contains(':') && !clearnet.starts_with('['))
+            {
+                return Err(invalid());
+            }
+            Ok(RacePair { clearnet, onion })
+        })
+        .collect()
+}
+
+fn parse_bridge_lines(value: &str) -> Result<Vec<String>, ConfigError> {
+    value
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|line| {
+            bridges::parse_line(line)
+                .map(|_| line.to_string())
+                .map_err(|_| ConfigError::InvalidValue(format!("bad bridge line {:?}", line)))
+        })
+        .collect()
+}
+
+/// Parse non-empty lines, each checked with `parse`
+fn parse_lines<T>(
+    value: &str,
+    parse: fn(&str) -> Result<T, String>,
+) -> Result<Vec<String>, ConfigError> {
+    value
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|line| {
+            parse(line)
+                .map(|_| line.to_string())
+                .map_err(|e| ConfigError::InvalidValue(format!("{} in {:?}", e, line)))
+        })
+        .collect()
+}
+
+/// Parse comma-separated `addr:port` patterns; a bare port stands for `*:port`
+fn parse_reachable(value: &str) -> Result<Vec<AddrPortPattern>, ConfigError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let pattern = if s.contains(':') {
+                s.to_string()
+            } else {
+                format!("*:{}", s)
+            };
+            pattern
+                .parse()
+                .map_err(|_| ConfigError::InvalidValue(format!("bad address pattern {:?}", s)))
+        })
+        .collect()
+}
+
+fn parse_listen_list(value: &str) -> Result<Vec<ListenSpec>, ConfigError> {
+    let specs = value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(parse_listen_spec)
+        .collect::<Result<Vec<_>, _>>()?;
+    if specs.is_empty() {
+        return Err(ConfigError::InvalidValue(
+            "at least one address required".into(),
+        ));
+    }
+    Ok(specs)
+}
+
+fn parse_listen_spec(s: &str) -> Result<ListenSpec, ConfigError> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(ListenSpec::Socket(addr));
+    }
+    let bare = s.trim_start_matches('[').trim_end_matches(']');
+    bare.parse::<IpAddr>()
+        .map(ListenSpec::Ip)
+        .map_err(|_| ConfigError::InvalidValue(format!("bad listen address {:?}", s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V3: &str = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+
+    #[test]
+    fn every_option_round_trips() {
+        let config = Config::default();
+        for key in KEYS {
+            let mut copy = Config::default();
+            copy.set(key, &config.get(key))
+                .unwrap_or_else(|e| panic!("{}: {}", key, e));
+            assert_eq!(copy.get(key), config.get(key), "{}", key);
+        }
+    }
+
+    #[test]
+    fn unknown_options() {
+        assert!(matches!(
+            Config::default().set("socks.nonsense", "1"),
+            Err(ConfigError::UnknownKey)
+        ));
+    }
+
+    #[test]
+    fn listen_lists() {
+        assert_eq!(
+            parse_listen_list(" 127.0.0.1 , [::1]:9051,::1,[fe80::1]").unwrap(),
+            vec![
+                ListenSpec::Ip(Ipv4Addr::LOCALHOST.into()),
+                ListenSpec::Socket("[::1]:9051".parse().unwrap()),
+                ListenSpec::Ip("::1".parse().unwrap()),
+                ListenSpec::Ip("fe80::1".parse().unwrap()),
+            ]
+        );
+        for value in ["", " , ", "localhost", "127.0.0.1:port", "[::1]:port"] {
+            assert!(parse_listen_list(value).is_err(), "{:?}", value);
+        }
+        assert_eq!(
+            ListenSpec::Ip(Ipv4Addr::LOCALHOST.into()).resolve(9050),
+            "127.0.0.1:9050".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn onion_lists() {
+        let upper = V3.to_ascii_uppercase();
+        assert_eq!(
+            parse_onion_list(&format!("{}:80, www.{}:443,", upper, V3)).unwrap(),
+            vec![(V3.to_string(), 80), (format!("www.{}", V3), 443)]
+        );
+        assert_eq!(parse_onion_list("").unwrap(), Vec::new());
+        for value in [
+            V3.to_string(),
+            format!("{}:0", V3),
+            format!("{}:65536", V3),
+            "expyuzz4wqqyqhjn.onion:80".to_string(),
+            "example.com:80".to_string(),
+        ] {
+            assert!(parse_onion_list(&value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn race_pairs() {
+        assert_eq!(
+            parse_race_pairs(&format!("Example.com={}, [2001:db8::1]={}", V3, V3)).unwrap(),
+            vec![
+                RacePair {
+                    clearnet: "example.com".into(),
+                    onion: V3.into()
+                },
+                RacePair {
+                    clearnet: "[2001:db8::1]".into(),
+                    onion: V3.into()
+                },
+            ]
+        );
+        for value in [
+            format!("={}", V3),
+            format!("{}={}", V3, V3),
+            format!("2001:db8::1={}", V3),
+            "example.com=example.org".to_string(),
+            "example.com".to_string(),
+        ] {
+            assert!(parse_race_pairs(&value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn reply_rules() {
+        let rules =
+            parse_reply_rules("v2_onion=blackhole, exit_hostname=redirect:a.example:80").unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(
+            (rules[0].rule, &rules[0].reply),
+            (Rejection::V2Onion, &Reply::Blackhole)
+        );
+        assert_eq!(
+            (rules[1].rule, &rules[1].reply),
+            (
+                Rejection::ExitHostname,
+                &Reply::Redirect("a.example".into(), 80)
+            )
+        );
+        for value in ["v2_onion", "v2=refuse", "v2_onion=drop"] {
+            assert!(parse_reply_rules(value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn reuse_rules() {
+        let rules = parse_reuse_rules("*.Example.com=stream, b.example=destination").unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(
+            (rules[0].host.as_str(), rules[0].reuse),
+            ("*.example.com", CircuitReuse::Stream)
+        );
+        assert_eq!(
+            (rules[1].host.as_str(), rules[1].reuse),
+            ("b.example", CircuitReuse::Destination)
+        );
+        for value in ["=stream", "*.=stream", "a.example", "a.example=shared"] {
+            assert!(parse_reuse_rules(value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn numbers_and_ports() {
+        assert_eq!(parse_number("5", 1).unwrap(), 5);
+        assert!(parse_number("0", 1).is_err());
+        assert!(parse_number("-1", 0).is_err());
+        assert!(parse_bool("1").unwrap());
+        assert!(!parse_bool("false").unwrap());
+        assert!(parse_bool("yes").is_err());
+        assert_eq!(parse_port_list("80, 443,").unwrap(), vec![80, 443]);
+        assert!(parse_port_list("0").is_err());
+        assert_eq!(parse_buffer_size("0").unwrap(), None);
+        assert!(parse_buffer_size(&(i32::MAX as u64 + 1).to_string()).is_err());
+        assert_eq!(parse_max_age("0").unwrap(), None);
+        assert!(parse_max_age("1000").is_err());
+    }
+
+    fn document(options: &[(&str, &str)]) -> String {
+        let options: Vec<_> = options
+            .iter()
+            .map(|(key, value)| serde_json::json!({ "key": key, "value": value }))
+            .collect();
+        serde_json::json!({ "options": options }).to_string()
+    }
+
+    #[test]
+    fn documents_fill_in_defaults() {
+        let options = from_document(&document(&[
+            ("socks.failure_limit", "3"),
+            ("no.such.option", "1"),
+        ]))
+        .unwrap();
+        assert_eq!(options.len(), KEYS.len());
+        assert_eq!(options["socks.failure_limit"], "3");
+        assert_eq!(
+            options["socks.handshake_timeout_ms"],
+            Config::default().get("socks.handshake_timeout_ms")
+        );
+        assert!(from_document("{}").is_err());
+    }
+
+    #[test]
+    fn diffs_list_changed_options_in_key_order() {
+        let old = document(&[("socks.failure_limit", "3")]);
+        let new = document(&[
+            ("socks.failure_limit", "3"),
+            ("socks.failure_block_ms", "1000"),
+            ("socks.exit_hostnames", "strip"),
+        ]);
+        let changes = diff(Some(&old), Some(&new)).unwrap();
+        let keys: Vec<_> = changes.iter().map(|c| c.key).collect();
+        assert_eq!(keys, ["socks.failure_block_ms", "socks.exit_hostnames"]);
+        assert_eq!(changes[1].old, "reject");
+        assert_eq!(changes[1].new, "strip");
+        assert!(diff(Some(&old), Some(&old)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn secrets_are_redacted_but_comparable() {
+        let a = redact("bridges", "192.0.2.1:443".into());
+        let b = redact("bridges", "192.0.2.2:443".into());
+        assert!(a.starts_with("<redacted:"));
+        assert_ne!(a, b);
+        assert_eq!(a, redact("bridges", "192.0.2.1:443".into()));
+        // Already redacted, as in an export passed back
+        assert_eq!(redact("bridges", a.clone()), a);
+        assert_eq!(redact("bridges", String::new()), "");
+        assert_eq!(redact("socks.failure_limit", "3".into()), "3");
+    }
+}
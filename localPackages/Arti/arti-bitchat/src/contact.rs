@@ -0,0 +1,298 @@
+//! Signed contact payloads for onboarding peers out of band
+//!
+//! A payload tells another peer how to reach us over Tor: our Noise static
+//! key, the onion address we host, optionally a restricted discovery key,
+//! and when the introduction expires. It is signed with the onion service's
+//! identity key, so checking it needs no key but the address it carries, and
+//! a valid payload proves control of that address. The encoding is unpadded
+//! base32, which a QR code stores in its compact alphanumeric mode.
+//!
+//! Layout: version (1) | flags (1) | Noise key (32) | onion identity (32) |
+//! client auth key (32, if flagged) | expiry in Unix seconds (8, big-endian)
+//! | ed25519 signature (64) over [`SIGNING_CONTEXT`] and everything before it.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use arti_client::HsId;
+use safelog::DisplayRedacted;
+use serde::Serialize;
+use tor_hscrypto::pk::{HsClientDescEncKey, HsIdKey};
+use tor_llcrypto::pk::{curve25519, ed25519};
+#[cfg(feature = "onion-service-service")]
+use zeroize::Zeroizing;
+
+const VERSION: u8 = 1;
+
+/// Flag set when a client auth key is included
+const FLAG_CLIENT_AUTH: u8 = 0x01;
+
+/// Prefix of the signed message, so the signature cannot be replayed elsewhere
+const SIGNING_CONTEXT: &[u8] = b"bitchat contact payload v1\0";
+
+const KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+#[derive(Debug)]
+pub(crate) enum ContactError {
+    /// Not a payload, or a field is malformed
+    Invalid,
+    /// The signature does not match the onion address
+    BadSignature,
+    Expired,
+}
+
+/// A verified payload
+#[derive(Debug, Serialize)]
+pub(crate) struct Contact {
+    /// Noise static public key, as hex
+    noise_key: String,
+    onion: String,
+    /// Restricted discovery key, as `descriptor:x25519:...`
+    client_auth_key: Option<String>,
+    /// Unix seconds after which the payload is no longer accepted
+    expires: u64,
+}
+
+/// Build a payload signed with `identity`, the hosted service's identity key.
+///
+/// `noise_key` is hex; `client_auth_key` is in Arti's `descriptor:x25519:`
+/// form.
+#[cfg(feature = "onion-service-service")]
+pub(crate) fn create(
+    identity: &ed25519::ExpandedKeypair,
+    noise_key: &str,
+    client_auth_key: Option<&str>,
+    expires: u64,
+) -> Result<String, ContactError> {
+    let noise_key = parse_hex_key(noise_key)?;
+    let client_auth_key = client_auth_key
+        .map(|k| {
+            k.trim()
+                .parse::<HsClientDescEncKey>()
+                .map_err(|_| ContactError::Invalid)
+        })
+        .transpose()?;
+    if expires <= now() {
+        return Err(ContactError::Invalid);
+    }
+
+    let flags = if client_auth_key.is_some() {
+        FLAG_CLIENT_AUTH
+    } else {
+        0
+    };
+    let mut payload = vec![VERSION, flags];
+    payload.extend_from_slice(noise_key.as_slice());
+    payload.extend_from_slice(identity.public().as_bytes());
+    if let Some(key) = &client_auth_key {
+        payload.extend_from_slice(key.as_bytes());
+    }
+    payload.extend_from_slice(&expires.to_be_bytes());
+    let signature = identity.sign(&signed_message(&payload));
+    payload.extend_from_slice(&signature.to_bytes());
+    Ok(data_encoding::BASE32_NOPAD.encode(&payload))
+}
+
+/// Decode `payload` and check its signature and expiry
+pub(crate) fn verify(payload: &str) -> Result<Contact, ContactError> {
+    let bytes = data_encoding::BASE32_NOPAD
+        .decode(payload.trim().to_ascii_uppercase().as_bytes())
+        .map_err(|_| ContactError::Invalid)?;
+    let mut reader = Reader(&bytes);
+    let [version, flags] = reader.take()?;
+    if version != VERSION || flags & !FLAG_CLIENT_AUTH != 0 {
+        return Err(ContactError::Invalid);
+    }
+    let noise_key: [u8; KEY_LEN] = reader.take()?;
+    let identity: [u8; KEY_LEN] = reader.take()?;
+    let client_auth_key = if flags & FLAG_CLIENT_AUTH != 0 {
+        Some(reader.take::<KEY_LEN>()?)
+    } else {
+        None
+    };
+    let expires = u64::from_be_bytes(reader.take()?);
+    let signed_len = bytes.len() - reader.0.len();
+    let signature: [u8; SIGNATURE_LEN] = reader.take()?;
+    if !reader.0.is_empty() {
+        return Err(ContactError::Invalid);
+    }
+
+    let identity = ed25519::PublicKey::from_bytes(&identity).map_err(|_| ContactError::Invalid)?;
+    identity
+        .verify(
+            &signed_message(&bytes[..signed_len]),
+            &ed25519::Signature::from_bytes(&signature),
+        )
+        .map_err(|_| ContactError::BadSignature)?;
+    if expires <= now() {
+        return Err(ContactError::Expired);
+    }
+
+    Ok(Contact {
+        noise_key: noise_key.iter().map(|b| format!("{:02x}", b)).collect(),
+        onion: HsId::from(HsIdKey::from(identity))
+            .display_unredacted()
+            .to_string(),
+        client_auth_key: client_auth_key
+            .map(|k| HsClientDescEncKey::from(curve25519::PublicKey::from(k)).to_string()),
+        expires,
+    })
+}
+
+fn signed_message(payload: &[u8]) -> Vec<u8> {
+    [SIGNING_CONTEXT, payload].concat()
+}
+
+#[cfg(feature = "onion-service-service")]
+fn parse_hex_key(hex: &str) -> Result<Zeroizing<[u8; KEY_LEN]>, ContactError> {
+    let hex = hex.trim();
+    if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+        return Err(ContactError::Invalid);
+    }
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte =
+            u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| ContactError::Invalid)?;
+    }
+    Ok(key)
+}
+
+/// Takes fixed-size fields off the front of a payload
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], ContactError> {
+        let (field, rest) = self
+            .0
+            .split_first_chunk::<N>()
+            .ok_or(ContactError::Invalid)?;
+        self.0 = rest;
+        Ok(*field)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity() -> ed25519::ExpandedKeypair {
+        ed25519::ExpandedKeypair::from(&ed25519::Keypair::from_bytes(&[7; 32]))
+    }
+
+    /// A payload laid out by hand, signed with [`identity`] unless `sign` is false
+    fn payload(flags: u8, client_auth: &[u8], expires: u64, sign: bool) -> String {
+        let identity = identity();
+        let mut payload = vec![VERSION, flags];
+        payload.extend_from_slice(&[0xab; KEY_LEN]);
+        payload.extend_from_slice(identity.public().as_bytes());
+        payload.extend_from_slice(client_auth);
+        payload.extend_from_slice(&expires.to_be_bytes());
+        let signature = if sign {
+            identity.sign(&signed_message(&payload)).to_bytes()
+        } else {
+            [0; SIGNATURE_LEN]
+        };
+        payload.extend_from_slice(&signature);
+        data_encoding::BASE32_NOPAD.encode(&payload)
+    }
+
+    #[test]
+    fn signed_payloads_verify() {
+        let contact = verify(&payload(0, &[], now() + 3600, true)).unwrap();
+        assert_eq!(contact.noise_key, "ab".repeat(KEY_LEN));
+        assert_eq!(
+            contact.onion,
+            HsId::from(HsIdKey::from(*identity().public()))
+                .display_unredacted()
+                .to_string()
+        );
+        assert!(contact.client_auth_key.is_none());
+
+        let contact = verify(&payload(
+            FLAG_CLIENT_AUTH,
+            &[9; KEY_LEN],
+            now() + 3600,
+            true,
+        ))
+        .unwrap();
+        let key = HsClientDescEncKey::from(curve25519::PublicKey::from([9; KEY_LEN]));
+        assert_eq!(contact.client_auth_key, Some(key.to_string()));
+        // Case and surrounding space do not matter
+        let lower = format!(" {}\n", payload(0, &[], now() + 3600, true).to_lowercase());
+        assert!(verify(&lower).is_ok());
+    }
+
+    #[test]
+    fn bad_payloads_are_rejected() {
+        let expires = now() + 3600;
+        assert!(matches!(
+            verify(&payload(0, &[], expires, false)),
+            Err(ContactError::BadSignature)
+        ));
+        assert!(matches!(
+            verify(&payload(0, &[], now() - 1, true)),
+            Err(ContactError::Expired)
+        ));
+
+        let valid = data_encoding::BASE32_NOPAD
+            .decode(payload(0, &[], expires, true).as_bytes())
+            .unwrap();
+        let mut flipped = valid.clone();
+        flipped[2] ^= 1;
+        let mut versioned = valid.clone();
+        versioned[0] = VERSION + 1;
+        let mut flagged = valid.clone();
+        flagged[1] = 0x02;
+        let mut long = valid.clone();
+        long.push(0);
+        let short = &valid[..valid.len() - 1];
+        for (bytes, bad_signature) in [
+            (&flipped[..], true),
+            (&versioned[..], false),
+            (&flagged[..], false),
+            (&long[..], false),
+            (short, false),
+            (&[], false),
+        ] {
+            let result = verify(&data_encoding::BASE32_NOPAD.encode(bytes));
+            match bad_signature {
+                true => assert!(matches!(result, Err(ContactError::BadSignature))),
+                false => assert!(matches!(result, Err(ContactError::Invalid))),
+            }
+        }
+        assert!(matches!(verify("not base32!"), Err(ContactError::Invalid)));
+    }
+
+    #[cfg(feature = "onion-service-service")]
+    #[test]
+    fn created_payloads_verify() {
+        let noise_key = "0f".repeat(KEY_LEN);
+        let client_auth =
+            HsClientDescEncKey::from(curve25519::PublicKey::from([9; KEY_LEN])).to_string();
+        let expires = now() + 3600;
+        let created = create(&identity(), &noise_key, Some(&client_auth), expires).unwrap();
+        let contact = verify(&created).unwrap();
+        assert_eq!(contact.noise_key, noise_key);
+        assert_eq!(contact.client_auth_key, Some(client_auth));
+        assert_eq!(contact.expires, expires);
+
+        for (noise_key, client_auth, expires) in [
+            ("0f", None, expires),
+            (&*"zz".repeat(KEY_LEN), None, expires),
+            (&*noise_key, Some("descriptor:x25519:nope"), expires),
+            (&*noise_key, None, now()),
+        ] {
+            assert!(matches!(
+                create(&identity(), noise_key, client_auth, expires),
+                Err(ContactError::Invalid)
+            ));
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! Deadlines the host sets on SOCKS connections
+//!
+//! A host with only a short window to finish in, such as an iOS app sending
+//! from the background, can give a connection a deadline with
+//! `arti_set_stream_deadline`, naming it by the local address of its
+//! client's socket. Whatever the connection is doing then is stopped: a
+//! handshake or connect still running fails, and a stream still relaying is
+//! closed, with `stream_deadline` saying how far it got. The address is
+//! known as soon as the client's socket is bound, so a deadline set before
+//! the connection arrives applies to it, unless the deadline has passed by
+//! then.
+//!
+//! Pipes all come from port 0, so they cannot be told apart here.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::events::{self, Event};
+
+/// Where a connection was when its deadline passed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Phase {
+    Handshake,
+    Connect,
+    Relay,
+}
+
+enum Entry {
+    /// Set for a connection that has not arrived yet
+    Pending(Instant),
+    /// Watched by the handler of connection `id`
+    Active {
+        id: u64,
+        deadline: watch::Sender<Option<Instant>>,
+    },
+}
+
+/// Keyed by the client's address as the listener saw it
+static DEADLINES: Lazy<Mutex<HashMap<SocketAddr, Entry>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The deadline of one connection, which may be set or moved while it runs
+pub(crate) struct Deadline {
+    peer_addr: SocketAddr,
+    /// Tells this connection's entry from a later one from the same address
+    id: u64,
+    /// None for pipes, which never get one
+    deadline: Option<watch::Receiver<Option<Instant>>>,
+    /// When the connection was accepted
+    started: Instant,
+}
+
+/// Watch the deadline of the connection from `peer_addr`, taking on any set
+/// before it arrived
+pub(crate) fn for_peer(peer_addr: SocketAddr) -> Deadline {
+    let started = Instant::now();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let deadline = match DEADLINES.lock() {
+        Ok(mut deadlines) if peer_addr.port() != 0 => {
+            let pending = match deadlines.remove(&peer_addr) {
+                Some(Entry::Pending(at)) if at > started => Some(at),
+                _ => None,
+            };
+            let (tx, rx) = watch::channel(pending);
+            deadlines.insert(peer_addr, Entry::Active { id, deadline: tx });
+            Some(rx)
+        }
+        _ => None,
+    };
+    Deadline {
+        peer_addr,
+        id,
+        deadline,
+        started,
+    }
+}
+
+/// Set the deadline of the connection from `peer_addr`, or clear it with
+/// None. Applies to the next connection from that address if none is open.
+pub(crate) fn set(peer_addr: SocketAddr, at: Option<Instant>) {
+    let Ok(mut deadlines) = DEADLINES.lock() else {
+        return;
+    };
+    // Deadlines for connections that never came are of no use once passed
+    let now = Instant::now();
+    deadlines.retain(|_, entry| !matches!(entry, Entry::Pending(at) if *at <= now));
+    match (deadlines.get(&peer_addr), at) {
+        (Some(Entry::Active { deadline, .. }), at) => {
+            deadline.send_replace(at);
+        }
+        (_, Some(at)) => {
+            deadlines.insert(peer_addr, Entry::Pending(at));
+        }
+        (_, None) => {
+            deadlines.remove(&peer_addr);
+        }
+    }
+}
+
+impl Deadline {
+    /// Wait until the deadline passes; never returns if there is none
+    pub(crate) async fn reached(&mut self) {
+        let Some(deadline) = &mut self.deadline else {
+            return std::future::pending().await;
+        };
+        loop {
+            let at = *deadline.borrow_and_update();
+            let changed = match at {
+                Some(at) => tokio::select! {
+                    _ = tokio::time::sleep_until(at.into()) => return,
+                    changed = deadline.changed() => changed,
+                },
+                None => deadline.changed().await,
+            };
+            if changed.is_err() {
+                return std::future::pending().await;
+            }
+        }
+    }
+
+    /// Report that the deadline stopped the connection in `phase`, having
+    /// moved `sent` and `received` bytes
+    pub(crate) fn report(
+        &self,
+        phase: Phase,
+        stream: Option<u64>,
+        destination: Option<String>,
+        sent: u64,
+        received: u64,
+    ) {
+        let elapsed = self.started.elapsed();
+        tracing::debug!(
+            "Connection from {} reached its deadline during {:?} after {:?}",
+            self.peer_addr,
+            phase,
+            elapsed
+        );
+        events::emit(Event::StreamDeadline {
+            stream,
+            client_port: self.peer_addr.port(),
+            destination,
+            phase,
+            sent,
+            received,
+            elapsed_ms: elapsed.as_millis() as u64,
+        });
+    }
+}
+
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        if self.deadline.is_none() {
+            return;
+        }
+        if let Ok(mut deadlines) = DEADLINES.lock() {
+            if let Some(Entry::Active { id, .. }) = deadlines.get(&self.peer_addr) {
+                if *id == self.id {
+                    deadlines.remove(&self.peer_addr);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn is_pending(port: u16) -> bool {
+        matches!(
+            DEADLINES.lock().unwrap().get(&peer(port)),
+            Some(Entry::Pending(_))
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadlines_set_early_are_taken_on() {
+        set(peer(40001), Some(Instant::now() + Duration::from_secs(60)));
+        assert!(is_pending(40001));
+        let mut deadline = for_peer(peer(40001));
+        assert!(!is_pending(40001));
+        // The clock is paused, so this wait takes no time
+        let reached = tokio::time::timeout(Duration::from_secs(61), deadline.reached());
+        assert!(reached.await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn passed_deadlines_are_not_taken_on() {
+        set(peer(40002), Some(Instant::now()));
+        let mut deadline = for_peer(peer(40002));
+        let reached = tokio::time::timeout(Duration::from_secs(3600), deadline.reached());
+        assert!(reached.await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadlines_can_be_moved_and_cleared() {
+        let mut deadline = for_peer(peer(40003));
+        set(
+            peer(40003),
+            Some(Instant::now() + Duration::from_secs(3600)),
+        );
+        set(peer(40003), None);
+        let reached = tokio::time::timeout(Duration::from_secs(7200), deadline.reached());
+        assert!(reached.await.is_err());
+        set(peer(40003), Some(Instant::now()));
+        tokio::time::timeout(Duration::from_secs(5), deadline.reached())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn only_the_latest_connection_clears_its_entry() {
+        let first = for_peer(peer(40004));
+        let second = for_peer(peer(40004));
+        drop(first);
+        assert!(DEADLINES.lock().unwrap().contains_key(&peer(40004)));
+        drop(second);
+        assert!(!DEADLINES.lock().unwrap().contains_key(&peer(40004)));
+        // Nor does a dropped connection clear a deadline set for the next one
+        let third = for_peer(peer(40004));
+        drop(third);
+        set(
+            peer(40004),
+            Some(Instant::now() + Duration::from_secs(3600)),
+        );
+        assert!(is_pending(40004));
+        set(peer(40004), None);
+        assert!(!DEADLINES.lock().unwrap().contains_key(&peer(40004)));
+    }
+
+    #[test]
+    fn pipes_never_get_a_deadline() {
+        let deadline = for_peer(peer(0));
+        assert!(deadline.deadline.is_none());
+        assert!(!DEADLINES.lock().unwrap().contains_key(&peer(0)));
+    }
+}
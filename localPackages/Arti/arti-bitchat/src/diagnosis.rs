@@ -0,0 +1,329 @@
+//! Explanations of failed connects
+//!
+//! A SOCKS client only learns that a connect failed and, from the reply code,
+//! roughly why, so all the app can tell the user is "connection failed". For
+//! each failed connect the last [`MAX_FAILURES`] are kept with what explains
+//! them: the classified reason and Arti's error kind, the chain of errors
+//! behind it, the circuit builds that failed and where on the path, whether
+//! Tor was ready for traffic, and the log lines written while connecting.
+//! `arti_diagnose_failure` returns one as JSON, for the app to show or attach
+//! to a bug report.
+//!
+//! Lines are captured at debug level whatever the log filter, but only those
+//! logged by the connect's own task. Arti builds circuits in tasks of its
+//! own, so warnings from any task logged while a connect ran are added too.
+//! As with `latency`, nothing is captured if the host process installed its
+//! own subscriber.
+
+use std::collections::VecDeque;
+use std::error::Error as StdError;
+use std::fmt::{self, Write as _};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use arti_client::HasKind;
+use serde::Serialize;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::{dynamic_filter_fn, LevelFilter};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::audit::host_kind;
+use crate::failure::{self, BuildFailure, ConnectFailure, HopPosition};
+
+/// Failed connects kept
+const MAX_FAILURES: usize = 16;
+
+/// Lines kept of one connect's task; the earliest are dropped first
+const MAX_LINES: usize = 64;
+
+/// Warnings kept from all tasks, to pick those of a connect's time from
+const MAX_WARNINGS: usize = 64;
+
+/// Characters of a log message kept
+const MAX_MESSAGE: usize = 512;
+
+tokio::task_local! {
+    /// Where the connect running in this task keeps its lines
+    static CAPTURE: Lines;
+}
+
+type Lines = Arc<Mutex<VecDeque<LogLine>>>;
+
+static FAILURES: Mutex<VecDeque<Diagnosis>> = Mutex::new(VecDeque::new());
+
+static WARNINGS: Mutex<VecDeque<LogLine>> = Mutex::new(VecDeque::new());
+
+#[derive(Clone)]
+struct LogLine {
+    at: Instant,
+    level: Level,
+    target: &'static str,
+    message: String,
+}
+
+/// One failed connect
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Diagnosis {
+    stream: u64,
+    client_port: u16,
+    /// `host:port`
+    destination: String,
+    /// When it failed, in milliseconds since the Unix epoch
+    failed_at: u64,
+    elapsed_ms: u64,
+    /// Classified failure, as in the `stream_failed` event
+    reason: &'static str,
+    /// SOCKS reply code sent to the client
+    socks_reply: u8,
+    /// Whether another exit might succeed where this one failed
+    retryable: bool,
+    /// Arti's error kind, e.g. `RemoteHostNotFound`
+    kind: String,
+    /// The error and each one it was caused by
+    errors: Vec<String>,
+    /// Exits tried, including retries
+    attempts: u32,
+    circuit_failures: Vec<CircuitFailure>,
+    /// Whether Tor was bootstrapped enough for traffic when it failed
+    ready_for_traffic: bool,
+    log: Vec<LogLineView>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct CircuitFailure {
+    reason: BuildFailure,
+    hop: HopPosition,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct LogLineView {
+    /// Milliseconds since the connect started
+    at_ms: u64,
+    level: &'static str,
+    target: &'static str,
+    message: String,
+}
+
+/// A connect that is explained if it fails
+pub(crate) struct Connect {
+    stream: u64,
+    client_port: u16,
+    destination: String,
+    started: Instant,
+    lines: Lines,
+}
+
+impl Connect {
+    pub(crate) fn new(stream: u64, client_port: u16, destination: String) -> Self {
+        Connect {
+            stream,
+            client_port,
+            destination,
+            started: Instant::now(),
+            lines: Lines::default(),
+        }
+    }
+
+    /// Run `connect`, capturing the lines it logs
+    pub(crate) async fn run<F: Future>(&self, connect: F) -> F::Output {
+        CAPTURE.scope(self.lines.clone(), connect).await
+    }
+
+    /// Keep the explanation of a connect that failed with `error`
+    pub(crate) fn failed(self, error: &arti_client::Error, attempts: u32, ready_for_traffic: bool) {
+        let classified = ConnectFailure::classify(error);
+        let now = Instant::now();
+        let mut lines: Vec<LogLine> = self
+            .lines
+            .lock()
+            .map(|l| l.iter().cloned().collect())
+            .unwrap_or_default();
+        if let Ok(warnings) = WARNINGS.lock() {
+            lines.extend(
+                warnings
+                    .iter()
+                    .filter(|w| {
+                        w.at >= self.started
+                            && !lines.iter().any(|l| l.at == w.at && l.message == w.message)
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>(),
+            );
+        }
+        lines.sort_by_key(|l| l.at);
+
+        let mut errors: Vec<String> = Vec::new();
+        let mut source: Option<&(dyn StdError + 'static)> = Some(error);
+        while let Some(e) = source {
+            let message = e.to_string();
+            if errors.last() != Some(&message) {
+                errors.push(message);
+            }
+            source = e.source();
+        }
+
+        let diagnosis = Diagnosis {
+            stream: self.stream,
+            client_port: self.client_port,
+            destination: self.destination,
+            failed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            elapsed_ms: (now - self.started).as_millis() as u64,
+            reason: classified.as_str(),
+            socks_reply: classified.socks_reply(),
+            retryable: classified.is_resolution_failure(),
+            kind: format!("{:?}", error.kind()),
+            errors,
+            attempts,
+            circuit_failures: failure::circuit_failures(error)
+                .into_iter()
+                .map(|(reason, hop)| CircuitFailure { reason, hop })
+                .collect(),
+            ready_for_traffic,
+            log: lines
+                .into_iter()
+                .map(|l| LogLineView {
+                    at_ms: l.at.saturating_duration_since(self.started).as_millis() as u64,
+                    level: l.level.as_str(),
+                    target: l.target,
+                    message: l.message,
+                })
+                .collect(),
+        };
+        let Ok(mut failures) = FAILURES.lock() else {
+            return;
+        };
+        if failures.len() >= MAX_FAILURES {
+            failures.pop_front();
+        }
+        failures.push_back(diagnosis);
+    }
+}
+
+impl Diagnosis {
+    /// Reduce the destination to its kind, also where it is named in errors
+    /// and log lines
+    fn redact(&mut self) {
+        let (host, port) = self
+            .destination
+            .rsplit_once(':')
+            .unwrap_or((self.destination.as_str(), ""));
+        let (host, kind) = (host.to_string(), host_kind(host));
+        let redacted = format!("{}:{}", kind, port);
+        self.destination = redacted;
+        if host.is_empty() {
+            return;
+        }
+        for text in self
+            .errors
+            .iter_mut()
+            .chain(self.log.iter_mut().map(|l| &mut l.message))
+        {
+            *text = text.replace(&host, kind);
+        }
+    }
+}
+
+/// The failed connect of stream `stream`, or with 0 the latest one
+pub(crate) fn find(stream: u64, redact: bool) -> Option<Diagnosis> {
+    let failures = FAILURES.lock().ok()?;
+    let mut diagnosis = if stream == 0 {
+        failures.back()
+    } else {
+        failures.iter().rev().find(|d| d.stream == stream)
+    }?
+    .clone();
+    drop(failures);
+    if redact {
+        diagnosis.redact();
+    }
+    Some(diagnosis)
+}
+
+/// Layer keeping the lines of connects and recent warnings
+pub(crate) fn layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    // Asked per event, so debug lines are only formatted within a connect
+    CaptureLayer.with_filter(
+        dynamic_filter_fn(|metadata, _| {
+            *metadata.level() <= Level::WARN || CAPTURE.try_with(|_| ()).is_ok()
+        })
+        .with_max_level_hint(LevelFilter::DEBUG),
+    )
+}
+
+struct CaptureLayer;
+
+impl<S: Subscriber> Layer<S> for CaptureLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = LineVisitor::default();
+        event.record(&mut visitor);
+        let mut message = visitor.message;
+        if !visitor.fields.is_empty() {
+            message.push_str(&visitor.fields);
+        }
+        if message.len() > MAX_MESSAGE {
+            let mut end = MAX_MESSAGE;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            message.truncate(end);
+        }
+        let metadata = event.metadata();
+        let line = LogLine {
+            at: Instant::now(),
+            level: *metadata.level(),
+            target: metadata.target(),
+            message,
+        };
+        let _ = CAPTURE.try_with(|lines| {
+            if let Ok(mut lines) = lines.lock() {
+                if lines.len() >= MAX_LINES {
+                    lines.pop_front();
+                }
+                lines.push_back(line.clone());
+            }
+        });
+        if line.level <= Level::WARN {
+            if let Ok(mut warnings) = WARNINGS.lock() {
+                if warnings.len() >= MAX_WARNINGS {
+                    warnings.pop_front();
+                }
+                warnings.push_back(line);
+            }
+        }
+    }
+}
+
+/// Formats an event as its message followed by ` name=value` for each other field
+#[derive(Default)]
+struct LineVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for LineVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
@@ -0,0 +1,430 @@
+//! Events delivered to the host app
+//!
+//! The app registers a C callback with `arti_set_event_callback`; each event
+//! is passed to it as a JSON object with a `type` field. The callback runs on
+//! an Arti worker thread and must not block.
+//!
+//! Hosts that cannot take calls on arbitrary threads enable the queue instead
+//! and fetch events with `arti_poll_events` on a thread of their choosing.
+//! The queue is bounded: when it is full the oldest event is dropped and
+//! counted, and the count is reported by the next poll.
+
+use std::ffi::{c_char, c_void, CString};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crossbeam_queue::ArrayQueue;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+/// Callback receiving one event as a null-terminated JSON string.
+///
+/// The string is only valid for the duration of the call.
+pub type ArtiEventCallback = extern "C" fn(event_json: *const c_char, context: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct Subscriber {
+    callback: ArtiEventCallback,
+    /// Opaque pointer handed back to the callback, kept as an integer so the
+    /// subscriber can live in a static
+    context: usize,
+}
+
+static SUBSCRIBER: Mutex<Option<Subscriber>> = Mutex::new(None);
+
+/// Events the queue holds before dropping the oldest
+pub(crate) const QUEUE_CAPACITY: usize = 256;
+
+static QUEUE_ENABLED: AtomicBool = AtomicBool::new(false);
+static QUEUE: Lazy<ArrayQueue<String>> = Lazy::new(|| ArrayQueue::new(QUEUE_CAPACITY));
+/// Events dropped from the full queue since the last poll
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+/// JSON bytes held by the queue and the carried event
+static QUEUED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// An event popped by a poll that did not fit in its buffer, returned first
+/// by the next poll
+static CARRY: Mutex<Option<String>> = Mutex::new(None);
+
+/// Wakes pollers waiting for an event; only taken while one is waiting, so
+/// emitting stays lock-free otherwise
+static WAITERS: AtomicUsize = AtomicUsize::new(0);
+static WAKE_LOCK: Mutex<()> = Mutex::new(());
+static WAKE: Condvar = Condvar::new();
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Event {
+    /// This is a `loopback` build: streams are connected without Tor
+    LoopbackMode {
+        /// `loopback.upstream`, where every stream is sent, if set
+        upstream: Option<String>,
+    },
+    /// A bootstrap attempt is starting (numbered from 1)
+    BootstrapAttempt { attempt: u32 },
+    /// A bootstrap attempt failed
+    BootstrapFailed {
+        attempt: u32,
+        error: String,
+        /// Retrying cannot help, e.g. the configuration is invalid
+        fatal: bool,
+        /// Delay before the next attempt, null if giving up
+        retry_in_ms: Option<u64>,
+    },
+    /// Bootstrap completed
+    BootstrapSucceeded { attempt: u32, elapsed_ms: u64 },
+    /// An attempt made no progress for `bootstrap.stall_timeout_ms`
+    BootstrapStalled {
+        diagnostics: crate::watchdog::Diagnostics,
+        /// Number of the client restart begun, null if none are left and bootstrap carries on
+        restart: Option<u32>,
+    },
+    /// Bootstrap is stalled because the device clock is wrong
+    ClockSkewDetected { message: String },
+    /// The clock skew no longer blocks bootstrap
+    ClockSkewCleared,
+    /// The pre-bootstrap probe was intercepted; the user must sign in to the network
+    CaptivePortalDetected { reason: String },
+    /// The probe got through after a portal was detected
+    CaptivePortalCleared,
+    /// A SOCKS request could not be connected through Tor
+    StreamFailed {
+        /// Id of the stream, see [`crate::stream_events`]
+        stream: u64,
+        client_port: u16,
+        destination: String,
+        /// Classified failure, e.g. `host_not_found` or `timeout`
+        reason: &'static str,
+        detail: String,
+        /// Exits tried, including retries
+        attempts: u32,
+    },
+    /// A SOCKS request was refused by local policy before reaching Tor
+    StreamRejected {
+        stream: u64,
+        client_port: u16,
+        destination: String,
+        /// Rule that refused it, e.g. `exit_hostname`
+        rule: &'static str,
+    },
+    /// A SOCKS client sent its handshake again as the first data of its stream
+    StreamPipelined {
+        stream: u64,
+        client_port: u16,
+        destination: String,
+        /// `tunneled` to the destination as data, `refused` before connecting,
+        /// or `closed` once connected, as `socks.pipelining` says
+        action: &'static str,
+    },
+    /// A SOCKS request listed in `onion.race` connected over one of its addresses
+    StreamRaced {
+        stream: u64,
+        client_port: u16,
+        /// The address that connected, `host:port`
+        destination: String,
+        /// `onion` or `clearnet`
+        route: &'static str,
+        elapsed_ms: u64,
+    },
+    /// A stream will be closed at `socks.max_lifetime_ms`; the client can
+    /// open its replacement now
+    StreamExpiring {
+        stream: u64,
+        client_port: u16,
+        destination: String,
+        closes_in_ms: u64,
+    },
+    /// A connection reached the deadline set by `arti_set_stream_deadline`
+    /// and was stopped
+    StreamDeadline {
+        /// None if it was still in its handshake
+        stream: Option<u64>,
+        client_port: u16,
+        destination: Option<String>,
+        phase: crate::deadline::Phase,
+        sent: u64,
+        received: u64,
+        /// Since the connection was accepted
+        elapsed_ms: u64,
+    },
+    /// With `events.streams`, a SOCKS request's connect got a circuit
+    StreamCircuitAttached {
+        stream: u64,
+        client_port: u16,
+        elapsed_ms: u64,
+    },
+    /// With `events.streams`, the remote end accepted the stream
+    StreamConnected {
+        stream: u64,
+        client_port: u16,
+        destination: String,
+        circuit: Option<String>,
+        elapsed_ms: u64,
+    },
+    /// With `events.streams`, the first data came back on the stream
+    StreamFirstByte {
+        stream: u64,
+        client_port: u16,
+        elapsed_ms: u64,
+    },
+    /// With `events.streams`, a connected stream ended
+    StreamClosed {
+        stream: u64,
+        client_port: u16,
+        /// `client`, `remote`, `client_error`, `client_stalled`, `tor_error`,
+        /// `shutdown` or `interface_changed`
+        reason: &'static str,
+        sent: u64,
+        received: u64,
+        elapsed_ms: u64,
+    },
+    /// moat wants a CAPTCHA solved before handing out bridges
+    BridgeChallenge {
+        /// Base64-encoded image to show the user
+        image: String,
+        mime_type: &'static str,
+    },
+    /// Bridges were obtained and stored in the `bridges` option
+    BridgesReceived { count: usize },
+    /// A bridge request failed
+    BridgeFetchFailed {
+        /// `fetch` (getting a CAPTCHA) or `check` (submitting the answer)
+        stage: &'static str,
+        /// `network`, `server`, `captcha_incorrect` or `no_bridges`
+        kind: &'static str,
+        error: String,
+    },
+    /// Traffic reached a quota; new SOCKS connections are refused
+    QuotaExceeded {
+        period: crate::quota::Period,
+        used: u64,
+        limit: u64,
+    },
+    /// The quota period rolled over or the quota was raised; connections are accepted again
+    QuotaCleared,
+    /// Connects over the last minute, or circuits carrying streams, went over their budget
+    BudgetExceeded {
+        budget: crate::budget::Budget,
+        value: u64,
+        limit: u64,
+    },
+    /// A budget from [`Event::BudgetExceeded`] is back down to three quarters of its limit
+    BudgetCleared {
+        budget: crate::budget::Budget,
+        value: u64,
+        limit: u64,
+    },
+    /// Periodic memory report, see [`crate::memory::Report`]
+    MemoryUsage(crate::memory::Report),
+    /// Circuit settings were adjusted to the network, see [`crate::tuning::Decision`]
+    NetworkTuned(crate::tuning::Decision),
+    /// Streams with different isolation tokens were put on the same circuit
+    IsolationNotHonored { circuit: String },
+    /// A hosted onion service changed state, see [`crate::onion_service`]
+    #[cfg(feature = "onion-service-service")]
+    OnionServiceState {
+        nickname: String,
+        /// Arti's view of the service, as in `arti_onion_services`
+        state: String,
+        /// `fatal`, `descriptor_upload`, `introduction_points` or `other`
+        problem: Option<&'static str>,
+        /// `pending`, `fresh` or `stale`, as in `arti_onion_services`
+        descriptor: crate::onion_service::Descriptor,
+    },
+    /// A client of a hosted onion service went over a limit and its circuit
+    /// was closed, see [`crate::service_limits`]
+    #[cfg(feature = "onion-service-service")]
+    OnionServiceClientLimited {
+        nickname: String,
+        client: u64,
+        limit: crate::service_limits::Limit,
+    },
+    /// An expiring onion service stopped and its keys were deleted, see
+    /// [`crate::onion_service::Expiry`]
+    #[cfg(feature = "onion-service-service")]
+    OnionServiceExpired {
+        nickname: String,
+        reason: crate::onion_service::Expired,
+        /// Streams it forwarded to the app
+        streams: u64,
+        /// False if the keystore failed, leaving keys behind
+        key_wiped: bool,
+    },
+    /// The host reported a network interface change, see [`crate::migration`]
+    InterfaceChanged {
+        old: Option<String>,
+        new: Option<String>,
+        /// Streams closed because they were on the old path
+        dropped: Vec<crate::migration::DroppedStream>,
+        circuits_closed: usize,
+        /// Favorite and pinned peers being reached again over the new path
+        migrated: Vec<String>,
+    },
+}
+
+impl Event {
+    /// Whether this reports something going wrong, for the status page's recent errors
+    pub(crate) fn is_problem(&self) -> bool {
+        matches!(
+            self,
+            Event::BootstrapFailed { .. }
+                | Event::BootstrapStalled { .. }
+                | Event::ClockSkewDetected { .. }
+                | Event::CaptivePortalDetected { .. }
+                | Event::StreamFailed { .. }
+                | Event::StreamRejected { .. }
+                | Event::StreamPipelined { .. }
+                | Event::StreamDeadline { .. }
+                | Event::BridgeFetchFailed { .. }
+                | Event::QuotaExceeded { .. }
+                | Event::IsolationNotHonored { .. }
+        ) || self.is_service_problem()
+    }
+
+    #[cfg(feature = "onion-service-service")]
+    fn is_service_problem(&self) -> bool {
+        matches!(
+            self,
+            Event::OnionServiceState {
+                problem: Some(_),
+                ..
+            } | Event::OnionServiceClientLimited { .. }
+        )
+    }
+
+    #[cfg(not(feature = "onion-service-service"))]
+    fn is_service_problem(&self) -> bool {
+        false
+    }
+}
+
+/// Register the event callback, replacing any previous one; `None` unregisters
+pub(crate) fn set_callback(callback: Option<ArtiEventCallback>, context: *mut c_void) {
+    if let Ok(mut subscriber) = SUBSCRIBER.lock() {
+        *subscriber = callback.map(|callback| Subscriber {
+            callback,
+            context: context as usize,
+        });
+    }
+}
+
+/// Deliver an event to the registered callback and the queue, if enabled
+pub(crate) fn emit(event: Event) {
+    crate::status_page::note_event(&event);
+    // Copy the subscriber out so the callback may re-register without deadlocking
+    let subscriber = SUBSCRIBER.lock().ok().and_then(|s| *s);
+    let queued = QUEUE_ENABLED.load(Ordering::SeqCst);
+    if subscriber.is_none() && !queued {
+        return;
+    }
+    let Ok(json) = serde_json::to_string(&event) else {
+        return;
+    };
+    if let Some(subscriber) = subscriber {
+        if let Ok(json) = CString::new(json.as_str()) {
+            (subscriber.callback)(json.as_ptr(), subscriber.context as *mut c_void);
+        }
+    }
+    if queued {
+        push(json);
+    }
+}
+
+/// Turn the queue on or off; turning it off discards what it holds
+pub(crate) fn set_queue_enabled(enabled: bool) {
+    QUEUE_ENABLED.store(enabled, Ordering::SeqCst);
+    if !enabled {
+        while QUEUE.pop().is_some() {}
+        DROPPED.store(0, Ordering::SeqCst);
+        QUEUED_BYTES.store(0, Ordering::SeqCst);
+        if let Ok(mut carry) = CARRY.lock() {
+            *carry = None;
+        }
+    }
+}
+
+fn push(json: String) {
+    QUEUED_BYTES.fetch_add(json.len(), Ordering::SeqCst);
+    if let Some(evicted) = QUEUE.force_push(json) {
+        release(evicted.len());
+        DROPPED.fetch_add(1, Ordering::SeqCst);
+    }
+    if WAITERS.load(Ordering::SeqCst) > 0 {
+        // Taking the lock orders this with a poller about to wait
+        drop(WAKE_LOCK.lock());
+        WAKE.notify_all();
+    }
+}
+
+/// Account for `n` bytes leaving the queue; saturating, as disabling the
+/// queue may reset the count while an event is being pushed
+fn release(n: usize) {
+    let _ = QUEUED_BYTES.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| {
+        Some(v.saturating_sub(n))
+    });
+}
+
+/// JSON bytes waiting to be polled
+pub(crate) fn queued_bytes() -> usize {
+    QUEUED_BYTES.load(Ordering::SeqCst)
+}
+
+/// Events taken by one poll
+pub(crate) struct Polled {
+    /// JSON objects, oldest first
+    pub(crate) events: Vec<String>,
+    /// Events lost to overflow since the previous poll
+    pub(crate) dropped: u64,
+}
+
+/// Take up to `max` events whose JSON totals at most `budget` bytes, waiting
+/// up to `timeout` for the first if none is queued.
+///
+/// Returns `None` if the oldest event alone exceeds `budget`; it stays queued.
+pub(crate) fn poll(max: usize, budget: usize, timeout: Duration) -> Option<Polled> {
+    let mut carry = CARRY.lock().ok()?;
+    let deadline = Instant::now() + timeout;
+    let mut next = carry.take().or_else(|| QUEUE.pop());
+    if next.is_none() && !timeout.is_zero() {
+        WAITERS.fetch_add(1, Ordering::SeqCst);
+        if let Ok(mut wake) = WAKE_LOCK.lock() {
+            loop {
+                next = QUEUE.pop();
+                let now = Instant::now();
+                if next.is_some() || now >= deadline {
+                    break;
+                }
+                match WAKE.wait_timeout(wake, deadline - now) {
+                    Ok((w, _)) => wake = w,
+                    Err(_) => break,
+                }
+            }
+        }
+        WAITERS.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    let mut events = Vec::new();
+    let mut used = 0;
+    while let Some(json) = next.take() {
+        // Each event also takes a separating comma
+        if used + json.len() + 1 > budget {
+            *carry = Some(json);
+            break;
+        }
+        used += json.len() + 1;
+        release(json.len());
+        events.push(json);
+        if events.len() >= max {
+            break;
+        }
+        next = QUEUE.pop();
+    }
+    if events.is_empty() && carry.is_some() {
+        return None;
+    }
+    Some(Polled {
+        events,
+        dropped: DROPPED.swap(0, Ordering::SeqCst),
+    })
+}
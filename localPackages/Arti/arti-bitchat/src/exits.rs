@@ -0,0 +1,135 @@
+//! Which addresses are Tor exits
+//!
+//! With `exits.refresh_ms` set, the Tor Project's bulk exit list, the
+//! addresses exits have been seen connecting from, is fetched over Tor once
+//! bootstrap completes and again whenever it is older than that.
+//! `arti_is_tor_exit` looks addresses up in it, so the app can label peers
+//! that reach it through Tor, or warn when a connection it thought direct
+//! ends at an exit. The list is kept in memory only, and stays in use after
+//! Arti stops.
+
+use std::collections::HashSet;
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use arti_client::TorClient;
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures_rustls::TlsConnector;
+use rustls::pki_types::ServerName;
+
+use crate::clock::TorRuntime;
+use crate::probe;
+
+const LIST_HOST: &str = "check.torproject.org";
+const LIST_PATH: &str = "/torbulkexitlist";
+
+/// Largest list accepted; the real one is well under 100 KB
+const MAX_LIST: usize = 4 << 20;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often to check whether a fetch is due, and so how soon a failed one is retried
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+struct ExitList {
+    addrs: HashSet<IpAddr>,
+    fetched: Instant,
+}
+
+static LIST: Mutex<Option<ExitList>> = Mutex::new(None);
+
+/// Keep the list fresh until the task is aborted
+pub(crate) async fn run(client: &TorClient<TorRuntime>, refresh: Duration) {
+    let mut ticks = tokio::time::interval(CHECK_INTERVAL);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        if crate::IS_DORMANT.load(Ordering::SeqCst)
+            || !client.bootstrap_status().ready_for_traffic()
+        {
+            continue;
+        }
+        let due = LIST
+            .lock()
+            .is_ok_and(|l| l.as_ref().is_none_or(|l| l.fetched.elapsed() >= refresh));
+        if !due {
+            continue;
+        }
+        match tokio::time::timeout(FETCH_TIMEOUT, fetch(client)).await {
+            Ok(Ok(addrs)) => {
+                tracing::info!("Fetched exit list of {} addresses", addrs.len());
+                if let Ok(mut list) = LIST.lock() {
+                    *list = Some(ExitList {
+                        addrs,
+                        fetched: Instant::now(),
+                    });
+                }
+            }
+            Ok(Err(e)) => tracing::warn!("Failed to fetch exit list: {}", e),
+            Err(_) => tracing::warn!("Fetching the exit list timed out"),
+        }
+    }
+}
+
+/// Whether `ip` is a known exit address, or `None` before the list is fetched
+pub(crate) fn is_exit(ip: IpAddr) -> Option<bool> {
+    let list = LIST.lock().ok()?;
+    list.as_ref().map(|l| l.addrs.contains(&ip.to_canonical()))
+}
+
+/// Download the list over Tor
+async fn fetch(client: &TorClient<TorRuntime>) -> io::Result<HashSet<IpAddr>> {
+    let stream = client
+        .connect((LIST_HOST, 443))
+        .await
+        .map_err(io::Error::other)?;
+    let config = probe::tls_config().map_err(io::Error::other)?;
+    let name = ServerName::try_from(LIST_HOST).map_err(io::Error::other)?;
+    let mut tls = TlsConnector::from(config).connect(name, stream).await?;
+
+    // HTTP/1.0 so the reply is never chunked
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: bitchat\r\n\r\n",
+        LIST_PATH, LIST_HOST
+    );
+    tls.write_all(request.as_bytes()).await?;
+    tls.flush().await?;
+
+    let mut reply = Vec::new();
+    let read = (&mut tls)
+        .take(MAX_LIST as u64 + 1)
+        .read_to_end(&mut reply)
+        .await;
+    // Servers often close without close_notify; keep whatever arrived
+    if reply.is_empty() {
+        read?;
+    }
+    if reply.len() > MAX_LIST {
+        return Err(io::Error::other("exit list too large"));
+    }
+    let split = reply
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| io::Error::other("malformed HTTP reply"))?;
+    let status = String::from_utf8_lossy(&reply[..split])
+        .split_whitespace()
+        .nth(1)
+        .map(str::to_string)
+        .unwrap_or_default();
+    if status != "200" {
+        return Err(io::Error::other(format!("HTTP status {}", status)));
+    }
+
+    let addrs: HashSet<IpAddr> = String::from_utf8_lossy(&reply[split + 4..])
+        .lines()
+        .filter_map(|line| line.trim().parse::<IpAddr>().ok())
+        .map(|ip| ip.to_canonical())
+        .collect();
+    if addrs.is_empty() {
+        return Err(io::Error::other("empty exit list"));
+    }
+    Ok(addrs)
+}
@@ -0,0 +1,181 @@
+//! Classification of failed Tor connections
+//!
+//! Maps Arti's error kinds onto the distinctions the SOCKS client and the
+//! app care about: whether the exit could not find the host, timed out, or
+//! refused, and whether trying another exit might help. Failed circuit builds
+//! behind a connect error are also picked out, for failure analytics.
+
+use std::error::Error as StdError;
+use std::io;
+
+use arti_client::{ErrorKind, HasKind};
+use serde::Serialize;
+
+// SOCKS5 reply codes (RFC 1928)
+const REP_GENERAL_FAILURE: u8 = 0x01;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_CONNECTION_REFUSED: u8 = 0x05;
+const REP_TTL_EXPIRED: u8 = 0x06;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ConnectFailure {
+    /// The exit reported the hostname does not resolve (NXDOMAIN, or a DNS
+    /// failure it could not tell apart)
+    HostNotFound,
+    /// The exit's resolver failed transiently
+    ResolveFailed,
+    /// The exit, or our own deadline, timed out
+    Timeout,
+    /// The destination refused the connection
+    Refused,
+    /// The exit's policy does not allow the destination
+    ExitPolicy,
+    /// The exit could not reach the destination's network
+    NetworkFailed,
+    Other,
+}
+
+impl ConnectFailure {
+    pub(crate) fn classify(error: &arti_client::Error) -> Self {
+        match error.kind() {
+            ErrorKind::RemoteHostNotFound => ConnectFailure::HostNotFound,
+            ErrorKind::RemoteHostResolutionFailed => ConnectFailure::ResolveFailed,
+            ErrorKind::ExitTimeout | ErrorKind::RemoteNetworkTimeout => ConnectFailure::Timeout,
+            ErrorKind::RemoteConnectionRefused => ConnectFailure::Refused,
+            ErrorKind::ExitPolicyRejected => ConnectFailure::ExitPolicy,
+            ErrorKind::RemoteNetworkFailed => ConnectFailure::NetworkFailed,
+            _ => ConnectFailure::Other,
+        }
+    }
+
+    /// Classify a failed direct connect, as made in `loopback` builds
+    pub(crate) fn classify_io(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => ConnectFailure::HostNotFound,
+            io::ErrorKind::TimedOut => ConnectFailure::Timeout,
+            io::ErrorKind::ConnectionRefused => ConnectFailure::Refused,
+            io::ErrorKind::NetworkUnreachable | io::ErrorKind::HostUnreachable => {
+                ConnectFailure::NetworkFailed
+            }
+            _ => ConnectFailure::Other,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ConnectFailure::HostNotFound => "host_not_found",
+            ConnectFailure::ResolveFailed => "resolve_failed",
+            ConnectFailure::Timeout => "timeout",
+            ConnectFailure::Refused => "refused",
+            ConnectFailure::ExitPolicy => "exit_policy",
+            ConnectFailure::NetworkFailed => "network_failed",
+            ConnectFailure::Other => "other",
+        }
+    }
+
+    /// SOCKS5 reply code to send the client
+    pub(crate) fn socks_reply(&self) -> u8 {
+        match self {
+            ConnectFailure::HostNotFound => REP_HOST_UNREACHABLE,
+            ConnectFailure::ResolveFailed | ConnectFailure::Timeout => REP_TTL_EXPIRED,
+            ConnectFailure::Refused => REP_CONNECTION_REFUSED,
+            ConnectFailure::ExitPolicy => REP_NOT_ALLOWED,
+            ConnectFailure::NetworkFailed => REP_NETWORK_UNREACHABLE,
+            ConnectFailure::Other => REP_GENERAL_FAILURE,
+        }
+    }
+
+    /// Whether a different exit's resolver might succeed where this one failed
+    pub(crate) fn is_resolution_failure(&self) -> bool {
+        matches!(
+            self,
+            ConnectFailure::HostNotFound | ConnectFailure::ResolveFailed | ConnectFailure::Timeout
+        )
+    }
+}
+
+/// Why a circuit build failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BuildFailure {
+    /// The build took longer than Arti's learned timeout
+    Timeout,
+    /// A relay on the path destroyed the circuit or refused to extend it
+    Destroyed,
+    /// No suitable relays or guard could be chosen
+    PathSelection,
+    /// The channel to the first hop could not be opened
+    Channel,
+    /// A handshake or other protocol step failed
+    Protocol,
+}
+
+/// Where on the path a circuit build failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HopPosition {
+    /// The guard
+    First,
+    /// Extending past the guard; Arti cannot tell which of the two relays
+    /// involved in an extension was at fault
+    Later,
+    /// Arti does not say, e.g. for timeouts and path selection
+    Unknown,
+}
+
+/// Circuit build failures behind a connect error, one per attempt Arti made.
+///
+/// Builds Arti retried successfully before the connect went through are not
+/// visible here.
+pub(crate) fn circuit_failures(error: &arti_client::Error) -> Vec<(BuildFailure, HopPosition)> {
+    let mut source: Option<&(dyn StdError + 'static)> = Some(error);
+    while let Some(e) = source {
+        if let Some(circ) = e.downcast_ref::<tor_circmgr::Error>() {
+            let mut failures = Vec::new();
+            collect_circuit_failures(circ, &mut failures);
+            return failures;
+        }
+        source = e.source();
+    }
+    Vec::new()
+}
+
+fn collect_circuit_failures(
+    error: &tor_circmgr::Error,
+    failures: &mut Vec<(BuildFailure, HopPosition)>,
+) {
+    use tor_circmgr::Error as E;
+    let failure = match error {
+        E::RequestFailed(attempts) => {
+            for attempt in attempts.sources() {
+                collect_circuit_failures(attempt, failures);
+            }
+            return;
+        }
+        E::PendingFailed(inner) => return collect_circuit_failures(inner, failures),
+        E::CircTimeout(_) | E::RequestTimeout => (BuildFailure::Timeout, HopPosition::Unknown),
+        E::NoRelay { .. } | E::Guard(_) | E::GuardNotUsable(_) => {
+            (BuildFailure::PathSelection, HopPosition::Unknown)
+        }
+        E::Channel { .. } => (BuildFailure::Channel, HopPosition::First),
+        E::Protocol { action, error, .. } => {
+            let reason = match error {
+                tor_proto::Error::CircuitClosed | tor_proto::Error::CircRefused(_) => {
+                    BuildFailure::Destroyed
+                }
+                _ => BuildFailure::Protocol,
+            };
+            let hop = match *action {
+                "creating first hop" | "running CREATE_FAST handshake" => HopPosition::First,
+                "extending circuit" | "extend tunnel" => HopPosition::Later,
+                _ => HopPosition::Unknown,
+            };
+            (reason, hop)
+        }
+        // Cancellations and usage races are not build failures
+        _ => return,
+    };
+    failures.push(failure);
+}
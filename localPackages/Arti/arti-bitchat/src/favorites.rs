@@ -0,0 +1,259 @@
+//! Kept-alive rendezvous circuits to favorite peers
+//!
+//! Arti closes a rendezvous circuit ten minutes after its last stream, so a
+//! DM to a peer not heard from in a while pays for a new rendezvous, and
+//! possibly a descriptor lookup. For the services in `onion.favorites`, a
+//! stream is opened and closed again every `onion.keepalive_ms`, which keeps
+//! their circuits open and their descriptors fresh. The peer's app sees
+//! each of these streams. Keepalives pause while dormant, and need the
+//! `onion-service-client` feature.
+//!
+//! Peers can also be pinned at run time with `arti_pin_peer`. A pinned peer
+//! is kept alive the same way, but every `onion.refresh_ms`, which is
+//! usually longer than Arti keeps an unused circuit. Arti has no way to
+//! fetch a descriptor on its own, so each refresh still builds a rendezvous
+//! circuit and opens a stream the peer sees. Pins are kept in memory until
+//! unpinned, across stops but not relaunches.
+
+use std::sync::Mutex;
+#[cfg(feature = "onion-service-client")]
+use std::time::Instant;
+
+#[cfg(feature = "onion-service-client")]
+use once_cell::sync::Lazy;
+use serde::Serialize;
+#[cfg(feature = "onion-service-client")]
+use tokio::sync::Notify;
+
+/// Peers that may be pinned at once
+#[cfg(feature = "onion-service-client")]
+pub(crate) const MAX_PINNED: usize = 64;
+
+static FAVORITES: Mutex<Vec<Favorite>> = Mutex::new(Vec::new());
+
+#[cfg(feature = "onion-service-client")]
+static PINS: Mutex<Vec<Pin>> = Mutex::new(Vec::new());
+
+/// Wakes the keepalive task to look up new pins straight away
+#[cfg(feature = "onion-service-client")]
+static PINS_CHANGED: Lazy<Notify> = Lazy::new(Notify::new);
+
+#[cfg(feature = "onion-service-client")]
+#[derive(Clone)]
+struct Pin {
+    address: String,
+    port: u16,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Favorite {
+    address: String,
+    port: u16,
+    /// Pinned with `arti_pin_peer` rather than listed in `onion.favorites`
+    pinned: bool,
+    /// Whether the last keepalive reached the service
+    reachable: bool,
+    /// When a keepalive last reached it, in seconds since the Unix epoch
+    last_reached: Option<u64>,
+    /// Keepalives failed in a row
+    failures: u32,
+    #[cfg(feature = "onion-service-client")]
+    #[serde(skip)]
+    last_attempt: Option<Instant>,
+}
+
+#[cfg(feature = "onion-service-client")]
+#[derive(Debug)]
+pub(crate) enum PinError {
+    /// [`MAX_PINNED`] peers are pinned already
+    TooMany,
+}
+
+/// Pin `address`, replacing an earlier pin of it
+#[cfg(feature = "onion-service-client")]
+pub(crate) fn pin(address: &str, port: u16) -> Result<(), PinError> {
+    let address = address.trim().to_ascii_lowercase();
+    let Ok(mut pins) = PINS.lock() else {
+        return Err(PinError::TooMany);
+    };
+    pins.retain(|p| p.address != address);
+    if pins.len() >= MAX_PINNED {
+        return Err(PinError::TooMany);
+    }
+    pins.push(Pin { address, port });
+    PINS_CHANGED.notify_one();
+    Ok(())
+}
+
+/// Unpin `address`, or every peer if `None`; returns whether any was pinned
+#[cfg(feature = "onion-service-client")]
+pub(crate) fn unpin(address: Option<&str>) -> bool {
+    let Ok(mut pins) = PINS.lock() else {
+        return false;
+    };
+    let before = pins.len();
+    match address.map(|a| a.trim().to_ascii_lowercase()) {
+        Some(address) => pins.retain(|p| p.address != address),
+        None => pins.clear(),
+    }
+    let unpinned = pins.len() != before;
+    drop(pins);
+    if unpinned {
+        PINS_CHANGED.notify_one();
+    }
+    unpinned
+}
+
+/// Reach every favorite and pinned peer again straight away, e.g. over a
+/// new network path; returns their addresses
+#[cfg(feature = "onion-service-client")]
+pub(crate) fn resume() -> Vec<String> {
+    let Ok(mut states) = FAVORITES.lock() else {
+        return Vec::new();
+    };
+    let addresses = states
+        .iter_mut()
+        .map(|state| {
+            state.last_attempt = None;
+            state.address.clone()
+        })
+        .collect();
+    drop(states);
+    PINS_CHANGED.notify_one();
+    addresses
+}
+
+/// Reach `favorites` every `interval` and pinned peers every `refresh`,
+/// until the task is aborted
+#[cfg(feature = "onion-service-client")]
+pub(crate) async fn keep_alive(
+    client: &arti_client::TorClient<crate::clock::TorRuntime>,
+    favorites: &[(String, u16)],
+    interval: std::time::Duration,
+    refresh: std::time::Duration,
+) {
+    use std::sync::atomic::Ordering;
+
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = PINS_CHANGED.notified() => {}
+        }
+        update_targets(favorites);
+        if crate::IS_DORMANT.load(Ordering::SeqCst)
+            || !client.bootstrap_status().ready_for_traffic()
+        {
+            continue;
+        }
+        let due: Vec<(String, u16)> = match FAVORITES.lock() {
+            Ok(mut states) => states
+                .iter_mut()
+                .filter(|state| {
+                    let period = if state.pinned { refresh } else { interval };
+                    // Ticks drift a little, so an entry due at about this tick counts
+                    state
+                        .last_attempt
+                        .is_none_or(|at| at.elapsed() >= period.mul_f32(0.9))
+                })
+                .map(|state| {
+                    state.last_attempt = Some(Instant::now());
+                    (state.address.clone(), state.port)
+                })
+                .collect(),
+            Err(_) => continue,
+        };
+        // A keepalive stuck on an unreachable peer must not hold up the others
+        let results = futures::future::join_all(
+            due.iter()
+                .map(|(address, port)| crate::prefetch::warm(client, address, *port, interval)),
+        )
+        .await;
+        if let Ok(mut states) = FAVORITES.lock() {
+            for ((address, port), result) in due.iter().zip(results) {
+                let Some(state) = states
+                    .iter_mut()
+                    .find(|s| s.address == *address && s.port == *port)
+                else {
+                    continue;
+                };
+                match result {
+                    Ok(()) => {
+                        state.reachable = true;
+                        state.last_reached = Some(now());
+                        state.failures = 0;
+                    }
+                    Err(e) => {
+                        tracing::debug!("Keepalive to {} failed: {:?}", state.address, e);
+                        state.reachable = false;
+                        state.failures = state.failures.saturating_add(1);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Track `favorites` and the current pins, keeping what is known of each
+#[cfg(feature = "onion-service-client")]
+fn update_targets(favorites: &[(String, u16)]) {
+    let pins = PINS.lock().map(|p| p.clone()).unwrap_or_default();
+    let mut targets: Vec<Pin> = favorites
+        .iter()
+        .map(|(address, port)| Pin {
+            address: address.clone(),
+            port: *port,
+        })
+        .collect();
+    let favorite_count = targets.len();
+    for pin in pins {
+        if !targets.iter().any(|t| t.address == pin.address) {
+            targets.push(pin);
+        }
+    }
+    let Ok(mut states) = FAVORITES.lock() else {
+        return;
+    };
+    let previous = std::mem::take(&mut *states);
+    *states = targets
+        .into_iter()
+        .enumerate()
+        .map(|(i, target)| {
+            let known = previous
+                .iter()
+                .find(|s| s.address == target.address && s.port == target.port);
+            Favorite {
+                pinned: i >= favorite_count,
+                ..known.cloned().unwrap_or(Favorite {
+                    address: target.address,
+                    port: target.port,
+                    pinned: false,
+                    reachable: false,
+                    last_reached: None,
+                    failures: 0,
+                    last_attempt: None,
+                })
+            }
+        })
+        .collect();
+}
+
+/// Forget the favorites' state (e.g. on shutdown); pins are kept
+pub(crate) fn clear() {
+    if let Ok(mut states) = FAVORITES.lock() {
+        states.clear();
+    }
+}
+
+pub(crate) fn snapshot() -> Vec<Favorite> {
+    FAVORITES.lock().map(|f| f.clone()).unwrap_or_default()
+}
+
+#[cfg(feature = "onion-service-client")]
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
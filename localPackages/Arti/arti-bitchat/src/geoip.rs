@@ -0,0 +1,109 @@
+//! Country lookups for relays and destinations
+//!
+//! Built with the `geoip` feature, the library embeds Arti's copy of the
+//! GeoIP database, in C Tor's `geoip`/`geoip6` format. The app can replace
+//! it with a newer one out of band: `arti_geoip_update` checks the files,
+//! keeps copies in the data directory, where each start loads them again,
+//! and uses them at once. The database answers `arti_geoip_country` and
+//! gives the country of each hop in `arti_streams`. Arti itself keeps using
+//! the embedded copy to choose exits for `exits.country`, so an update only
+//! changes what lookups report.
+
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use tor_geoip::GeoipDb;
+
+/// Where an updated database is kept, under the data directory
+const GEOIP_DIR: &str = "geoip";
+const V4_FILE: &str = "geoip";
+const V6_FILE: &str = "geoip6";
+
+/// Database replacing the embedded one, if the app supplied one
+static UPDATED: Mutex<Option<Arc<GeoipDb>>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub(crate) enum UpdateError {
+    /// The files could not be read or are not a GeoIP database
+    Invalid(String),
+    /// The database could not be stored in the data directory
+    Store(io::Error),
+}
+
+fn db() -> Arc<GeoipDb> {
+    UPDATED
+        .lock()
+        .ok()
+        .and_then(|db| db.clone())
+        .unwrap_or_else(GeoipDb::new_embedded)
+}
+
+/// Two-letter country code of `ip`, if the database places it
+pub(crate) fn country(ip: IpAddr) -> Option<String> {
+    db().lookup_country_code(ip.to_canonical())
+        .map(|cc| cc.as_ref().to_string())
+}
+
+/// Country of a host with several addresses, if they agree
+pub(crate) fn country_of(ips: impl IntoIterator<Item = IpAddr>) -> Option<String> {
+    db().lookup_country_code_multi(ips)
+        .map(|cc| cc.as_ref().to_string())
+}
+
+/// Use the database stored under `data_dir`, or the embedded one if there is none
+pub(crate) fn open(data_dir: &Path) {
+    let dir = data_dir.join(GEOIP_DIR);
+    let stored =
+        match read(&dir.join(V4_FILE), &dir.join(V6_FILE)).and_then(|(v4, v6)| parse(&v4, &v6)) {
+            Ok(db) => Some(Arc::new(db)),
+            Err(UpdateError::Invalid(e)) if dir.exists() => {
+                tracing::warn!("Ignoring stored GeoIP database: {}", e);
+                None
+            }
+            Err(_) => None,
+        };
+    if let Ok(mut updated) = UPDATED.lock() {
+        *updated = stored;
+    }
+}
+
+/// Replace the database with the one in `v4` and `v6`, keeping a copy under `data_dir`
+pub(crate) fn update(data_dir: &Path, v4: &Path, v6: &Path) -> Result<(), UpdateError> {
+    let (v4, v6) = read(v4, v6)?;
+    let db = parse(&v4, &v6)?;
+    let dir = data_dir.join(GEOIP_DIR);
+    fs::create_dir_all(&dir).map_err(UpdateError::Store)?;
+    for (text, name) in [(&v4, V4_FILE), (&v6, V6_FILE)] {
+        // Written via a temporary file so a failed write never leaves a torn database
+        let tmp = dir.join(format!("{}.tmp", name));
+        fs::write(&tmp, text).map_err(UpdateError::Store)?;
+        fs::rename(&tmp, dir.join(name)).map_err(UpdateError::Store)?;
+    }
+    if let Ok(mut updated) = UPDATED.lock() {
+        *updated = Some(Arc::new(db));
+    }
+    Ok(())
+}
+
+fn read(v4: &Path, v6: &Path) -> Result<(String, String), UpdateError> {
+    let read = |path: &Path| {
+        fs::read_to_string(path)
+            .map_err(|e| UpdateError::Invalid(format!("{}: {}", path.display(), e)))
+    };
+    Ok((read(v4)?, read(v6)?))
+}
+
+fn parse(v4: &str, v6: &str) -> Result<GeoipDb, UpdateError> {
+    // An empty file parses, but would leave every address unplaced
+    let has_entries = |text: &str| {
+        text.lines()
+            .any(|l| !l.trim().is_empty() && !l.starts_with('#'))
+    };
+    if !has_entries(v4) || !has_entries(v6) {
+        return Err(UpdateError::Invalid("database has no entries".into()));
+    }
+    GeoipDb::new_from_legacy_format(v4, v6).map_err(|e| UpdateError::Invalid(e.to_string()))
+}
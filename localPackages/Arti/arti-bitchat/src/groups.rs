@@ -0,0 +1,532 @@
+//! Isolation groups of SOCKS streams
+//!
+//! A client names its group by the username and password it authenticates
+//! with, as Tor's IsolateSOCKSAuth does; with `socks.require_token` the
+//! username is the token, so the password alone names the group. Clients
+//! that do not authenticate share the default group. Streams of different
+//! groups never share a circuit.
+//!
+//! Within a group, `isolation.circuits` decides how far streams share:
+//! sharing lets a pool of relay connections ride one warm circuit, while
+//! separate circuits keep an exit from linking them. With `token`, the
+//! default, streams of a group may share circuits; with `destination`, only
+//! streams to the same host and port do; with `stream`, none do.
+//! `isolation.circuits_by_host` sets this per destination host.
+//!
+//! Groups are also what connection limits apply to, so one part of the app,
+//! such as media prefetching, cannot crowd out another, such as DMs.
+//! `isolation.max_streams` caps the streams a group has open and
+//! `isolation.max_new_per_second` how fast it opens them; requests over
+//! either are refused. With `socks.max_connecting` set, Tor connects beyond
+//! that many wait, and each free slot goes to the next waiting group in
+//! turn, so a group with a long queue delays the others by at most one
+//! connect each.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use arti_client::IsolationToken;
+use once_cell::sync::Lazy;
+use tokio::sync::oneshot;
+
+use crate::config::Config;
+
+/// Tracked groups beyond which those without open streams are forgotten
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Destinations a group keeps circuits apart for before starting over,
+/// which only costs the forgotten ones their warm circuits
+const MAX_DESTINATIONS: usize = 256;
+
+/// How the streams of a group share circuits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CircuitReuse {
+    /// Any streams of the group may share a circuit
+    Token,
+    /// Streams to the same destination may share a circuit
+    Destination,
+    /// Every stream gets a circuit of its own
+    Stream,
+}
+
+impl CircuitReuse {
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        match s {
+            "token" => Some(CircuitReuse::Token),
+            "destination" => Some(CircuitReuse::Destination),
+            "stream" => Some(CircuitReuse::Stream),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            CircuitReuse::Token => "token",
+            CircuitReuse::Destination => "destination",
+            CircuitReuse::Stream => "stream",
+        }
+    }
+
+    /// The reuse for streams to `host`: the first rule of
+    /// `isolation.circuits_by_host` naming it, else `isolation.circuits`
+    fn for_host(host: &str, config: &Config) -> Self {
+        config
+            .circuit_reuse_by_host
+            .iter()
+            .find(|rule| rule.matches(host))
+            .map_or(config.circuit_reuse, |rule| rule.reuse)
+    }
+}
+
+/// An entry of `isolation.circuits_by_host`
+#[derive(Clone, Debug)]
+pub(crate) struct ReuseRule {
+    /// Host name in lower case; a leading `*.` matches any subdomain
+    pub(crate) host: String,
+    pub(crate) reuse: CircuitReuse,
+}
+
+impl ReuseRule {
+    fn matches(&self, host: &str) -> bool {
+        match self.host.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|sub| sub.ends_with('.') && sub.len() > 1),
+            None => host == self.host,
+        }
+    }
+}
+
+struct Group {
+    isolation: IsolationToken,
+    /// Under `destination` reuse, the circuits of each `host:port`
+    destinations: HashMap<String, IsolationToken>,
+    /// Streams admitted and not yet closed
+    streams: usize,
+    /// New streams the group may open now, refilled at the configured rate
+    allowance: f64,
+    refilled: Instant,
+}
+
+static GROUPS: Lazy<Mutex<HashMap<Vec<u8>, Group>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Refusal {
+    /// The group has `isolation.max_streams` streams open
+    TooManyStreams,
+    /// The group is opening streams faster than `isolation.max_new_per_second`
+    TooFast,
+}
+
+impl Refusal {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Refusal::TooManyStreams => "isolation_streams",
+            Refusal::TooFast => "isolation_rate",
+        }
+    }
+}
+
+/// A stream counted against its group until dropped
+pub(crate) struct Admission {
+    key: Vec<u8>,
+    /// Circuits of the group
+    pub(crate) isolation: IsolationToken,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        if let Ok(mut groups) = GROUPS.lock() {
+            if let Some(group) = groups.get_mut(&self.key) {
+                group.streams = group.streams.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// Admit a new stream of the group `key` (empty for the default group) to
+/// `host` and `port`
+pub(crate) fn admit(
+    key: &[u8],
+    host: &str,
+    port: u16,
+    config: &Config,
+) -> Result<Admission, Refusal> {
+    admit_at(key, host, port, config, Instant::now())
+}
+
+/// [`admit`] as of `now`
+fn admit_at(
+    key: &[u8],
+    host: &str,
+    port: u16,
+    config: &Config,
+    now: Instant,
+) -> Result<Admission, Refusal> {
+    let Ok(mut groups) = GROUPS.lock() else {
+        return Ok(Admission {
+            key: key.to_vec(),
+            isolation: IsolationToken::new(),
+        });
+    };
+    if groups.len() >= PRUNE_THRESHOLD && !groups.contains_key(key) {
+        groups.retain(|_, g| g.streams > 0);
+    }
+    let rate = config.group_max_new_per_second as f64;
+    let group = groups.entry(key.to_vec()).or_insert_with(|| Group {
+        isolation: if key.is_empty() {
+            IsolationToken::no_isolation()
+        } else {
+            IsolationToken::new()
+        },
+        destinations: HashMap::new(),
+        streams: 0,
+        allowance: rate,
+        refilled: now,
+    });
+
+    if config.group_max_streams != 0 && group.streams >= config.group_max_streams {
+        return Err(Refusal::TooManyStreams);
+    }
+    if rate > 0.0 {
+        // Bursts of up to a second's worth are allowed
+        let elapsed = now.duration_since(group.refilled).as_secs_f64();
+        group.allowance = (group.allowance + elapsed * rate).min(rate.max(1.0));
+        group.refilled = now;
+        if group.allowance < 1.0 {
+            return Err(Refusal::TooFast);
+        }
+        group.allowance -= 1.0;
+    }
+    group.streams += 1;
+
+    let host = host.to_ascii_lowercase();
+    let isolation = match CircuitReuse::for_host(&host, config) {
+        CircuitReuse::Token => group.isolation,
+        CircuitReuse::Destination => {
+            if group.destinations.len() >= MAX_DESTINATIONS {
+                group.destinations.clear();
+            }
+            *group
+                .destinations
+                .entry(format!("{}:{}", host, port))
+                .or_insert_with(IsolationToken::new)
+        }
+        CircuitReuse::Stream => IsolationToken::new(),
+    };
+    Ok(Admission {
+        key: key.to_vec(),
+        isolation,
+    })
+}
+
+/// Give every named group new circuits for its next streams, e.g. once the
+/// network path the old ones were built over is gone
+pub(crate) fn renew_isolation() {
+    if let Ok(mut groups) = GROUPS.lock() {
+        for (key, group) in groups.iter_mut() {
+            if !key.is_empty() {
+                group.isolation = IsolationToken::new();
+            }
+            group.destinations.clear();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Connects {
+    in_flight: usize,
+    /// Groups with connects waiting, in the order they are served
+    turns: VecDeque<Vec<u8>>,
+    waiting: HashMap<Vec<u8>, VecDeque<oneshot::Sender<ConnectSlot>>>,
+}
+
+static CONNECTS: Lazy<Mutex<Connects>> = Lazy::new(|| Mutex::new(Connects::default()));
+
+/// One of the `socks.max_connecting` connects, handed on when dropped
+pub(crate) struct ConnectSlot(());
+
+impl Drop for ConnectSlot {
+    fn drop(&mut self) {
+        let Ok(mut connects) = CONNECTS.lock() else {
+            return;
+        };
+        while let Some(key) = connects.turns.pop_front() {
+            let Some(queue) = connects.waiting.get_mut(&key) else {
+                continue;
+            };
+            let next = queue.pop_front();
+            if queue.is_empty() {
+                connects.waiting.remove(&key);
+            } else {
+                connects.turns.push_back(key);
+            }
+            let Some(next) = next else {
+                continue;
+            };
+            // The slot stays counted and moves to the waiter; a waiter that
+            // has gone away hands back a slot that must not be released twice
+            match next.send(ConnectSlot(())) {
+                Ok(()) => return,
+                Err(slot) => std::mem::forget(slot),
+            }
+        }
+        connects.in_flight = connects.in_flight.saturating_sub(1);
+    }
+}
+
+/// Wait for a connect slot for the group `key`; `None` if connects are unlimited
+pub(crate) async fn connect_slot(key: &[u8], limit: usize) -> Option<ConnectSlot> {
+    if limit == 0 {
+        return None;
+    }
+    let granted = {
+        let Ok(mut connects) = CONNECTS.lock() else {
+            return None;
+        };
+        if connects.in_flight < limit && connects.turns.is_empty() {
+            connects.in_flight += 1;
+            return Some(ConnectSlot(()));
+        }
+        let (tx, rx) = oneshot::channel();
+        if !connects.waiting.contains_key(key) {
+            connects.turns.push_back(key.to_vec());
+        }
+        connects
+            .waiting
+            .entry(key.to_vec())
+            .or_default()
+            .push_back(tx);
+        rx
+    };
+    granted.await.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    fn rule(host: &str, reuse: CircuitReuse) -> ReuseRule {
+        ReuseRule {
+            host: host.into(),
+            reuse,
+        }
+    }
+
+    #[test]
+    fn stream_limits() {
+        let config = Config {
+            group_max_streams: 2,
+            ..Config::default()
+        };
+        let first = admit(b"limits", "example.com", 443, &config).unwrap();
+        let _second = admit(b"limits", "example.com", 443, &config).unwrap();
+        assert!(matches!(
+            admit(b"limits", "example.com", 443, &config),
+            Err(Refusal::TooManyStreams)
+        ));
+        // Other groups have limits of their own
+        assert!(admit(b"limits-other", "example.com", 443, &config).is_ok());
+        drop(first);
+        assert!(admit(b"limits", "example.com", 443, &config).is_ok());
+    }
+
+    #[test]
+    fn rate_limits() {
+        let config = Config {
+            group_max_new_per_second: 2,
+            ..Config::default()
+        };
+        let start = Instant::now();
+        let admit = |after_ms: u64| {
+            let now = start + std::time::Duration::from_millis(after_ms);
+            admit_at(b"rate", "example.com", 443, &config, now).map(drop)
+        };
+        assert!(admit(0).is_ok());
+        assert!(admit(0).is_ok());
+        assert!(matches!(admit(0), Err(Refusal::TooFast)));
+        assert!(matches!(admit(499), Err(Refusal::TooFast)));
+        assert!(admit(500).is_ok());
+        // An idle group saves up no more than a second's worth
+        assert!(admit(60_000).is_ok());
+        assert!(admit(60_000).is_ok());
+        assert!(matches!(admit(60_000), Err(Refusal::TooFast)));
+    }
+
+    /// Streams with different credentials never share an isolation token,
+    /// whatever the reuse settings, destinations and order of streams
+    #[test]
+    fn credentials_never_share_circuits() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        const HOSTS: [&str; 5] = [
+            "example.com",
+            "a.cdn.example",
+            "b.cdn.example",
+            "EXAMPLE.com",
+            "192.0.2.1",
+        ];
+        const REUSE: [CircuitReuse; 3] = [
+            CircuitReuse::Token,
+            CircuitReuse::Destination,
+            CircuitReuse::Stream,
+        ];
+        // Seeded so that a failure can be reproduced
+        let mut rng = StdRng::seed_from_u64(190);
+        for case in 0..200 {
+            let config = Config {
+                circuit_reuse: REUSE[rng.random_range(0..REUSE.len())],
+                circuit_reuse_by_host: (0..rng.random_range(0..3))
+                    .map(|_| {
+                        let host = match rng.random_bool(0.5) {
+                            true => "*.cdn.example",
+                            false => HOSTS[rng.random_range(0..HOSTS.len())],
+                        };
+                        rule(
+                            &host.to_ascii_lowercase(),
+                            REUSE[rng.random_range(0..REUSE.len())],
+                        )
+                    })
+                    .collect(),
+                ..Config::default()
+            };
+            // Unique to this test and often prefixes of one another, along
+            // with the default group of clients that do not authenticate
+            let stem = format!("property-{}-", case).into_bytes();
+            let credentials: Vec<Vec<u8>> = (0..rng.random_range(2..6))
+                .map(|i| {
+                    if i == 0 && rng.random_bool(0.5) {
+                        return Vec::new();
+                    }
+                    let mut key = stem.clone();
+                    key.extend((0..rng.random_range(0..3)).map(|_| rng.random_range(b'a'..=b'b')));
+                    key
+                })
+                .collect();
+
+            let mut admitted: Vec<(Vec<u8>, Admission)> = Vec::new();
+            for _ in 0..rng.random_range(1..40) {
+                let key = &credentials[rng.random_range(0..credentials.len())];
+                let host = HOSTS[rng.random_range(0..HOSTS.len())];
+                let port = [80, 443][rng.random_range(0..2)];
+                let admission = admit(key, host, port, &config).unwrap();
+                for (other, earlier) in &admitted {
+                    assert!(
+                        other == key || earlier.isolation != admission.isolation,
+                        "case {}: {:?} and {:?} share a circuit under {:?}",
+                        case,
+                        String::from_utf8_lossy(other),
+                        String::from_utf8_lossy(key),
+                        config.circuit_reuse
+                    );
+                }
+                admitted.push((key.clone(), admission));
+                // Streams close at random, which must not affect the tokens
+                if rng.random_bool(0.2) {
+                    admitted.swap_remove(rng.random_range(0..admitted.len()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reuse_within_a_group() {
+        let token = |host: &str, port: u16, config: &Config| {
+            admit(b"reuse", host, port, config).unwrap().isolation
+        };
+        let config = Config {
+            circuit_reuse: CircuitReuse::Destination,
+            circuit_reuse_by_host: vec![
+                rule("*.cdn.example", CircuitReuse::Token),
+                rule("tracker.example", CircuitReuse::Stream),
+            ],
+            ..Config::default()
+        };
+        assert_eq!(
+            token("example.com", 443, &config),
+            token("EXAMPLE.com", 443, &config)
+        );
+        assert_ne!(
+            token("example.com", 443, &config),
+            token("example.com", 80, &config)
+        );
+        assert_eq!(
+            token("a.cdn.example", 443, &config),
+            token("b.cdn.example", 80, &config)
+        );
+        assert_ne!(
+            token("tracker.example", 443, &config),
+            token("tracker.example", 443, &config)
+        );
+    }
+
+    #[test]
+    fn reuse_rules_match_hosts() {
+        let wildcard = rule("*.example.com", CircuitReuse::Stream);
+        assert!(wildcard.matches("a.example.com"));
+        assert!(wildcard.matches("a.b.example.com"));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches(".example.com"));
+        assert!(!wildcard.matches("aexample.com"));
+        let exact = rule("example.com", CircuitReuse::Stream);
+        assert!(exact.matches("example.com"));
+        assert!(!exact.matches("a.example.com"));
+    }
+
+    fn poll<F: Future>(future: &mut Pin<Box<F>>) -> Poll<F::Output> {
+        future
+            .as_mut()
+            .poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    fn granted(
+        future: &mut Pin<Box<impl Future<Output = Option<ConnectSlot>>>>,
+    ) -> Option<ConnectSlot> {
+        match poll(future) {
+            Poll::Ready(slot) => Some(slot.unwrap()),
+            Poll::Pending => None,
+        }
+    }
+
+    /// All connect slot tests, as they share the one count of connects
+    #[test]
+    fn connect_slots_go_to_groups_in_turn() {
+        let in_flight = || CONNECTS.lock().unwrap().in_flight;
+        assert!(matches!(
+            poll(&mut Box::pin(connect_slot(b"a", 0))),
+            Poll::Ready(None)
+        ));
+        assert_eq!(in_flight(), 0);
+
+        let slot = granted(&mut Box::pin(connect_slot(b"a", 1))).unwrap();
+        let mut a1 = Box::pin(connect_slot(b"a", 1));
+        let mut a2 = Box::pin(connect_slot(b"a", 1));
+        let mut b1 = Box::pin(connect_slot(b"b", 1));
+        let mut gone = Box::pin(connect_slot(b"c", 1));
+        for waiter in [&mut a1, &mut a2, &mut b1] {
+            assert!(granted(waiter).is_none());
+        }
+        assert!(granted(&mut gone).is_none());
+        drop(gone);
+
+        drop(slot);
+        let slot = granted(&mut a1).unwrap();
+        assert!(granted(&mut a2).is_none());
+        assert!(granted(&mut b1).is_none());
+        drop(slot);
+        let slot = granted(&mut b1).unwrap();
+        assert!(granted(&mut a2).is_none());
+        // The waiter that went away is skipped
+        drop(slot);
+        let slot = granted(&mut a2).unwrap();
+        assert_eq!(in_flight(), 1);
+        drop(slot);
+        assert_eq!(in_flight(), 0);
+        assert!(CONNECTS.lock().unwrap().turns.is_empty());
+    }
+}
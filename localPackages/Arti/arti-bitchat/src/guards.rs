@@ -0,0 +1,355 @@
+//! Entry guard controls
+//!
+//! Arti keeps its guard samples in `state/state/guards.json` and gives us no
+//! API to change them, so the controls here work on that file. Because Arti
+//! owns the state while it runs, a pin or rotation is recorded in
+//! `guard-controls.json` in the data directory and applied the next time
+//! Arti starts. The record is what persists the user's decision: a pin stays
+//! until it is removed, and a rotation survives the app being killed before
+//! the next start.
+//!
+//! Rotating drops guards from the sample so Arti picks new ones. Pinning
+//! uses the guard as a bridge, so every circuit starts there even if the
+//! relay later loses its Guard flag; configured `bridges` take precedence.
+
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{metrics, storage};
+
+/// Characters of the fingerprint shown when redacting, and the shortest
+/// prefix accepted to name a guard
+const FINGERPRINT_PREFIX: usize = 8;
+
+#[derive(Debug)]
+pub(crate) enum GuardError {
+    /// No listed guard matches, or a prefix matches several
+    NotFound,
+    Io(io::Error),
+}
+
+impl From<io::Error> for GuardError {
+    fn from(e: io::Error) -> Self {
+        GuardError::Io(e)
+    }
+}
+
+/// Decisions waiting to be applied, or (for the pin) kept applied
+#[derive(Serialize, Deserialize, Default)]
+struct Controls {
+    #[serde(default)]
+    pinned: Option<Pinned>,
+    /// Discard the whole sample on next start
+    #[serde(default)]
+    rotate_all: bool,
+    /// RSA fingerprints to drop from the sample on next start
+    #[serde(default)]
+    rotate: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Pinned {
+    rsa_id: String,
+    addrs: Vec<SocketAddr>,
+}
+
+/// A guard from Arti's sample
+#[derive(Serialize)]
+pub(crate) struct GuardEntry {
+    /// RSA fingerprint, lower-case hex; a short prefix when redacted
+    fingerprint: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ed_id: Option<String>,
+    /// Empty when redacted
+    addrs: Vec<String>,
+    /// Sample it belongs to: `default`, `restricted` or `bridges`
+    sample: String,
+    added_at: Option<String>,
+    /// Has been used successfully
+    confirmed: bool,
+    /// Arti stopped using it after too many failures
+    disabled: bool,
+    /// Missing from the latest consensus
+    unlisted: bool,
+    /// Used by the most recent stream
+    current: bool,
+    pinned: bool,
+    rotation_pending: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct GuardList {
+    guards: Vec<GuardEntry>,
+    /// Fingerprint of the pinned guard (redacted like the entries)
+    pinned: Option<String>,
+    rotate_all_pending: bool,
+}
+
+fn guards_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("state").join("state").join("guards.json")
+}
+
+fn controls_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("guard-controls.json")
+}
+
+fn load_controls(data_dir: &Path) -> Controls {
+    let mut controls: Controls = fs::read(controls_path(data_dir))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default();
+    // Only a damaged or hand-edited file has a pin with nowhere to connect
+    if controls.pinned.as_ref().is_some_and(|p| p.addrs.is_empty()) {
+        tracing::warn!("Ignoring the pinned guard in guard-controls.json, which has no address");
+        controls.pinned = None;
+    }
+    controls
+}
+
+fn save_controls(data_dir: &Path, controls: &Controls) -> io::Result<()> {
+    fs::create_dir_all(data_dir)?;
+    let json = serde_json::to_vec_pretty(controls).map_err(io::Error::other)?;
+    storage::replace_file(&controls_path(data_dir), &json)
+}
+
+/// Guard samples from guards.json as (sample name, guard object) pairs
+fn load_samples(data_dir: &Path) -> Vec<(String, Value)> {
+    let Some(Value::Object(sets)) = fs::read(guards_path(data_dir))
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+    else {
+        return Vec::new();
+    };
+    sets.into_iter()
+        .filter_map(|(name, set)| match set.get("guards") {
+            Some(Value::Array(guards)) => Some((name, guards.clone())),
+            _ => None,
+        })
+        .flat_map(|(name, guards)| guards.into_iter().map(move |g| (name.clone(), g)))
+        .collect()
+}
+
+fn rsa_id(guard: &Value) -> Option<String> {
+    guard
+        .pointer("/id/rsa")?
+        .as_str()
+        .map(str::to_ascii_lowercase)
+}
+
+fn redact_id(id: &str, redact: bool) -> String {
+    if redact {
+        id.chars().take(FINGERPRINT_PREFIX).collect()
+    } else {
+        id.to_string()
+    }
+}
+
+/// List the sampled guards, in sample order
+pub(crate) fn list(data_dir: &Path, redact: bool) -> GuardList {
+    let controls = load_controls(data_dir);
+    let current = metrics::current_guard().map(|g| g.fingerprint().to_ascii_lowercase());
+    let pinned = controls.pinned.as_ref().map(|p| p.rsa_id.as_str());
+
+    let guards = load_samples(data_dir)
+        .into_iter()
+        .filter_map(|(sample, guard)| {
+            let id = rsa_id(&guard)?;
+            let addrs = guard
+                .get("orports")
+                .and_then(Value::as_array)
+                .map(|a| {
+                    a.iter()
+                        .filter_map(|v| v.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default();
+            let ed_id = guard
+                .pointer("/id/ed25519")
+                .and_then(Value::as_str)
+                .map(String::from);
+            let field = |name: &str| guard.get(name).and_then(Value::as_str).map(String::from);
+            Some(GuardEntry {
+                fingerprint: redact_id(&id, redact),
+                ed_id: ed_id.filter(|_| !redact),
+                addrs: if redact { Vec::new() } else { addrs },
+                sample,
+                added_at: field("added_at"),
+                confirmed: field("confirmed_at").is_some(),
+                disabled: guard.get("disabled").is_some_and(|d| !d.is_null()),
+                unlisted: field("unlisted_since").is_some(),
+                current: current.as_deref() == Some(id.as_str()),
+                pinned: pinned == Some(id.as_str()),
+                rotation_pending: controls.rotate_all || controls.rotate.contains(&id),
+            })
+        })
+        .collect();
+
+    GuardList {
+        guards,
+        pinned: pinned.map(|id| redact_id(id, redact)),
+        rotate_all_pending: controls.rotate_all,
+    }
+}
+
+/// Find the one sampled guard whose fingerprint starts with `prefix`
+fn find(data_dir: &Path, prefix: &str) -> Result<Value, GuardError> {
+    let prefix = prefix.trim().trim_start_matches('$').to_ascii_lowercase();
+    if prefix.len() < FINGERPRINT_PREFIX {
+        return Err(GuardError::NotFound);
+    }
+    let mut matches = load_samples(data_dir)
+        .into_iter()
+        .map(|(_, g)| g)
+        .filter(|g| rsa_id(g).is_some_and(|id| id.starts_with(&prefix)));
+    match (matches.next(), matches.next()) {
+        (Some(guard), None) => Ok(guard),
+        (Some(first), Some(second)) if rsa_id(&first) == rsa_id(&second) => Ok(first),
+        _ => Err(GuardError::NotFound),
+    }
+}
+
+/// Pin the guard named by `fingerprint` (a prefix will do), or unpin with `None`
+pub(crate) fn pin(data_dir: &Path, fingerprint: Option<&str>) -> Result<(), GuardError> {
+    let mut controls = load_controls(data_dir);
+    controls.pinned = match fingerprint {
+        None => None,
+        Some(fp) => {
+            let guard = find(data_dir, fp)?;
+            let addrs: Vec<SocketAddr> = guard
+                .get("orports")
+                .and_then(Value::as_array)
+                .map(|a| a.iter().filter_map(|v| v.as_str()?.parse().ok()).collect())
+                .unwrap_or_default();
+            if addrs.is_empty() {
+                return Err(GuardError::NotFound);
+            }
+            Some(Pinned {
+                rsa_id: rsa_id(&guard).ok_or(GuardError::NotFound)?,
+                addrs,
+            })
+        }
+    };
+    save_controls(data_dir, &controls)?;
+    Ok(())
+}
+
+/// Schedule the guard named by `fingerprint`, or with `None` every guard, to
+/// be replaced on next start
+pub(crate) fn rotate(data_dir: &Path, fingerprint: Option<&str>) -> Result<(), GuardError> {
+    let mut controls = load_controls(data_dir);
+    match fingerprint {
+        None => controls.rotate_all = true,
+        Some(fp) => {
+            let id = rsa_id(&find(data_dir, fp)?).ok_or(GuardError::NotFound)?;
+            if !controls.rotate.contains(&id) {
+                controls.rotate.push(id);
+            }
+        }
+    }
+    save_controls(data_dir, &controls)?;
+    Ok(())
+}
+
+/// Apply pending rotations before Arti opens its state, returning the
+/// bridge line for the pinned guard, if any.
+pub(crate) fn apply(data_dir: &Path) -> io::Result<Option<String>> {
+    let mut controls = load_controls(data_dir);
+    let path = guards_path(data_dir);
+
+    if controls.rotate_all {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => tracing::info!("Discarded guard sample"),
+        }
+    } else if !controls.rotate.is_empty() {
+        if let Some(mut sets) = fs::read(&path)
+            .ok()
+            .and_then(|b| serde_json::from_slice::<Value>(&b).ok())
+        {
+            let drop = |entry: &Value| {
+                // Guards are objects with an `id`; `confirmed` lists bare ids
+                let id = entry
+                    .get("id")
+                    .unwrap_or(entry)
+                    .get("rsa")
+                    .and_then(Value::as_str);
+                id.is_some_and(|id| controls.rotate.contains(&id.to_ascii_lowercase()))
+            };
+            if let Some(sets) = sets.as_object_mut() {
+                for set in sets.values_mut() {
+                    for key in ["guards", "confirmed"] {
+                        if let Some(Value::Array(list)) = set.get_mut(key) {
+                            list.retain(|entry| !drop(entry));
+                        }
+                    }
+                }
+            }
+            storage::replace_file(
+                &path,
+                &serde_json::to_vec_pretty(&sets).map_err(io::Error::other)?,
+            )?;
+            tracing::info!("Dropped {} guard(s) from the sample", controls.rotate.len());
+        }
+    }
+
+    if controls.rotate_all || !controls.rotate.is_empty() {
+        controls.rotate_all = false;
+        controls.rotate.clear();
+        save_controls(data_dir, &controls)?;
+    }
+
+    Ok(controls.pinned.and_then(|p| {
+        let Some(addr) = p.addrs.iter().find(|a| a.is_ipv4()).or(p.addrs.first()) else {
+            tracing::warn!("Not pinning guard {}, which has no address", p.rsa_id);
+            return None;
+        };
+        Some(format!("{} {}", addr, p.rsa_id.to_ascii_uppercase()))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("arti-guards-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const FINGERPRINT: &str = "4352e58420e68f5e40bf7c74faddccd9d1349413";
+
+    #[test]
+    fn pins_become_bridge_lines() {
+        let dir = data_dir("pin");
+        let controls = format!(
+            r#"{{"pinned":{{"rsa_id":"{}","addrs":["[2001:db8::1]:443","192.0.2.1:9001"]}}}}"#,
+            FINGERPRINT
+        );
+        fs::write(controls_path(&dir), controls).unwrap();
+        assert_eq!(
+            apply(&dir).unwrap(),
+            Some(format!(
+                "192.0.2.1:9001 {}",
+                FINGERPRINT.to_ascii_uppercase()
+            ))
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn pins_without_addresses_are_ignored() {
+        let dir = data_dir("no-addrs");
+        let controls = format!(r#"{{"pinned":{{"rsa_id":"{}","addrs":[]}}}}"#, FINGERPRINT);
+        fs::write(controls_path(&dir), controls).unwrap();
+        assert_eq!(apply(&dir).unwrap(), None);
+        assert!(load_controls(&dir).pinned.is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,78 @@
+//! Self-check that stream isolation is honoured
+//!
+//! Opens a stream to a destination under one isolation token, then, while
+//! it is still open, a second under another, and compares the circuits Arti
+//! attached them to. Isolated streams must never share a circuit; if these
+//! do, something in the configuration or in Arti is merging circuits that
+//! should be kept apart, and an `isolation_not_honored` event is emitted so
+//! the app can warn the user.
+
+use std::time::Duration;
+
+use arti_client::{DataStream, IntoTorAddr, IsolationToken, StreamPrefs, TorAddr, TorClient};
+use serde::Serialize;
+use tor_proto::client::stream::ClientStreamCtrl;
+
+use crate::clock::TorRuntime;
+use crate::events::{self, Event};
+
+#[derive(Debug)]
+pub(crate) enum CheckError {
+    BadAddress,
+    Connect(String),
+    Timeout,
+}
+
+#[derive(Serialize)]
+pub(crate) struct Report {
+    /// `None` if a stream's circuit could not be inspected
+    isolated: Option<bool>,
+    /// Circuit of each stream, in the order they were opened
+    circuits: [Option<String>; 2],
+}
+
+/// Run the check against `host:port` within `limit`
+pub(crate) async fn check(
+    client: &TorClient<TorRuntime>,
+    host: &str,
+    port: u16,
+    limit: Duration,
+) -> Result<Report, CheckError> {
+    let addr = (host, port)
+        .into_tor_addr()
+        .map_err(|_| CheckError::BadAddress)?;
+    tokio::time::timeout(limit, async {
+        let first = connect(client, addr.clone()).await?;
+        let second = connect(client, addr).await?;
+        let circuits = [circuit(&first), circuit(&second)];
+        let isolated = match &circuits {
+            [Some(a), Some(b)] => Some(a != b),
+            _ => None,
+        };
+        if isolated == Some(false) {
+            let circuit = circuits[0].clone().unwrap_or_default();
+            tracing::warn!(
+                "Streams with different isolation tokens shared circuit {}",
+                circuit
+            );
+            events::emit(Event::IsolationNotHonored { circuit });
+        }
+        Ok(Report { isolated, circuits })
+    })
+    .await
+    .map_err(|_| CheckError::Timeout)?
+}
+
+async fn connect(client: &TorClient<TorRuntime>, addr: TorAddr) -> Result<DataStream, CheckError> {
+    let mut prefs = StreamPrefs::new();
+    prefs.set_isolation(IsolationToken::new());
+    client
+        .connect_with_prefs(addr, &prefs)
+        .await
+        .map_err(|e| CheckError::Connect(e.to_string()))
+}
+
+fn circuit(stream: &DataStream) -> Option<String> {
+    let tunnel = stream.client_stream_ctrl()?.tunnel()?;
+    Some(tunnel.unique_id().to_string())
+}
@@ -0,0 +1,81 @@
+//! Wiping key material, for the app's panic mode
+//!
+//! Secret keys only pass through this crate on their way in or out of
+//! Arti's keystore, as onion service identity keys being imported or
+//! exported; restricted discovery keys cross the FFI as their public half
+//! only, and so do the Noise keys put into contact payloads. Identity keys,
+//! and the decoded Noise keys too, are held in `Zeroizing` buffers, which
+//! are overwritten when dropped; the keys Arti itself holds are wiped by its
+//! own types. What cannot be reached
+//! from here are the C strings the app passes in and the buffers it gets
+//! keys back in, which the app has to wipe itself.
+//!
+//! At rest, onion service identity keys and restricted discovery client keys
+//! live in Arti's keystore under the state directory. [`wipe_all`] overwrites
+//! and deletes the keystore of a data directory and of every profile under
+//! it. Arti must not be running on any of them. Flash storage may keep old
+//! copies of blocks out of reach of any file API, so this protects against
+//! someone reading the files, not a forensic read of the chip.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::profiles;
+
+/// Where Arti keeps its keystore within the state directory
+const KEYSTORE_DIR: &str = "keystore";
+
+/// Wipe the keystores of `data_dir` and of the profiles under it.
+///
+/// Carries on past failures so as much as possible is wiped, and returns
+/// the first one.
+pub(crate) fn wipe_all(data_dir: &Path) -> io::Result<()> {
+    let mut result = wipe_keystore(&data_dir.join("state").join(KEYSTORE_DIR));
+    for profile in profiles::dirs(data_dir) {
+        let wiped = wipe_keystore(&profile.join("state").join(KEYSTORE_DIR));
+        if result.is_ok() {
+            result = wiped;
+        }
+    }
+    result
+}
+
+/// Overwrite every file in `dir` with zeros, then delete the directory
+fn wipe_keystore(dir: &Path) -> io::Result<()> {
+    let mut result = Ok(());
+    overwrite(dir, &mut result);
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => result,
+    }
+}
+
+fn overwrite(dir: &Path, result: &mut io::Result<()>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        let Ok(kind) = entry.file_type() else {
+            continue;
+        };
+        if kind.is_dir() {
+            overwrite(&path, result);
+        } else if kind.is_file() {
+            if let Err(e) = zero_file(&path) {
+                tracing::warn!("Could not overwrite {}: {}", path.display(), e);
+                if result.is_ok() {
+                    *result = Err(e);
+                }
+            }
+        }
+    }
+}
+
+fn zero_file(path: &Path) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let len = file.metadata()?.len();
+    file.write_all(&vec![0; len as usize])?;
+    file.sync_all()
+}
@@ -0,0 +1,173 @@
+//! Stream connect latency histograms
+//!
+//! Each successful SOCKS connect records its total time to connect, and, split
+//! at the point Arti has a circuit for it, the time to attach a circuit and
+//! the time to open the stream over it. Arti's connect call does not expose
+//! that point, so it is taken from the debug event Arti logs there, seen by a
+//! layer on our subscriber. If Arti stops logging it, or the host process
+//! installed its own subscriber, only totals are recorded. The same point
+//! drives the `stream_circuit_attached` event.
+
+use std::cell::Cell;
+use std::fmt;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Upper bounds of the histogram buckets; a final bucket takes the rest
+const BUCKET_BOUNDS_MS: [u64; 9] = [100, 250, 500, 1_000, 2_000, 5_000, 10_000, 20_000, 60_000];
+
+/// Where Arti logs that it has a circuit for a stream, and what it says
+const ATTACH_TARGET: &str = "arti_client::client";
+const ATTACH_MESSAGE: &str = "Got a circuit for ";
+
+tokio::task_local! {
+    /// The connect running in this task
+    static ATTACHED: Attach;
+}
+
+struct Attach {
+    /// When it got its circuit
+    at: Cell<Option<Instant>>,
+    /// Called when it gets its circuit
+    notify: Box<dyn Fn() + Send>,
+}
+
+static HISTOGRAMS: Mutex<Latency> = Mutex::new(Latency::new());
+
+#[derive(Clone, Debug, Serialize)]
+struct Histogram {
+    bounds_ms: [u64; BUCKET_BOUNDS_MS.len()],
+    /// Connects per bucket, with one more entry for those above the last bound
+    counts: [u64; BUCKET_BOUNDS_MS.len() + 1],
+    count: u64,
+    sum_ms: u64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            bounds_ms: BUCKET_BOUNDS_MS,
+            counts: [0; BUCKET_BOUNDS_MS.len() + 1],
+            count: 0,
+            sum_ms: 0,
+        }
+    }
+
+    fn record(&mut self, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        let bucket = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum_ms += ms;
+    }
+}
+
+/// Connect latencies since launch
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Latency {
+    /// From the SOCKS request to the stream being open, across retries
+    total: Histogram,
+    /// From the final attempt starting to Arti having a circuit
+    circuit_attach: Histogram,
+    /// From having a circuit to the exit or service confirming the stream
+    stream_open: Histogram,
+}
+
+impl Latency {
+    const fn new() -> Self {
+        Latency {
+            total: Histogram::new(),
+            circuit_attach: Histogram::new(),
+            stream_open: Histogram::new(),
+        }
+    }
+}
+
+/// Run one connect attempt, noting when it gets a circuit and calling
+/// `on_attach` then
+pub(crate) async fn watch_attach<F: Future>(
+    attempt: F,
+    on_attach: impl Fn() + Send + 'static,
+) -> (F::Output, Option<Instant>) {
+    let attach = Attach {
+        at: Cell::new(None),
+        notify: Box::new(on_attach),
+    };
+    ATTACHED
+        .scope(attach, async {
+            let output = attempt.await;
+            (output, ATTACHED.with(|a| a.at.get()))
+        })
+        .await
+}
+
+/// Record a successful connect that started at `started`, whose final attempt
+/// started at `attempt_started` and got its circuit at `attached`
+pub(crate) fn record(started: Instant, attempt_started: Instant, attached: Option<Instant>) {
+    let now = Instant::now();
+    let Ok(mut histograms) = HISTOGRAMS.lock() else {
+        return;
+    };
+    histograms.total.record(now - started);
+    if let Some(attached) = attached {
+        histograms.circuit_attach.record(attached - attempt_started);
+        histograms.stream_open.record(now - attached);
+    }
+}
+
+pub(crate) fn snapshot() -> Latency {
+    HISTOGRAMS
+        .lock()
+        .map(|h| h.clone())
+        .unwrap_or_else(|_| Latency::new())
+}
+
+/// Layer noting when a connect gets its circuit
+pub(crate) fn layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    AttachLayer.with_filter(Targets::new().with_target(ATTACH_TARGET, Level::DEBUG))
+}
+
+struct AttachLayer;
+
+impl<S: Subscriber> Layer<S> for AttachLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        // Only connects run through `watch_attach` are of interest
+        if ATTACHED.try_with(|_| ()).is_err() {
+            return;
+        }
+        let mut visitor = MessageVisitor(false);
+        event.record(&mut visitor);
+        if visitor.0 {
+            let _ = ATTACHED.try_with(|a| {
+                a.at.set(Some(Instant::now()));
+                (a.notify)();
+            });
+        }
+    }
+}
+
+/// Checks whether an event's message is the circuit attach message
+struct MessageVisitor(bool);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value).starts_with(ATTACH_MESSAGE);
+        }
+    }
+}
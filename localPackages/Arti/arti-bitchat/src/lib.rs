@@ -3,44 +3,121 @@
 //! Provides a C-compatible interface for embedding Arti (Rust Tor) in iOS/macOS apps.
 //! Exposes a SOCKS5 proxy on localhost that Swift code can route traffic through.
 
-use std::ffi::{c_char, c_int, CStr};
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use arti_client::TorClient;
+use arti_client::config::TorClientConfigBuilder;
+use arti_client::{BootstrapBehavior, TorClient};
 use once_cell::sync::OnceCell;
 use tokio::net::TcpListener;
 use tokio::runtime::Runtime;
-use tokio::sync::oneshot;
-use tor_rtcompat::PreferredRuntime;
+use tokio::task::JoinHandle;
 
+use crate::clock::TorRuntime;
+
+mod audit;
+mod bootstrap;
+mod bridges;
+mod budget;
+mod cache;
+mod circuit_timeout;
+mod client_stream;
+mod clock;
+mod config;
+#[cfg(feature = "onion-service-client")]
+mod contact;
+mod deadline;
+mod diagnosis;
+mod events;
+mod exits;
+mod failure;
+mod favorites;
+#[cfg(feature = "geoip")]
+mod geoip;
+mod groups;
+mod guards;
+mod isolation;
+mod keys;
+mod latency;
+mod listener;
+mod logging;
+mod loopback;
+mod memory;
+mod metrics;
+mod migration;
+mod moat;
+mod monitor;
+#[cfg(feature = "onion-service-client")]
+mod onion_auth;
+#[cfg(feature = "onion-service-service")]
+mod onion_service;
+mod padding;
+mod pipe;
+mod policy;
+mod prefetch;
+mod probe;
+mod profiles;
+mod quota;
+mod race;
+mod ratelimit;
+mod recording;
+#[cfg(debug_assertions)]
+mod relay_stats;
+mod remote;
+#[cfg(feature = "onion-service-service")]
+mod service_limits;
+mod shutdown;
+mod sockopt;
 mod socks;
+mod stats;
+mod status;
+mod status_page;
+mod storage;
+mod stream_events;
+#[cfg(feature = "otlp")]
+mod telemetry;
+mod testnet;
+mod timeslice;
+mod tuning;
+mod watchdog;
 
 /// Global state for the Arti instance
 struct ArtiState {
-    /// Tokio runtime (owned, single instance)
-    runtime: Runtime,
-    /// Shutdown signal sender
-    shutdown_tx: Option<oneshot::Sender<()>>,
+    /// Tokio runtime (owned, single instance), shared with blocking calls
+    runtime: Arc<Runtime>,
+    /// Shutdown controller for the running instance
+    shutdown: Option<Arc<shutdown::ShutdownController>>,
+    /// Signalled when the running instance has fully stopped; kept after a
+    /// stop that timed out, until the instance is done
+    stopped_rx: Option<mpsc::Receiver<()>>,
     /// TorClient handle for status queries
-    client: Option<Arc<TorClient<PreferredRuntime>>>,
+    client: Option<Arc<TorClient<TorRuntime>>>,
+    /// Data directory of the running instance
+    data_dir: Option<PathBuf>,
 }
 
 static ARTI_STATE: OnceCell<Mutex<ArtiState>> = OnceCell::new();
 static BOOTSTRAP_PROGRESS: AtomicI32 = AtomicI32::new(0);
 static IS_RUNNING: AtomicBool = AtomicBool::new(false);
+static IS_DORMANT: AtomicBool = AtomicBool::new(false);
 static BOOTSTRAP_SUMMARY: Mutex<String> = Mutex::new(String::new());
 
 /// Initialize the global state with a new runtime
 fn init_state() -> Result<(), &'static str> {
+    logging::init();
     ARTI_STATE.get_or_try_init(|| -> Result<Mutex<ArtiState>, &'static str> {
-        let runtime = Runtime::new().map_err(|_| "Failed to create tokio runtime")?;
+        let runtime = timeslice::build(config::current().host_driven)
+            .map_err(|_| "Failed to create tokio runtime")?;
         Ok(Mutex::new(ArtiState {
-            runtime,
-            shutdown_tx: None,
+            runtime: Arc::new(runtime),
+            shutdown: None,
+            stopped_rx: None,
             client: None,
+            data_dir: None,
         }))
     })?;
     Ok(())
@@ -48,18 +125,29 @@ fn init_state() -> Result<(), &'static str> {
 
 /// Start Arti with a SOCKS5 proxy.
 ///
+/// The proxy listens on every address in the `socks.listen` option
+/// (127.0.0.1 by default); addresses given without a port use `socks_port`.
+/// Listeners are bound before this returns, so with a `socks_port` of 0 the
+/// chosen port is available from `arti_socks_port` immediately.
+///
 /// # Arguments
 /// * `data_dir` - Path to data directory for Tor state (C string)
-/// * `socks_port` - Port for SOCKS5 proxy (e.g., 39050)
+/// * `socks_port` - Port for SOCKS5 proxy (e.g., 39050), or 0 for any free port
 ///
 /// # Returns
 /// * 0 on success
-/// * -1 if already running
+/// * -1 if already running, still finishing a stop that ran out of time, or
+///   `arti_prefetch` is in progress
 /// * -2 if data_dir is invalid
 /// * -3 if runtime initialization failed
 /// * -4 if bootstrap failed
+/// * -5 if a SOCKS listener could not be bound
+/// * -6 if the audit log could not be opened
+///
+/// # Safety
+/// `data_dir` must be a valid, null-terminated C string.
 #[no_mangle]
-pub extern "C" fn arti_start(data_dir: *const c_char, socks_port: u16) -> c_int {
+pub unsafe extern "C" fn arti_start(data_dir: *const c_char, socks_port: u16) -> c_int {
     // Check if already running
     if IS_RUNNING.load(Ordering::SeqCst) {
         return -1;
@@ -70,9 +158,17 @@ pub extern "C" fn arti_start(data_dir: *const c_char, socks_port: u16) -> c_int
         Ok(s) => PathBuf::from(s),
         Err(_) => return -2,
     };
+    start(data_path, socks_port)
+}
+
+/// Start on `data_path`; returns as `arti_start`
+fn start(data_path: PathBuf, socks_port: u16) -> c_int {
+    if IS_RUNNING.load(Ordering::SeqCst) {
+        return -1;
+    }
 
     // Initialize runtime if needed
-    if let Err(_) = init_state() {
+    if init_state().is_err() {
         return -3;
     }
 
@@ -86,18 +182,65 @@ pub extern "C" fn arti_start(data_dir: *const c_char, socks_port: u16) -> c_int
         Err(_) => return -3,
     };
 
-    // Create shutdown channel
-    let (shutdown_tx, shutdown_rx) = oneshot::channel();
-    guard.shutdown_tx = Some(shutdown_tx);
+    // A prefetch holds the state directory until its budget runs out
+    if prefetch::PREFETCHING.load(Ordering::SeqCst) {
+        return -1;
+    }
+
+    // A session that did not stop in time still cleans up once it ends,
+    // which would tear down the new one
+    if let Some(rx) = &guard.stopped_rx {
+        if let Err(mpsc::TryRecvError::Empty) = rx.try_recv() {
+            return -1;
+        }
+    }
+
+    let config = config::current();
+    if let Err(e) = audit::open(&config, &data_path) {
+        tracing::error!("Failed to open audit log: {}", e);
+        return -6;
+    }
+    if let Err(e) = recording::open(&config, &data_path) {
+        tracing::warn!("Failed to open SOCKS recording: {}", e);
+    }
+    quota::open(&data_path, &config);
+    stats::open(&data_path);
+    socks::reset_token(config.require_token);
+
+    // Bind SOCKS listeners up front so the port is known before we return
+    let listeners = {
+        let _rt = guard.runtime.enter();
+        match listener::bind_all(&config.socks_listen, socks_port) {
+            Ok(l) => l,
+            Err(e) => {
+                tracing::error!("{}", e);
+                audit::close();
+                quota::close();
+                stats::close();
+                recording::close();
+                return -5;
+            }
+        }
+    };
+
+    // A debugging aid, so failing to bind it does not stop Arti from starting
+    let status_page = config.status_page_listen.and_then(|addr| {
+        let _rt = guard.runtime.enter();
+        status_page::start(addr)
+            .inspect_err(|e| tracing::warn!("Failed to serve status page on {}: {}", addr, e))
+            .ok()
+    });
 
-    let socks_addr: SocketAddr = format!("127.0.0.1:{}", socks_port)
-        .parse()
-        .expect("valid addr");
+    let shutdown = Arc::new(shutdown::ShutdownController::new(config.shutdown_drain));
+    let (stopped_tx, stopped_rx) = mpsc::channel();
+    guard.shutdown = Some(shutdown.clone());
+    guard.stopped_rx = Some(stopped_rx);
+    guard.data_dir = Some(data_path.clone());
 
     // Spawn the main Arti task
     let data_path_clone = data_path.clone();
     guard.runtime.spawn(async move {
-        match run_arti(data_path_clone, socks_addr, shutdown_rx).await {
+        match run_arti(data_path_clone, config, listeners, shutdown).await {
             Ok(_) => {
                 tracing::info!("Arti shutdown cleanly");
             }
@@ -108,208 +251,3286 @@ pub extern "C" fn arti_start(data_dir: *const c_char, socks_port: u16) -> c_int
         }
         IS_RUNNING.store(false, Ordering::SeqCst);
         BOOTSTRAP_PROGRESS.store(0, Ordering::SeqCst);
+        config::note_running(false);
+        listener::clear_bound_addrs();
+        audit::close();
+        quota::close();
+        stats::close();
+        recording::close();
+        socks::reset_token(false);
+        if let Some(page) = status_page {
+            page.abort();
+            status_page::clear();
+        }
+        padding::clear();
+        #[cfg(feature = "onion-service-service")]
+        onion_service::clear();
+        storage::cleanup();
+        let _ = stopped_tx.send(());
     });
 
     IS_RUNNING.store(true, Ordering::SeqCst);
+    IS_DORMANT.store(false, Ordering::SeqCst);
+    config::note_running(true);
+    listener::set_accept_state(listener::AcceptState::Accepting);
     BOOTSTRAP_PROGRESS.store(0, Ordering::SeqCst);
     update_summary("Starting...");
 
     0
 }
 
-/// Stop Arti gracefully.
+/// Set a configuration option, applied the next time Arti starts.
+///
+/// An environment variable named after the key, upper-cased with dots as
+/// underscores and prefixed with `BITCHAT_TOR_` (e.g.
+/// `BITCHAT_TOR_SOCKS_LISTEN`), overrides the value set here. Variables are
+/// read once, on first use; invalid ones are logged and ignored.
+/// `arti_options` shows the resulting values.
+///
+/// Supported keys:
+/// * `socks.listen` - Comma-separated SOCKS listen addresses, e.g.
+///   `127.0.0.1,::1` or `127.0.0.1:39050,[::1]:39051`. Addresses without a
+///   port use the port passed to `arti_start`.
+/// * `socks.handshake_timeout_ms` - Time a client may take to complete the
+///   SOCKS greeting and request (default 10000)
+/// * `socks.handshake_max_bytes` - Bytes a client may send before its
+///   request is complete (default 2048)
+/// * `socks.failure_limit` - Failed handshakes from one source address
+///   before its connections are refused; 0 disables (default 10)
+/// * `socks.failure_window_ms` - Window over which failures are counted
+///   (default 60000)
+/// * `socks.failure_block_ms` - How long an offending source is refused
+///   (default 300000)
+/// * `audit.path` - Append a JSON line per connection decision to this
+///   file, relative to the data directory; empty disables (default)
+/// * `audit.max_bytes` - Rotate the audit log to `<path>.1` beyond this
+///   size; 0 never rotates (default 1048576)
+/// * `audit.redact` - `none` records everything, `host` replaces
+///   destination hosts with their kind, `all` also omits the source
+///   address (default `host`)
+/// * `socks.coalesce_ms` - How long less than a cell's worth of data
+///   written towards Tor waits for more before being sent, so bursts of
+///   tiny writes share cells; also applies to hosted onion services; 0
+///   sends each write at once (default 0)
+/// * `socks.write_timeout_ms` - How long a write towards a SOCKS client may
+///   block before its stream is closed, so a client that stopped reading,
+///   e.g. a suspended app still holding its socket, does not hold Tor data
+///   buffered; 0 waits forever (default 60000)
+/// * `socks.max_lifetime_ms` - How long a stream may stay open before it is
+///   closed, e.g. to move long-lived relay connections to new circuits now
+///   and then; 0 for no limit (default). Arti puts no new streams on a
+///   circuit in use for over ten minutes, so the replacement of a stream
+///   older than that gets a new circuit.
+/// * `socks.lifetime_notice_ms` - How long before `socks.max_lifetime_ms`
+///   closes a stream `stream_expiring` is emitted, so the app can open the
+///   replacement and switch over first (default 60000)
+/// * `socks.resolve_retries` - When an exit fails to resolve a hostname,
+///   retry on up to this many other exits before failing (default 0)
+/// * `socks.exit_hostnames` - `reject` (default) refuses legacy
+///   `host.relay.exit` names; `strip` connects to `host` through an exit
+///   Arti chooses, since a specific exit cannot be requested. `host` is
+///   then checked like any other request. `.noconnect` names are always
+///   refused.
+/// * `socks.reject_replies` - Comma-separated `rule=reply` entries choosing
+///   how requests refused under `noconnect_hostname`, `exit_hostname` or
+///   `v2_onion` are answered: `refuse` sends the rule's SOCKS error at once
+///   (default); `blackhole` sends nothing and closes after
+///   `socks.blackhole_ms`, so a client probing the policy cannot tell a
+///   refusal from a destination that does not answer; `redirect:host:port`
+///   connects to that destination instead, without checking it against the
+///   policy again. Refusals other than redirects still emit
+///   `stream_rejected`.
+/// * `socks.blackhole_ms` - How long a blackholed request is held open
+///   (default 30000)
+/// * `socks.pipelining` - What to do when a client sends a SOCKS5 greeting
+///   or request again as the first data of its stream, as some libraries
+///   do: `tunnel` relays it to the destination as data (default), `reject`
+///   refuses the request, or closes the stream if it is already connected.
+///   Either way `stream_pipelined` is emitted.
+/// * `socks.record_path` - Append a replayable recording of each SOCKS
+///   session to this file, relative to the data directory: handshake bytes
+///   and replies exactly, payloads only by length. For debugging client
+///   compatibility; the destination is recorded. Empty disables (default).
+/// * `socks.require_token` - `true` makes clients authenticate with
+///   username/password, giving the token from `arti_socks_token` as the
+///   username, so other apps on the device cannot use the proxy (default
+///   `false`). The password then names the client's isolation group.
+/// * `socks.max_connecting` - Tor connects allowed at once; more wait, and
+///   free slots go to the waiting isolation groups in turn. 0 is unlimited
+///   (default).
+/// * `isolation.max_streams` - Streams one isolation group may have open;
+///   more are refused. 0 is unlimited (default).
+/// * `isolation.max_new_per_second` - Streams one isolation group may open
+///   per second, in bursts of up to as many; more are refused. 0 is
+///   unlimited (default).
+/// * `isolation.circuits` - How streams of one isolation group share
+///   circuits: `token` lets any of them share (default), `destination` only
+///   those to the same host and port, and `stream` gives each stream its
+///   own circuit. Sharing saves circuit builds for pools of relay
+///   connections; separate circuits keep an exit from linking them.
+/// * `isolation.circuits_by_host` - Comma-separated `host=mode` entries
+///   overriding `isolation.circuits` for streams to that host, e.g.
+///   `*.example.com=stream`; a leading `*.` matches any subdomain. The
+///   first matching entry applies. Empty by default.
+///
+///   Clients that authenticate with username/password are isolated by their
+///   credentials, as with Tor's IsolateSOCKSAuth: streams of different
+///   credentials never share a circuit, and each set of credentials is a
+///   group the options above apply to. Clients that do not authenticate
+///   share one group.
+/// * `socket.bulk_ports` - Comma-separated destination ports whose clients
+///   are bulk transfers, e.g. media: their sockets get TCP_NODELAY off and
+///   256 KiB buffers. Other clients are interactive, with TCP_NODELAY on and
+///   the system's buffers. Empty by default.
+/// * `socket.nodelay` - `true` or `false` overrides TCP_NODELAY for every
+///   client; `auto` decides by class (default)
+/// * `socket.keepalive_ms` - Idle time before TCP keepalive probes on client
+///   sockets, for clients on other machines; 0 disables (default)
+/// * `socket.send_buffer_bytes`, `socket.recv_buffer_bytes` - Client socket
+///   buffer sizes for every class; 0 uses the class default (default)
+/// * `circuit.interactive_timeout`, `circuit.bulk_timeout` - How long a
+///   SOCKS connect of the class waits for a circuit before it is moved to a
+///   new one, so a chat send fails over quickly instead of waiting out
+///   Arti's retries: `auto` for the build timeout Arti learned, as in
+///   `arti_status`, `<min>-<max>` for that clamped to a range of
+///   milliseconds, or a fixed number of milliseconds. After two failovers a
+///   connect waits as Arti would. 0 always does (default).
+/// * `bootstrap.max_attempts` - Failed bootstraps before giving up; 0
+///   retries until stopped (default 0). Configuration errors are never retried.
+/// * `bootstrap.backoff_initial_ms` - Delay before the first retry, doubled
+///   with jitter after each failure (default 1000)
+/// * `bootstrap.backoff_max_ms` - Cap on the retry delay (default 300000)
+/// * `bootstrap.stall_timeout_ms` - An attempt whose progress does not move
+///   for this long has hung, and the client is replaced by a new one; time
+///   dormant or blocked by clock skew does not count. 0 disables (default
+///   120000).
+/// * `bootstrap.max_restarts` - New clients to try for hung bootstraps per
+///   start; after that bootstrap carries on with the last one (default 2)
+/// * `probe.url` - HTTPS URL fetched directly (not over Tor) before
+///   bootstrap; if it is intercepted, bootstrap waits until the user signs
+///   in to the network. Empty disables (default).
+/// * `probe.timeout_ms` - Time allowed for the probe (default 5000)
+/// * `probe.retry_ms` - How often to re-probe while a captive portal is
+///   present (default 15000)
+/// * `bridges` - Newline-separated bridge lines to connect through instead
+///   of connecting to the Tor network directly; empty disables (default).
+///   Only direct bridges are supported. Check lines first with
+///   `arti_parse_bridge_line`.
+/// * `firewall.reachable_addresses` - Comma-separated `addr:port` patterns
+///   Tor may connect to directly, for networks that block most ports; a bare
+///   port means any address, so `80,443` allows only web ports. Ranges
+///   (`*:8000-8080`) and networks (`192.0.2.0/24:*`) are accepted. Empty
+///   allows all (default).
+/// * `moat.url` - Where `arti_request_bridges` asks for bridges
+///   (default `https://bridges.torproject.org/moat`)
+/// * `moat.front` - Domain to connect to instead of the moat host, which is
+///   then only named inside TLS; empty disables (default)
+/// * `testnet.authorities` - Newline-separated `DirAuthority` lines of a
+///   private test network, such as chutney's, to use instead of the public
+///   Tor network; relays there may share a subnet and streams to local
+///   addresses are allowed. Needs the `testnet` feature; empty uses the
+///   public network (default).
+/// * `testnet.fallbacks` - Newline-separated `addr:orport rsa_id ed25519_id`
+///   lines of the test network's relays to bootstrap from (default empty)
+/// * `loopback.upstream` - `addr:port` a `loopback` build connects every
+///   stream to, e.g. a local echo server; empty connects to each requested
+///   destination directly (default). Needs the `loopback` feature.
+/// * `runtime.host_driven` - `true` to give Arti no threads of its own, so
+///   it only runs within `arti_run_for` and other blocking calls, for hosts
+///   that ration background CPU time. Read once, when the runtime is created
+///   by the first start or prefetch (default `false`)
+/// * `storage.ephemeral` - `true` to keep Tor state and the directory cache
+///   only for the session, in a temporary directory deleted on stop; see
+///   `arti_status` for the tradeoffs (default `false`)
+/// * `storage.microdescs_max_age_ms` - Arti's directory cache is shrunk
+///   at each start; with this set, cached microdescriptors no consensus has
+///   listed for this long are deleted first. 0 leaves them to Arti, which
+///   keeps them 7 days (default 0); at least a day otherwise.
+/// * `storage.consensuses_max_age_ms` - The same for consensuses expired
+///   this long; Arti keeps them 2 days (default 0). See
+///   `arti_storage_report`.
+/// * `prefetch.onions` - Comma-separated `name.onion:port` services of
+///   pinned peers for `arti_prefetch` to look up; empty disables (default)
+/// * `onion.favorites` - Comma-separated `name.onion:port` services whose
+///   rendezvous circuits are kept open while running, so messages to them
+///   skip circuit setup; needs the `onion-service-client` feature; empty
+///   disables (default)
+/// * `onion.keepalive_ms` - How often a stream is opened to each favorite
+///   to keep its circuit open; Arti closes it after 10 minutes unused
+///   (default 300000, minimum 1000)
+/// * `onion.refresh_ms` - How often a stream is opened to each peer pinned
+///   with `arti_pin_peer`, keeping its descriptor fresh (default 1800000,
+///   minimum 1000)
+/// * `onion.race` - Comma-separated `host=name.onion` destinations reachable
+///   at both addresses. A SOCKS request for either connects to the onion
+///   first and to the host once `onion.race_head_start_ms` passes or the
+///   onion fails, on the requested port, keeping whichever connects first
+///   and emitting `stream_raced`. Needs the `onion-service-client` feature;
+///   empty disables (default).
+/// * `onion.race_head_start_ms` - How long a raced onion connects alone
+///   (default 3000)
+/// * `service.client_streams_per_minute` - Streams a client of a hosted
+///   onion service may open a minute over one rendezvous circuit before the
+///   circuit is closed; 0 for no limit (default 0)
+/// * `service.client_max_bytes` - Bytes a client of a hosted onion service
+///   may send towards the app over one rendezvous circuit before it is
+///   closed; 0 for no limit (default 0)
+/// * `padding.foreground` - Connection padding while in use: `normal`,
+///   `reduced` or `off` (default `normal`)
+/// * `padding.dormant` - Connection padding between `arti_go_dormant` and
+///   `arti_wake`, with the same values (default `reduced`). Less padding
+///   saves battery but makes traffic timing easier to analyse.
+/// * `quota.daily_bytes` - Bytes of SOCKS traffic, both directions, allowed
+///   per UTC day before new connections are refused; 0 for no quota (default)
+/// * `quota.monthly_bytes` - The same per UTC calendar month (default 0).
+///   Totals are kept in `traffic.json` in the data directory across restarts.
+/// * `memory.report_interval_ms` - How often to emit a `memory_usage` event
+///   while running; 0 never does (default 0)
+/// * `budget.connects_per_minute` - SOCKS connects over the last minute
+///   beyond which `budget_exceeded` is emitted, e.g. to hold back background
+///   fetches on battery; 0 for no budget (default). Nothing is refused.
+/// * `budget.circuits` - Circuits carrying streams beyond which
+///   `budget_exceeded` is emitted; 0 for no budget (default)
+/// * `tuning.adaptive` - `true` to adjust circuit timeouts, retries and
+///   prebuilt exit circuits to the network as measured from bootstrap time
+///   and recent connects, e.g. giving circuits longer on a slow cellular
+///   link; decisions are announced with `network_tuned` (default `false`)
+/// * `exits.refresh_ms` - Fetch the Tor Project's list of exit addresses
+///   over Tor once bootstrapped, and again when it is this old, for
+///   `arti_is_tor_exit`; 0 never fetches it (default 0). 3600000 (an hour)
+///   follows the list's own update rate closely enough.
+/// * `exits.country` - Two-letter code of the country every SOCKS stream's
+///   exit must be in, e.g. `DE`; empty allows any (default). Connects fail if
+///   no usable exit is in the country. Ignored without the `geoip` feature.
+/// * `status.http_listen` - Loopback `address:port` serving a status page
+///   while running, for debugging: `/` shows status, streams and recent
+///   errors, also at `/status.json`, `/streams.json` and `/errors.json`.
+///   Everything is redacted as by `arti_status` and `arti_streams`. Empty
+///   disables (default).
+/// * `events.streams` - `true` emits progress events for each SOCKS stream,
+///   see `arti_set_event_callback` (default `false`)
+/// * `shutdown.drain_ms` - Time open connections get to close on
+///   `arti_stop` before being cut (default 2000)
 ///
 /// # Returns
 /// * 0 on success
-/// * -1 if not running
+/// * -1 if the key is unknown
+/// * -2 if the value is invalid
+/// * -3 if key or value is not a valid C string
+///
+/// # Safety
+/// `key` and `value` must be valid, null-terminated C strings.
 #[no_mangle]
-pub extern "C" fn arti_stop() -> c_int {
-    if !IS_RUNNING.load(Ordering::SeqCst) {
+pub unsafe extern "C" fn arti_set_option(key: *const c_char, value: *const c_char) -> c_int {
+    if key.is_null() || value.is_null() {
+        return -3;
+    }
+    let (key, value) = match (CStr::from_ptr(key).to_str(), CStr::from_ptr(value).to_str()) {
+        (Ok(k), Ok(v)) => (k, v),
+        _ => return -3,
+    };
+
+    match config::set_option(key, value) {
+        Ok(()) => 0,
+        Err(e) => {
+            tracing::warn!("Rejected option {}: {}", key, e);
+            match e {
+                config::ConfigError::UnknownKey => -1,
+                config::ConfigError::InvalidValue(_) => -2,
+            }
+        }
+    }
+}
+
+/// List every option with its effective value and where it comes from.
+///
+/// Writes `{"options":[{"key":..,"value":..,"origin":..}]}`, where `value`
+/// is in the form `arti_set_option` takes and `origin` is `default`, `api`
+/// or `environment`.
+///
+/// # Arguments
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if buffer is null
+/// * -2 if buffer is too small
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_options(buf: *mut c_char, len: c_int) -> c_int {
+    if buf.is_null() || len <= 0 {
         return -1;
     }
+    let json = serde_json::json!({ "options": config::effective() });
+    write_str(&json.to_string(), buf, len)
+}
 
-    let state = match ARTI_STATE.get() {
-        Some(s) => s,
-        None => return -1,
+/// Export the options Arti is running with, with secrets redacted.
+///
+/// Writes `{"running":..,"options":[{"key":..,"value":..,"origin":..}]}` as
+/// `arti_options` does, but with the options the running instance started
+/// with, or those the next start will use when `running` is false. Values
+/// that are secret (`bridges`, `prefetch.onions`, `onion.favorites` and
+/// `onion.race`) are written as `<redacted:digest>`: the digest differs when
+/// the value does, but is keyed anew in every process and reveals nothing
+/// of the value. Suitable for a debug screen or a bug report.
+///
+/// # Arguments
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if buffer is null
+/// * -2 if buffer is too small
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_export_config(buf: *mut c_char, len: c_int) -> c_int {
+    if buf.is_null() || len <= 0 {
+        return -1;
+    }
+    write_str(
+        &serde_json::to_string(&config::export()).unwrap_or_default(),
+        buf,
+        len,
+    )
+}
+
+/// Compare two sets of options, e.g. to show what pending changes would
+/// alter once Arti restarts.
+///
+/// Both are JSON in the form `arti_export_config` writes, of which only each
+/// option's `key` and `value` are read; options left out take their defaults
+/// and unknown ones are ignored. A null `old_options` stands for the running
+/// instance's options (the defaults when stopped), a null `new_options` for
+/// those the next start will use, so two nulls show the changes made since
+/// the running instance started.
+///
+/// Writes `{"changes":[{"key":..,"old":..,"new":..}]}`, in the order options
+/// are documented, with secrets redacted as in `arti_export_config`. Secret
+/// values from an export of an earlier process never match.
+///
+/// # Arguments
+/// * `old_options` - Options before (JSON C string), or null
+/// * `new_options` - Options after (JSON C string), or null
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if buffer is null
+/// * -2 if buffer is too small
+/// * -3 if either is not valid UTF-8 or not an export
+///
+/// # Safety
+/// `old_options` and `new_options` must each be null or a valid, null-terminated C string,
+/// and `buf` must point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_diff_config(
+    old_options: *const c_char,
+    new_options: *const c_char,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let text = |s: *const c_char| {
+        if s.is_null() {
+            Ok(None)
+        } else {
+            CStr::from_ptr(s).to_str().map(Some)
+        }
+    };
+    let (Ok(old), Ok(new)) = (text(old_options), text(new_options)) else {
+        return -3;
     };
+    match config::diff(old, new) {
+        Ok(changes) => write_str(
+            &serde_json::json!({ "changes": changes }).to_string(),
+            buf,
+            len,
+        ),
+        Err(e) => {
+            tracing::debug!("Cannot compare options: {}", e);
+            -3
+        }
+    }
+}
 
-    let mut guard = match state.lock() {
-        Ok(g) => g,
-        Err(_) => return -1,
+/// Register a callback for events, replacing any previous one.
+///
+/// Each event is passed as a JSON object with a `type` field:
+/// * `loopback_mode` - on every start of a `loopback` build, which never
+///   bootstraps and connects streams without Tor; carries `upstream`
+///   (`loopback.upstream`, or null)
+/// * `bootstrap_attempt` - a bootstrap attempt is starting; carries `attempt`
+/// * `bootstrap_failed` - an attempt failed; carries `attempt`, `error`,
+///   `fatal` and `retry_in_ms` (null when giving up)
+/// * `bootstrap_succeeded` - carries `attempt` and `elapsed_ms`
+/// * `bootstrap_stalled` - an attempt hung; carries `diagnostics` (`attempt`,
+///   `percent`, `status`, `blocked`, `stalled_ms`, `elapsed_ms`) and
+///   `restart`, the number of the client restart begun, or null if none are
+///   left. The last diagnostics are also in `arti_status`.
+/// * `clock_skew_detected` - the device clock is wrong enough to block
+///   bootstrap; carries `message`. The host can correct it with
+///   `arti_set_clock_offset`.
+/// * `clock_skew_cleared` - the skew no longer blocks bootstrap
+/// * `captive_portal_detected` - the pre-bootstrap probe was intercepted;
+///   carries `reason`
+/// * `captive_portal_cleared` - the probe got through again
+/// * `stream_failed` - a SOCKS request could not be connected; carries
+///   `stream`, `client_port`, `destination`, `reason` (`host_not_found`,
+///   `resolve_failed`, `timeout`, `refused`, `exit_policy`, `network_failed`
+///   or `other`), `detail` and `attempts`; `arti_diagnose_failure` explains it
+/// * `stream_rejected` - a SOCKS request was refused by local policy;
+///   carries `stream`, `client_port`, `destination` and `rule`
+///   (`noconnect_hostname`, `exit_hostname`, `v2_onion`, `isolation_streams`
+///   or `isolation_rate`)
+/// * `stream_pipelined` - a SOCKS client sent its handshake again as stream
+///   data; carries `stream`, `client_port`, `destination` and `action`
+///   (`tunneled`, `refused` or `closed`, see `socks.pipelining`)
+/// * `stream_expiring` - a stream will be closed at `socks.max_lifetime_ms`;
+///   carries `stream`, `client_port`, `destination` and `closes_in_ms`
+/// * `stream_deadline` - a connection was stopped at the deadline set with
+///   `arti_set_stream_deadline`; carries `stream` and `destination` (null if
+///   it was still in its handshake), `client_port`, `phase` (`handshake`,
+///   `connect` or `relay`), `sent`, `received` and `elapsed_ms` since the
+///   connection was accepted
+/// * `stream_raced` - a SOCKS request listed in `onion.race` connected;
+///   carries `stream`, `client_port`, `destination` (the address that
+///   connected), `route` (`onion` or `clearnet`) and `elapsed_ms`
+/// * `bridge_challenge` - a CAPTCHA to show for `arti_request_bridges`;
+///   carries `image` (base64) and `mime_type`
+/// * `bridges_received` - bridges were stored in the `bridges` option;
+///   carries `count`
+/// * `bridge_fetch_failed` - carries `stage` (`fetch` or `check`), `kind`
+///   (`network`, `server`, `captcha_incorrect` or `no_bridges`) and `error`
+/// * `quota_exceeded` - carries `period` (`daily` or `monthly`), `used` and
+///   `limit` in bytes; new SOCKS connections are refused from now on
+/// * `quota_cleared` - the quota period rolled over; connections are accepted again
+/// * `budget_exceeded` - SOCKS connects or circuits went over their budget;
+///   carries `budget` (`connects_per_minute` or `circuits`), `value` and
+///   `limit`
+/// * `budget_cleared` - the same budget is back down to three quarters of
+///   its limit, checked every 5 seconds; carries the same fields
+/// * `memory_usage` - every `memory.report_interval_ms`; carries the fields
+///   described at `arti_memory_usage`
+/// * `network_tuned` - with `tuning.adaptive`, circuit settings were changed;
+///   carries the fields described for `network_tuning` at `arti_status`
+/// * `isolation_not_honored` - `arti_check_isolation` found two isolated
+///   streams on one circuit; carries `circuit`
+/// * `interface_changed` - `arti_interface_changed` moved Arti off the old
+///   interface; carries `old`, `new`, `dropped` (the `stream`,
+///   `client_port` and `destination` of each stream closed),
+///   `circuits_closed` and `migrated` (the favorite and pinned peers being
+///   reached again)
+/// * `onion_service_state` - a hosted onion service changed state; carries
+///   `nickname`, `state` and `descriptor` (as in `arti_onion_services`) and
+///   `problem` (`fatal`, `descriptor_upload`, `introduction_points`,
+///   `other`, or null if none). Only sent by builds with onion service
+///   hosting.
+/// * `onion_service_expired` - a service from
+///   `arti_onion_service_create_expiring` stopped; carries `nickname`,
+///   `reason` (`ttl`, `connections`, or `stopped` if stopped by the app),
+///   `streams` (forwarded to the app) and `key_wiped` (false if the keystore
+///   failed and its keys remain).
+/// * `onion_service_client_limited` - a client of a hosted onion service
+///   went over `service.client_streams_per_minute` or
+///   `service.client_max_bytes` and its circuit was closed; carries
+///   `nickname`, `client` (numbered per service) and `limit` (`streams` or
+///   `bytes`). Only sent by builds with onion service hosting.
+///
+/// With the `events.streams` option, each SOCKS stream also reports its
+/// progress. These carry `stream`, the id `arti_streams` lists it by,
+/// `client_port`, the port of the app's end of the SOCKS connection, and
+/// `elapsed_ms` since the request was read:
+/// * `stream_circuit_attached` - a connect attempt got its circuit; not sent
+///   if the host app installed its own tracing subscriber
+/// * `stream_connected` - the remote end accepted the stream; also carries
+///   `destination` and `circuit`
+/// * `stream_first_byte` - the first data came back
+/// * `stream_closed` - the stream ended; carries `reason` (`client`,
+///   `remote`, `client_error`, `client_stalled` (see
+///   `socks.write_timeout_ms`), `pipelined_request` (see
+///   `socks.pipelining`), `max_lifetime` (see `socks.max_lifetime_ms`),
+///   `deadline` (see `arti_set_stream_deadline`), `tor_error`, `shutdown`
+///   or `interface_changed`),
+///   `sent` and `received`. Streams that never connect end with
+///   `stream_failed` or `stream_rejected` instead.
+///
+/// The callback runs on an Arti worker thread and must return quickly. The
+/// JSON string is only valid during the call.
+///
+/// # Arguments
+/// * `callback` - Function to call, or NULL to unregister
+/// * `context` - Opaque pointer passed back to the callback
+#[no_mangle]
+pub extern "C" fn arti_set_event_callback(
+    callback: Option<events::ArtiEventCallback>,
+    context: *mut c_void,
+) {
+    events::set_callback(callback, context);
+}
+
+/// Queue events for `arti_poll_events`, alongside or instead of a callback.
+///
+/// The queue holds up to 256 events; when full, the oldest is dropped and
+/// counted. Disabling it discards any events still queued.
+///
+/// # Arguments
+/// * `enabled` - Whether to queue events
+#[no_mangle]
+pub extern "C" fn arti_set_event_queue(enabled: bool) {
+    events::set_queue_enabled(enabled);
+}
+
+/// JSON around polled events: `{"events":[],"dropped":N}` with the widest
+/// count, plus the null terminator
+const POLL_OVERHEAD: usize = 45;
+
+/// Take queued events, waiting up to `timeout_ms` for one if none is queued.
+///
+/// Writes `{"events":[...],"dropped":N}`, with the event objects described
+/// at `arti_set_event_callback`, oldest first. `dropped` counts events lost
+/// to overflow since the previous poll. Events that do not fit in the buffer
+/// stay queued for the next poll. Blocks the calling thread while waiting.
+///
+/// # Arguments
+/// * `max` - Most events to take (at least 1)
+/// * `timeout_ms` - Time to wait for an event, or 0 to return at once
+/// * `buf` - Buffer to write JSON into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if buf is null, len is not positive or max is less than 1
+/// * -2 if buffer is too small for the oldest event
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_poll_events(
+    max: c_int,
+    timeout_ms: u32,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if buf.is_null() || len <= 0 || max < 1 {
+        return -1;
+    }
+    let budget = (len as usize).saturating_sub(POLL_OVERHEAD);
+    let timeout = Duration::from_millis(timeout_ms.into());
+    let Some(polled) = events::poll(max as usize, budget, timeout) else {
+        return -2;
+    };
+    let json = format!(
+        "{{\"events\":[{}],\"dropped\":{}}}",
+        polled.events.join(","),
+        polled.dropped
+    );
+    write_str(&json, buf, len)
+}
+
+/// Set which logs are written, without restarting.
+///
+/// `filter` is a comma-separated list of `target=level` directives, with an
+/// optional bare level for all other targets, e.g. `arti=warn,arti_bitchat=debug`
+/// or `info,tor_guardmgr=trace`. Levels are `off`, `error`, `warn`, `info`,
+/// `debug` and `trace`. A target covers every target it is a prefix of, and
+/// the longest match wins. Targets not matched are not logged, so an empty
+/// filter turns logging off; the default is `warn`. Logs are written to stderr.
+///
+/// # Arguments
+/// * `filter` - Filter directives (C string)
+///
+/// # Returns
+/// * 0 on success
+/// * -1 if filter is null or not valid UTF-8
+/// * -2 if filter is invalid
+/// * -3 if the host process installed its own tracing subscriber
+///
+/// # Safety
+/// `filter` must be a valid, null-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn arti_set_log_filter(filter: *const c_char) -> c_int {
+    if filter.is_null() {
+        return -1;
+    }
+    let Ok(filter) = CStr::from_ptr(filter).to_str() else {
+        return -1;
     };
+    match logging::set_filter(filter) {
+        Ok(()) => 0,
+        Err(logging::FilterError::Invalid) => -2,
+        Err(logging::FilterError::Unavailable) => -3,
+    }
+}
+
+/// Stop Arti gracefully.
+///
+/// Open connections are asked to close and given up to `shutdown.drain_ms`
+/// before being cut; the outcome is reported by `arti_status`. Blocks until
+/// the proxy has stopped.
+///
+/// # Returns
+/// * 0 on success
+/// * -1 if not running
+#[no_mangle]
+pub extern "C" fn arti_stop() -> c_int {
+    match stop(None) {
+        Some(_) => 0,
+        None => -1,
+    }
+}
+
+/// Stop Arti within `deadline_ms`, for an app about to be suspended or
+/// killed, e.g. from a background task's expiration handler.
+///
+/// Works like `arti_stop`, but open connections get only part of the
+/// deadline to close before being cut, so the rest is left for Arti to write
+/// its guard and circuit timing state and close its channels. Directory
+/// documents are already on disk as they arrive; traffic totals are saved.
+///
+/// # Arguments
+/// * `deadline_ms` - Time the app can wait for this call to return
+///
+/// # Returns
+/// * 0 if Arti stopped within the deadline
+/// * -1 if not running
+/// * -2 if the deadline passed first; stopping carries on in the background,
+///   and `arti_start` fails until it is done
+#[no_mangle]
+pub extern "C" fn arti_prepare_for_termination(deadline_ms: u32) -> c_int {
+    match stop(Some(Duration::from_millis(deadline_ms.into()))) {
+        Some(true) => 0,
+        Some(false) => -2,
+        None => -1,
+    }
+}
+
+/// Time kept back from draining connections for Arti to save its state
+const TEARDOWN_MARGIN: Duration = Duration::from_millis(250);
 
-    // Send shutdown signal
-    if let Some(tx) = guard.shutdown_tx.take() {
-        let _ = tx.send(());
+/// Shut the running instance down, taking no longer than `limit` if given.
+///
+/// Returns whether it stopped in time, or `None` if it was not running.
+fn stop(limit: Option<Duration>) -> Option<bool> {
+    let started = Instant::now();
+    if !IS_RUNNING.load(Ordering::SeqCst) {
+        return None;
     }
 
-    // Clear client reference
-    guard.client = None;
+    let state = ARTI_STATE.get()?;
+    let (runtime, shutdown, stopped_rx) = {
+        let mut guard = state.lock().ok()?;
+        // Clear client reference
+        guard.client = None;
+        guard.data_dir = None;
+        (
+            guard.runtime.clone(),
+            guard.shutdown.take(),
+            guard.stopped_rx.take(),
+        )
+    };
 
-    // Give async tasks time to complete
-    std::thread::sleep(std::time::Duration::from_millis(200));
+    // Signal shutdown and wait for connections to drain, without holding the
+    // state lock the Arti task may need
+    let mut stopped = true;
+    if let Some(shutdown) = shutdown {
+        let wait = match limit {
+            Some(limit) => {
+                shutdown.limit_deadline(limit.saturating_sub(TEARDOWN_MARGIN));
+                limit
+            }
+            None => shutdown.deadline() + Duration::from_secs(1),
+        };
+        shutdown.cancel();
+        if let Some(rx) = stopped_rx {
+            stopped = timeslice::wait(&runtime, &rx, wait.saturating_sub(started.elapsed()));
+            if !stopped {
+                if let Ok(mut guard) = state.lock() {
+                    guard.stopped_rx = Some(rx);
+                }
+            }
+        }
+    }
 
     IS_RUNNING.store(false, Ordering::SeqCst);
+    IS_DORMANT.store(false, Ordering::SeqCst);
     BOOTSTRAP_PROGRESS.store(0, Ordering::SeqCst);
+    config::note_running(false);
+    metrics::clear_guard();
+    ratelimit::clear();
+    listener::clear_bound_addrs();
     update_summary("");
+    #[cfg(feature = "otlp")]
+    telemetry::flush();
 
-    0
+    Some(stopped)
 }
 
-/// Check if Arti is currently running.
+/// Create a profile, a separate data directory for one identity.
+///
+/// Profiles live under `<root>/profiles/<name>`, each with its own guards,
+/// state and directory cache, keys, statistics and audit log, so nothing Tor
+/// keeps links one identity to another. Start one with
+/// `arti_profile_switch`.
+///
+/// # Arguments
+/// * `root` - Directory holding the profiles (C string)
+/// * `name` - Profile name: 1 to 64 ASCII letters, digits, `-` and `_` (C string)
 ///
 /// # Returns
-/// * 1 if running
-/// * 0 if not running
+/// * 0 on success
+/// * -1 if a pointer is null
+/// * -3 if root or name is not valid
+/// * -4 if the profile exists
+/// * -5 if the directory could not be created
+///
+/// # Safety
+/// `root` and `name` must be valid, null-terminated C strings.
 #[no_mangle]
-pub extern "C" fn arti_is_running() -> c_int {
+pub unsafe extern "C" fn arti_profile_create(root: *const c_char, name: *const c_char) -> c_int {
+    let (root, name) = match profile_args(root, name) {
+        Ok(args) => args,
+        Err(code) => return code,
+    };
+    match profiles::create(&root, name) {
+        Ok(_) => 0,
+        Err(e) => profile_error(e),
+    }
+}
+
+/// Run Arti on a profile, stopping it first if it runs on another one.
+///
+/// The new session gets a new client on the profile's own data directory,
+/// so it shares no guards, circuits or streams with the one before. Does
+/// nothing if Arti already runs on the profile.
+///
+/// # Arguments
+/// * `root` - Directory holding the profiles (C string)
+/// * `name` - Profile to run on (C string)
+/// * `socks_port` - As for `arti_start`
+///
+/// # Returns
+/// * As `arti_start`, with -2 also if root or name is null or not valid
+/// * -7 if there is no such profile
+/// * -8 if the session on the other profile did not stop in time; nothing
+///   runs until it has, and the switch can be retried then
+///
+/// # Safety
+/// `root` and `name` must be valid, null-terminated C strings.
+#[no_mangle]
+pub unsafe extern "C" fn arti_profile_switch(
+    root: *const c_char,
+    name: *const c_char,
+    socks_port: u16,
+) -> c_int {
+    let Ok((root, name)) = profile_args(root, name) else {
+        return -2;
+    };
+    let dir = match profiles::existing(&root, name) {
+        Ok(dir) => dir,
+        Err(profiles::ProfileError::NotFound) => return -7,
+        Err(_) => return -2,
+    };
     if IS_RUNNING.load(Ordering::SeqCst) {
-        1
-    } else {
-        0
+        if running_data_dir().as_deref() == Some(dir.as_path()) {
+            return 0;
+        }
+        if stop(None) == Some(false) {
+            return -8;
+        }
     }
+    start(dir, socks_port)
 }
 
-/// Get the current bootstrap progress (0-100).
+/// Delete a profile and everything Tor kept for it, e.g. when its identity
+/// is wiped. Arti is stopped first if it runs on the profile.
+///
+/// # Arguments
+/// * `root` - Directory holding the profiles (C string)
+/// * `name` - Profile to delete (C string)
+///
+/// # Returns
+/// * 0 on success
+/// * -1 if a pointer is null
+/// * -3 if root or name is not valid
+/// * -4 if there is no such profile
+/// * -5 if the directory could not be removed
+///
+/// # Safety
+/// `root` and `name` must be valid, null-terminated C strings.
 #[no_mangle]
-pub extern "C" fn arti_bootstrap_progress() -> c_int {
-    BOOTSTRAP_PROGRESS.load(Ordering::SeqCst)
+pub unsafe extern "C" fn arti_profile_delete(root: *const c_char, name: *const c_char) -> c_int {
+    let (root, name) = match profile_args(root, name) {
+        Ok(args) => args,
+        Err(code) => return code,
+    };
+    let dir = match profiles::existing(&root, name) {
+        Ok(dir) => dir,
+        Err(e) => return profile_error(e),
+    };
+    if running_data_dir().as_deref() == Some(dir.as_path()) {
+        stop(None);
+    }
+    match profiles::delete(&root, name) {
+        Ok(()) => 0,
+        Err(e) => profile_error(e),
+    }
 }
 
-/// Get the current bootstrap summary string.
+/// List the profiles, as JSON.
+///
+/// Writes `{"profiles":[{"name":..,"active":..}]}` sorted by name, where
+/// `active` marks the profile Arti is running on.
 ///
 /// # Arguments
-/// * `buf` - Buffer to write the summary into
+/// * `root` - Directory holding the profiles (C string)
+/// * `buf` - Buffer to write the JSON into
 /// * `len` - Length of the buffer
 ///
 /// # Returns
 /// * Number of bytes written (not including null terminator)
-/// * -1 if buffer is null or too small
+/// * -1 if a pointer is null
+/// * -2 if buffer is too small
+/// * -3 if root is not valid UTF-8
+///
+/// # Safety
+/// `root` must be a valid, null-terminated C string and `buf` must point to
+/// at least `len` writable bytes.
 #[no_mangle]
-pub extern "C" fn arti_bootstrap_summary(buf: *mut c_char, len: c_int) -> c_int {
-    if buf.is_null() || len <= 0 {
+pub unsafe extern "C" fn arti_profiles(root: *const c_char, buf: *mut c_char, len: c_int) -> c_int {
+    if root.is_null() || buf.is_null() || len <= 0 {
         return -1;
     }
-
-    let summary = match BOOTSTRAP_SUMMARY.lock() {
-        Ok(s) => s.clone(),
-        Err(_) => return -1,
+    let Ok(root) = CStr::from_ptr(root).to_str() else {
+        return -3;
     };
+    let list = profiles::list(Path::new(root), running_data_dir().as_deref());
+    write_str(
+        &serde_json::json!({ "profiles": list }).to_string(),
+        buf,
+        len,
+    )
+}
 
-    let bytes = summary.as_bytes();
-    let copy_len = std::cmp::min(bytes.len(), (len - 1) as usize);
+/// Parse the root directory and profile name, failing with -1 if either is
+/// null and -3 if either is not valid UTF-8
+unsafe fn profile_args<'a>(
+    root: *const c_char,
+    name: *const c_char,
+) -> Result<(PathBuf, &'a str), c_int> {
+    if root.is_null() || name.is_null() {
+        return Err(-1);
+    }
+    match (CStr::from_ptr(root).to_str(), CStr::from_ptr(name).to_str()) {
+        (Ok(root), Ok(name)) => Ok((PathBuf::from(root), name)),
+        _ => Err(-3),
+    }
+}
 
-    unsafe {
-        std::ptr::copy_nonoverlapping(bytes.as_ptr(), buf as *mut u8, copy_len);
-        *buf.add(copy_len) = 0; // null terminator
+fn profile_error(e: profiles::ProfileError) -> c_int {
+    match e {
+        profiles::ProfileError::Invalid => -3,
+        profiles::ProfileError::Exists | profiles::ProfileError::NotFound => -4,
+        profiles::ProfileError::Io(e) => {
+            tracing::warn!("Profile directory operation failed: {}", e);
+            -5
+        }
     }
+}
 
-    copy_len as c_int
+/// Wipe every key Tor keeps, for panic mode.
+///
+/// Stops Arti if it is running, then overwrites and deletes the keystore of
+/// `data_dir` and of every profile under it: hosted onion services' identity
+/// keys and the keys for reaching services in restricted discovery mode.
+/// The services get new addresses if they are launched again. Ephemeral
+/// state is deleted as well. Guards, statistics and other state are kept;
+/// delete profiles with `arti_profile_delete` to remove those too.
+///
+/// # Arguments
+/// * `data_dir` - Data directory given to `arti_start`, also the root of the
+///   profiles (C string)
+///
+/// # Returns
+/// * 0 on success
+/// * -1 if data_dir is null
+/// * -3 if data_dir is not valid UTF-8
+/// * -5 if a key file could not be overwritten or deleted; the rest are
+///   still wiped
+///
+/// # Safety
+/// `data_dir` must be a valid, null-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn arti_wipe_all_keys(data_dir: *const c_char) -> c_int {
+    if data_dir.is_null() {
+        return -1;
+    }
+    let Ok(data_dir) = CStr::from_ptr(data_dir).to_str() else {
+        return -3;
+    };
+    // Dropping the client drops the keys it holds in memory
+    stop(None);
+    storage::cleanup();
+    match keys::wipe_all(Path::new(data_dir)) {
+        Ok(()) => 0,
+        Err(e) => {
+            tracing::warn!("Could not wipe keys: {}", e);
+            -5
+        }
+    }
 }
 
-/// Signal Arti to go dormant (reduce resource usage).
-/// This is a hint; Arti may not fully support dormant mode yet.
+/// Data directory of the running instance
+fn running_data_dir() -> Option<PathBuf> {
+    ARTI_STATE.get()?.lock().ok()?.data_dir.clone()
+}
+
+/// Stop accepting SOCKS connections while keeping the listeners bound.
+///
+/// Intended for identity switches and dormancy, so clients never see the
+/// port disappear. Resumes automatically on the next start.
+///
+/// # Arguments
+/// * `refuse` - If true, keep accepting but fail each request with a SOCKS
+///   error; otherwise leave new connections waiting in the backlog
 ///
 /// # Returns
 /// * 0 on success
 /// * -1 if not running
 #[no_mangle]
-pub extern "C" fn arti_go_dormant() -> c_int {
+pub extern "C" fn arti_pause_listener(refuse: bool) -> c_int {
     if !IS_RUNNING.load(Ordering::SeqCst) {
         return -1;
     }
-    // Arti doesn't have explicit dormant mode yet, but we can note the intent
-    update_summary("Dormant");
+    listener::set_accept_state(if refuse {
+        listener::AcceptState::Refusing
+    } else {
+        listener::AcceptState::Paused
+    });
     0
 }
 
-/// Signal Arti to wake from dormant mode.
+/// Resume accepting SOCKS connections after `arti_pause_listener`.
 ///
 /// # Returns
 /// * 0 on success
 /// * -1 if not running
 #[no_mangle]
-pub extern "C" fn arti_wake() -> c_int {
+pub extern "C" fn arti_resume_listener() -> c_int {
     if !IS_RUNNING.load(Ordering::SeqCst) {
         return -1;
     }
-    update_summary("Active");
+    listener::set_accept_state(listener::AcceptState::Accepting);
     0
 }
 
-fn update_summary(s: &str) {
-    if let Ok(mut guard) = BOOTSTRAP_SUMMARY.lock() {
-        guard.clear();
-        guard.push_str(s);
-    }
+/// Open an in-memory SOCKS connection, for a client the host holds itself.
+///
+/// Serves the pipe as the listeners serve a connection they accept, without
+/// a local socket: what the host's client sends goes in with
+/// `arti_pipe_write`, and what the SOCKS handler sends back is passed to
+/// `callback`, which gets a `len` of 0 once the pipe is closed. Pipes count
+/// as connections from 0.0.0.0:0 and are reported with client port 0, but
+/// their failed handshakes are not rate limited.
+///
+/// The callback runs on an Arti worker thread, must return quickly, and must
+/// not call `arti_pipe_write` or `arti_pipe_close` itself. `data` is only
+/// valid during the call.
+///
+/// # Arguments
+/// * `callback` - Function to pass the handler's output to
+/// * `context` - Opaque pointer passed back to the callback
+///
+/// # Returns
+/// * The pipe's id, which is positive
+/// * -1 if callback is null
+/// * -2 if not serving: not running, or not bootstrapped yet
+#[no_mangle]
+pub extern "C" fn arti_pipe_open(
+    callback: Option<pipe::ArtiPipeCallback>,
+    context: *mut c_void,
+) -> c_int {
+    let Some(callback) = callback else {
+        return -1;
+    };
+    let Some(Ok(guard)) = ARTI_STATE.get().map(|s| s.lock()) else {
+        return -2;
+    };
+    let runtime = guard.runtime.clone();
+    drop(guard);
+
+    let _rt = runtime.enter();
+    pipe::open(callback, context).unwrap_or(-2)
+}
+
+/// Pass data from the host's client to the SOCKS handler of a pipe.
+///
+/// Blocks until the pipe's buffer has taken all of `data`, which holds the
+/// client back while the handler is connecting or Tor is slow.
+///
+/// # Arguments
+/// * `pipe` - Id from `arti_pipe_open`
+/// * `data` - Bytes to pass
+/// * `len` - Number of bytes
+///
+/// # Returns
+/// * 0 on success
+/// * -1 if data is null or len is negative
+/// * -2 if there is no such pipe, or it has closed
+/// * -3 if called on Arti's own thread, e.g. from a callback
+///
+/// # Safety
+/// `data` must point to at least `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_pipe_write(pipe: c_int, data: *const u8, len: c_int) -> c_int {
+    if data.is_null() || len < 0 {
+        return -1;
+    }
+    if timeslice::on_runtime() {
+        return -3;
+    }
+    let Some(Ok(guard)) = ARTI_STATE.get().map(|s| s.lock()) else {
+        return -2;
+    };
+    let runtime = guard.runtime.clone();
+    drop(guard);
+
+    let data = std::slice::from_raw_parts(data, len as usize);
+    match pipe::write(&runtime, pipe, data) {
+        Ok(()) => 0,
+        Err(_) => -2,
+    }
+}
+
+/// Tell a pipe's SOCKS handler that the host's client is done sending.
+///
+/// The handler finishes as it would when a socket client shuts down its
+/// side; the callback gets a `len` of 0 once it is done.
+///
+/// # Arguments
+/// * `pipe` - Id from `arti_pipe_open`
+///
+/// # Returns
+/// * 0 on success
+/// * -2 if there is no such pipe, or it has closed
+/// * -3 if called on Arti's own thread, e.g. from a callback
+#[no_mangle]
+pub extern "C" fn arti_pipe_close(pipe: c_int) -> c_int {
+    if timeslice::on_runtime() {
+        return -3;
+    }
+    let Some(Ok(guard)) = ARTI_STATE.get().map(|s| s.lock()) else {
+        return -2;
+    };
+    let runtime = guard.runtime.clone();
+    drop(guard);
+
+    match pipe::close(&runtime, pipe) {
+        Ok(()) => 0,
+        Err(_) => -2,
+    }
+}
+
+/// Give a SOCKS connection a deadline, after which it is stopped wherever
+/// it has got to.
+///
+/// A handshake or connect still running then fails, the latter with SOCKS
+/// reply 0x06 (TTL expired), and a stream still relaying is closed with
+/// reason `deadline`. Either way `stream_deadline` reports how far it got.
+/// The connection is named by the local address of the client's socket, so
+/// the deadline can be set before connecting to the SOCKS port; it then
+/// applies to the next connection from that address, if that comes before
+/// the deadline. Setting it again moves it. Pipes cannot be given a
+/// deadline.
+///
+/// # Arguments
+/// * `client_addr` - Local address of the client's connection to the SOCKS
+///   port, e.g. `127.0.0.1:50123` or `[::1]:50123` (C string)
+/// * `deadline_ms` - Milliseconds from now; 0 clears the deadline
+///
+/// # Returns
+/// * 0 on success
+/// * -1 if client_addr is null
+/// * -2 if not running
+/// * -3 if client_addr is not an address with a port other than 0
+///
+/// # Safety
+/// `client_addr` must be a valid, null-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn arti_set_stream_deadline(
+    client_addr: *const c_char,
+    deadline_ms: u32,
+) -> c_int {
+    if client_addr.is_null() {
+        return -1;
+    }
+    if !IS_RUNNING.load(Ordering::SeqCst) {
+        return -2;
+    }
+    let Some(client_addr) = CStr::from_ptr(client_addr)
+        .to_str()
+        .ok()
+        .and_then(|s| s.trim().parse::<SocketAddr>().ok())
+        .filter(|addr| addr.port() != 0)
+    else {
+        return -3;
+    };
+    let at = (deadline_ms > 0).then(|| Instant::now() + Duration::from_millis(deadline_ms as u64));
+    deadline::set(client_addr, at);
+    0
+}
+
+/// Get the port the SOCKS proxy is bound to.
+///
+/// Valid as soon as `arti_start` returns; changes if the proxy is restarted
+/// with an ephemeral port. With several listeners, this is the first one's port.
+///
+/// # Returns
+/// * The bound port
+/// * -1 if no listener is bound
+#[no_mangle]
+pub extern "C" fn arti_socks_port() -> c_int {
+    listener::bound_addrs()
+        .first()
+        .map(|a| a.port() as c_int)
+        .unwrap_or(-1)
+}
+
+/// Get the token SOCKS clients must give as their username while
+/// `socks.require_token` is set.
+///
+/// A new random token is made at each start, so fetch it after
+/// `arti_start` and hand it only to the code that should use the proxy.
+///
+/// # Arguments
+/// * `buf` - Buffer to write the token into (at least 33 bytes)
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if not running or buf is null
+/// * -2 if buffer is too small
+/// * -3 if no token is required
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_socks_token(buf: *mut c_char, len: c_int) -> c_int {
+    if buf.is_null() || len <= 0 || !IS_RUNNING.load(Ordering::SeqCst) {
+        return -1;
+    }
+    match socks::token() {
+        Some(token) => write_str(&token, buf, len),
+        None => -3,
+    }
+}
+
+/// Check whether an IP address belongs to a Tor exit.
+///
+/// Looks the address up in the exit list fetched under `exits.refresh_ms`,
+/// e.g. to label a peer whose connection arrives from Tor. Works after Arti
+/// stops, with the list last fetched.
+///
+/// # Arguments
+/// * `ip` - IPv4 or IPv6 address (C string)
+///
+/// # Returns
+/// * 1 if it is an exit address
+/// * 0 if it is not
+/// * -1 if ip is null
+/// * -3 if ip is not an IP address
+/// * -4 if no exit list has been fetched yet
+///
+/// # Safety
+/// `ip` must be a valid, null-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn arti_is_tor_exit(ip: *const c_char) -> c_int {
+    if ip.is_null() {
+        return -1;
+    }
+    let Some(ip) = CStr::from_ptr(ip)
+        .to_str()
+        .ok()
+        .and_then(|s| s.trim().parse::<IpAddr>().ok())
+    else {
+        return -3;
+    };
+    match exits::is_exit(ip) {
+        Some(true) => 1,
+        Some(false) => 0,
+        None => -4,
+    }
+}
+
+/// Look up the country of an IP address, e.g. a destination or a relay.
+///
+/// Uses the embedded GeoIP database, or the one installed with
+/// `arti_geoip_update`. Works whether or not Arti is running.
+///
+/// # Arguments
+/// * `ip` - IPv4 or IPv6 address (C string)
+/// * `buf` - Buffer to write the two-letter country code into (at least 3 bytes)
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if a pointer is null
+/// * -2 if buffer is too small
+/// * -3 if ip is not an IP address
+/// * -4 if the database does not place the address
+/// * -6 if built without the `geoip` feature
+///
+/// # Safety
+/// `ip` must be a valid, null-terminated C string, and `buf` must point to
+/// at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_geoip_country(
+    ip: *const c_char,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if ip.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Some(ip) = CStr::from_ptr(ip)
+        .to_str()
+        .ok()
+        .and_then(|s| s.trim().parse::<IpAddr>().ok())
+    else {
+        return -3;
+    };
+    #[cfg(feature = "geoip")]
+    {
+        match geoip::country(ip) {
+            Some(country) => write_str(&country, buf, len),
+            None => -4,
+        }
+    }
+    #[cfg(not(feature = "geoip"))]
+    {
+        let _ = ip;
+        -6
+    }
+}
+
+/// Replace the GeoIP database with a newer one, e.g. downloaded by the app.
+///
+/// The files are in C Tor's `geoip` and `geoip6` formats. They are checked,
+/// copied into `<data_dir>/geoip` so later starts load them too, and used
+/// for lookups at once. Arti still chooses exits for `exits.country` from
+/// its embedded copy. Works whether or not Arti is running.
+///
+/// # Arguments
+/// * `data_dir` - Data directory passed to `arti_start` (C string)
+/// * `v4_path` - IPv4 database file (C string)
+/// * `v6_path` - IPv6 database file (C string)
+///
+/// # Returns
+/// * 0 on success
+/// * -1 if a pointer is null
+/// * -3 if a path is not valid UTF-8
+/// * -4 if a file cannot be read or is not a GeoIP database
+/// * -5 if the database could not be stored in the data directory
+/// * -6 if built without the `geoip` feature
+///
+/// # Safety
+/// All arguments must be valid, null-terminated C strings.
+#[no_mangle]
+pub unsafe extern "C" fn arti_geoip_update(
+    data_dir: *const c_char,
+    v4_path: *const c_char,
+    v6_path: *const c_char,
+) -> c_int {
+    if data_dir.is_null() || v4_path.is_null() || v6_path.is_null() {
+        return -1;
+    }
+    let (Ok(data_dir), Ok(v4_path), Ok(v6_path)) = (
+        CStr::from_ptr(data_dir).to_str(),
+        CStr::from_ptr(v4_path).to_str(),
+        CStr::from_ptr(v6_path).to_str(),
+    ) else {
+        return -3;
+    };
+    #[cfg(feature = "geoip")]
+    {
+        match geoip::update(Path::new(data_dir), Path::new(v4_path), Path::new(v6_path)) {
+            Ok(()) => 0,
+            Err(geoip::UpdateError::Invalid(e)) => {
+                tracing::warn!("Rejected GeoIP database: {}", e);
+                -4
+            }
+            Err(geoip::UpdateError::Store(e)) => {
+                tracing::warn!("Could not store GeoIP database: {}", e);
+                -5
+            }
+        }
+    }
+    #[cfg(not(feature = "geoip"))]
+    {
+        let _ = (data_dir, v4_path, v6_path);
+        -6
+    }
+}
+
+/// Check if Arti is currently running.
+///
+/// # Returns
+/// * 1 if running
+/// * 0 if not running
+#[no_mangle]
+pub extern "C" fn arti_is_running() -> c_int {
+    if IS_RUNNING.load(Ordering::SeqCst) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Get the current bootstrap progress (0-100).
+#[no_mangle]
+pub extern "C" fn arti_bootstrap_progress() -> c_int {
+    BOOTSTRAP_PROGRESS.load(Ordering::SeqCst)
+}
+
+/// Get the current bootstrap summary string.
+///
+/// # Arguments
+/// * `buf` - Buffer to write the summary into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if buffer is null or too small
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_bootstrap_summary(buf: *mut c_char, len: c_int) -> c_int {
+    if buf.is_null() || len <= 0 {
+        return -1;
+    }
+
+    let summary = match BOOTSTRAP_SUMMARY.lock() {
+        Ok(s) => s.clone(),
+        Err(_) => return -1,
+    };
+
+    let bytes = summary.as_bytes();
+    let copy_len = std::cmp::min(bytes.len(), (len - 1) as usize);
+    copy_to_c_buf(&bytes[..copy_len], buf);
+
+    copy_len as c_int
+}
+
+/// Get a JSON status snapshot (bootstrap, guard, circuits, streams, traffic,
+/// dormancy, versions) for the app's Tor status sheet.
+///
+/// `storage` is `ephemeral` while running with `storage.ephemeral` set, else
+/// `persistent`. An ephemeral session downloads the full directory on every
+/// start, so it bootstraps more slowly and uses more data, and it picks new
+/// guards each time, which gives an observer more chances to be chosen as
+/// the entry point.
+///
+/// `loopback` is true in builds with the `loopback` feature, whose streams
+/// are connected directly instead of over Tor; show the user a banner.
+///
+/// `padding` is the connection padding level in use while running. `quota`
+/// carries `today_bytes`, `month_bytes`, `daily_limit` and `monthly_limit`
+/// (null without a quota) and `exceeded` (`daily`, `monthly` or null).
+///
+/// `circuit_failures` counts circuit builds that failed behind failed
+/// connects since launch, `by_reason` (`timeout`, `destroyed`,
+/// `path_selection`, `channel`, `protocol`) and `by_hop` (`first`, `later`,
+/// `unknown`). Builds Arti retried successfully are not seen.
+///
+/// `connect_latency` has histograms of successful connects since launch:
+/// `total` from the SOCKS request to the stream opening, and its final
+/// attempt split into `circuit_attach` (until Arti has a circuit) and
+/// `stream_open` (until the exit confirms). Each has `bounds_ms`, `counts`
+/// (one more than the bounds, for slower connects), `count` and `sum_ms`.
+///
+/// `favorites` lists the services in `onion.favorites` and those pinned
+/// with `arti_pin_peer` with `address`, `port`, `pinned`, `reachable` (the
+/// last keepalive got through),
+/// `last_reached` (Unix seconds, or null) and `failures` (keepalives failed
+/// in a row).
+///
+/// `network_tuning` is null until `tuning.adaptive` has rated the network,
+/// then has `quality` (`good`, `fair` or `poor`), `reason` (the measurements
+/// behind it), and the circuit settings applied: `request_timeout_ms`,
+/// `request_max_retries` and `prebuilt_exit_circuits` for each predicted
+/// port. A change in rating must hold for two assessments, 30 s apart.
+///
+/// `circuit_timeouts` is null while stopped, else has `learned_ms`, the
+/// build timeout Arti has learned for a 3-hop circuit so far, the limits
+/// `interactive_ms` and `bulk_ms` connects of each class get (null to wait
+/// as Arti does; see `circuit.interactive_timeout`), and `failovers`, the
+/// connects moved to a new circuit since launch.
+///
+/// # Arguments
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+/// * `redact_guard` - Report the guard only by a short fingerprint prefix
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if buffer is null
+/// * -2 if buffer is too small for the snapshot
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_status(buf: *mut c_char, len: c_int, redact_guard: bool) -> c_int {
+    if buf.is_null() || len <= 0 {
+        return -1;
+    }
+
+    write_str(&status::snapshot(redact_guard).to_json(), buf, len)
+}
+
+/// Report approximate memory use by subsystem, as JSON.
+///
+/// Writes `{"total_bytes":..,"directory_bytes":..,"circuit_bytes":..,
+/// "stream_buffer_bytes":..,"event_queue_bytes":..}`. `total_bytes` is the
+/// whole process's physical footprint, the figure iOS limits a network
+/// extension by, or null where the OS does not report it. Arti does not
+/// report its own allocations, so `directory_bytes` and `circuit_bytes` are
+/// estimates from relay and circuit counts; `stream_buffer_bytes` (SOCKS
+/// relay buffers) and `event_queue_bytes` are exact.
+///
+/// # Arguments
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if buffer is null
+/// * -2 if buffer is too small
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_memory_usage(buf: *mut c_char, len: c_int) -> c_int {
+    if buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let report = memory::report(running_client().as_deref());
+    match serde_json::to_string(&report) {
+        Ok(json) => write_str(&json, buf, len),
+        Err(_) => -1,
+    }
+}
+
+/// Daily usage statistics, as JSON, for charting trends.
+///
+/// Writes `{"days":[..]}`, oldest first, with an entry for each UTC day with
+/// activity: `date` (start of the day, Unix seconds), `bytes_sent`,
+/// `bytes_received`, `connects_succeeded`, `connects_failed`,
+/// `connect_success_rate` and `average_connect_ms` (null without connects)
+/// and `connected_secs` (time Tor was ready for traffic). Statistics are
+/// kept in the data directory for 90 days, and can be read while stopped.
+///
+/// # Arguments
+/// * `data_dir` - Data directory passed to `arti_start` (C string)
+/// * `days` - Number of days to include, counting today
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if a pointer is null
+/// * -2 if buffer is too small
+/// * -3 if data_dir is not valid UTF-8
+///
+/// # Safety
+/// `data_dir` must be a valid, null-terminated C string and `buf` must point
+/// to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_stats(
+    data_dir: *const c_char,
+    days: u32,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if data_dir.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Ok(data_dir) = CStr::from_ptr(data_dir).to_str() else {
+        return -3;
+    };
+    let report = stats::report(Path::new(data_dir), days as u64);
+    write_str(
+        &serde_json::to_string(&report).unwrap_or_default(),
+        buf,
+        len,
+    )
+}
+
+/// List active streams with the circuit path each one takes, as JSON.
+///
+/// Writes `{"streams":[{"id":..,"destination":"host:port","circuit":..,
+/// "hops":[{"role":..,"fingerprint":..,"nickname":..,"country":..,
+/// "addrs":[..]}]}]}` for a "your traffic takes this path" view. `role` is
+/// `guard`, `middle`, `exit`, or `rendezvous` for the last hop to an onion
+/// service. `nickname` is missing for bridges; `country` is null unless the
+/// library was built with the `geoip` feature, and is looked up in the
+/// database from `arti_geoip_update` if one was installed.
+///
+/// Each stream also has `multipath`, true when its traffic is split over
+/// several circuits (conflux), in which case `hops` shows the first. Arti
+/// 0.38 does not yet build multipath circuits for client streams, so this is
+/// currently always false.
+///
+/// Debug builds of the library add `relay`, with `client_to_tor` and
+/// `tor_to_client` each counting the `reads`, `writes` and `flushes` the
+/// relay loop made, `avg_read_bytes` and `avg_write_bytes`, and the time it
+/// waited for data to read (`read_wait_ms`) and for room to write it
+/// (`write_wait_ms`), for tuning buffer sizes.
+///
+/// # Arguments
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+/// * `redact` - Report destinations only by kind (`<domain>`, `<onion>`,
+///   `<ip>`) and relays by a short fingerprint prefix and country
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if buffer is null
+/// * -2 if buffer is too small
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_streams(buf: *mut c_char, len: c_int, redact: bool) -> c_int {
+    if buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let json = serde_json::json!({ "streams": metrics::stream_paths(redact) });
+    write_str(&json.to_string(), buf, len)
+}
+
+/// Explain why a SOCKS request failed to connect, as JSON.
+///
+/// Writes `{"stream":..,"client_port":..,"destination":"host:port",
+/// "failed_at":..,"elapsed_ms":..,"reason":..,"socks_reply":..,
+/// "retryable":..,"kind":..,"errors":[..],"attempts":..,
+/// "circuit_failures":[{"reason":..,"hop":..}],"ready_for_traffic":..,
+/// "log":[{"at_ms":..,"level":..,"target":..,"message":..}]}`, for the app
+/// to show or attach to a bug report. `reason` is as in the `stream_failed`
+/// event and `kind` is Arti's error kind; `errors` is the error followed by
+/// each one it was caused by. `circuit_failures` are those counted in
+/// `arti_status`. `log` has the lines logged while connecting, at debug
+/// level whatever the log filter, with `at_ms` counted from the request.
+/// The last 16 failures are kept, while running and after.
+///
+/// # Arguments
+/// * `stream` - Id of the stream, as in `stream_failed`, or 0 for the latest
+///   failure
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+/// * `redact` - Report the destination only by kind (`<domain>`, `<onion>`,
+///   `<ip>`), also where errors and log lines name it
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if buffer is null
+/// * -2 if buffer is too small
+/// * -4 if no such failure is kept
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_diagnose_failure(
+    stream: u64,
+    buf: *mut c_char,
+    len: c_int,
+    redact: bool,
+) -> c_int {
+    if buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Some(diagnosis) = diagnosis::find(stream, redact) else {
+        return -4;
+    };
+    write_str(
+        &serde_json::to_string(&diagnosis).unwrap_or_default(),
+        buf,
+        len,
+    )
+}
+
+/// Check a bridge line the user entered, without storing it.
+///
+/// Writes a JSON object: `{"valid":true,"bridge":{"addrs":[..],"rsa_id":..,
+/// "ed_id":..}}` or `{"valid":false,"error":{"kind":..,"message":..}}`, where
+/// `kind` is one of `empty`, `invalid_address`, `invalid_identity`,
+/// `duplicate_identity`, `unsupported_identity`, `missing_rsa_identity`,
+/// `unexpected_parameters`, `transport_unsupported` or `invalid`.
+///
+/// # Arguments
+/// * `line` - Bridge line (C string), with or without the leading `Bridge`
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if a pointer is null
+/// * -2 if buffer is too small
+/// * -3 if line is not valid UTF-8
+///
+/// # Safety
+/// `line` must be a valid, null-terminated C string and `buf` must point to
+/// at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_parse_bridge_line(
+    line: *const c_char,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if line.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Ok(line) = CStr::from_ptr(line).to_str() else {
+        return -3;
+    };
+    let json = serde_json::to_string(&bridges::parse_report(line)).unwrap_or_default();
+    write_str(&json, buf, len)
+}
+
+/// Check that a bridge is reachable from this network.
+///
+/// Connects directly (not over Tor) to the bridge's address and reports the
+/// latency. This does not authenticate the bridge. Blocks for up to
+/// `timeout_ms` per address; call it off the main thread.
+///
+/// Writes a JSON object with `valid`, `reachable`, and when known `address`,
+/// `latency_ms` and `error` (`{"kind":..,"message":..}`; kinds as for
+/// `arti_parse_bridge_line`, plus `timeout`, `unreachable` and `no_address`).
+///
+/// # Arguments
+/// * `line` - Bridge line (C string)
+/// * `timeout_ms` - Connect timeout per address
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if a pointer is null
+/// * -2 if buffer is too small
+/// * -3 if line is not valid UTF-8
+///
+/// # Safety
+/// `line` must be a valid, null-terminated C string and `buf` must point to
+/// at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_test_bridge(
+    line: *const c_char,
+    timeout_ms: u32,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if line.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Ok(line) = CStr::from_ptr(line).to_str() else {
+        return -3;
+    };
+    let report = bridges::test(line, Duration::from_millis(timeout_ms.max(1) as u64));
+    write_str(
+        &serde_json::to_string(&report).unwrap_or_default(),
+        buf,
+        len,
+    )
+}
+
+/// Signal Arti to go dormant (reduce resource usage).
+/// This is a hint; Arti may not fully support dormant mode yet. Connection
+/// padding drops to the `padding.dormant` level until `arti_wake`.
+///
+/// # Returns
+/// * 0 on success
+/// * -1 if not running
+#[no_mangle]
+pub extern "C" fn arti_go_dormant() -> c_int {
+    if !IS_RUNNING.load(Ordering::SeqCst) {
+        return -1;
+    }
+    // Arti's own dormant mode stops padding outright, so only the padding
+    // level changes here
+    IS_DORMANT.store(true, Ordering::SeqCst);
+    apply_padding(true);
+    update_summary("Dormant");
+    0
+}
+
+/// Signal Arti to wake from dormant mode.
+///
+/// # Returns
+/// * 0 on success
+/// * -1 if not running
+#[no_mangle]
+pub extern "C" fn arti_wake() -> c_int {
+    if !IS_RUNNING.load(Ordering::SeqCst) {
+        return -1;
+    }
+    IS_DORMANT.store(false, Ordering::SeqCst);
+    apply_padding(false);
+    update_summary("Active");
+    0
+}
+
+/// Correct a wrong device clock by an offset the host worked out elsewhere,
+/// e.g. from NTP or the cell network.
+///
+/// Arti checks the directory and relays' certificates against the current
+/// time, so a skewed clock blocks bootstrap (see `clock_skew_detected`).
+/// The corrected time applies at once, including to a running instance;
+/// Arti's tolerance for directory documents that look stale or not yet
+/// valid is widened by the offset at the next start. The offset is kept
+/// across stops; pass 0 to go back to the device clock.
+///
+/// # Arguments
+/// * `offset_ms` - The right time minus the device's, in milliseconds
+#[no_mangle]
+pub extern "C" fn arti_set_clock_offset(offset_ms: i64) {
+    clock::set_offset(offset_ms);
+}
+
+/// Tell Arti the device moved to another network interface, e.g. from Wi-Fi
+/// to cellular.
+///
+/// Streams being relayed are closed and the circuits they were on torn
+/// down, since they ride connections over the old interface that Arti would
+/// only notice were gone once they time out; apps reconnect at once instead.
+/// Favorite and pinned peers are reached again straight away. An
+/// `interface_changed` event lists the streams dropped and the peers
+/// migrated. Interface names are the host's own, e.g. "wifi" or "en0".
+///
+/// # Arguments
+/// * `from` - Interface moved off, or NULL if there was none
+/// * `to` - Interface now in use, or NULL if there is none
+///
+/// # Returns
+/// * 0 on success
+/// * -1 if not running
+/// * -3 if `from` or `to` is not valid UTF-8
+///
+/// # Safety
+/// `from` and `to` must each be NULL or a valid, null-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn arti_interface_changed(from: *const c_char, to: *const c_char) -> c_int {
+    let name = |ptr: *const c_char| {
+        if ptr.is_null() {
+            Ok(None)
+        } else {
+            CStr::from_ptr(ptr).to_str().map(Some)
+        }
+    };
+    let (Ok(from), Ok(to)) = (name(from), name(to)) else {
+        return -3;
+    };
+    if !IS_RUNNING.load(Ordering::SeqCst) {
+        return -1;
+    }
+    migration::interface_changed(from, to);
+    0
+}
+
+/// The running instance's client, once it has been created
+fn running_client() -> Option<Arc<TorClient<TorRuntime>>> {
+    ARTI_STATE
+        .get()
+        .and_then(|s| s.lock().ok())
+        .and_then(|g| g.client.clone())
+}
+
+/// Move the running client's padding to the dormant or foreground level
+fn apply_padding(dormant: bool) {
+    if let Some(client) = running_client() {
+        if let Err(e) = padding::set_dormant(&client, dormant) {
+            tracing::warn!("Failed to change padding: {}", e);
+        }
+    }
+}
+
+/// Copy `bytes` into a C buffer and null-terminate it.
+///
+/// # Safety
+/// `buf` must point to at least `bytes.len() + 1` writable bytes.
+unsafe fn copy_to_c_buf(bytes: &[u8], buf: *mut c_char) {
+    std::ptr::copy_nonoverlapping(bytes.as_ptr(), buf as *mut u8, bytes.len());
+    *buf.add(bytes.len()) = 0; // null terminator
+}
+
+/// Refresh directory information, and the onion services in
+/// `prefetch.onions`, within a time budget.
+///
+/// Intended for background refresh tasks, so that the next foreground start
+/// connects quickly. Uses the running instance if there is one; otherwise a
+/// temporary client works on the same state, and `arti_start` fails with -1
+/// until the prefetch returns. Blocks for up to `budget_ms`.
+///
+/// Writes a JSON report: `complete`, `directory_percent`, `directory_ready`,
+/// `onions_fetched`, `onions_remaining`, `onion_support` (onion services are
+/// only fetched in builds with the `onion-service-client` feature),
+/// `elapsed_ms` and `error`.
+///
+/// # Arguments
+/// * `data_dir` - Data directory passed to `arti_start` (C string)
+/// * `budget_ms` - Time the prefetch may take
+/// * `buf` - Buffer to write the report into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if a pointer is null
+/// * -2 if buffer is too small
+/// * -3 if data_dir is not valid UTF-8
+/// * -4 if the runtime could not be initialized
+/// * -5 if another prefetch is in progress
+///
+/// # Safety
+/// `data_dir` must be a valid, null-terminated C string and `buf` must point
+/// to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_prefetch(
+    data_dir: *const c_char,
+    budget_ms: u32,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if data_dir.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Ok(data_dir) = CStr::from_ptr(data_dir).to_str() else {
+        return -3;
+    };
+    if init_state().is_err() {
+        return -4;
+    }
+    let Some(Ok(guard)) = ARTI_STATE.get().map(|s| s.lock()) else {
+        return -4;
+    };
+    if prefetch::PREFETCHING.swap(true, Ordering::SeqCst) {
+        return -5;
+    }
+    let session = if IS_RUNNING.load(Ordering::SeqCst) {
+        prefetch::Session::Running
+    } else {
+        prefetch::Session::Stopped(PathBuf::from(data_dir))
+    };
+    let runtime = guard.runtime.clone();
+    drop(guard);
+
+    let budget = Duration::from_millis(budget_ms as u64);
+    let report = runtime.block_on(prefetch::run(session, config::current(), budget));
+    prefetch::PREFETCHING.store(false, Ordering::SeqCst);
+    write_str(
+        &serde_json::to_string(&report).unwrap_or_default(),
+        buf,
+        len,
+    )
+}
+
+/// Report how much storage a data directory takes, and on what.
+///
+/// Writes `{"total_bytes":..,"state_bytes":..,"cache_bytes":..,
+/// "consensuses":{"count":..,"bytes":..},"microdescriptors":..,
+/// "router_descriptors":..,"bridge_descriptors":..,
+/// "authority_certificates":..,"unused_bytes":..}`. `state_bytes` covers
+/// guards, keys, statistics and the rest of Arti's state. `unused_bytes` is
+/// space in the directory cache holding nothing, which the next start gives
+/// back; see `storage.microdescs_max_age_ms` for pruning more. Onion service
+/// descriptors are kept in memory only and take no storage. Works whether
+/// or not Arti is running; while an ephemeral session runs on `data_dir`,
+/// its temporary directory is reported.
+///
+/// # Arguments
+/// * `data_dir` - Data directory passed to `arti_start` (C string)
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if a pointer is null
+/// * -2 if buffer is too small
+/// * -3 if data_dir is not valid UTF-8
+///
+/// # Safety
+/// `data_dir` must be a valid, null-terminated C string and `buf` must point
+/// to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_storage_report(
+    data_dir: *const c_char,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if data_dir.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Ok(data_dir) = CStr::from_ptr(data_dir).to_str() else {
+        return -3;
+    };
+    let data_dir = Path::new(data_dir);
+    let running = ARTI_STATE
+        .get()
+        .and_then(|s| s.lock().ok())
+        .is_some_and(|g| g.data_dir.as_deref() == Some(data_dir));
+    let dirs = storage::dirs(data_dir, running);
+    let report = cache::report(&dirs.state, &dirs.cache);
+    write_str(
+        &serde_json::to_string(&report).unwrap_or_default(),
+        buf,
+        len,
+    )
+}
+
+/// Lend the calling thread to a host-driven runtime for `budget_ms`.
+///
+/// With `runtime.host_driven` set, Arti runs nothing on threads of its own:
+/// directory maintenance, descriptor refreshes, timeouts and SOCKS traffic
+/// all wait until the host calls this, so work can be fitted into the short
+/// bursts of CPU time hosts such as watchOS hand out. Work that came due
+/// meanwhile is caught up. Blocks for `budget_ms`; several threads may call
+/// it at once, but only one runs Arti at a time.
+///
+/// # Arguments
+/// * `budget_ms` - Time to run for
+///
+/// # Returns
+/// * 0 once the budget is used up
+/// * -1 if the runtime is not host-driven, or not created yet
+/// * -2 if called on Arti's own thread, e.g. from an event callback
+#[no_mangle]
+pub extern "C" fn arti_run_for(budget_ms: u32) -> c_int {
+    if !timeslice::host_driven() {
+        return -1;
+    }
+    if timeslice::on_runtime() {
+        return -2;
+    }
+    let Some(Ok(guard)) = ARTI_STATE.get().map(|s| s.lock()) else {
+        return -1;
+    };
+    let runtime = guard.runtime.clone();
+    drop(guard);
+
+    timeslice::run_for(&runtime, Duration::from_millis(budget_ms as u64));
+    0
+}
+
+/// Look up an onion service ahead of connecting to it.
+///
+/// Fetches the service's descriptor and builds its introduction circuits on
+/// the running client, e.g. when a pinned peer's chat is opened, so the
+/// connect that follows skips the lookup. Blocks for up to `timeout_ms`.
+/// Arti keeps descriptors in memory only; they are not kept across a stop, so
+/// use `prefetch.onions` and `arti_prefetch` to look peers up after launch.
+///
+/// # Arguments
+/// * `onion` - The service's `.onion` address (C string)
+/// * `port` - A port the service accepts streams on
+/// * `timeout_ms` - Time the lookup may take
+///
+/// # Returns
+/// * 0 if the service accepted a stream
+/// * -1 if not running, not bootstrapped, or onion is null
+/// * -3 if onion is not a valid v3 onion address
+/// * -4 if the service could not be reached
+/// * -5 if the lookup did not finish within `timeout_ms`
+/// * -6 if built without onion service support
+///
+/// # Safety
+/// `onion` must be a valid, null-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn arti_warm_onion(
+    onion: *const c_char,
+    port: u16,
+    timeout_ms: u32,
+) -> c_int {
+    if onion.is_null() {
+        return -1;
+    }
+    let Ok(onion) = CStr::from_ptr(onion).to_str() else {
+        return -3;
+    };
+    #[cfg(feature = "onion-service-client")]
+    {
+        if BOOTSTRAP_PROGRESS.load(Ordering::SeqCst) < 100 {
+            return -1;
+        }
+        let Some(guard) = ARTI_STATE.get().and_then(|s| s.lock().ok()) else {
+            return -1;
+        };
+        let Some(client) = guard.client.clone() else {
+            return -1;
+        };
+        let runtime = guard.runtime.clone();
+        drop(guard);
+
+        let limit = Duration::from_millis(timeout_ms as u64);
+        match runtime.block_on(prefetch::warm(&client, onion, port, limit)) {
+            Ok(()) => 0,
+            Err(prefetch::WarmError::BadAddress) => -3,
+            Err(prefetch::WarmError::Connect(e)) => {
+                tracing::debug!("Warming onion service failed: {}", e);
+                -4
+            }
+            Err(prefetch::WarmError::Timeout) => -5,
+        }
+    }
+    #[cfg(not(feature = "onion-service-client"))]
+    {
+        let _ = (onion, port, timeout_ms);
+        -6
+    }
+}
+
+/// Pin a peer's onion service, so sending to it stays fast.
+///
+/// While running, a stream is opened to the service and closed again every
+/// `onion.refresh_ms`, like the `onion.favorites` are every
+/// `onion.keepalive_ms`, which keeps its descriptor fresh. Arti cannot fetch
+/// a descriptor alone, so each refresh builds a rendezvous circuit, and the
+/// peer's app sees the stream. The first refresh starts straight away.
+/// Pinning a peer again replaces its port. Pins are kept until unpinned,
+/// across `arti_stop`, and are listed under `favorites` in `arti_status`.
+///
+/// # Arguments
+/// * `onion` - The service's `.onion` address (C string)
+/// * `port` - A port the service accepts streams on
+///
+/// # Returns
+/// * 0 if pinned
+/// * -1 if onion is null
+/// * -3 if onion is not a valid v3 onion address
+/// * -4 if 64 peers are pinned already
+/// * -6 if built without onion service support
+///
+/// # Safety
+/// `onion` must be a valid, null-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn arti_pin_peer(onion: *const c_char, port: u16) -> c_int {
+    if onion.is_null() {
+        return -1;
+    }
+    let Ok(onion) = CStr::from_ptr(onion).to_str() else {
+        return -3;
+    };
+    #[cfg(feature = "onion-service-client")]
+    {
+        if port == 0 || onion.trim().parse::<arti_client::HsId>().is_err() {
+            return -3;
+        }
+        match favorites::pin(onion, port) {
+            Ok(()) => 0,
+            Err(favorites::PinError::TooMany) => -4,
+        }
+    }
+    #[cfg(not(feature = "onion-service-client"))]
+    {
+        let _ = (onion, port);
+        -6
+    }
+}
+
+/// Unpin a peer pinned with `arti_pin_peer`, or every pinned peer.
+///
+/// # Arguments
+/// * `onion` - The service's `.onion` address (C string), or NULL for all
+///
+/// # Returns
+/// * 0 if unpinned
+/// * -2 if it was not pinned
+/// * -6 if built without onion service support
+///
+/// # Safety
+/// `onion` must be NULL or a valid, null-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn arti_unpin_peer(onion: *const c_char) -> c_int {
+    let onion = if onion.is_null() {
+        None
+    } else {
+        match CStr::from_ptr(onion).to_str() {
+            Ok(onion) => Some(onion),
+            Err(_) => return -2,
+        }
+    };
+    #[cfg(feature = "onion-service-client")]
+    {
+        if favorites::unpin(onion) || onion.is_none() {
+            0
+        } else {
+            -2
+        }
+    }
+    #[cfg(not(feature = "onion-service-client"))]
+    {
+        let _ = onion;
+        -6
+    }
+}
+
+/// Check that streams with different isolation tokens get different circuits.
+///
+/// Opens two streams to `host:port`, the second while the first is still
+/// open, each under its own isolation token, and compares their circuits.
+/// Writes `{"isolated":..,"circuits":[..,..]}`, where `isolated` is null if a
+/// circuit could not be inspected. If they share a circuit, an
+/// `isolation_not_honored` event is emitted too. Blocks for up to
+/// `timeout_ms`; call it off the main thread.
+///
+/// # Arguments
+/// * `host` - Destination to connect to, e.g. a hostname the app uses (C string)
+/// * `port` - Destination port
+/// * `timeout_ms` - Time both connects may take
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if not running, not bootstrapped, or an argument is null
+/// * -2 if buffer is too small
+/// * -3 if host is not a valid destination
+/// * -4 if a stream could not be opened
+/// * -5 if the check did not finish within `timeout_ms`
+///
+/// # Safety
+/// `host` must be a valid, null-terminated C string, and `buf` must point
+/// to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_check_isolation(
+    host: *const c_char,
+    port: u16,
+    timeout_ms: u32,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if host.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Ok(host) = CStr::from_ptr(host).to_str() else {
+        return -3;
+    };
+    if BOOTSTRAP_PROGRESS.load(Ordering::SeqCst) < 100 {
+        return -1;
+    }
+    let Some(guard) = ARTI_STATE.get().and_then(|s| s.lock().ok()) else {
+        return -1;
+    };
+    let Some(client) = guard.client.clone() else {
+        return -1;
+    };
+    let runtime = guard.runtime.clone();
+    drop(guard);
+
+    let limit = Duration::from_millis(timeout_ms as u64);
+    match runtime.block_on(isolation::check(&client, host, port, limit)) {
+        Ok(report) => write_str(
+            &serde_json::to_string(&report).unwrap_or_default(),
+            buf,
+            len,
+        ),
+        Err(isolation::CheckError::BadAddress) => -3,
+        Err(isolation::CheckError::Connect(e)) => {
+            tracing::debug!("Isolation check could not connect: {}", e);
+            -4
+        }
+        Err(isolation::CheckError::Timeout) => -5,
+    }
+}
+
+/// List the entry guards Arti has sampled, as JSON.
+///
+/// Writes `{"guards":[..],"pinned":..,"rotate_all_pending":..}`. Each guard
+/// carries `fingerprint`, `ed_id`, `addrs`, `sample`, `added_at`, and the
+/// flags `confirmed`, `disabled`, `unlisted`, `current`, `pinned` and
+/// `rotation_pending`. Works whether or not Arti is running.
+///
+/// # Arguments
+/// * `data_dir` - Data directory passed to `arti_start` (C string)
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+/// * `redact` - Shorten fingerprints to a prefix and omit addresses and ed25519 ids
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if a pointer is null
+/// * -2 if buffer is too small
+/// * -3 if data_dir is not valid UTF-8
+///
+/// # Safety
+/// `data_dir` must be a valid, null-terminated C string and `buf` must point
+/// to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_guards(
+    data_dir: *const c_char,
+    buf: *mut c_char,
+    len: c_int,
+    redact: bool,
+) -> c_int {
+    if data_dir.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Ok(data_dir) = CStr::from_ptr(data_dir).to_str() else {
+        return -3;
+    };
+    let list = guards::list(Path::new(data_dir), redact);
+    write_str(&serde_json::to_string(&list).unwrap_or_default(), buf, len)
+}
+
+/// Always start circuits at one guard, or stop doing so.
+///
+/// The pin is kept in the data directory and applies from the next
+/// `arti_start`. The guard is used like a bridge, so it stays in use even if
+/// it loses its Guard flag; configured `bridges` take precedence over it.
+///
+/// # Arguments
+/// * `data_dir` - Data directory passed to `arti_start` (C string)
+/// * `fingerprint` - Guard fingerprint, or a prefix of at least 8 hex digits
+///   as shown when redacted; NULL unpins
+///
+/// # Returns
+/// * 0 on success
+/// * -1 if data_dir is null
+/// * -2 if no sampled guard (or more than one) matches
+/// * -3 if a string is not valid UTF-8
+/// * -4 if the decision could not be saved
+///
+/// # Safety
+/// `data_dir` and, if not null, `fingerprint` must be valid, null-terminated C strings.
+#[no_mangle]
+pub unsafe extern "C" fn arti_pin_guard(
+    data_dir: *const c_char,
+    fingerprint: *const c_char,
+) -> c_int {
+    guard_control(data_dir, fingerprint, guards::pin)
+}
+
+/// Replace a guard, or every guard, with freshly chosen ones.
+///
+/// Applied at the next `arti_start`, and remembered until then even if the
+/// app exits.
+///
+/// # Arguments
+/// * `data_dir` - Data directory passed to `arti_start` (C string)
+/// * `fingerprint` - Guard fingerprint or prefix as for `arti_pin_guard`;
+///   NULL rotates all guards
+///
+/// # Returns
+/// * 0 on success
+/// * -1 if data_dir is null
+/// * -2 if no sampled guard (or more than one) matches
+/// * -3 if a string is not valid UTF-8
+/// * -4 if the decision could not be saved
+///
+/// # Safety
+/// `data_dir` and, if not null, `fingerprint` must be valid, null-terminated C strings.
+#[no_mangle]
+pub unsafe extern "C" fn arti_rotate_guards(
+    data_dir: *const c_char,
+    fingerprint: *const c_char,
+) -> c_int {
+    guard_control(data_dir, fingerprint, guards::rotate)
+}
+
+/// Shared argument handling for `arti_pin_guard` and `arti_rotate_guards`
+///
+/// # Safety
+/// As for those functions.
+unsafe fn guard_control(
+    data_dir: *const c_char,
+    fingerprint: *const c_char,
+    action: fn(&Path, Option<&str>) -> Result<(), guards::GuardError>,
+) -> c_int {
+    if data_dir.is_null() {
+        return -1;
+    }
+    let Ok(data_dir) = CStr::from_ptr(data_dir).to_str() else {
+        return -3;
+    };
+    let fingerprint = if fingerprint.is_null() {
+        None
+    } else {
+        match CStr::from_ptr(fingerprint).to_str() {
+            Ok(fp) => Some(fp),
+            Err(_) => return -3,
+        }
+    };
+    match action(Path::new(data_dir), fingerprint) {
+        Ok(()) => 0,
+        Err(guards::GuardError::NotFound) => -2,
+        Err(guards::GuardError::Io(e)) => {
+            tracing::warn!("Could not save guard decision: {}", e);
+            -4
+        }
+    }
+}
+
+/// Ask moat for bridges, for users who cannot reach the Tor network directly.
+///
+/// Runs in the background and works while Arti is stopped. The CAPTCHA to
+/// show the user arrives as a `bridge_challenge` event; pass the user's
+/// answer to `arti_solve_bridge_challenge`. Failures are reported as
+/// `bridge_fetch_failed` events.
+///
+/// # Returns
+/// * 0 if the request was started
+/// * -1 if a bridge request is already in progress
+#[no_mangle]
+pub extern "C" fn arti_request_bridges() -> c_int {
+    match moat::request_bridges() {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Answer the CAPTCHA from the last `bridge_challenge` event.
+///
+/// Runs in the background. On success the bridges are stored in the
+/// `bridges` option, used from the next `arti_start`, and a
+/// `bridges_received` event is sent. Each CAPTCHA can be answered once; after
+/// a `captcha_incorrect` failure call `arti_request_bridges` again.
+///
+/// # Arguments
+/// * `solution` - The text the user read from the image (C string)
+///
+/// # Returns
+/// * 0 if the answer was submitted
+/// * -1 if there is no CAPTCHA to answer
+/// * -2 if a bridge request is already in progress
+/// * -3 if solution is null or not valid UTF-8
+///
+/// # Safety
+/// `solution` must be a valid, null-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn arti_solve_bridge_challenge(solution: *const c_char) -> c_int {
+    if solution.is_null() {
+        return -3;
+    }
+    let Ok(solution) = CStr::from_ptr(solution).to_str() else {
+        return -3;
+    };
+    match moat::solve_challenge(solution) {
+        Ok(()) => 0,
+        Err(moat::RequestError::NoChallenge) => -1,
+        Err(moat::RequestError::Busy) => -2,
+    }
+}
+
+/// Host an onion service that forwards streams for `port` to
+/// `127.0.0.1:target_port`, where the app listens.
+///
+/// The identity key is kept in the keystore under `nickname`, so creating
+/// the same nickname on a later start gives the same address. Pass `key`
+/// from `arti_export_onion_service_key` to move an address from another
+/// install, then wipe it from your own memory. The service stops with
+/// `arti_onion_service_stop` or `arti_stop`. Requires the
+/// `onion-service-service` feature.
+///
+/// # Arguments
+/// * `nickname` - Local name for the service (C string)
+/// * `port` - Virtual port clients connect to
+/// * `target_port` - Local port streams are forwarded to
+/// * `key` - Hex identity key to import, or null to use or generate the stored one
+/// * `buf` - Buffer to write the `.onion` address into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if not running or an argument is null
+/// * -2 if buffer is too small
+/// * -3 if a string is not valid UTF-8, or the nickname or key is invalid
+/// * -4 if the service could not be launched, e.g. `key` was given but the
+///   nickname already has one
+/// * -5 if a service with this nickname is already running
+/// * -6 if built without onion service support
+///
+/// # Safety
+/// `nickname` and non-null `key` must be valid, null-terminated C strings,
+/// and `buf` must point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_onion_service_create(
+    nickname: *const c_char,
+    port: u16,
+    target_port: u16,
+    key: *const c_char,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if nickname.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Ok(nickname) = CStr::from_ptr(nickname).to_str() else {
+        return -3;
+    };
+    let key = if key.is_null() {
+        None
+    } else {
+        match CStr::from_ptr(key).to_str() {
+            Ok(k) => Some(k),
+            Err(_) => return -3,
+        }
+    };
+    #[cfg(feature = "onion-service-service")]
+    {
+        let Some(guard) = ARTI_STATE.get().and_then(|s| s.lock().ok()) else {
+            return -1;
+        };
+        let (Some(client), Some(shutdown)) = (guard.client.clone(), guard.shutdown.clone()) else {
+            return -1;
+        };
+        let _rt = guard.runtime.enter();
+        let expiry = onion_service::Expiry::default();
+        let created =
+            onion_service::create(&client, &shutdown, nickname, port, target_port, key, expiry);
+        write_created(nickname, created, buf, len)
+    }
+    #[cfg(not(feature = "onion-service-service"))]
+    {
+        let _ = (nickname, port, target_port, key);
+        -6
+    }
+}
+
+/// Host an onion service like `arti_onion_service_create` that stops by
+/// itself, for one-shot handoffs between contacts.
+///
+/// The service stops once `ttl_ms` has passed or it has forwarded
+/// `max_connections` streams to the app, whichever comes first; streams past
+/// the last are refused, and the service stops when the last one closes.
+/// A stream counts only once the app's port accepts it.
+/// However it stops, including by `arti_onion_service_stop` or `arti_stop`,
+/// every key stored under `nickname` is deleted, so the address is never
+/// served again, and an `onion_service_expired` event is sent. The key is
+/// always new, so `nickname` must not have one stored. If the process dies
+/// before the service stops its keys remain until `arti_wipe_all_keys`.
+///
+/// # Arguments
+/// * `nickname` - Local name for the service (C string)
+/// * `port` - Virtual port clients connect to
+/// * `target_port` - Local port streams are forwarded to
+/// * `ttl_ms` - How long the service runs, or 0 for no limit
+/// * `max_connections` - Streams it forwards, or 0 for no limit
+/// * `buf` - Buffer to write the `.onion` address into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if not running or an argument is null
+/// * -2 if buffer is too small
+/// * -3 if nickname is not valid UTF-8 or is invalid, or both limits are 0
+/// * -4 if the service could not be launched, e.g. the nickname already has
+///   a key
+/// * -5 if a service with this nickname is already running
+/// * -6 if built without onion service support
+///
+/// # Safety
+/// `nickname` must be a valid, null-terminated C string, and `buf` must
+/// point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_onion_service_create_expiring(
+    nickname: *const c_char,
+    port: u16,
+    target_port: u16,
+    ttl_ms: u32,
+    max_connections: u32,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if nickname.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Ok(nickname) = CStr::from_ptr(nickname).to_str() else {
+        return -3;
+    };
+    if ttl_ms == 0 && max_connections == 0 {
+        return -3;
+    }
+    #[cfg(feature = "onion-service-service")]
+    {
+        let Some(guard) = ARTI_STATE.get().and_then(|s| s.lock().ok()) else {
+            return -1;
+        };
+        let (Some(client), Some(shutdown)) = (guard.client.clone(), guard.shutdown.clone()) else {
+            return -1;
+        };
+        let _rt = guard.runtime.enter();
+        let expiry = onion_service::Expiry {
+            ttl: (ttl_ms > 0).then(|| Duration::from_millis(ttl_ms.into())),
+            connections: (max_connections > 0).then_some(u64::from(max_connections)),
+        };
+        let created = onion_service::create(
+            &client,
+            &shutdown,
+            nickname,
+            port,
+            target_port,
+            None,
+            expiry,
+        );
+        write_created(nickname, created, buf, len)
+    }
+    #[cfg(not(feature = "onion-service-service"))]
+    {
+        let _ = (nickname, port, target_port, ttl_ms, max_connections);
+        -6
+    }
+}
+
+/// Write the address of a service just created, or the error code for why
+/// it was not
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[cfg(feature = "onion-service-service")]
+unsafe fn write_created(
+    nickname: &str,
+    created: Result<String, onion_service::ServiceError>,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    match created {
+        Ok(address) => write_str(&address, buf, len),
+        Err(onion_service::ServiceError::Invalid) => -3,
+        Err(onion_service::ServiceError::Exists) => -5,
+        Err(onion_service::ServiceError::Launch(e)) => {
+            tracing::warn!("Failed to launch onion service {}: {}", nickname, e);
+            -4
+        }
+        Err(onion_service::ServiceError::NotFound) => -4,
+    }
+}
+
+/// List hosted onion services as JSON.
+///
+/// Writes `{"services":[{"nickname":..,"address":..,"port":..,
+/// "target_port":..,"state":..,"descriptor":..,"descriptor_ms":..,
+/// "clients":..,"counts":{..}}]}`, where `state` is Arti's view of the
+/// service, e.g. `Bootstrapping`, `Running` or `Broken`. `descriptor` is
+/// `pending` until the descriptor is first published, `fresh` while Arti
+/// believes the service reachable and `stale` otherwise, which it has been
+/// for `descriptor_ms`; Arti republishes on its own when the network or the
+/// introduction points change. `clients` counts open rendezvous circuits.
+/// `counts` totals what the service has handled since it was launched:
+/// `introductions`, `rendezvous`, `rendezvous_failed`, `streams`,
+/// `streams_refused` (for another port, or not accepted by the app),
+/// `clients_limited`, `bytes_sent` and `bytes_received`. Services from
+/// `arti_onion_service_create_expiring` also carry `expires_ms`, the time
+/// left of their TTL, and `connections_left`, the streams they have left,
+/// each null if not limited. `self_test` is the latest result of
+/// `arti_onion_service_self_test`, or null if none has run.
+///
+/// # Arguments
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if buffer is null
+/// * -2 if buffer is too small
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_onion_services(buf: *mut c_char, len: c_int) -> c_int {
+    if buf.is_null() || len <= 0 {
+        return -1;
+    }
+    #[cfg(feature = "onion-service-service")]
+    let services = onion_service::list();
+    #[cfg(not(feature = "onion-service-service"))]
+    let services: Vec<()> = Vec::new();
+    let json = serde_json::json!({ "services": services });
+    write_str(&json.to_string(), buf, len)
+}
+
+/// Stop a hosted onion service. Its key stays in the keystore, unless it was
+/// created with `arti_onion_service_create_expiring`.
+///
+/// Arti only releases the service's state once it has had a directory, so a
+/// service stopped before bootstrap completes cannot be created again under
+/// the same nickname until the process exits.
+///
+/// # Returns
+/// * 0 on success
+/// * -1 if nickname is null or no such service is running
+/// * -3 if nickname is not valid UTF-8
+/// * -6 if built without onion service support
+///
+/// # Safety
+/// `nickname` must be a valid, null-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn arti_onion_service_stop(nickname: *const c_char) -> c_int {
+    if nickname.is_null() {
+        return -1;
+    }
+    let Ok(nickname) = CStr::from_ptr(nickname).to_str() else {
+        return -3;
+    };
+    #[cfg(feature = "onion-service-service")]
+    match onion_service::stop(nickname) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+    #[cfg(not(feature = "onion-service-service"))]
+    {
+        let _ = nickname;
+        -6
+    }
+}
+
+/// Check that a hosted onion service can be reached, the way a contact
+/// would reach it.
+///
+/// Looks the service up and connects to it from new circuits, isolated from
+/// everything else, so the descriptor is fetched from the network and the
+/// introduction and rendezvous are done in full. The stream asks for a port
+/// the service does not serve, so the service itself turns it away, which
+/// proves the whole path without the app seeing a connection; it shows in
+/// `counts` as a refused stream. Blocks for up to `timeout_ms`; call it off
+/// the main thread.
+///
+/// Writes `{"reachable":..,"latency_ms":..,"error":..}`, where `latency_ms`
+/// is the time from starting the lookup until the service answered, and
+/// `error` says why it was not reached. The latest result is also listed by
+/// `arti_onion_services` as `self_test`.
+///
+/// # Arguments
+/// * `nickname` - Service nickname (C string)
+/// * `timeout_ms` - Time the test may take
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if not running, not bootstrapped, or an argument is null
+/// * -2 if buffer is too small
+/// * -3 if nickname is not valid UTF-8
+/// * -4 if no such service is running
+/// * -6 if built without onion service support
+///
+/// # Safety
+/// `nickname` must be a valid, null-terminated C string, and `buf` must
+/// point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_onion_service_self_test(
+    nickname: *const c_char,
+    timeout_ms: u32,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if nickname.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Ok(nickname) = CStr::from_ptr(nickname).to_str() else {
+        return -3;
+    };
+    #[cfg(feature = "onion-service-service")]
+    {
+        if BOOTSTRAP_PROGRESS.load(Ordering::SeqCst) < 100 {
+            return -1;
+        }
+        let Some(guard) = ARTI_STATE.get().and_then(|s| s.lock().ok()) else {
+            return -1;
+        };
+        let Some(client) = guard.client.clone() else {
+            return -1;
+        };
+        let runtime = guard.runtime.clone();
+        drop(guard);
+
+        let limit = Duration::from_millis(timeout_ms.into());
+        match runtime.block_on(onion_service::self_test(&client, nickname, limit)) {
+            Ok(test) => write_str(&serde_json::to_string(&test).unwrap_or_default(), buf, len),
+            Err(onion_service::ServiceError::NotFound) => -4,
+            Err(e) => {
+                tracing::warn!("Onion service {} self-test failed: {:?}", nickname, e);
+                -4
+            }
+        }
+    }
+    #[cfg(not(feature = "onion-service-service"))]
+    {
+        let _ = (nickname, timeout_ms);
+        -6
+    }
+}
+
+/// Export the identity key of an onion service as hex, for
+/// `arti_onion_service_create` on another install.
+///
+/// Anyone holding the key can impersonate the service; treat it like a
+/// password, and overwrite `buf` once it has been used.
+///
+/// # Arguments
+/// * `nickname` - Service nickname (C string)
+/// * `buf` - Buffer to write the key into (at least 129 bytes)
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if not running or an argument is null
+/// * -2 if buffer is too small
+/// * -3 if nickname is invalid
+/// * -4 if there is no key for the nickname or the keystore failed
+/// * -6 if built without onion service support
+///
+/// # Safety
+/// `nickname` must be a valid, null-terminated C string, and `buf` must
+/// point to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_export_onion_service_key(
+    nickname: *const c_char,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if nickname.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Ok(nickname) = CStr::from_ptr(nickname).to_str() else {
+        return -3;
+    };
+    #[cfg(feature = "onion-service-service")]
+    {
+        let Some(client) = running_client() else {
+            return -1;
+        };
+        match onion_service::export_key(&client, nickname) {
+            Ok(key) => write_str(&key, buf, len),
+            Err(onion_service::ServiceError::Invalid) => -3,
+            Err(onion_service::ServiceError::Launch(e)) => {
+                tracing::warn!("Keystore error: {}", e);
+                -4
+            }
+            Err(_) => -4,
+        }
+    }
+    #[cfg(not(feature = "onion-service-service"))]
+    {
+        let _ = nickname;
+        -6
+    }
+}
+
+/// Generate a key for reaching an onion service in restricted discovery
+/// mode, and get the public part to give to the service's operator.
+///
+/// # Arguments
+/// * `onion` - The service's `.onion` address (C string)
+/// * `replace` - Replace an existing key instead of failing
+/// * `buf` - Buffer to write the `descriptor:x25519:...` public key into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if not running or an argument is null
+/// * -2 if buffer is too small
+/// * -3 if onion is not a valid v3 onion address
+/// * -4 if a key exists and `replace` is false
+/// * -5 if the keystore failed
+/// * -6 if built without onion service support
+///
+/// # Safety
+/// `onion` must be a valid, null-terminated C string, and `buf` must point
+/// to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_generate_client_auth_key(
+    onion: *const c_char,
+    replace: bool,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if onion.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Ok(onion) = CStr::from_ptr(onion).to_str() else {
+        return -3;
+    };
+    #[cfg(feature = "onion-service-client")]
+    {
+        let Some(client) = running_client() else {
+            return -1;
+        };
+        match onion_auth::generate(&client, onion, replace) {
+            Ok(key) => write_str(&key, buf, len),
+            Err(e) => client_auth_error(e),
+        }
+    }
+    #[cfg(not(feature = "onion-service-client"))]
+    {
+        let _ = (onion, replace);
+        -6
+    }
+}
+
+/// Get the public part of the key held for a restricted discovery service.
+///
+/// # Returns
+/// As `arti_generate_client_auth_key`, with -4 if no key is held.
+///
+/// # Safety
+/// `onion` must be a valid, null-terminated C string, and `buf` must point
+/// to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_client_auth_key(
+    onion: *const c_char,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if onion.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Ok(onion) = CStr::from_ptr(onion).to_str() else {
+        return -3;
+    };
+    #[cfg(feature = "onion-service-client")]
+    {
+        let Some(client) = running_client() else {
+            return -1;
+        };
+        match onion_auth::get(&client, onion) {
+            Ok(key) => write_str(&key, buf, len),
+            Err(e) => client_auth_error(e),
+        }
+    }
+    #[cfg(not(feature = "onion-service-client"))]
+    {
+        let _ = onion;
+        -6
+    }
+}
+
+/// Delete the key held for a restricted discovery service.
+///
+/// # Returns
+/// * 0 on success
+/// * -1 if not running or onion is null
+/// * -3 if onion is not a valid v3 onion address
+/// * -4 if no key is held
+/// * -5 if the keystore failed
+/// * -6 if built without onion service support
+///
+/// # Safety
+/// `onion` must be a valid, null-terminated C string.
+#[no_mangle]
+pub unsafe extern "C" fn arti_remove_client_auth_key(onion: *const c_char) -> c_int {
+    if onion.is_null() {
+        return -1;
+    }
+    let Ok(onion) = CStr::from_ptr(onion).to_str() else {
+        return -3;
+    };
+    #[cfg(feature = "onion-service-client")]
+    {
+        let Some(client) = running_client() else {
+            return -1;
+        };
+        match onion_auth::remove(&client, onion) {
+            Ok(()) => 0,
+            Err(e) => client_auth_error(e),
+        }
+    }
+    #[cfg(not(feature = "onion-service-client"))]
+    {
+        let _ = onion;
+        -6
+    }
+}
+
+/// Create a signed payload introducing this device to a peer, e.g. as a QR
+/// code.
+///
+/// The payload carries `noise_key`, the address of the service launched as
+/// `nickname`, `client_auth_key` if given, and `expires`, and is signed with
+/// the service's identity key. It is unpadded base32, so it fits a QR code's
+/// alphanumeric mode. The peer checks it with `arti_contact_payload_verify`.
+///
+/// # Arguments
+/// * `nickname` - Nickname the service was launched under (C string)
+/// * `noise_key` - Noise static public key as 64 hex digits (C string)
+/// * `client_auth_key` - A `descriptor:x25519:...` key to pass on, or null
+/// * `expires` - Unix time in seconds after which the payload is refused
+/// * `buf` - Buffer to write the payload into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if not running or an argument is null
+/// * -2 if buffer is too small
+/// * -3 if a string is not valid UTF-8, an argument is malformed, or
+///   `expires` has passed
+/// * -4 if no identity key is stored for `nickname`, or the keystore failed
+/// * -6 if built without onion service support
+///
+/// # Safety
+/// `nickname`, `noise_key` and non-null `client_auth_key` must be valid,
+/// null-terminated C strings, and `buf` must point to at least `len`
+/// writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_contact_payload_create(
+    nickname: *const c_char,
+    noise_key: *const c_char,
+    client_auth_key: *const c_char,
+    expires: u64,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if nickname.is_null() || noise_key.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let (Ok(nickname), Ok(noise_key)) = (
+        CStr::from_ptr(nickname).to_str(),
+        CStr::from_ptr(noise_key).to_str(),
+    ) else {
+        return -3;
+    };
+    let client_auth_key = if client_auth_key.is_null() {
+        None
+    } else {
+        match CStr::from_ptr(client_auth_key).to_str() {
+            Ok(k) => Some(k),
+            Err(_) => return -3,
+        }
+    };
+    #[cfg(feature = "onion-service-service")]
+    {
+        let Some(client) = running_client() else {
+            return -1;
+        };
+        let identity = match onion_service::identity(&client, nickname) {
+            Ok(identity) => identity,
+            Err(onion_service::ServiceError::Invalid) => return -3,
+            Err(e) => {
+                tracing::debug!("No identity key for {}: {:?}", nickname, e);
+                return -4;
+            }
+        };
+        match contact::create(identity.as_ref(), noise_key, client_auth_key, expires) {
+            Ok(payload) => write_str(&payload, buf, len),
+            Err(_) => -3,
+        }
+    }
+    #[cfg(not(feature = "onion-service-service"))]
+    {
+        let _ = (nickname, noise_key, client_auth_key, expires);
+        -6
+    }
+}
+
+/// Check a payload from `arti_contact_payload_create` and decode it as JSON.
+///
+/// Writes `{"noise_key":..,"onion":..,"client_auth_key":..,"expires":..}`,
+/// with `noise_key` as hex and `client_auth_key` null if none was included.
+/// A valid signature proves the payload was made by whoever controls
+/// `onion`. Works whether or not Arti is running.
+///
+/// # Arguments
+/// * `payload` - The payload (C string)
+/// * `buf` - Buffer to write the JSON into
+/// * `len` - Length of the buffer
+///
+/// # Returns
+/// * Number of bytes written (not including null terminator)
+/// * -1 if a pointer is null
+/// * -2 if buffer is too small
+/// * -3 if payload is not a contact payload
+/// * -4 if the signature is not valid
+/// * -5 if the payload has expired
+/// * -6 if built without onion service support
+///
+/// # Safety
+/// `payload` must be a valid, null-terminated C string, and `buf` must point
+/// to at least `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn arti_contact_payload_verify(
+    payload: *const c_char,
+    buf: *mut c_char,
+    len: c_int,
+) -> c_int {
+    if payload.is_null() || buf.is_null() || len <= 0 {
+        return -1;
+    }
+    let Ok(payload) = CStr::from_ptr(payload).to_str() else {
+        return -3;
+    };
+    #[cfg(feature = "onion-service-client")]
+    {
+        match contact::verify(payload) {
+            Ok(contact) => write_str(
+                &serde_json::to_string(&contact).unwrap_or_default(),
+                buf,
+                len,
+            ),
+            Err(contact::ContactError::Invalid) => -3,
+            Err(contact::ContactError::BadSignature) => -4,
+            Err(contact::ContactError::Expired) => -5,
+        }
+    }
+    #[cfg(not(feature = "onion-service-client"))]
+    {
+        let _ = payload;
+        -6
+    }
+}
+
+#[cfg(feature = "onion-service-client")]
+fn client_auth_error(e: onion_auth::KeyError) -> c_int {
+    match e {
+        onion_auth::KeyError::BadAddress => -3,
+        onion_auth::KeyError::NotFound | onion_auth::KeyError::Exists => -4,
+        onion_auth::KeyError::Keystore(e) => {
+            tracing::warn!("Keystore error: {}", e);
+            -5
+        }
+    }
+}
+
+/// Copy a string (usually JSON) into a C buffer, returning its length, -1
+/// if `len` is not positive or -2 if it does not fit.
+///
+/// # Safety
+/// `buf` must point to at least `len` writable bytes.
+unsafe fn write_str(s: &str, buf: *mut c_char, len: c_int) -> c_int {
+    if len <= 0 {
+        return -1;
+    }
+    if s.len() >= len as usize {
+        return -2;
+    }
+    copy_to_c_buf(s.as_bytes(), buf);
+    s.len() as c_int
+}
+
+fn update_summary(s: &str) {
+    if let Ok(mut guard) = BOOTSTRAP_SUMMARY.lock() {
+        guard.clear();
+        guard.push_str(s);
+    }
 }
 
 /// Main async entry point for Arti
 async fn run_arti(
     data_dir: PathBuf,
-    socks_addr: SocketAddr,
-    mut shutdown_rx: oneshot::Receiver<()>,
+    config: config::Config,
+    listeners: Vec<TcpListener>,
+    shutdown: Arc<shutdown::ShutdownController>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Ensure data directory exists
-    std::fs::create_dir_all(&data_dir)?;
-
     update_summary("Configuring...");
+    #[cfg(feature = "geoip")]
+    {
+        // Parsing a stored database takes a moment; lookups use the
+        // embedded one until it is done
+        let data_dir = data_dir.clone();
+        tokio::task::spawn_blocking(move || geoip::open(&data_dir));
+    }
+    watchdog::clear();
+    let mut restarts = 0;
+    loop {
+        let client = create_client(&data_dir, &config).await?;
+        let tasks = spawn_tasks(&client, &config);
+        let restart = (restarts < config.bootstrap_max_restarts).then_some(restarts + 1);
+        let outcome = if loopback::ENABLED {
+            loopback::announce(&config);
+            bootstrap::Outcome::Bootstrapped
+        } else {
+            bootstrap_client(&client, &config, restart, &shutdown).await
+        };
+        let result = match outcome {
+            bootstrap::Outcome::Bootstrapped => {
+                serve(client, config, listeners, shutdown).await;
+                Ok(())
+            }
+            bootstrap::Outcome::Cancelled => Ok(()),
+            bootstrap::Outcome::Failed(e) => Err(e.into()),
+            bootstrap::Outcome::Stalled => {
+                restarts += 1;
+                tracing::warn!(
+                    "Replacing the client ({} of {})",
+                    restarts,
+                    config.bootstrap_max_restarts
+                );
+                update_summary("Bootstrap stalled, restarting...");
+                // The old client must be gone before the new one takes its state directory
+                if let Some(state) = ARTI_STATE.get() {
+                    if let Ok(mut guard) = state.lock() {
+                        guard.client = None;
+                    }
+                }
+                drop(client);
+                stop_tasks(tasks);
+                continue;
+            }
+        };
+        stop_tasks(tasks);
+        probe::set_captive_portal(false);
+        return result;
+    }
+}
 
-    // Build Arti configuration with custom directories
-    let cache_dir = data_dir.join("cache");
-    let state_dir = data_dir.join("state");
-
-    // Use from_directories which sets up storage correctly
-    use arti_client::config::TorClientConfigBuilder;
-    let config = TorClientConfigBuilder::from_directories(state_dir, cache_dir)
-        .build()?;
-
-    update_summary("Bootstrapping...");
+/// Create a client for `data_dir` and publish it for status queries
+async fn create_client(
+    data_dir: &Path,
+    config: &config::Config,
+) -> Result<Arc<TorClient<TorRuntime>>, Box<dyn std::error::Error + Send + Sync>> {
+    let builder = tor_client_config(data_dir, config)?;
+    padding::remember(&builder, config);
+    let tor_config = builder.build()?;
 
-    // Create and bootstrap the Tor client
-    let client = TorClient::create_bootstrapped(config).await?;
+    // Creating the client only fails on configuration or storage problems,
+    // which retrying will not fix
+    let mut builder = TorClient::with_runtime(clock::runtime()?).config(tor_config);
+    if loopback::ENABLED {
+        // Anything still asking for Tor fails instead of bootstrapping
+        builder = builder.bootstrap_behavior(BootstrapBehavior::Manual);
+    }
+    let client = builder.create_unbootstrapped_async().await?;
     let client = Arc::new(client);
 
-    // Store client reference for status queries
+    // Store client reference for status queries, including during bootstrap
     if let Some(state) = ARTI_STATE.get() {
         if let Ok(mut guard) = state.lock() {
             guard.client = Some(client.clone());
         }
     }
+    if IS_DORMANT.load(Ordering::SeqCst) {
+        if let Err(e) = padding::set_dormant(&client, true) {
+            tracing::warn!("Failed to apply dormant padding: {}", e);
+        }
+    }
+    Ok(client)
+}
+
+/// Start the background tasks that run alongside `client`
+fn spawn_tasks(
+    client: &Arc<TorClient<TorRuntime>>,
+    config: &config::Config,
+) -> Vec<JoinHandle<()>> {
+    let mut tasks = Vec::new();
+    {
+        let client = client.clone();
+        tasks.push(tokio::spawn(async move {
+            monitor::watch_bootstrap(&client).await
+        }));
+    }
+    tasks.push(tokio::spawn(quota::autosave()));
+    tasks.push(tokio::spawn(stats::autosave()));
+    if !config.memory_report_interval.is_zero() {
+        let client = client.clone();
+        let interval = config.memory_report_interval;
+        tasks.push(tokio::spawn(async move {
+            memory::report_periodically(&client, interval).await
+        }));
+    }
+    // Always running, as peers may be pinned at any time
+    #[cfg(feature = "onion-service-client")]
+    {
+        let client = client.clone();
+        let favorites = config.favorite_onions.clone();
+        let (interval, refresh) = (config.favorite_keepalive, config.pin_refresh);
+        tasks.push(tokio::spawn(async move {
+            favorites::keep_alive(&client, &favorites, interval, refresh).await
+        }));
+    }
+    if config.budget_connects_per_minute != 0 || config.budget_circuits != 0 {
+        tasks.push(tokio::spawn(budget::watch(config.clone())));
+    }
+    if config.adaptive_tuning {
+        let client = client.clone();
+        tasks.push(tokio::spawn(async move { tuning::run(&client).await }));
+    }
+    if !config.exit_list_refresh.is_zero() {
+        let client = client.clone();
+        let refresh = config.exit_list_refresh;
+        tasks.push(tokio::spawn(
+            async move { exits::run(&client, refresh).await },
+        ));
+    }
+    tasks
+}
+
+/// Stop the tasks from [`spawn_tasks`] and forget what they observed
+fn stop_tasks(tasks: Vec<JoinHandle<()>>) {
+    for task in tasks {
+        task.abort();
+    }
+    favorites::clear();
+    tuning::clear();
+    budget::clear();
+    monitor::clear();
+}
+
+/// Build the Arti configuration for a session rooted at `data_dir`, applying
+/// pending guard decisions first
+fn tor_client_config(
+    data_dir: &Path,
+    config: &config::Config,
+) -> Result<TorClientConfigBuilder, Box<dyn std::error::Error + Send + Sync>> {
+    // Ensure data directory exists
+    std::fs::create_dir_all(data_dir)?;
+
+    // Build Arti configuration with custom directories
+    let dirs = storage::prepare(data_dir, config.ephemeral)?;
+    cache::prune(&dirs.cache, config);
+
+    // Use from_directories which sets up storage correctly
+    let mut tor_config = TorClientConfigBuilder::from_directories(dirs.state, dirs.cache);
+    let pinned_guard = guards::apply(data_dir)?;
+    let bridge_lines = match pinned_guard {
+        Some(line) if config.bridges.is_empty() => vec![line],
+        _ => config.bridges.clone(),
+    };
+    for line in &bridge_lines {
+        let (bridge, _) = bridges::parse_line(line).map_err(|e| e.message)?;
+        tor_config.bridges().bridges().push(bridge);
+    }
+    if !config.reachable_addresses.is_empty() {
+        *tor_config.path_rules().reachable_addrs() = config.reachable_addresses.clone();
+    }
+    testnet::apply(&mut tor_config, config)?;
+    tor_config.channel().padding(config.padding_foreground);
+    clock::widen_tolerance(&mut tor_config);
+    Ok(tor_config)
+}
+
+/// Wait for an open network and bootstrap `client`; a hang ends it only if
+/// `restart` is set, see [`bootstrap::supervise`]
+async fn bootstrap_client(
+    client: &TorClient<TorRuntime>,
+    config: &config::Config,
+    restart: Option<u32>,
+    shutdown: &shutdown::ShutdownController,
+) -> bootstrap::Outcome {
+    if !bootstrap::wait_for_open_network(config, shutdown).await {
+        return bootstrap::Outcome::Cancelled;
+    }
+    bootstrap::supervise(client, config, restart, shutdown).await
+}
 
+/// Serve SOCKS on a bootstrapped client until shutdown
+async fn serve(
+    client: Arc<TorClient<TorRuntime>>,
+    config: config::Config,
+    listeners: Vec<TcpListener>,
+    shutdown: Arc<shutdown::ShutdownController>,
+) {
     // Mark bootstrap complete
     BOOTSTRAP_PROGRESS.store(100, Ordering::SeqCst);
     update_summary("Ready");
-
-    // Bind SOCKS listener
-    let listener = TcpListener::bind(socks_addr).await?;
-    tracing::info!("SOCKS5 proxy listening on {}", socks_addr);
+    stats::set_connected(true);
 
     // Accept connections until shutdown
-    loop {
-        tokio::select! {
-            accept_result = listener.accept() => {
-                match accept_result {
-                    Ok((stream, peer_addr)) => {
-                        let client = client.clone();
-                        tokio::spawn(async move {
-                            if let Err(e) = socks::handle_socks_connection(stream, peer_addr, client).await {
-                                tracing::debug!("SOCKS connection error from {}: {}", peer_addr, e);
-                            }
-                        });
-                    }
-                    Err(e) => {
-                        tracing::warn!("Accept error: {}", e);
-                    }
-                }
-            }
-            _ = &mut shutdown_rx => {
-                tracing::info!("Shutdown signal received");
-                break;
-            }
-        }
-    }
+    listener::serve(listeners, client, Arc::new(config), shutdown).await;
+    stats::set_connected(false);
 
     update_summary("Shutting down...");
-    Ok(())
 }
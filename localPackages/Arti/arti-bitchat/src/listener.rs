@@ -0,0 +1,298 @@
+//! SOCKS listeners
+//!
+//! Binds the SOCKS server on every configured address and runs one accept
+//! loop per socket, all sharing the Tor client and a single shutdown signal.
+//!
+//! Binding happens synchronously in `arti_start`, before bootstrap, so the
+//! chosen port is known (and reportable) before any client is told to
+//! connect. Connections made before bootstrap completes wait in the accept
+//! backlog.
+//!
+//! Accepting can be paused without unbinding: a paused listener leaves new
+//! connections in the backlog, or accepts them only to fail their SOCKS
+//! request when pausing in refusing mode. An exceeded traffic quota refuses
+//! the same way.
+//!
+//! Connections handed over by the host rather than accepted, such as
+//! [`pipe`](crate::pipe)s, are served alongside the listeners' and treated
+//! alike, a paused listener leaving them waiting.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use arti_client::TorClient;
+use once_cell::sync::Lazy;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use crate::audit::{self, AuditRecord, Verdict};
+use crate::client_stream::ClientStream;
+use crate::clock::TorRuntime;
+use crate::config::{Config, ListenSpec};
+use crate::shutdown::ShutdownController;
+use crate::socks;
+use crate::{quota, ratelimit};
+
+/// Addresses the SOCKS server is currently bound to
+static BOUND_ADDRS: Mutex<Vec<SocketAddr>> = Mutex::new(Vec::new());
+
+/// What connections from outside the listeners are served with, while serving
+struct Serving {
+    client: Arc<TorClient<TorRuntime>>,
+    config: Arc<Config>,
+    shutdown: Arc<ShutdownController>,
+}
+
+static SERVING: Mutex<Option<Serving>> = Mutex::new(None);
+
+/// Whether the accept loops are currently taking connections
+static ACCEPT_STATE: Lazy<watch::Sender<AcceptState>> =
+    Lazy::new(|| watch::Sender::new(AcceptState::Accepting));
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AcceptState {
+    /// Accepting and serving connections normally
+    Accepting,
+    /// Not accepting; connections queue in the backlog
+    Paused,
+    /// Accepting, but failing every request with a SOCKS error
+    Refusing,
+}
+
+impl AcceptState {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            AcceptState::Accepting => "accepting",
+            AcceptState::Paused => "paused",
+            AcceptState::Refusing => "refusing",
+        }
+    }
+}
+
+/// Change whether the listeners accept connections; the sockets stay bound
+pub(crate) fn set_accept_state(state: AcceptState) {
+    ACCEPT_STATE.send_replace(state);
+}
+
+pub(crate) fn accept_state() -> AcceptState {
+    *ACCEPT_STATE.borrow()
+}
+
+/// Bind every listen address, failing if any of them cannot be bound.
+///
+/// With a `default_port` of 0 the OS picks a port for the first address
+/// without one, and the remaining such addresses reuse it so that e.g.
+/// 127.0.0.1 and ::1 end up on the same port.
+///
+/// Must be called within the Tokio runtime context.
+pub(crate) fn bind_all(specs: &[ListenSpec], default_port: u16) -> io::Result<Vec<TcpListener>> {
+    let mut port = default_port;
+    let mut listeners = Vec::with_capacity(specs.len());
+    let mut bound = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let addr = spec.resolve(port);
+        let listener = bind(addr).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to bind SOCKS listener on {}: {}", addr, e),
+            )
+        })?;
+        let local_addr = listener.local_addr()?;
+        if port == 0 && matches!(spec, ListenSpec::Ip(_)) {
+            port = local_addr.port();
+        }
+        bound.push(local_addr);
+        listeners.push(listener);
+    }
+
+    if let Ok(mut addrs) = BOUND_ADDRS.lock() {
+        *addrs = bound;
+    }
+    Ok(listeners)
+}
+
+fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+/// Addresses currently bound, in configuration order
+pub(crate) fn bound_addrs() -> Vec<SocketAddr> {
+    BOUND_ADDRS.lock().map(|a| a.clone()).unwrap_or_default()
+}
+
+/// Forget the bound addresses once the listeners are gone
+pub(crate) fn clear_bound_addrs() {
+    if let Ok(mut addrs) = BOUND_ADDRS.lock() {
+        addrs.clear();
+    }
+}
+
+/// Accept connections on all listeners until shutdown, then drain the
+/// connections still open
+pub(crate) async fn serve(
+    listeners: Vec<TcpListener>,
+    client: Arc<TorClient<TorRuntime>>,
+    config: Arc<Config>,
+    shutdown: Arc<ShutdownController>,
+) {
+    let mut accept_loops = JoinSet::new();
+    if let Ok(mut serving) = SERVING.lock() {
+        *serving = Some(Serving {
+            client: client.clone(),
+            config: config.clone(),
+            shutdown: shutdown.clone(),
+        });
+    }
+
+    for listener in listeners {
+        if let Ok(addr) = listener.local_addr() {
+            tracing::info!("SOCKS5 proxy listening on {}", addr);
+        }
+        accept_loops.spawn(accept_loop(
+            listener,
+            client.clone(),
+            config.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    shutdown.cancelled().await;
+    tracing::info!("Shutdown signal received");
+    if let Ok(mut serving) = SERVING.lock() {
+        *serving = None;
+    }
+    while accept_loops.join_next().await.is_some() {}
+    shutdown.drain().await;
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    client: Arc<TorClient<TorRuntime>>,
+    config: Arc<Config>,
+    shutdown: Arc<ShutdownController>,
+) {
+    let stop = shutdown.token();
+    let mut state_rx = ACCEPT_STATE.subscribe();
+    loop {
+        let state = *state_rx.borrow_and_update();
+        if state == AcceptState::Paused {
+            tokio::select! {
+                _ = state_rx.changed() => continue,
+                _ = stop.cancelled() => break,
+            }
+        }
+
+        tokio::select! {
+            accept_result = listener.accept() => {
+                match accept_result {
+                    Ok((stream, peer_addr)) => dispatch(&shutdown, stream, peer_addr, state, &client, &config),
+                    Err(e) => {
+                        tracing::warn!("Accept error: {}", e);
+                    }
+                }
+            }
+            _ = state_rx.changed() => continue,
+            _ = stop.cancelled() => break,
+        }
+    }
+}
+
+/// Serve a connection from outside the listeners as if one had accepted it
+/// from `peer_addr`, waiting while they are paused.
+///
+/// Returns false, dropping `stream`, unless the listeners are serving.
+pub(crate) fn serve_stream<S: ClientStream>(stream: S, peer_addr: SocketAddr) -> bool {
+    let Some((client, config, shutdown)) = SERVING.lock().ok().and_then(|s| {
+        s.as_ref()
+            .map(|s| (s.client.clone(), s.config.clone(), s.shutdown.clone()))
+    }) else {
+        return false;
+    };
+    let stop = shutdown.token();
+    let mut state_rx = ACCEPT_STATE.subscribe();
+    shutdown.clone().spawn(async move {
+        let state = loop {
+            let state = *state_rx.borrow_and_update();
+            if state != AcceptState::Paused {
+                break state;
+            }
+            tokio::select! {
+                _ = state_rx.changed() => {}
+                _ = stop.cancelled() => return,
+            }
+        };
+        dispatch(&shutdown, stream, peer_addr, state, &client, &config);
+    });
+    true
+}
+
+/// Serve a connection accepted in `state`, or refuse it
+fn dispatch<S: ClientStream>(
+    shutdown: &ShutdownController,
+    stream: S,
+    peer_addr: SocketAddr,
+    state: AcceptState,
+    client: &Arc<TorClient<TorRuntime>>,
+    config: &Arc<Config>,
+) {
+    if S::RATE_LIMITED && ratelimit::check_blocked(peer_addr.ip()) {
+        tracing::debug!("Closed SOCKS connection from refused source {}", peer_addr);
+        audit::record(AuditRecord::new(peer_addr, Verdict::Blocked, "rate_limit"));
+    } else if state == AcceptState::Refusing {
+        spawn_refusal(
+            shutdown,
+            stream,
+            peer_addr,
+            config.clone(),
+            "listener_refusing",
+        )
+    } else if quota::exceeded().is_some() {
+        spawn_refusal(
+            shutdown,
+            stream,
+            peer_addr,
+            config.clone(),
+            "quota_exceeded",
+        )
+    } else {
+        spawn_handler(shutdown, stream, peer_addr, client.clone(), config.clone())
+    }
+}
+
+fn spawn_refusal<S: ClientStream>(
+    shutdown: &ShutdownController,
+    stream: S,
+    peer_addr: SocketAddr,
+    config: Arc<Config>,
+    reason: &'static str,
+) {
+    let cancel = shutdown.token();
+    shutdown.spawn(async move {
+        if let Err(e) =
+            socks::refuse_socks_connection(stream, peer_addr, config, reason, cancel).await
+        {
+            tracing::debug!("SOCKS refusal error: {}", e);
+        }
+    });
+}
+
+fn spawn_handler<S: ClientStream>(
+    shutdown: &ShutdownController,
+    stream: S,
+    peer_addr: SocketAddr,
+    client: Arc<TorClient<TorRuntime>>,
+    config: Arc<Config>,
+) {
+    let cancel = shutdown.token();
+    shutdown.spawn(async move {
+        if let Err(e) =
+            socks::handle_socks_connection(stream, peer_addr, client, config, cancel).await
+        {
+            tracing::debug!("SOCKS connection error from {}: {}", peer_addr, e);
+        }
+    });
+}
@@ -0,0 +1,70 @@
+//! Log output and its runtime filter
+//!
+//! Logs are written to stderr, which ends up in the Xcode console, by a
+//! subscriber installed on first use. The filter is a comma-separated list of
+//! `target=level` directives with an optional bare level for everything
+//! else, e.g. `warn,arti_bitchat=debug`. A target also covers every target it
+//! is a prefix of, so `arti` includes `arti_client`, and the longest match
+//! wins. This lets users turn up a single subsystem when reporting a bug.
+//! The filter only applies to log output, not to spans exported with the
+//! `otlp` feature.
+
+use once_cell::sync::OnceCell;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, Layer, Registry};
+
+/// Filter used until the app sets one
+const DEFAULT_FILTER: &str = "warn";
+
+/// Reload handle for the filter; `None` if the host process had already
+/// installed its own subscriber
+static HANDLE: OnceCell<Option<reload::Handle<Targets, Registry>>> = OnceCell::new();
+
+#[derive(Debug)]
+pub(crate) enum FilterError {
+    /// Not a valid list of directives
+    Invalid,
+    /// Another subscriber owns the log output
+    Unavailable,
+}
+
+fn handle() -> Option<&'static reload::Handle<Targets, Registry>> {
+    HANDLE
+        .get_or_init(|| {
+            let filter: Targets = DEFAULT_FILTER.parse().ok()?;
+            let (filter, handle) = reload::Layer::new(filter);
+            let subscriber = tracing_subscriber::registry()
+                .with(
+                    fmt::layer()
+                        .with_ansi(false)
+                        .with_writer(std::io::stderr)
+                        .with_filter(filter),
+                )
+                .with(crate::latency::layer())
+                .with(crate::diagnosis::layer());
+            #[cfg(feature = "otlp")]
+            let subscriber = subscriber.with(crate::telemetry::layer());
+            subscriber.try_init().ok()?;
+            Some(handle)
+        })
+        .as_ref()
+}
+
+/// Install the subscriber with the default filter, if not done yet
+pub(crate) fn init() {
+    handle();
+}
+
+/// Replace the filter, e.g. with `arti=warn,arti_bitchat=debug`
+pub(crate) fn set_filter(directives: &str) -> Result<(), FilterError> {
+    let filter: Targets = directives
+        .trim()
+        .parse()
+        .map_err(|_| FilterError::Invalid)?;
+    handle()
+        .ok_or(FilterError::Unavailable)?
+        .reload(filter)
+        .map_err(|_| FilterError::Unavailable)
+}
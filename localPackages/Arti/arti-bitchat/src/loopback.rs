@@ -0,0 +1,71 @@
+//! Loopback mode, for developing the app without Tor
+//!
+//! Built with the `loopback` feature, the library never bootstraps: it is
+//! ready as soon as it starts, and SOCKS streams are connected directly,
+//! resolving names with the system resolver, or all to `loopback.upstream`
+//! when set, e.g. a local echo server or mock backend. Everything in front
+//! of the connect runs as usual, so the app sees the same handshakes,
+//! policy, group limits, events and audit records as over Tor; streams just
+//! have no circuit, and isolation, onion services and anything else that
+//! needs the Tor network are not available.
+//!
+//! A loopback build offers no anonymity at all. `loopback_mode` is emitted
+//! on every start and `arti_status` reports `loopback`, so the app can show
+//! a banner saying so.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+use crate::events::{self, Event};
+use crate::stats;
+use crate::stream_events::Progress;
+
+/// Whether this build connects without Tor
+pub(crate) const ENABLED: bool = cfg!(feature = "loopback");
+
+/// Time a direct connect may take, about what Arti allows a stream
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Say that this start will not use Tor
+pub(crate) fn announce(config: &Config) {
+    let upstream = config.loopback_upstream.map(|a| a.to_string());
+    match &upstream {
+        Some(upstream) => tracing::warn!(
+            "Loopback build: streams are sent to {} without Tor",
+            upstream
+        ),
+        None => tracing::warn!("Loopback build: streams are connected directly, without Tor"),
+    }
+    events::emit(Event::LoopbackMode { upstream });
+}
+
+/// Connect to `host:port`, or to `loopback.upstream` instead
+pub(crate) async fn connect(
+    host: &str,
+    port: u16,
+    config: &Config,
+    progress: &Progress,
+) -> io::Result<TcpStream> {
+    let started = Instant::now();
+    let connect = async {
+        let addrs: Vec<SocketAddr> = match config.loopback_upstream {
+            Some(upstream) => vec![upstream],
+            None => tokio::net::lookup_host((host, port))
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?
+                .collect(),
+        };
+        TcpStream::connect(&addrs[..]).await
+    };
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, connect)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    // Reported as over Tor, so the app sees the same sequence of events
+    progress.attached();
+    stats::note_connect_succeeded(started.elapsed());
+    Ok(stream)
+}
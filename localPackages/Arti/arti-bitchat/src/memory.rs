@@ -0,0 +1,93 @@
+//! Approximate memory use, for staying under the network extension's limit
+//!
+//! iOS kills a network extension that grows past roughly 50 MB, so the app
+//! needs to see where memory goes. The process total comes from the OS (the
+//! physical footprint jetsam acts on, on Apple platforms). Arti does not
+//! report its own allocations, so the directory and circuit figures are
+//! estimates from relay and circuit counts; the relay buffers and event
+//! queue are counted exactly.
+
+use std::time::Duration;
+
+use arti_client::TorClient;
+use serde::Serialize;
+
+use crate::clock::TorRuntime;
+use crate::events::{self, Event};
+use crate::{metrics, socks};
+
+/// Rough in-memory size of one relay's consensus entry and microdescriptor
+const DIRECTORY_BYTES_PER_RELAY: u64 = 2 * 1024;
+
+/// Rough size of one circuit's hop state and cell queues
+const CIRCUIT_BYTES: u64 = 16 * 1024;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct Report {
+    /// Physical footprint of the whole process; null where the OS does not say
+    total_bytes: Option<u64>,
+    /// Estimated size of the network directory in use
+    directory_bytes: u64,
+    /// Estimated size of the circuits carrying streams
+    circuit_bytes: u64,
+    /// Copy buffers of the SOCKS streams being relayed
+    stream_buffer_bytes: u64,
+    /// Events waiting for `arti_poll_events`
+    event_queue_bytes: u64,
+}
+
+/// Measure memory use now; `client` is the running client, if any
+pub(crate) fn report(client: Option<&TorClient<TorRuntime>>) -> Report {
+    let relays = client
+        .and_then(|c| c.dirmgr().timely_netdir().ok())
+        .map_or(0, |dir| dir.relays().count() as u64);
+    Report {
+        total_bytes: process_footprint(),
+        directory_bytes: relays * DIRECTORY_BYTES_PER_RELAY,
+        circuit_bytes: metrics::active_circuits() as u64 * CIRCUIT_BYTES,
+        // One buffer for each direction
+        stream_buffer_bytes: metrics::active_streams() as u64 * 2 * socks::RELAY_BUF_SIZE as u64,
+        event_queue_bytes: events::queued_bytes() as u64,
+    }
+}
+
+/// Emit a `memory_usage` event every `interval` while the client runs
+pub(crate) async fn report_periodically(client: &TorClient<TorRuntime>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        events::emit(Event::MemoryUsage(report(Some(client))));
+    }
+}
+
+#[cfg(any(target_os = "ios", target_os = "macos"))]
+fn process_footprint() -> Option<u64> {
+    let mut info: libc::rusage_info_v4 = unsafe { std::mem::zeroed() };
+    let rc = unsafe {
+        libc::proc_pid_rusage(
+            libc::getpid(),
+            libc::RUSAGE_INFO_V4,
+            &mut info as *mut _ as *mut libc::rusage_info_t,
+        )
+    };
+    (rc == 0).then_some(info.ri_phys_footprint)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn process_footprint() -> Option<u64> {
+    // Resident set size, the second field, in pages
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    u64::try_from(page_size).ok().map(|size| pages * size)
+}
+
+#[cfg(not(any(
+    target_os = "ios",
+    target_os = "macos",
+    target_os = "linux",
+    target_os = "android"
+)))]
+fn process_footprint() -> Option<u64> {
+    None
+}
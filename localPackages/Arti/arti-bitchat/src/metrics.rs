@@ -0,0 +1,509 @@
+//! Stream and traffic accounting
+//!
+//! Tracks the streams currently relayed through the SOCKS proxy and the bytes
+//! they carry, so status queries can be answered without reaching into Arti.
+//! Each stream's circuit path is captured when it attaches, for the app's
+//! circuit display.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use arti_client::{DataStream, TorClient};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tor_linkspec::{HasAddrs, HasRelayIds};
+use tor_netdir::NetDir;
+use tor_proto::client::stream::ClientStreamCtrl;
+use tor_proto::ClientTunnel;
+
+use crate::clock::TorRuntime;
+use crate::failure::{BuildFailure, HopPosition};
+#[cfg(debug_assertions)]
+use crate::relay_stats::{RelayStats, RelayView};
+use crate::{audit, quota, stats};
+
+const SECS_PER_DAY: u64 = 86_400;
+
+static NEXT_STREAM_ID: AtomicU64 = AtomicU64::new(1);
+static STREAMS: Lazy<Mutex<HashMap<u64, StreamRecord>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static LAST_GUARD: Mutex<Option<GuardInfo>> = Mutex::new(None);
+
+/// UTC day number the byte counters below belong to
+static BYTES_DAY: AtomicU64 = AtomicU64::new(0);
+static BYTES_SENT_TODAY: AtomicU64 = AtomicU64::new(0);
+static BYTES_RECEIVED_TODAY: AtomicU64 = AtomicU64::new(0);
+
+/// Clients dropped for exceeding the handshake time or size limit
+static HANDSHAKES_DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Connections closed because their source was over the failure limit
+static CONNECTIONS_RATE_LIMITED: AtomicU64 = AtomicU64::new(0);
+
+static CIRCUIT_FAILURES: Mutex<CircuitFailures> = Mutex::new(CircuitFailures::new());
+
+/// Characters of a relay fingerprint kept when redacting
+const FINGERPRINT_PREFIX: usize = 8;
+
+/// What we know about the circuit carrying an active stream
+struct StreamRecord {
+    client_port: u16,
+    destination: String,
+    circuit: Option<String>,
+    tunnel: Option<Arc<ClientTunnel>>,
+    hops: Vec<Hop>,
+    /// Circuits (conflux legs) the stream's tunnel is split across
+    legs: usize,
+    #[cfg(debug_assertions)]
+    relay: Arc<RelayStats>,
+}
+
+/// A relay on a stream's circuit, as seen when the stream attached
+#[derive(Clone)]
+struct Hop {
+    /// RSA identity fingerprint, hex encoded
+    fingerprint: String,
+    /// From the consensus; unknown for bridges
+    nickname: Option<String>,
+    /// Two-letter country code, in builds with the `geoip` feature
+    country: Option<String>,
+    addrs: Vec<String>,
+}
+
+/// An active stream and the path its circuit takes
+#[derive(Serialize)]
+pub(crate) struct StreamPath {
+    id: u64,
+    /// `host:port`, or the kind of host when redacted
+    destination: String,
+    circuit: Option<String>,
+    /// Traffic is split over several circuits (conflux); `hops` shows the first
+    multipath: bool,
+    hops: Vec<HopView>,
+    /// Relay loop counters, see [`crate::relay_stats`]
+    #[cfg(debug_assertions)]
+    relay: RelayView,
+}
+
+#[derive(Serialize)]
+struct HopView {
+    /// `guard`, `middle`, `exit`, or for onion services `rendezvous`
+    role: &'static str,
+    fingerprint: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nickname: Option<String>,
+    country: Option<String>,
+    /// Omitted when redacted
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    addrs: Vec<String>,
+}
+
+/// First hop of a circuit we have used
+#[derive(Clone)]
+pub(crate) struct GuardInfo {
+    /// RSA identity fingerprint, hex encoded
+    fingerprint: String,
+    /// Full description including addresses
+    description: String,
+}
+
+impl GuardInfo {
+    pub(crate) fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Describe the guard, optionally reducing it to a short fingerprint prefix
+    pub(crate) fn describe(&self, redact: bool) -> String {
+        if redact {
+            let prefix: String = self.fingerprint.chars().take(8).collect();
+            format!("${}…", prefix)
+        } else {
+            self.description.clone()
+        }
+    }
+}
+
+/// Registration of a relayed stream; deregisters itself when dropped
+pub(crate) struct StreamHandle {
+    id: u64,
+    circuit: Option<String>,
+    #[cfg(debug_assertions)]
+    relay: Arc<RelayStats>,
+}
+
+/// Number a new SOCKS request, for its registration and events
+pub(crate) fn new_stream_id() -> u64 {
+    NEXT_STREAM_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+impl StreamHandle {
+    /// Register a newly connected stream and note the circuit it is attached
+    /// to; `tor_stream` is `None` in `loopback` builds
+    pub(crate) fn register(
+        id: u64,
+        client_port: u16,
+        tor_stream: Option<&DataStream>,
+        client: &TorClient<TorRuntime>,
+        destination: String,
+    ) -> Self {
+        let tunnel = tor_stream
+            .and_then(|stream| stream.client_stream_ctrl())
+            .and_then(|ctrl| ctrl.tunnel());
+
+        let circuit = tunnel.as_ref().map(|t| t.unique_id().to_string());
+        let netdir = client.dirmgr().timely_netdir().ok();
+        let paths = tunnel.as_ref().map(|t| t.all_paths()).unwrap_or_default();
+        let legs = paths.len();
+        let hops = paths
+            .into_iter()
+            .next()
+            .map(|path| {
+                path.iter()
+                    .filter_map(|entry| entry.as_chan_target())
+                    .map(|target| Hop::describe(target, netdir.as_deref()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if let Some(first_hop) = tunnel.as_ref().and_then(|t| t.first_hop().ok()) {
+            let fingerprint = first_hop
+                .rsa_identity()
+                .map(|id| id.to_string().trim_start_matches('$').to_string())
+                .unwrap_or_default();
+            if let Ok(mut guard) = LAST_GUARD.lock() {
+                *guard = Some(GuardInfo {
+                    fingerprint,
+                    description: first_hop.to_string(),
+                });
+            }
+        }
+
+        #[cfg(debug_assertions)]
+        let relay = Arc::new(RelayStats::default());
+        if let Ok(mut streams) = STREAMS.lock() {
+            streams.insert(
+                id,
+                StreamRecord {
+                    client_port,
+                    destination,
+                    circuit: circuit.clone(),
+                    tunnel,
+                    hops,
+                    legs,
+                    #[cfg(debug_assertions)]
+                    relay: relay.clone(),
+                },
+            );
+        }
+        StreamHandle {
+            id,
+            circuit,
+            #[cfg(debug_assertions)]
+            relay,
+        }
+    }
+
+    /// Unique id of the circuit the stream attached to, if known
+    pub(crate) fn circuit(&self) -> Option<&str> {
+        self.circuit.as_deref()
+    }
+
+    /// Counters for the stream's relay loop to update
+    #[cfg(debug_assertions)]
+    pub(crate) fn relay_stats(&self) -> &RelayStats {
+        &self.relay
+    }
+}
+
+impl Drop for StreamHandle {
+    fn drop(&mut self) {
+        if let Ok(mut streams) = STREAMS.lock() {
+            streams.remove(&self.id);
+        }
+    }
+}
+
+impl Hop {
+    fn describe<T: HasRelayIds + HasAddrs>(target: &T, netdir: Option<&NetDir>) -> Self {
+        let relay = netdir.and_then(|dir| dir.by_ids(target));
+        #[cfg(feature = "geoip")]
+        let country = crate::geoip::country_of(target.addrs().map(|a| a.ip()));
+        #[cfg(not(feature = "geoip"))]
+        let country = None;
+        Hop {
+            fingerprint: target
+                .rsa_identity()
+                .map(|id| id.to_string().trim_start_matches('$').to_string())
+                .unwrap_or_default(),
+            nickname: relay.map(|r| r.rs().nickname().to_string()),
+            country,
+            addrs: target.addrs().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    fn view(&self, role: &'static str, redact: bool) -> HopView {
+        HopView {
+            role,
+            fingerprint: if redact {
+                self.fingerprint.chars().take(FINGERPRINT_PREFIX).collect()
+            } else {
+                self.fingerprint.clone()
+            },
+            nickname: self.nickname.clone().filter(|_| !redact),
+            country: self.country.clone(),
+            addrs: if redact {
+                Vec::new()
+            } else {
+                self.addrs.clone()
+            },
+        }
+    }
+}
+
+/// Active streams with their circuit paths, oldest first.
+///
+/// With `redact`, destinations are reduced to their kind, fingerprints to a
+/// short prefix, and nicknames and addresses are left out; countries are kept.
+pub(crate) fn stream_paths(redact: bool) -> Vec<StreamPath> {
+    let Ok(streams) = STREAMS.lock() else {
+        return Vec::new();
+    };
+    let mut paths: Vec<StreamPath> = streams
+        .iter()
+        .map(|(id, record)| {
+            let onion = record.destination.to_ascii_lowercase().contains(".onion:");
+            let last = record.hops.len().saturating_sub(1);
+            let hops = record
+                .hops
+                .iter()
+                .enumerate()
+                .map(|(i, hop)| {
+                    let role = match i {
+                        0 => "guard",
+                        i if i == last && onion => "rendezvous",
+                        i if i == last => "exit",
+                        _ => "middle",
+                    };
+                    hop.view(role, redact)
+                })
+                .collect();
+            let destination = if redact {
+                let (host, port) = record
+                    .destination
+                    .rsplit_once(':')
+                    .unwrap_or((&record.destination, ""));
+                format!("{}:{}", audit::host_kind(host), port)
+            } else {
+                record.destination.clone()
+            };
+            StreamPath {
+                id: *id,
+                destination,
+                circuit: record.circuit.clone(),
+                multipath: record.legs > 1,
+                hops,
+                #[cfg(debug_assertions)]
+                relay: record.relay.view(),
+            }
+        })
+        .collect();
+    paths.sort_by_key(|p| p.id);
+    paths
+}
+
+/// Number of streams currently being relayed
+pub(crate) fn active_streams() -> usize {
+    STREAMS.lock().map(|s| s.len()).unwrap_or(0)
+}
+
+/// Id, client port and destination of each stream being relayed
+pub(crate) fn stream_endpoints() -> Vec<(u64, u16, String)> {
+    let Ok(streams) = STREAMS.lock() else {
+        return Vec::new();
+    };
+    let mut endpoints: Vec<_> = streams
+        .iter()
+        .map(|(id, record)| (*id, record.client_port, record.destination.clone()))
+        .collect();
+    endpoints.sort_by_key(|e| e.0);
+    endpoints
+}
+
+/// Close the circuits carrying the active streams, and the streams with
+/// them; returns how many were closed
+pub(crate) fn terminate_circuits() -> usize {
+    let tunnels: Vec<Arc<ClientTunnel>> = match STREAMS.lock() {
+        Ok(streams) => streams.values().filter_map(|r| r.tunnel.clone()).collect(),
+        Err(_) => return 0,
+    };
+    let mut closed = HashSet::new();
+    for tunnel in tunnels {
+        if closed.insert(tunnel.unique_id()) {
+            tunnel.terminate();
+        }
+    }
+    closed.len()
+}
+
+/// Number of distinct circuits carrying the active streams
+pub(crate) fn active_circuits() -> usize {
+    STREAMS
+        .lock()
+        .map(|s| {
+            s.values()
+                .filter_map(|r| r.circuit.as_deref())
+                .collect::<HashSet<_>>()
+                .len()
+        })
+        .unwrap_or(0)
+}
+
+/// Guard used by the most recently attached stream
+pub(crate) fn current_guard() -> Option<GuardInfo> {
+    LAST_GUARD.lock().ok().and_then(|g| g.clone())
+}
+
+/// Forget the last seen guard (e.g. on shutdown)
+pub(crate) fn clear_guard() {
+    if let Ok(mut guard) = LAST_GUARD.lock() {
+        *guard = None;
+    }
+}
+
+/// Record bytes sent from the local client into Tor
+pub(crate) fn add_sent(n: u64) {
+    roll_day();
+    BYTES_SENT_TODAY.fetch_add(n, Ordering::Relaxed);
+    quota::add(n);
+    stats::add_sent(n);
+}
+
+/// Record bytes received from Tor for the local client
+pub(crate) fn add_received(n: u64) {
+    roll_day();
+    BYTES_RECEIVED_TODAY.fetch_add(n, Ordering::Relaxed);
+    quota::add(n);
+    stats::add_received(n);
+}
+
+/// Bytes (sent, received) since UTC midnight
+pub(crate) fn bytes_today() -> (u64, u64) {
+    roll_day();
+    (
+        BYTES_SENT_TODAY.load(Ordering::Relaxed),
+        BYTES_RECEIVED_TODAY.load(Ordering::Relaxed),
+    )
+}
+
+/// Count a client dropped for a slow or oversized handshake
+pub(crate) fn note_handshake_dropped() {
+    HANDSHAKES_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Clients dropped for slow or oversized handshakes since launch
+pub(crate) fn handshakes_dropped() -> u64 {
+    HANDSHAKES_DROPPED.load(Ordering::Relaxed)
+}
+
+/// Count a connection closed because its source is being refused
+pub(crate) fn note_rate_limited() {
+    CONNECTIONS_RATE_LIMITED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Connections closed for rate limiting since launch
+pub(crate) fn connections_rate_limited() -> u64 {
+    CONNECTIONS_RATE_LIMITED.load(Ordering::Relaxed)
+}
+
+/// Failed circuit builds since launch, by reason and by hop position
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct CircuitFailures {
+    by_reason: ReasonCounts,
+    by_hop: HopCounts,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct ReasonCounts {
+    timeout: u64,
+    destroyed: u64,
+    path_selection: u64,
+    channel: u64,
+    protocol: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct HopCounts {
+    first: u64,
+    later: u64,
+    unknown: u64,
+}
+
+impl CircuitFailures {
+    const fn new() -> Self {
+        CircuitFailures {
+            by_reason: ReasonCounts {
+                timeout: 0,
+                destroyed: 0,
+                path_selection: 0,
+                channel: 0,
+                protocol: 0,
+            },
+            by_hop: HopCounts {
+                first: 0,
+                later: 0,
+                unknown: 0,
+            },
+        }
+    }
+}
+
+/// Count the circuit builds that failed behind a connect error
+pub(crate) fn note_circuit_failures(error: &arti_client::Error) {
+    let failures = crate::failure::circuit_failures(error);
+    if failures.is_empty() {
+        return;
+    }
+    let Ok(mut counts) = CIRCUIT_FAILURES.lock() else {
+        return;
+    };
+    for (reason, hop) in failures {
+        let by_reason = &mut counts.by_reason;
+        *match reason {
+            BuildFailure::Timeout => &mut by_reason.timeout,
+            BuildFailure::Destroyed => &mut by_reason.destroyed,
+            BuildFailure::PathSelection => &mut by_reason.path_selection,
+            BuildFailure::Channel => &mut by_reason.channel,
+            BuildFailure::Protocol => &mut by_reason.protocol,
+        } += 1;
+        let by_hop = &mut counts.by_hop;
+        *match hop {
+            HopPosition::First => &mut by_hop.first,
+            HopPosition::Later => &mut by_hop.later,
+            HopPosition::Unknown => &mut by_hop.unknown,
+        } += 1;
+    }
+}
+
+pub(crate) fn circuit_failures() -> CircuitFailures {
+    CIRCUIT_FAILURES
+        .lock()
+        .map(|c| c.clone())
+        .unwrap_or_else(|_| CircuitFailures::new())
+}
+
+/// Reset the daily byte counters when the UTC day changes
+fn roll_day() {
+    let today = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / SECS_PER_DAY)
+        .unwrap_or(0);
+    let previous = BYTES_DAY.load(Ordering::Relaxed);
+    if previous != today
+        && BYTES_DAY
+            .compare_exchange(previous, today, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    {
+        BYTES_SENT_TODAY.store(0, Ordering::Relaxed);
+        BYTES_RECEIVED_TODAY.store(0, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,81 @@
+//! Moving to a new network interface
+//!
+//! When the device moves between networks, e.g. from Wi-Fi to cellular,
+//! Arti's connections to its guards are left on an interface that is gone.
+//! Arti only notices once they time out, and streams on them hang until
+//! then. The host reports the change with `arti_interface_changed`, which:
+//!
+//! * closes the streams being relayed, so apps reconnect at once instead of
+//!   waiting on a dead path; these are reported as dropped
+//! * closes the circuits they were on and gives isolation groups new
+//!   circuits, so new streams are not attached to the old path
+//! * reaches favorite and pinned peers again straight away, which builds
+//!   their circuits over the new path; these are reported as migrated
+//!
+//! Channels to guards belong to Arti and close when it notices they are gone.
+
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::events::{self, Event};
+#[cfg(feature = "onion-service-client")]
+use crate::favorites;
+use crate::{groups, metrics};
+
+/// Cancelled when the network path the current streams use goes away
+static PATH: Lazy<Mutex<CancellationToken>> = Lazy::new(|| Mutex::new(CancellationToken::new()));
+
+/// A stream closed by an interface change
+#[derive(Debug, Serialize)]
+pub(crate) struct DroppedStream {
+    stream: u64,
+    client_port: u16,
+    destination: String,
+}
+
+/// The current path, for a stream about to connect over it
+pub(crate) fn path() -> CancellationToken {
+    PATH.lock().map(|p| p.clone()).unwrap_or_default()
+}
+
+/// Move off interface `old` to `new`, where `None` is no interface
+pub(crate) fn interface_changed(old: Option<&str>, new: Option<&str>) {
+    tracing::info!(
+        "Network interface changed from {} to {}",
+        old.unwrap_or("none"),
+        new.unwrap_or("none")
+    );
+    let dropped: Vec<DroppedStream> = metrics::stream_endpoints()
+        .into_iter()
+        .map(|(stream, client_port, destination)| DroppedStream {
+            stream,
+            client_port,
+            destination,
+        })
+        .collect();
+    if let Ok(mut path) = PATH.lock() {
+        std::mem::take(&mut *path).cancel();
+    }
+    let circuits_closed = metrics::terminate_circuits();
+    groups::renew_isolation();
+
+    #[cfg(feature = "onion-service-client")]
+    let migrated = if new.is_some() {
+        favorites::resume()
+    } else {
+        Vec::new()
+    };
+    #[cfg(not(feature = "onion-service-client"))]
+    let migrated = Vec::new();
+
+    events::emit(Event::InterfaceChanged {
+        old: old.map(str::to_string),
+        new: new.map(str::to_string),
+        dropped,
+        circuits_closed,
+        migrated,
+    });
+}
@@ -0,0 +1,302 @@
+//! In-app bridge acquisition over the moat API
+//!
+//! Censored users can't reach bridges.torproject.org in a browser, so the
+//! app fetches bridges itself: `arti_request_bridges` asks moat for a
+//! CAPTCHA, which is handed to the UI as a `bridge_challenge` event, and
+//! `arti_solve_bridge_challenge` submits the user's answer. Bridges received
+//! are stored in the `bridges` option for the next start.
+//!
+//! Requests go directly over HTTPS, not over Tor. With `moat.front` set, the
+//! TLS connection is made to that domain and the moat host is named only in
+//! the encrypted Host header, so a censor watching SNI sees the front.
+//!
+//! Only `vanilla` (direct) bridges are requested since this build has no
+//! pluggable transport support.
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::config::{self, Config};
+use crate::events::{self, Event};
+use crate::probe;
+
+/// Time allowed for each moat request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest response accepted; CAPTCHA images are tens of kilobytes
+const MAX_RESPONSE: usize = 1024 * 1024;
+
+const MOAT_VERSION: &str = "0.1.0";
+const TRANSPORT: &str = "vanilla";
+
+/// moat's error code for a wrong CAPTCHA solution
+const CAPTCHA_INCORRECT: u16 = 419;
+
+/// Set while a fetch or check is in flight
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Challenge string of the CAPTCHA the user is currently solving
+static PENDING_CHALLENGE: Mutex<Option<String>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub(crate) enum RequestError {
+    /// Another request is in flight
+    Busy,
+    /// No CAPTCHA has been fetched, or it was already answered
+    NoChallenge,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    #[serde(default)]
+    data: Vec<serde_json::Value>,
+    #[serde(default)]
+    errors: Vec<MoatError>,
+}
+
+#[derive(Deserialize)]
+struct MoatError {
+    #[serde(default)]
+    code: u16,
+    #[serde(default)]
+    detail: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    challenge: String,
+    /// Base64-encoded JPEG
+    image: String,
+}
+
+#[derive(Deserialize)]
+struct Bridges {
+    #[serde(default)]
+    bridges: Vec<String>,
+}
+
+/// Failure reported in a `bridge_fetch_failed` event
+struct Failure {
+    /// `network`, `server`, `captcha_incorrect` or `no_bridges`
+    kind: &'static str,
+    message: String,
+}
+
+impl Failure {
+    fn new(kind: &'static str, message: impl Into<String>) -> Self {
+        Failure {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    fn emit(self, stage: &'static str) {
+        tracing::warn!("moat {} failed: {}", stage, self.message);
+        events::emit(Event::BridgeFetchFailed {
+            stage,
+            kind: self.kind,
+            error: self.message,
+        });
+    }
+}
+
+/// Start fetching a CAPTCHA in the background
+pub(crate) fn request_bridges() -> Result<(), RequestError> {
+    let config = config::current();
+    start(move || match fetch_challenge(&config) {
+        Ok(challenge) => {
+            if let Ok(mut pending) = PENDING_CHALLENGE.lock() {
+                *pending = Some(challenge.challenge);
+            }
+            events::emit(Event::BridgeChallenge {
+                image: challenge.image,
+                mime_type: "image/jpeg",
+            });
+        }
+        Err(failure) => failure.emit("fetch"),
+    })
+}
+
+/// Submit the user's answer to the pending CAPTCHA in the background
+pub(crate) fn solve_challenge(solution: &str) -> Result<(), RequestError> {
+    let challenge = PENDING_CHALLENGE
+        .lock()
+        .ok()
+        .and_then(|p| p.clone())
+        .ok_or(RequestError::NoChallenge)?;
+    let config = config::current();
+    let solution = solution.to_string();
+    start(move || {
+        // A challenge can only be answered once, right or wrong
+        if let Ok(mut pending) = PENDING_CHALLENGE.lock() {
+            pending.take();
+        }
+        match check_solution(&config, &challenge, &solution).and_then(store) {
+            Ok(count) => events::emit(Event::BridgesReceived { count }),
+            Err(failure) => failure.emit("check"),
+        }
+    })
+}
+
+fn start(work: impl FnOnce() + Send + 'static) -> Result<(), RequestError> {
+    if BUSY.swap(true, Ordering::SeqCst) {
+        return Err(RequestError::Busy);
+    }
+    std::thread::spawn(move || {
+        work();
+        BUSY.store(false, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+fn fetch_challenge(config: &Config) -> Result<Challenge, Failure> {
+    let body = json!({
+        "data": [{
+            "version": MOAT_VERSION,
+            "type": "client-transports",
+            "supported": [TRANSPORT],
+        }]
+    });
+    call(config, "fetch", &body)
+}
+
+fn check_solution(
+    config: &Config,
+    challenge: &str,
+    solution: &str,
+) -> Result<Vec<String>, Failure> {
+    let body = json!({
+        "data": [{
+            "id": "2",
+            "version": MOAT_VERSION,
+            "type": "moat-solution",
+            "transport": TRANSPORT,
+            "challenge": challenge,
+            "solution": solution,
+            "qrcode": "false",
+        }]
+    });
+    call::<Bridges>(config, "check", &body).map(|b| b.bridges)
+}
+
+/// Keep the bridge lines this build can use and save them for the next start
+fn store(lines: Vec<String>) -> Result<usize, Failure> {
+    let usable: Vec<String> = lines
+        .into_iter()
+        .filter(|line| crate::bridges::parse_line(line).is_ok())
+        .collect();
+    if usable.is_empty() {
+        return Err(Failure::new(
+            "no_bridges",
+            "moat returned no usable bridges",
+        ));
+    }
+    config::set_option("bridges", &usable.join("\n"))
+        .map_err(|e| Failure::new("no_bridges", format!("could not store bridges: {:?}", e)))?;
+    Ok(usable.len())
+}
+
+/// POST a moat request and decode the first data item of the reply
+fn call<T: for<'de> Deserialize<'de>>(
+    config: &Config,
+    endpoint: &str,
+    body: &serde_json::Value,
+) -> Result<T, Failure> {
+    let url = format!("{}/{}", config.moat_url.trim_end_matches('/'), endpoint);
+    let (status, reply) = post(&url, config.moat_front.as_deref(), &body.to_string())?;
+    let response: Response = serde_json::from_slice(&reply).map_err(|e| {
+        Failure::new(
+            "server",
+            format!("HTTP {}: unreadable reply: {}", status, e),
+        )
+    })?;
+    if let Some(error) = response.errors.into_iter().next() {
+        let kind = if error.code == CAPTCHA_INCORRECT {
+            "captcha_incorrect"
+        } else {
+            "server"
+        };
+        return Err(Failure::new(
+            kind,
+            format!("{} ({})", error.detail, error.code),
+        ));
+    }
+    let item = response
+        .data
+        .into_iter()
+        .next()
+        .ok_or_else(|| Failure::new("server", "empty reply"))?;
+    serde_json::from_value(item)
+        .map_err(|e| Failure::new("server", format!("unexpected reply: {}", e)))
+}
+
+/// Blocking HTTPS POST, returning the status code and body.
+///
+/// With `front`, TCP and TLS go to the front domain while the Host header
+/// names the real server.
+fn post(url: &str, front: Option<&str>, body: &str) -> Result<(u16, Vec<u8>), Failure> {
+    let network = |msg: String| Failure::new("network", msg);
+    let (host, port, path) =
+        probe::parse_https_url(url).ok_or_else(|| network(format!("bad moat URL {}", url)))?;
+    let connect_host = front.unwrap_or(&host).to_string();
+
+    let addr = (connect_host.as_str(), port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut a| a.next())
+        .ok_or_else(|| network(format!("cannot resolve {}", connect_host)))?;
+    let mut tcp = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT)
+        .map_err(|e| network(format!("cannot connect to {}: {}", addr, e)))?;
+    let _ = tcp.set_read_timeout(Some(REQUEST_TIMEOUT));
+    let _ = tcp.set_write_timeout(Some(REQUEST_TIMEOUT));
+
+    let server_name = ServerName::try_from(connect_host.clone())
+        .map_err(|_| network(format!("invalid host {}", connect_host)))?;
+    let mut tls = probe::tls_config()
+        .and_then(|c| rustls::ClientConnection::new(c, server_name).map_err(|e| e.to_string()))
+        .map_err(network)?;
+    let mut stream = rustls::Stream::new(&mut tls, &mut tcp);
+
+    // HTTP/1.0 so the reply is never chunked
+    let request = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: bitchat\r\n\
+         Content-Type: application/vnd.api+json\r\nContent-Length: {}\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    );
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|e| network(format!("request failed: {}", e)))?;
+
+    let mut reply = Vec::new();
+    let read = stream.take(MAX_RESPONSE as u64 + 1).read_to_end(&mut reply);
+    // Servers often close without close_notify; keep whatever arrived
+    if reply.is_empty() {
+        if let Err(e) = read {
+            return Err(network(format!("no reply: {}", e)));
+        }
+    }
+    if reply.len() > MAX_RESPONSE {
+        return Err(Failure::new("server", "reply too large"));
+    }
+
+    let split = reply
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| Failure::new("server", "malformed HTTP reply"))?;
+    let status = String::from_utf8_lossy(&reply[..split])
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| Failure::new("server", "malformed HTTP status line"))?;
+    Ok((status, reply[split + 4..].to_vec()))
+}
@@ -0,0 +1,61 @@
+//! Watches Arti's bootstrap status for conditions the app should explain
+//!
+//! Currently this is clock skew: an old or badly set device clock makes
+//! every consensus look expired or not yet valid, and bootstrap stalls with
+//! no outward sign of why. The skew is kept for the status snapshot and
+//! announced with an event when it appears or clears.
+
+use std::sync::Mutex;
+
+use arti_client::status::BlockageKind;
+use arti_client::TorClient;
+use futures::StreamExt;
+
+use crate::clock::TorRuntime;
+use crate::events::{self, Event};
+
+/// Description of the detected clock skew, e.g. "Clock is skewed. (Clock is 2 hours slow)"
+static CLOCK_SKEW: Mutex<Option<String>> = Mutex::new(None);
+
+/// Follow bootstrap status changes until the client goes away
+pub(crate) async fn watch_bootstrap(client: &TorClient<TorRuntime>) {
+    let mut statuses = client.bootstrap_events();
+    while let Some(status) = statuses.next().await {
+        let skew = status
+            .blocked()
+            .filter(|b| matches!(b.kind(), BlockageKind::ClockSkewed))
+            .map(|b| b.to_string());
+        update_skew(skew);
+    }
+}
+
+fn update_skew(skew: Option<String>) {
+    let Ok(mut current) = CLOCK_SKEW.lock() else {
+        return;
+    };
+    if *current == skew {
+        return;
+    }
+    match &skew {
+        Some(message) => {
+            tracing::warn!("Bootstrap blocked by clock skew: {}", message);
+            events::emit(Event::ClockSkewDetected {
+                message: message.clone(),
+            });
+        }
+        None => events::emit(Event::ClockSkewCleared),
+    }
+    *current = skew;
+}
+
+/// Detected clock skew, if the device clock is currently blocking bootstrap
+pub(crate) fn clock_skew() -> Option<String> {
+    CLOCK_SKEW.lock().ok().and_then(|s| s.clone())
+}
+
+/// Forget any detected skew (e.g. on shutdown)
+pub(crate) fn clear() {
+    if let Ok(mut current) = CLOCK_SKEW.lock() {
+        *current = None;
+    }
+}
@@ -0,0 +1,67 @@
+//! Keys for reaching onion services in restricted discovery mode
+//!
+//! A service in restricted discovery mode only lets clients holding an
+//! authorized key find it. The app generates one key per service and gives
+//! the public part (`descriptor:x25519:...`) to the service's operator. Keys
+//! live in Arti's keystore under the state directory, so they are lost on
+//! stop in ephemeral storage mode.
+
+use arti_client::{HsId, KeystoreSelector, TorClient};
+
+use crate::clock::TorRuntime;
+
+#[derive(Debug)]
+pub(crate) enum KeyError {
+    /// Not a v3 `.onion` address
+    BadAddress,
+    /// No key is held for this service
+    NotFound,
+    /// A key is already held and was not to be replaced
+    Exists,
+    Keystore(String),
+}
+
+fn parse_address(onion: &str) -> Result<HsId, KeyError> {
+    onion.trim().parse().map_err(|_| KeyError::BadAddress)
+}
+
+/// Generate a key for `onion`, replacing any existing one if `replace` is set.
+///
+/// Returns the public key to give to the service operator.
+pub(crate) fn generate(
+    client: &TorClient<TorRuntime>,
+    onion: &str,
+    replace: bool,
+) -> Result<String, KeyError> {
+    let hsid = parse_address(onion)?;
+    if !replace && get(client, onion).is_ok() {
+        return Err(KeyError::Exists);
+    }
+    let key = if replace {
+        client.rotate_service_discovery_key(KeystoreSelector::Primary, hsid)
+    } else {
+        client.generate_service_discovery_key(KeystoreSelector::Primary, hsid)
+    };
+    key.map(|k| k.to_string())
+        .map_err(|e| KeyError::Keystore(e.to_string()))
+}
+
+/// Public key held for `onion`
+pub(crate) fn get(client: &TorClient<TorRuntime>, onion: &str) -> Result<String, KeyError> {
+    let hsid = parse_address(onion)?;
+    client
+        .get_service_discovery_key(hsid)
+        .map_err(|e| KeyError::Keystore(e.to_string()))?
+        .map(|k| k.to_string())
+        .ok_or(KeyError::NotFound)
+}
+
+/// Delete the key held for `onion`
+pub(crate) fn remove(client: &TorClient<TorRuntime>, onion: &str) -> Result<(), KeyError> {
+    let hsid = parse_address(onion)?;
+    match client.remove_service_discovery_key(KeystoreSelector::Primary, hsid) {
+        Ok(Some(())) => Ok(()),
+        Ok(None) => Err(KeyError::NotFound),
+        Err(e) => Err(KeyError::Keystore(e.to_string())),
+    }
+}
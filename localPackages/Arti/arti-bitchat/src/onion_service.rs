@@ -0,0 +1,804 @@
+//! Onion services hosted by the app
+//!
+//! Each service is launched on the running client under a nickname and
+//! forwards streams for its virtual port to a port on 127.0.0.1, where the
+//! app listens. The identity key is kept in Arti's keystore under the
+//! nickname, so launching the same nickname again after a restart gives the
+//! same address (except in ephemeral storage mode). Services stop with the
+//! client. Changes in a service's state are reported as events, and its
+//! clients are held to the limits in [`service_limits`](crate::service_limits).
+//!
+//! Arti publishes the descriptor itself: again on each new consensus, and
+//! whenever introduction points die and are replaced, at most once a
+//! minute, besides its scheduled republish every one to two hours. It has no
+//! way to be told to publish sooner, so what is reported here is how fresh
+//! the descriptor is: `pending` until it is first published, `fresh` while
+//! Arti believes the service reachable, and `stale` from when it stops
+//! doing so until a new one is up, e.g. while introduction points are
+//! rebuilt after `arti_interface_changed`.
+//!
+//! A service can be given an [`Expiry`], for one-shot handoffs between
+//! contacts: it stops by itself after a time or once it has forwarded a
+//! number of streams, and however it stops, every key stored under its
+//! nickname is deleted so the address is never served again. If the process
+//! dies first the keys stay, and `arti_wipe_all_keys` is the way to clear
+//! them.
+//!
+//! [`self_test`] answers whether a service can be reached by reaching it as
+//! a contact would, on a client isolated from everything else.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use arti_client::config::onion_service::OnionServiceConfigBuilder;
+use arti_client::{ErrorKind, HasKind, TorClient};
+use futures::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use safelog::DisplayRedacted;
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tokio_util::sync::CancellationToken;
+use tor_cell::relaycell::msg::{Connected, End, EndReason};
+use tor_hscrypto::pk::HsIdKeypair;
+use tor_hsservice::status::Problem;
+use tor_hsservice::{
+    HsIdKeypairSpecifier, HsNickname, RendRequest, RunningOnionService, StreamRequest,
+};
+use tor_keymgr::KeyPathPattern;
+use tor_llcrypto::pk::ed25519::ExpandedKeypair;
+use tor_proto::client::stream::IncomingStreamRequest;
+use zeroize::Zeroizing;
+
+use crate::clock::TorRuntime;
+use crate::events::{self, Event};
+use crate::service_limits::{Client, Count, Counts, Guard};
+use crate::shutdown::ShutdownController;
+use crate::{metrics, socks};
+
+/// Length of an exported identity key: the expanded ed25519 secret key
+const KEY_BYTES: usize = 64;
+
+struct Service {
+    /// Tells this launch from a later one under the same nickname
+    id: u64,
+    running: Arc<RunningOnionService>,
+    port: u16,
+    target_port: u16,
+    /// Stops the accept loop and the streams it forwarded
+    stop: CancellationToken,
+    guard: Arc<Guard>,
+    descriptor: Arc<Mutex<Freshness>>,
+    expiring: Option<Expiring>,
+    self_test: Arc<Mutex<Option<SelfTest>>>,
+}
+
+/// How a [`self_test`] went
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct SelfTest {
+    reachable: bool,
+    /// From starting the lookup until the service answered
+    latency_ms: Option<u64>,
+    error: Option<String>,
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// When a service stops by itself; both None for one that runs until stopped
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Expiry {
+    pub(crate) ttl: Option<Duration>,
+    /// Streams forwarded before it stops
+    pub(crate) connections: Option<u64>,
+}
+
+impl Expiry {
+    fn is_set(&self) -> bool {
+        self.ttl.is_some() || self.connections.is_some()
+    }
+}
+
+/// Why an expiring service stopped
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Expired {
+    Ttl,
+    Connections,
+    /// By `arti_onion_service_stop` or `arti_stop`
+    Stopped,
+}
+
+struct Expiring {
+    /// For deleting the keys once the service stops
+    client: TorClient<TorRuntime>,
+    expires: Option<Instant>,
+    countdown: Option<Arc<Countdown>>,
+}
+
+/// The streams an expiring service has left
+struct Countdown {
+    nickname: String,
+    id: u64,
+    limit: u64,
+    started: AtomicU64,
+    finished: AtomicU64,
+}
+
+impl Countdown {
+    /// Take a stream, or None once all have been taken
+    fn take(self: &Arc<Self>) -> Option<Ticket> {
+        self.started
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < self.limit).then_some(n + 1)
+            })
+            .ok()?;
+        Some(Ticket {
+            countdown: self.clone(),
+            forwarded: false,
+        })
+    }
+
+    /// A stream forwarded is done; expires the service after the last
+    fn finish(&self) {
+        if self.finished.fetch_add(1, Ordering::Relaxed) + 1 == self.limit {
+            expire(&self.nickname, self.id, Expired::Connections);
+        }
+    }
+
+    fn left(&self) -> u64 {
+        self.limit - self.started.load(Ordering::Relaxed)
+    }
+}
+
+/// A stream taken from a [`Countdown`]. Counts once dropped if it was
+/// forwarded to the local port, and is handed back otherwise, so streams
+/// the app never saw do not use up the service.
+struct Ticket {
+    countdown: Arc<Countdown>,
+    forwarded: bool,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if self.forwarded {
+            self.countdown.finish();
+        } else {
+            self.countdown.started.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// How current the published descriptor is, see the module docs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Descriptor {
+    Pending,
+    Fresh,
+    Stale,
+}
+
+struct Freshness {
+    descriptor: Descriptor,
+    since: Instant,
+}
+
+impl Freshness {
+    fn new() -> Self {
+        Freshness {
+            descriptor: Descriptor::Pending,
+            since: Instant::now(),
+        }
+    }
+
+    /// Move to the freshness Arti's `state` implies, returning it
+    fn update(&mut self, state: tor_hsservice::status::State) -> Descriptor {
+        let descriptor = match (state.is_fully_reachable(), self.descriptor) {
+            (true, _) => Descriptor::Fresh,
+            (false, Descriptor::Pending) => Descriptor::Pending,
+            (false, _) => Descriptor::Stale,
+        };
+        if descriptor != self.descriptor {
+            self.descriptor = descriptor;
+            self.since = Instant::now();
+        }
+        descriptor
+    }
+}
+
+static SERVICES: Lazy<Mutex<HashMap<String, Service>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug)]
+pub(crate) enum ServiceError {
+    /// Not a valid nickname or key
+    Invalid,
+    /// A service with this nickname is already running
+    Exists,
+    NotFound,
+    /// Arti refused the service, or the keystore failed
+    Launch(String),
+}
+
+#[derive(Serialize)]
+pub(crate) struct ServiceInfo {
+    nickname: String,
+    address: Option<String>,
+    port: u16,
+    target_port: u16,
+    /// Arti's view of the service, e.g. `Bootstrapping` or `Running`
+    state: String,
+    descriptor: Descriptor,
+    /// Time since `descriptor` last changed
+    descriptor_ms: u64,
+    /// Rendezvous circuits open
+    clients: usize,
+    counts: Counts,
+    /// Time left of an expiring service's TTL
+    expires_ms: Option<u64>,
+    /// Streams an expiring service has left
+    connections_left: Option<u64>,
+    /// The latest [`self_test`], if any
+    self_test: Option<SelfTest>,
+}
+
+/// Launch a service forwarding `port` to `127.0.0.1:target_port`.
+///
+/// `key`, if given, is a hex identity key from [`export_key`]; it is stored
+/// under `nickname`, which must not already have one. A service with an
+/// `expiry` set gets a new key, so `nickname` must not have one either.
+/// Returns the address.
+pub(crate) fn create(
+    client: &TorClient<TorRuntime>,
+    shutdown: &Arc<ShutdownController>,
+    nickname: &str,
+    port: u16,
+    target_port: u16,
+    key: Option<&str>,
+    expiry: Expiry,
+) -> Result<String, ServiceError> {
+    let nick = HsNickname::new(nickname.to_string()).map_err(|_| ServiceError::Invalid)?;
+    let keypair = key.map(parse_key).transpose()?;
+    if expiry.connections == Some(0) {
+        return Err(ServiceError::Invalid);
+    }
+    let mut services = SERVICES
+        .lock()
+        .map_err(|_| ServiceError::Launch("service lock poisoned".into()))?;
+    if services.contains_key(nickname) {
+        return Err(ServiceError::Exists);
+    }
+    // Its keys are deleted when it stops, which must not take a kept address
+    if expiry.is_set() && identity(client, nickname).is_ok() {
+        return Err(ServiceError::Launch("nickname already has a key".into()));
+    }
+
+    let config = OnionServiceConfigBuilder::default()
+        .nickname(nick)
+        .build()
+        .map_err(|e| ServiceError::Launch(e.to_string()))?;
+    let launched = match keypair {
+        Some(keypair) => client
+            .launch_onion_service_with_hsid(config, keypair)
+            .map(|l| l.map(|(running, requests)| (running, requests.boxed()))),
+        None => client
+            .launch_onion_service(config)
+            .map(|l| l.map(|(running, requests)| (running, requests.boxed()))),
+    }
+    .map_err(|e| ServiceError::Launch(e.to_string()))?;
+    let Some((running, rend_requests)) = launched else {
+        return Err(ServiceError::Launch("service is disabled".into()));
+    };
+
+    let address = running
+        .onion_address()
+        .map(|id| id.display_unredacted().to_string())
+        .ok_or_else(|| ServiceError::Launch("no identity key".into()))?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let stop = shutdown.token().child_token();
+    let config = crate::config::current();
+    let guard = Guard::new(nickname, &config);
+    let countdown = expiry.connections.map(|limit| {
+        Arc::new(Countdown {
+            nickname: nickname.to_string(),
+            id,
+            limit,
+            started: AtomicU64::new(0),
+            finished: AtomicU64::new(0),
+        })
+    });
+    let listener = Listener {
+        port,
+        target_port,
+        window: config.coalesce_window,
+        shutdown: shutdown.clone(),
+        countdown: countdown.clone(),
+    };
+    if let Some(ttl) = expiry.ttl {
+        let (nickname, stop) = (nickname.to_string(), stop.clone());
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep(ttl) => expire(&nickname, id, Expired::Ttl),
+                _ = stop.cancelled() => {}
+            }
+        });
+    }
+    let expiring = expiry.is_set().then(|| Expiring {
+        client: client.clone(),
+        expires: expiry.ttl.map(|ttl| Instant::now() + ttl),
+        countdown,
+    });
+    tokio::spawn(serve(rend_requests, listener, guard.clone(), stop.clone()));
+    let descriptor = Arc::new(Mutex::new(Freshness::new()));
+    tokio::spawn(report_status(
+        nickname.to_string(),
+        running.clone(),
+        descriptor.clone(),
+        stop.clone(),
+    ));
+    services.insert(
+        nickname.to_string(),
+        Service {
+            id,
+            running,
+            port,
+            target_port,
+            stop,
+            guard,
+            descriptor,
+            expiring,
+            self_test: Arc::new(Mutex::new(None)),
+        },
+    );
+    Ok(address)
+}
+
+/// Emit an event each time the state of the service `nickname` or its
+/// problem changes, until it is stopped
+async fn report_status(
+    nickname: String,
+    running: Arc<RunningOnionService>,
+    freshness: Arc<Mutex<Freshness>>,
+    stop: CancellationToken,
+) {
+    let mut statuses = running.status_events();
+    let mut last = None;
+    loop {
+        let status = tokio::select! {
+            status = statuses.next() => match status {
+                Some(status) => status,
+                None => return,
+            },
+            _ = stop.cancelled() => return,
+        };
+        let descriptor = match freshness.lock() {
+            Ok(mut freshness) => freshness.update(status.state()),
+            Err(_) => return,
+        };
+        let state = format!("{:?}", status.state());
+        let problem = status.current_problem().map(problem_kind);
+        if last.as_ref() == Some(&(state.clone(), problem, descriptor)) {
+            continue;
+        }
+        tracing::debug!(
+            "Onion service {} is {}, descriptor {:?}",
+            nickname,
+            state,
+            descriptor
+        );
+        last = Some((state.clone(), problem, descriptor));
+        events::emit(Event::OnionServiceState {
+            nickname: nickname.clone(),
+            state,
+            problem,
+            descriptor,
+        });
+    }
+}
+
+fn problem_kind(problem: &Problem) -> &'static str {
+    match problem {
+        Problem::Runtime(_) => "fatal",
+        Problem::DescriptorUpload(_) => "descriptor_upload",
+        Problem::Ipt(_) => "introduction_points",
+        _ => "other",
+    }
+}
+
+/// Stop the service launched as `nickname`
+pub(crate) fn stop(nickname: &str) -> Result<(), ServiceError> {
+    let service = SERVICES
+        .lock()
+        .ok()
+        .and_then(|mut s| s.remove(nickname))
+        .ok_or(ServiceError::NotFound)?;
+    shut_down(nickname, service, Expired::Stopped);
+    Ok(())
+}
+
+/// Stop every service (e.g. on shutdown)
+pub(crate) fn clear() {
+    let services: Vec<_> = match SERVICES.lock() {
+        Ok(mut services) => services.drain().collect(),
+        Err(_) => return,
+    };
+    for (nickname, service) in services {
+        shut_down(&nickname, service, Expired::Stopped);
+    }
+}
+
+/// Stop the expiring service `nickname` if it is still launch `id`
+fn expire(nickname: &str, id: u64, reason: Expired) {
+    let service = SERVICES
+        .lock()
+        .ok()
+        .and_then(|mut s| match s.get(nickname) {
+            Some(service) if service.id == id => s.remove(nickname),
+            _ => None,
+        });
+    if let Some(service) = service {
+        shut_down(nickname, service, reason);
+    }
+}
+
+/// Stop `service`, deleting its keys if it was expiring
+fn shut_down(nickname: &str, service: Service, reason: Expired) {
+    service.stop.cancel();
+    let Some(expiring) = service.expiring else {
+        return;
+    };
+    let key_wiped = match wipe_keys(&expiring.client, nickname) {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!(
+                "Failed to delete the keys of onion service {}: {:?}",
+                nickname,
+                e
+            );
+            false
+        }
+    };
+    tracing::info!("Onion service {} expired ({:?})", nickname, reason);
+    events::emit(Event::OnionServiceExpired {
+        nickname: nickname.to_string(),
+        reason,
+        streams: service.guard.streams(),
+        key_wiped,
+    });
+}
+
+/// Delete every key stored under `nickname`
+fn wipe_keys(client: &TorClient<TorRuntime>, nickname: &str) -> Result<(), ServiceError> {
+    let keymgr = client
+        .keymgr()
+        .map_err(|e| ServiceError::Launch(e.to_string()))?;
+    let pattern = KeyPathPattern::Arti(format!("hss/{}/**", nickname));
+    let entries = keymgr
+        .list_matching(&pattern)
+        .map_err(|e| ServiceError::Launch(e.to_string()))?;
+    for entry in entries {
+        keymgr
+            .remove_entry(&entry)
+            .map_err(|e| ServiceError::Launch(e.to_string()))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn list() -> Vec<ServiceInfo> {
+    let Ok(services) = SERVICES.lock() else {
+        return Vec::new();
+    };
+    let mut list: Vec<ServiceInfo> = services
+        .iter()
+        .map(|(nickname, s)| {
+            let (descriptor, since) = s
+                .descriptor
+                .lock()
+                .map(|f| (f.descriptor, f.since.elapsed()))
+                .unwrap_or((Descriptor::Stale, Duration::ZERO));
+            ServiceInfo {
+                nickname: nickname.clone(),
+                address: s
+                    .running
+                    .onion_address()
+                    .map(|id| id.display_unredacted().to_string()),
+                port: s.port,
+                target_port: s.target_port,
+                state: format!("{:?}", s.running.status().state()),
+                descriptor,
+                descriptor_ms: since.as_millis() as u64,
+                clients: s.guard.clients(),
+                counts: s.guard.counts(),
+                expires_ms: s
+                    .expiring
+                    .as_ref()
+                    .and_then(|e| e.expires)
+                    .map(|at| at.saturating_duration_since(Instant::now()).as_millis() as u64),
+                connections_left: s
+                    .expiring
+                    .as_ref()
+                    .and_then(|e| e.countdown.as_ref())
+                    .map(|c| c.left()),
+                self_test: s.self_test.lock().ok().and_then(|t| t.clone()),
+            }
+        })
+        .collect();
+    list.sort_by(|a, b| a.nickname.cmp(&b.nickname));
+    list
+}
+
+/// Reach the service `nickname` from a new isolated client within `limit`.
+///
+/// The stream is opened to a port the service does not serve, so it is
+/// turned away by the service itself: getting that answer proves the
+/// lookup, introduction and rendezvous all worked, without the app seeing a
+/// connection. It is counted as a refused stream.
+pub(crate) async fn self_test(
+    client: &TorClient<TorRuntime>,
+    nickname: &str,
+    limit: Duration,
+) -> Result<SelfTest, ServiceError> {
+    let (address, port, last) = {
+        let services = SERVICES
+            .lock()
+            .map_err(|_| ServiceError::Launch("service lock poisoned".into()))?;
+        let service = services.get(nickname).ok_or(ServiceError::NotFound)?;
+        let address = service
+            .running
+            .onion_address()
+            .map(|id| id.display_unredacted().to_string())
+            .ok_or_else(|| ServiceError::Launch("no identity key".into()))?;
+        (address, service.port, service.self_test.clone())
+    };
+    let probe_port = port.checked_add(1).unwrap_or(1);
+    let isolated = client.isolated_client();
+    let started = Instant::now();
+    let reached = match tokio::time::timeout(limit, isolated.connect((address, probe_port))).await {
+        Ok(Ok(_stream)) => Ok(()),
+        Ok(Err(e)) if e.kind() == ErrorKind::RemoteStreamClosed => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    };
+    let test = match reached {
+        Ok(()) => SelfTest {
+            reachable: true,
+            latency_ms: Some(started.elapsed().as_millis() as u64),
+            error: None,
+        },
+        Err(e) => SelfTest {
+            reachable: false,
+            latency_ms: None,
+            error: Some(e),
+        },
+    };
+    tracing::debug!("Onion service {} self-test: {:?}", nickname, test);
+    if let Ok(mut last) = last.lock() {
+        *last = Some(test.clone());
+    }
+    Ok(test)
+}
+
+/// Identity key stored for `nickname`
+pub(crate) fn identity(
+    client: &TorClient<TorRuntime>,
+    nickname: &str,
+) -> Result<HsIdKeypair, ServiceError> {
+    let nick = HsNickname::new(nickname.to_string()).map_err(|_| ServiceError::Invalid)?;
+    client
+        .keymgr()
+        .and_then(|k| Ok(k.get::<HsIdKeypair>(&HsIdKeypairSpecifier::new(nick))?))
+        .map_err(|e| ServiceError::Launch(e.to_string()))?
+        .ok_or(ServiceError::NotFound)
+}
+
+/// Identity key of `nickname` as hex, for moving the address to another install
+pub(crate) fn export_key(
+    client: &TorClient<TorRuntime>,
+    nickname: &str,
+) -> Result<Zeroizing<String>, ServiceError> {
+    let keypair = identity(client, nickname)?;
+    let expanded: &ExpandedKeypair = keypair.as_ref();
+    let bytes = Zeroizing::new(expanded.to_secret_key_bytes());
+    // Sized up front, so growing it leaves no copies behind
+    let mut hex = Zeroizing::new(String::with_capacity(KEY_BYTES * 2));
+    for b in bytes.iter() {
+        let _ = write!(hex, "{:02x}", b);
+    }
+    Ok(hex)
+}
+
+fn parse_key(hex: &str) -> Result<HsIdKeypair, ServiceError> {
+    let hex = hex.trim();
+    if hex.len() != KEY_BYTES * 2 || !hex.is_ascii() {
+        return Err(ServiceError::Invalid);
+    }
+    let mut bytes = Zeroizing::new([0u8; KEY_BYTES]);
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte =
+            u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| ServiceError::Invalid)?;
+    }
+    ExpandedKeypair::from_secret_key_bytes(*bytes)
+        .map(HsIdKeypair::from)
+        .ok_or(ServiceError::Invalid)
+}
+
+/// What a service's streams are forwarded with
+#[derive(Clone)]
+struct Listener {
+    port: u16,
+    target_port: u16,
+    window: Duration,
+    shutdown: Arc<ShutdownController>,
+    /// Set for a service that expires after so many streams
+    countdown: Option<Arc<Countdown>>,
+}
+
+/// Accept clients until the service is stopped
+async fn serve<S>(rend_requests: S, listener: Listener, guard: Arc<Guard>, stop: CancellationToken)
+where
+    S: Stream<Item = RendRequest> + Send + 'static,
+{
+    let mut rend_requests = Box::pin(rend_requests);
+    loop {
+        let request = tokio::select! {
+            request = rend_requests.next() => request,
+            _ = stop.cancelled() => None,
+        };
+        let Some(request) = request else {
+            break;
+        };
+        guard.note(Count::Introduction);
+        let client = guard.admit(&stop);
+        let listener = listener.clone();
+        listener.shutdown.clone().spawn(async move {
+            match request.accept().await {
+                Ok(requests) => {
+                    client.note(Count::Rendezvous);
+                    serve_client(requests, listener, client).await
+                }
+                Err(e) => {
+                    client.note(Count::RendezvousFailed);
+                    tracing::debug!("Onion service rendezvous failed: {}", e)
+                }
+            }
+        });
+    }
+}
+
+/// Accept the streams one client opens for `port` until it is done or cut off
+async fn serve_client<S>(mut requests: S, listener: Listener, client: Arc<Client>)
+where
+    S: Stream<Item = StreamRequest> + Unpin,
+{
+    loop {
+        let request = tokio::select! {
+            request = requests.next() => request,
+            _ = client.cancel.cancelled() => None,
+        };
+        let Some(request) = request else {
+            break;
+        };
+        let wanted = matches!(request.request(), IncomingStreamRequest::Begin(begin) if begin.port() == listener.port);
+        if !wanted {
+            client.note(Count::StreamRefused);
+            let _ = request.reject(End::new_with_reason(EndReason::DONE)).await;
+            continue;
+        }
+        if !client.note_stream() {
+            let _ = request.shutdown_circuit();
+            break;
+        }
+        let ticket = match &listener.countdown {
+            Some(countdown) => match countdown.take() {
+                Some(ticket) => Some(ticket),
+                None => {
+                    client.note(Count::StreamRefused);
+                    let _ = request.reject(End::new_with_reason(EndReason::DONE)).await;
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let (target_port, window, client) = (listener.target_port, listener.window, client.clone());
+        listener.shutdown.spawn(async move {
+            if let Err(e) = forward(request, target_port, window, &client, ticket).await {
+                tracing::debug!("Onion service stream error: {}", e);
+            }
+        });
+    }
+    // The circuit closes once its streams are gone
+}
+
+/// Relay one onion service stream to the app's local port, counting it
+/// against `ticket` once it is
+async fn forward(
+    request: StreamRequest,
+    target_port: u16,
+    window: Duration,
+    client: &Client,
+    mut ticket: Option<Ticket>,
+) -> io::Result<()> {
+    let local = match TcpStream::connect((Ipv4Addr::LOCALHOST, target_port)).await {
+        Ok(local) => local,
+        Err(e) => {
+            client.note(Count::StreamRefused);
+            let _ = request
+                .reject(End::new_with_reason(EndReason::CONNECTREFUSED))
+                .await;
+            return Err(e);
+        }
+    };
+    client.note(Count::Stream);
+    let onion = request
+        .accept(Connected::new_empty())
+        .await
+        .map_err(io::Error::other)?;
+    if let Some(ticket) = &mut ticket {
+        ticket.forwarded = true;
+    }
+
+    let (mut local_read, mut local_write) = local.into_split();
+    let (mut onion_read, mut onion_write) = onion.split();
+    let (mut sent, mut received) = (0, 0);
+    let count_sent = |n| {
+        metrics::add_sent(n);
+        client.note_sent(n);
+    };
+    let count_received = |n| {
+        metrics::add_received(n);
+        client.note_received(n);
+    };
+    let cancel = &client.cancel;
+    tokio::select! {
+        result = socks::copy_counted(&mut local_read, &mut onion_write, count_sent, &mut sent, window, Duration::ZERO, cancel) => result,
+        result = socks::copy_counted(&mut onion_read, &mut local_write, count_received, &mut received, Duration::ZERO, Duration::ZERO, cancel) => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn countdown(limit: u64) -> Arc<Countdown> {
+        Arc::new(Countdown {
+            // Not a running service, so finishing cannot expire anything
+            nickname: "countdown-test".into(),
+            id: 0,
+            limit,
+            started: AtomicU64::new(0),
+            finished: AtomicU64::new(0),
+        })
+    }
+
+    #[test]
+    fn rejected_streams_are_handed_back() {
+        let countdown = countdown(1);
+        // The local port refused it, so the app never saw it
+        drop(countdown.take().unwrap());
+        assert_eq!(countdown.left(), 1);
+        assert_eq!(countdown.finished.load(Ordering::Relaxed), 0);
+
+        let mut ticket = countdown.take().unwrap();
+        assert!(countdown.take().is_none());
+        ticket.forwarded = true;
+        drop(ticket);
+        assert_eq!(countdown.left(), 0);
+        assert_eq!(countdown.finished.load(Ordering::Relaxed), 1);
+        assert!(countdown.take().is_none());
+    }
+
+    #[test]
+    fn streams_in_flight_hold_their_place() {
+        let countdown = countdown(2);
+        let first = countdown.take().unwrap();
+        let second = countdown.take().unwrap();
+        assert!(countdown.take().is_none());
+        drop(first);
+        // A refused stream's place is free again while the other is open
+        assert_eq!(countdown.left(), 1);
+        assert!(countdown.take().is_some());
+        drop(second);
+    }
+}
@@ -0,0 +1,123 @@
+//! Connection padding for foreground and dormant use
+//!
+//! Arti sends padding on each channel so that traffic timing is harder to
+//! analyse, at the cost of waking the radio more often. The host app picks a
+//! level for the foreground (`padding.foreground`) and one for while it is
+//! dormant (`padding.dormant`). Open channels take up a new level when the
+//! client is reconfigured, so the configuration the client was created with
+//! is kept here to rebuild from, along with later changes such as network
+//! tuning.
+
+use std::sync::Mutex;
+
+use arti_client::config::TorClientConfigBuilder;
+use arti_client::TorClient;
+use tor_config::{PaddingLevel, Reconfigure};
+
+use crate::clock::TorRuntime;
+use crate::config::Config;
+
+/// Configuration of the running client, with the levels to switch between
+struct Session {
+    builder: TorClientConfigBuilder,
+    foreground: PaddingLevel,
+    dormant: PaddingLevel,
+    current: PaddingLevel,
+}
+
+static SESSION: Mutex<Option<Session>> = Mutex::new(None);
+
+/// Parse an option value: "normal", "reduced" or "off"
+pub(crate) fn parse(s: &str) -> Option<PaddingLevel> {
+    match s {
+        "normal" => Some(PaddingLevel::Normal),
+        "reduced" => Some(PaddingLevel::Reduced),
+        "off" => Some(PaddingLevel::None),
+        _ => None,
+    }
+}
+
+pub(crate) fn as_str(level: PaddingLevel) -> &'static str {
+    match level {
+        PaddingLevel::Normal => "normal",
+        PaddingLevel::Reduced => "reduced",
+        PaddingLevel::None => "off",
+    }
+}
+
+/// Keep the configuration a client is created from, which already carries
+/// the foreground level
+pub(crate) fn remember(builder: &TorClientConfigBuilder, config: &Config) {
+    if let Ok(mut session) = SESSION.lock() {
+        *session = Some(Session {
+            builder: builder.clone(),
+            foreground: config.padding_foreground,
+            dormant: config.padding_dormant,
+            current: config.padding_foreground,
+        });
+    }
+}
+
+/// Forget the stopped client's configuration
+pub(crate) fn clear() {
+    if let Ok(mut session) = SESSION.lock() {
+        *session = None;
+    }
+}
+
+/// Switch `client` to the dormant or foreground level
+pub(crate) fn set_dormant(client: &TorClient<TorRuntime>, dormant: bool) -> Result<(), String> {
+    let mut guard = SESSION
+        .lock()
+        .map_err(|_| "padding lock poisoned".to_string())?;
+    let Some(session) = guard.as_mut() else {
+        return Ok(());
+    };
+    let level = if dormant {
+        session.dormant
+    } else {
+        session.foreground
+    };
+    if level == session.current {
+        return Ok(());
+    }
+    let mut builder = session.builder.clone();
+    builder.channel().padding(level);
+    let config = builder.build().map_err(|e| e.to_string())?;
+    client
+        .reconfigure(&config, Reconfigure::WarnOnFailures)
+        .map_err(|e| e.to_string())?;
+    session.current = level;
+    Ok(())
+}
+
+/// Change the kept configuration with `change` and apply it to `client`, so
+/// later padding switches keep the change
+pub(crate) fn update(
+    client: &TorClient<TorRuntime>,
+    change: impl FnOnce(&mut TorClientConfigBuilder),
+) -> Result<(), String> {
+    let mut guard = SESSION
+        .lock()
+        .map_err(|_| "padding lock poisoned".to_string())?;
+    let Some(session) = guard.as_mut() else {
+        return Ok(());
+    };
+    let mut builder = session.builder.clone();
+    change(&mut builder);
+    builder.channel().padding(session.current);
+    let config = builder.build().map_err(|e| e.to_string())?;
+    client
+        .reconfigure(&config, Reconfigure::WarnOnFailures)
+        .map_err(|e| e.to_string())?;
+    session.builder = builder;
+    Ok(())
+}
+
+/// Level the running client pads with, if one is running
+pub(crate) fn current() -> Option<&'static str> {
+    SESSION
+        .lock()
+        .ok()
+        .and_then(|s| s.as_ref().map(|s| as_str(s.current)))
+}
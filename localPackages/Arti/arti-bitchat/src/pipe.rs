@@ -0,0 +1,129 @@
+//! SOCKS connections over in-memory pipes fed by the host
+//!
+//! A host that holds the client connection itself, such as an `NWConnection`
+//! handed over by another process, can have it served without a local
+//! socket: `arti_pipe_open` connects a pipe to the SOCKS handler, the host
+//! writes what its client sends with `arti_pipe_write`, and what the handler
+//! sends back is passed to a callback. Pipes are served like connections the
+//! listeners accepted from 0.0.0.0:0, so they share pausing and quotas, and
+//! are listed and reported with client port 0. They come from the host
+//! itself, so failed handshakes on them are not rate limited.
+
+use std::collections::HashMap;
+use std::ffi::{c_int, c_void};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::runtime::Runtime;
+
+use crate::listener;
+
+/// Callback receiving data the SOCKS handler sent on a pipe.
+///
+/// `data` is only valid for the duration of the call. A `len` of 0 means
+/// the handler is done and the pipe is closed.
+pub type ArtiPipeCallback =
+    extern "C" fn(pipe: c_int, data: *const u8, len: c_int, context: *mut c_void);
+
+/// Bytes buffered in each direction before writers wait
+const PIPE_BUFFER: usize = 64 * 1024;
+
+/// Largest chunk handed to the callback at once
+const CHUNK: usize = 16 * 1024;
+
+/// The source pipes are served as
+const PEER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+static NEXT_PIPE: AtomicI32 = AtomicI32::new(1);
+
+/// The host's end of a pipe, for writing
+type HostWriter = Arc<tokio::sync::Mutex<WriteHalf<DuplexStream>>>;
+
+static PIPES: Lazy<Mutex<HashMap<c_int, HostWriter>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug)]
+pub(crate) enum PipeError {
+    /// The SOCKS server is not serving: not started, or not bootstrapped yet
+    NotServing,
+    /// No such pipe, or it has closed
+    NotFound,
+}
+
+/// Open a pipe to the SOCKS handler whose output goes to `callback`;
+/// returns its id.
+///
+/// Must be called within the runtime context.
+pub(crate) fn open(callback: ArtiPipeCallback, context: *mut c_void) -> Result<c_int, PipeError> {
+    let (host, handler) = tokio::io::duplex(PIPE_BUFFER);
+    let (reader, writer) = tokio::io::split(host);
+    let id = NEXT_PIPE.fetch_add(1, Ordering::Relaxed);
+    if let Ok(mut pipes) = PIPES.lock() {
+        pipes.insert(id, Arc::new(tokio::sync::Mutex::new(writer)));
+    }
+    if !listener::serve_stream(handler, PEER) {
+        forget(id);
+        return Err(PipeError::NotServing);
+    }
+    tokio::spawn(deliver(id, reader, callback, context as usize));
+    Ok(id)
+}
+
+/// Pass `data` to the handler of pipe `id`, waiting for room in its buffer.
+///
+/// Drives a host-driven runtime meanwhile, so must not be called on it.
+pub(crate) fn write(runtime: &Runtime, id: c_int, data: &[u8]) -> Result<(), PipeError> {
+    let writer = writer(id)?;
+    runtime
+        .block_on(async move {
+            let mut writer = writer.lock().await;
+            writer.write_all(data).await?;
+            writer.flush().await
+        })
+        .map_err(|_| PipeError::NotFound)
+}
+
+/// Tell the handler of pipe `id` that the host's client is done sending.
+///
+/// The pipe stays open until the handler is done too.
+pub(crate) fn close(runtime: &Runtime, id: c_int) -> Result<(), PipeError> {
+    let writer = writer(id)?;
+    runtime
+        .block_on(async move { writer.lock().await.shutdown().await })
+        .map_err(|_| PipeError::NotFound)
+}
+
+fn writer(id: c_int) -> Result<HostWriter, PipeError> {
+    PIPES
+        .lock()
+        .ok()
+        .and_then(|p| p.get(&id).cloned())
+        .ok_or(PipeError::NotFound)
+}
+
+fn forget(id: c_int) {
+    if let Ok(mut pipes) = PIPES.lock() {
+        pipes.remove(&id);
+    }
+}
+
+/// Hand what the handler sends to `callback` until it is done
+async fn deliver(
+    id: c_int,
+    mut reader: ReadHalf<DuplexStream>,
+    callback: ArtiPipeCallback,
+    context: usize,
+) {
+    let mut buf = vec![0u8; CHUNK];
+    loop {
+        let n = reader.read(&mut buf).await.unwrap_or(0);
+        if n == 0 {
+            break;
+        }
+        callback(id, buf.as_ptr(), n as c_int, context as *mut c_void);
+    }
+    forget(id);
+    callback(id, std::ptr::null(), 0, context as *mut c_void);
+}
@@ -0,0 +1,464 @@
+//! Destination policy applied before a SOCKS request reaches Arti
+//!
+//! Legacy Tor special hostnames are recognised here rather than passed
+//! through as ordinary names: `.noconnect` is always refused, and `.exit`
+//! follows the `socks.exit_hostnames` option. Retired v2 onion addresses
+//! are refused up front instead of failing after a lookup timeout. The host
+//! left after stripping a `.exit` name is checked again, so stripping never
+//! lets through a name that would be refused if requested directly.
+//!
+//! A refused request is answered with the rule's SOCKS error by default.
+//! `socks.reject_replies` can instead blackhole it, sending nothing and
+//! closing after `socks.blackhole_ms`, so a client probing the policy cannot
+//! tell a refusal from a destination that does not answer; or redirect it,
+//! connecting to another destination as if it had been requested. A
+//! redirect's target is not checked against the policy again.
+
+use crate::config::Config;
+
+/// Length of the base32 label of a v2 onion address (v3 uses 56)
+const V2_ONION_LABEL_LEN: usize = 16;
+
+/// Tor extended SOCKS5 reply: onion address invalid (proposal 304)
+const SOCKS5_REP_ONION_INVALID: u8 = 0xF6;
+/// SOCKS5 reply: connection not allowed by ruleset
+const SOCKS5_REP_NOT_ALLOWED: u8 = 0x02;
+
+/// What to do with `host.relay.exit` names
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ExitHostnames {
+    /// Refuse the request
+    Reject,
+    /// Connect to `host` through an exit Arti chooses. Arti cannot pin a
+    /// particular exit, so the requested relay is ignored.
+    Strip,
+}
+
+impl ExitHostnames {
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        match s {
+            "reject" => Some(ExitHostnames::Reject),
+            "strip" => Some(ExitHostnames::Strip),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ExitHostnames::Reject => "reject",
+            ExitHostnames::Strip => "strip",
+        }
+    }
+}
+
+/// Outcome of checking a destination
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Decision {
+    /// Connect to this host, which may differ from the one requested
+    Allow(String),
+    /// Refuse the request
+    Reject(Rejection),
+}
+
+/// Why a destination was refused
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Rejection {
+    NoconnectHostname,
+    ExitHostname,
+    /// v2 onion services no longer exist on the Tor network
+    V2Onion,
+}
+
+impl Rejection {
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        match s {
+            "noconnect_hostname" => Some(Rejection::NoconnectHostname),
+            "exit_hostname" => Some(Rejection::ExitHostname),
+            "v2_onion" => Some(Rejection::V2Onion),
+            _ => None,
+        }
+    }
+
+    /// Rule name used in events and the audit log
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::NoconnectHostname => "noconnect_hostname",
+            Rejection::ExitHostname => "exit_hostname",
+            Rejection::V2Onion => "v2_onion",
+        }
+    }
+
+    /// SOCKS5 reply code to send the client
+    pub(crate) fn socks_reply(&self) -> u8 {
+        match self {
+            Rejection::V2Onion => SOCKS5_REP_ONION_INVALID,
+            Rejection::NoconnectHostname | Rejection::ExitHostname => SOCKS5_REP_NOT_ALLOWED,
+        }
+    }
+}
+
+/// How a refused request is answered
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Reply {
+    /// Send the rule's SOCKS error at once
+    Refuse,
+    /// Send nothing, and close after `socks.blackhole_ms`
+    Blackhole,
+    /// Connect to this host and port instead
+    Redirect(String, u16),
+}
+
+impl Reply {
+    /// Parse `refuse`, `blackhole` or `redirect:host:port`
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        match s {
+            "refuse" => Some(Reply::Refuse),
+            "blackhole" => Some(Reply::Blackhole),
+            _ => {
+                let (host, port) = s.strip_prefix("redirect:")?.rsplit_once(':')?;
+                let port = port.parse().ok().filter(|p| *p != 0)?;
+                (!host.is_empty()).then(|| Reply::Redirect(host.to_string(), port))
+            }
+        }
+    }
+
+    pub(crate) fn render(&self) -> String {
+        match self {
+            Reply::Refuse => "refuse".to_string(),
+            Reply::Blackhole => "blackhole".to_string(),
+            Reply::Redirect(host, port) => format!("redirect:{}:{}", host, port),
+        }
+    }
+}
+
+/// `socks.reject_replies` entry: how requests refused under `rule` are answered
+#[derive(Clone, Debug)]
+pub(crate) struct ReplyRule {
+    pub(crate) rule: Rejection,
+    pub(crate) reply: Reply,
+}
+
+/// How to answer a request refused under `rejection`
+pub(crate) fn reply_for(rejection: Rejection, config: &Config) -> Reply {
+    config
+        .reject_replies
+        .iter()
+        .find(|r| r.rule == rejection)
+        .map(|r| r.reply.clone())
+        .unwrap_or(Reply::Refuse)
+}
+
+/// Check a requested hostname against the policy
+pub(crate) fn evaluate(host: &str, config: &Config) -> Decision {
+    let name = host.trim_end_matches('.').to_ascii_lowercase();
+
+    if name == "noconnect" || name.ends_with(".noconnect") {
+        return Decision::Reject(Rejection::NoconnectHostname);
+    }
+
+    if let Some(rest) = name.strip_suffix(".exit") {
+        // `host.relay.exit`; a bare `relay.exit` names no destination
+        return match (config.exit_hostnames, rest.rsplit_once('.')) {
+            (ExitHostnames::Strip, Some((target, _relay))) if !target.is_empty() => {
+                evaluate(target, config)
+            }
+            _ => Decision::Reject(Rejection::ExitHostname),
+        };
+    }
+    if name == "exit" {
+        return Decision::Reject(Rejection::ExitHostname);
+    }
+
+    if is_v2_onion(&name) {
+        return Decision::Reject(Rejection::V2Onion);
+    }
+
+    Decision::Allow(host.to_string())
+}
+
+/// Whether `name` (lowercase) is a v2 onion address, possibly with subdomains
+fn is_v2_onion(name: &str) -> bool {
+    let Some(rest) = name.strip_suffix(".onion") else {
+        return false;
+    };
+    let label = rest.rsplit('.').next().unwrap_or(rest);
+    label.len() == V2_ONION_LABEL_LEN
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || (b'2'..=b'7').contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(exit_hostnames: ExitHostnames) -> Config {
+        Config {
+            exit_hostnames,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn noconnect_is_always_refused() {
+        for exits in [ExitHostnames::Reject, ExitHostnames::Strip] {
+            for host in [
+                "noconnect",
+                "a.noconnect",
+                "A.B.NoConnect.",
+                "x.exit.noconnect",
+            ] {
+                assert_eq!(
+                    evaluate(host, &config(exits)),
+                    Decision::Reject(Rejection::NoconnectHostname),
+                    "{}",
+                    host
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn exit_hostnames() {
+        let reject = config(ExitHostnames::Reject);
+        let strip = config(ExitHostnames::Strip);
+        for host in [
+            "example.com.relay.exit",
+            "exit",
+            "relay.exit",
+            ".relay.exit",
+        ] {
+            assert_eq!(
+                evaluate(host, &reject),
+                Decision::Reject(Rejection::ExitHostname),
+                "{}",
+                host
+            );
+        }
+        assert_eq!(
+            evaluate("Example.COM.relay.exit", &strip),
+            Decision::Allow("example.com".into())
+        );
+        // What is left is checked like any other request
+        assert_eq!(
+            evaluate("a.noconnect.relay.exit", &strip),
+            Decision::Reject(Rejection::NoconnectHostname)
+        );
+        assert_eq!(
+            evaluate("expyuzz4wqqyqhjn.onion.relay.exit", &strip),
+            Decision::Reject(Rejection::V2Onion)
+        );
+        assert_eq!(
+            evaluate("example.com.a.exit.b.exit", &strip),
+            Decision::Allow("example.com".into())
+        );
+        // Nothing but a relay to strip down to
+        for host in ["exit", "relay.exit", ".relay.exit"] {
+            assert_eq!(
+                evaluate(host, &strip),
+                Decision::Reject(Rejection::ExitHostname),
+                "{}",
+                host
+            );
+        }
+    }
+
+    #[test]
+    fn v2_onions_are_refused() {
+        let config = Config::default();
+        for host in [
+            "expyuzz4wqqyqhjn.onion",
+            "www.expyuzz4wqqyqhjn.onion",
+            "EXPYUZZ4WQQYQHJN.onion.",
+        ] {
+            assert_eq!(
+                evaluate(host, &config),
+                Decision::Reject(Rejection::V2Onion),
+                "{}",
+                host
+            );
+        }
+        let v3 = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+        assert_eq!(evaluate(v3, &config), Decision::Allow(v3.into()));
+        // Not base32: 0, 1, 8 and 9 never appear in an onion label
+        let not_base32 = "expyuzz4wqqyqh10.onion";
+        assert_eq!(
+            evaluate(not_base32, &config),
+            Decision::Allow(not_base32.into())
+        );
+    }
+
+    #[test]
+    fn other_hosts_pass_unchanged() {
+        for host in [
+            "Example.com",
+            "192.0.2.1",
+            "[2001:db8::1]",
+            "exit.example",
+            "onion",
+        ] {
+            assert_eq!(
+                evaluate(host, &Config::default()),
+                Decision::Allow(host.into())
+            );
+        }
+    }
+
+    #[test]
+    fn replies_round_trip() {
+        for s in [
+            "refuse",
+            "blackhole",
+            "redirect:example.com:443",
+            "redirect:[::1]:80",
+        ] {
+            let reply = Reply::parse(s).unwrap();
+            assert_eq!(reply.render(), s);
+        }
+        assert_eq!(
+            Reply::parse("redirect:[::1]:80"),
+            Some(Reply::Redirect("[::1]".into(), 80))
+        );
+        for s in [
+            "",
+            "Refuse",
+            "redirect:",
+            "redirect:host",
+            "redirect::80",
+            "redirect:host:0",
+            "redirect:host:65536",
+        ] {
+            assert_eq!(Reply::parse(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn rejections_round_trip() {
+        for rule in [
+            Rejection::NoconnectHostname,
+            Rejection::ExitHostname,
+            Rejection::V2Onion,
+        ] {
+            assert_eq!(Rejection::parse(rule.as_str()), Some(rule));
+        }
+    }
+
+    #[test]
+    fn replies_follow_their_rule() {
+        let config = Config {
+            reject_replies: vec![ReplyRule {
+                rule: Rejection::ExitHostname,
+                reply: Reply::Blackhole,
+            }],
+            ..Config::default()
+        };
+        assert_eq!(
+            reply_for(Rejection::ExitHostname, &config),
+            Reply::Blackhole
+        );
+        assert_eq!(reply_for(Rejection::V2Onion, &config), Reply::Refuse);
+        assert_eq!(
+            reply_for(Rejection::NoconnectHostname, &Config::default()),
+            Reply::Refuse
+        );
+    }
+
+    /// A name a deny rule covers is refused however the allow settings are
+    /// set and however it is dressed up, and nothing allowed is such a name
+    #[test]
+    fn deny_beats_allow() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        const LABELS: [&str; 6] = ["www", "example", "com", "onion", "exit", "relay"];
+        const DENIED: [(&str, Rejection); 3] = [
+            ("noconnect", Rejection::NoconnectHostname),
+            ("expyuzz4wqqyqhjn.onion", Rejection::V2Onion),
+            ("duskgytldkxiuqc6.onion", Rejection::V2Onion),
+        ];
+        const REPLIES: [Reply; 2] = [Reply::Refuse, Reply::Blackhole];
+        const RULES: [Rejection; 3] = [
+            Rejection::NoconnectHostname,
+            Rejection::ExitHostname,
+            Rejection::V2Onion,
+        ];
+        // Seeded so that a failure can be reproduced
+        let mut rng = StdRng::seed_from_u64(190);
+        for case in 0..2000 {
+            let config = Config {
+                exit_hostnames: match rng.random_bool(0.5) {
+                    true => ExitHostnames::Strip,
+                    false => ExitHostnames::Reject,
+                },
+                reject_replies: (0..rng.random_range(0..4))
+                    .map(|_| ReplyRule {
+                        rule: RULES[rng.random_range(0..RULES.len())],
+                        reply: match rng.random_bool(0.3) {
+                            true => Reply::Redirect("example.com".into(), 443),
+                            false => REPLIES[rng.random_range(0..REPLIES.len())].clone(),
+                        },
+                    })
+                    .collect(),
+                ..Config::default()
+            };
+            let mut labels: Vec<String> = (0..rng.random_range(0..3))
+                .map(|_| LABELS[rng.random_range(0..LABELS.len())].to_string())
+                .collect();
+            let denied = rng
+                .random_bool(0.5)
+                .then(|| DENIED[rng.random_range(0..DENIED.len())]);
+            if let Some((name, _)) = denied {
+                labels.push(name.into());
+            }
+            // Wrapped in `.exit` names, which `strip` allows
+            let exits = rng.random_range(0..3);
+            for _ in 0..exits {
+                labels.push(LABELS[rng.random_range(0..LABELS.len())].into());
+                labels.push("exit".into());
+            }
+            let mut host: String = labels
+                .join(".")
+                .chars()
+                .map(|c| match rng.random_bool(0.3) {
+                    true => c.to_ascii_uppercase(),
+                    false => c,
+                })
+                .collect();
+            if rng.random_bool(0.2) {
+                host.push('.');
+            }
+
+            let decision = evaluate(&host, &config);
+            match (denied, &decision) {
+                (Some((_, rule)), Decision::Reject(rejection)) => {
+                    if exits == 0 || config.exit_hostnames == ExitHostnames::Strip {
+                        assert_eq!(*rejection, rule, "case {}: {}", case, host);
+                    }
+                }
+                (Some(_), Decision::Allow(_)) => panic!("case {}: {} allowed", case, host),
+                (None, Decision::Allow(allowed)) => {
+                    // What is allowed would be allowed if requested directly,
+                    // even with every allow setting off
+                    let strict = Config {
+                        exit_hostnames: ExitHostnames::Reject,
+                        ..Config::default()
+                    };
+                    assert_eq!(
+                        evaluate(allowed, &strict),
+                        Decision::Allow(allowed.clone()),
+                        "case {}: {}",
+                        case,
+                        host
+                    );
+                }
+                (None, Decision::Reject(_)) => {}
+            }
+            // Replies only change how a refusal is answered
+            let plain = Config {
+                reject_replies: Vec::new(),
+                ..config.clone()
+            };
+            assert_eq!(evaluate(&host, &plain), decision);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Bounded background prefetch
+//!
+//! Meant for an iOS `BGAppRefreshTask`: in the few seconds the system grants,
+//! bring the directory up to date and look up the onion services of pinned
+//! peers, so the next foreground connect doesn't have to. Works through the
+//! running client if there is one, otherwise through a short-lived client
+//! sharing the same state and cache.
+//!
+//! Onion services are only looked up when built with the
+//! `onion-service-client` feature; otherwise they are reported as remaining.
+//!
+//! Arti keeps onion service descriptors in memory only, so what a prefetch or
+//! [`warm`] fetches lasts until the client stops; after a relaunch, a
+//! prefetch is what gets pinned peers looked up before the first connect.
+
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arti_client::TorClient;
+use futures::StreamExt;
+use serde::Serialize;
+use tokio::time::{timeout_at, Instant};
+
+use crate::clock::TorRuntime;
+use crate::config::Config;
+
+/// Set while a prefetch owns Arti's state, so `arti_start` waits its turn
+pub(crate) static PREFETCHING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Default)]
+pub(crate) struct PrefetchReport {
+    /// Everything was fetched within the budget
+    complete: bool,
+    /// Directory progress, 0-100
+    directory_percent: u8,
+    /// Enough directory information to build circuits
+    directory_ready: bool,
+    onions_fetched: usize,
+    /// Entries of `prefetch.onions` not fetched yet
+    onions_remaining: usize,
+    /// Whether this build can look up onion services at all
+    onion_support: bool,
+    elapsed_ms: u64,
+    error: Option<String>,
+}
+
+/// Where the prefetch gets its client from
+pub(crate) enum Session {
+    /// Arti is running; use its client once it has one
+    Running,
+    /// Arti is stopped; create a client just for the prefetch
+    Stopped(std::path::PathBuf),
+}
+
+/// Prefetch for at most `budget`
+pub(crate) async fn run(session: Session, config: Config, budget: Duration) -> PrefetchReport {
+    let started = Instant::now();
+    let deadline = started + budget;
+    let mut report = PrefetchReport {
+        onions_remaining: config.prefetch_onions.len(),
+        onion_support: cfg!(feature = "onion-service-client"),
+        ..Default::default()
+    };
+
+    let client = match session {
+        Session::Running => running_client(deadline).await,
+        Session::Stopped(_) if config.ephemeral => {
+            Err("ephemeral storage keeps nothing for a prefetch to update".into())
+        }
+        Session::Stopped(data_dir) => temporary_client(&data_dir, &config).await,
+    };
+    match client {
+        Ok(client) => fetch(&client, &config, deadline, &mut report).await,
+        Err(e) => report.error = Some(e),
+    }
+
+    report.elapsed_ms = started.elapsed().as_millis() as u64;
+    report.complete =
+        report.error.is_none() && report.directory_ready && report.onions_remaining == 0;
+    report
+}
+
+async fn running_client(deadline: Instant) -> Result<Arc<TorClient<TorRuntime>>, String> {
+    // The client appears once the running instance has finished configuring
+    loop {
+        let client = crate::ARTI_STATE
+            .get()
+            .and_then(|s| s.lock().ok())
+            .and_then(|g| g.client.clone());
+        if let Some(client) = client {
+            return Ok(client);
+        }
+        if Instant::now() >= deadline {
+            return Err("Arti did not finish starting within the budget".into());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+async fn temporary_client(
+    data_dir: &std::path::Path,
+    config: &Config,
+) -> Result<Arc<TorClient<TorRuntime>>, String> {
+    let tor_config = crate::tor_client_config(data_dir, config)
+        .and_then(|b| Ok(b.build()?))
+        .map_err(|e| e.to_string())?;
+    let runtime = crate::clock::runtime().map_err(|e| e.to_string())?;
+    let client = TorClient::with_runtime(runtime)
+        .config(tor_config)
+        .create_unbootstrapped_async()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(Arc::new(client))
+}
+
+async fn fetch(
+    client: &TorClient<TorRuntime>,
+    config: &Config,
+    deadline: Instant,
+    report: &mut PrefetchReport,
+) {
+    if let Err(e) = refresh_directory(client, deadline).await {
+        report.error = Some(e);
+    }
+    let status = client.bootstrap_status();
+    report.directory_percent = (status.as_frac() * 100.0) as u8;
+    report.directory_ready = status.ready_for_traffic();
+    if !report.directory_ready {
+        return;
+    }
+
+    #[cfg(feature = "onion-service-client")]
+    for (host, port) in &config.prefetch_onions {
+        // Connecting fetches and caches the descriptor and introduction
+        // circuits; the stream itself is not needed
+        match timeout_at(deadline, client.connect((host.as_str(), *port))).await {
+            Ok(Ok(_stream)) => {
+                report.onions_fetched += 1;
+                report.onions_remaining -= 1;
+            }
+            Ok(Err(e)) => tracing::debug!("Prefetch of {} failed: {}", host, e),
+            Err(_) => break,
+        }
+    }
+    #[cfg(not(feature = "onion-service-client"))]
+    let _ = config;
+}
+
+#[cfg(feature = "onion-service-client")]
+#[derive(Debug)]
+pub(crate) enum WarmError {
+    /// Not a v3 `.onion` address
+    BadAddress,
+    /// The service could not be reached
+    Connect(String),
+    Timeout,
+}
+
+/// Look up `onion` on the running client within `limit`, so a connect to it
+/// soon after finds its descriptor and introduction circuits ready.
+///
+/// Succeeds once the service accepts a stream on `port`; the stream is closed
+/// again straight away.
+#[cfg(feature = "onion-service-client")]
+pub(crate) async fn warm(
+    client: &TorClient<TorRuntime>,
+    onion: &str,
+    port: u16,
+    limit: Duration,
+) -> Result<(), WarmError> {
+    let onion = onion.trim();
+    onion
+        .parse::<arti_client::HsId>()
+        .map_err(|_| WarmError::BadAddress)?;
+    match tokio::time::timeout(limit, client.connect((onion, port))).await {
+        Ok(Ok(_stream)) => Ok(()),
+        Ok(Err(e)) => Err(WarmError::Connect(e.to_string())),
+        Err(_) => Err(WarmError::Timeout),
+    }
+}
+
+/// Bootstrap if needed, then wait for the directory to be fresh enough to use
+async fn refresh_directory(
+    client: &TorClient<TorRuntime>,
+    deadline: Instant,
+) -> Result<(), String> {
+    match timeout_at(deadline, client.bootstrap()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(_) => return Err("budget exhausted while bootstrapping".into()),
+    }
+
+    // After a long suspension the consensus may have expired; Arti refetches
+    // it on its own, so just watch for it to become usable again
+    let mut events = client.bootstrap_events();
+    let waited = timeout_at(deadline, async {
+        while !client.bootstrap_status().ready_for_traffic() {
+            if events.next().await.is_none() {
+                break;
+            }
+        }
+    })
+    .await;
+    waited.map_err(|_| "budget exhausted while refreshing the directory".into())
+}
@@ -0,0 +1,159 @@
+//! Captive-portal probe run before bootstrap
+//!
+//! Fetches a configured HTTPS URL directly, not over Tor. Hotel and airport
+//! Wi-Fi typically intercepts this with a redirect or an untrusted
+//! certificate; when that happens we report that the user needs to sign in
+//! and hold off bootstrapping, which would otherwise spend minutes retrying
+//! directory fetches that can never succeed.
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+
+/// Bytes of response read to find the status line and headers
+const MAX_RESPONSE_HEAD: usize = 4096;
+
+static CAPTIVE_PORTAL: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub(crate) enum ProbeResult {
+    /// The endpoint answered over a trusted TLS connection
+    Online,
+    /// Something between us and the endpoint intercepted the request
+    CaptivePortal(String),
+    /// The endpoint could not be reached at all
+    Offline(String),
+}
+
+/// Fetch `url` and classify the result. Blocks; run with `spawn_blocking`.
+pub(crate) fn probe(url: &str, timeout: Duration) -> ProbeResult {
+    let Some((host, port, path)) = parse_https_url(url) else {
+        return ProbeResult::Offline(format!("not an https URL: {}", url));
+    };
+
+    let addr = match (host.as_str(), port)
+        .to_socket_addrs()
+        .map(|mut a| a.next())
+    {
+        Ok(Some(addr)) => addr,
+        Ok(None) | Err(_) => return ProbeResult::Offline(format!("cannot resolve {}", host)),
+    };
+    let mut tcp = match TcpStream::connect_timeout(&addr, timeout) {
+        Ok(tcp) => tcp,
+        Err(e) => return ProbeResult::Offline(format!("cannot connect to {}: {}", addr, e)),
+    };
+    let _ = tcp.set_read_timeout(Some(timeout));
+    let _ = tcp.set_write_timeout(Some(timeout));
+
+    let Ok(server_name) = ServerName::try_from(host.clone()) else {
+        return ProbeResult::Offline(format!("invalid host {}", host));
+    };
+    let mut tls = match tls_config()
+        .and_then(|c| rustls::ClientConnection::new(c, server_name).map_err(|e| e.to_string()))
+    {
+        Ok(tls) => tls,
+        Err(e) => return ProbeResult::Offline(e),
+    };
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nUser-Agent: bitchat\r\n\r\n",
+        path, host
+    );
+    let mut stream = rustls::Stream::new(&mut tls, &mut tcp);
+    if let Err(e) = stream.write_all(request.as_bytes()) {
+        return classify_tls_error(e);
+    }
+    let mut head = vec![0u8; MAX_RESPONSE_HEAD];
+    let mut len = 0;
+    while len < head.len() && !head[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut head[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if len == 0 => return classify_tls_error(e),
+            Err(_) => break,
+        }
+    }
+    classify_response(&head[..len])
+}
+
+pub(crate) fn tls_config() -> Result<Arc<rustls::ClientConfig>, String> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|e| e.to_string())?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// A failed handshake means something answered on the endpoint's behalf
+fn classify_tls_error(e: std::io::Error) -> ProbeResult {
+    let certificate = e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+        .is_some_and(|tls| matches!(tls, rustls::Error::InvalidCertificate(_)));
+    if certificate {
+        ProbeResult::CaptivePortal(format!("untrusted certificate: {}", e))
+    } else if matches!(
+        e.kind(),
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+    ) {
+        ProbeResult::Offline(format!("timed out: {}", e))
+    } else {
+        ProbeResult::CaptivePortal(format!("TLS failed: {}", e))
+    }
+}
+
+fn classify_response(head: &[u8]) -> ProbeResult {
+    let text = String::from_utf8_lossy(head);
+    let mut lines = text.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok());
+    match status {
+        Some(code) if (300..400).contains(&code) => {
+            let location = lines
+                .find_map(|l| {
+                    l.split_once(':')
+                        .filter(|(k, _)| k.eq_ignore_ascii_case("location"))
+                })
+                .map(|(_, v)| v.trim().to_string())
+                .unwrap_or_default();
+            ProbeResult::CaptivePortal(format!("redirected ({}) to {}", code, location))
+        }
+        Some(_) => ProbeResult::Online,
+        None => ProbeResult::CaptivePortal("malformed HTTP response".into()),
+    }
+}
+
+/// Split `https://host[:port][/path]`
+pub(crate) fn parse_https_url(url: &str) -> Option<(String, u16, String)> {
+    let rest = url.strip_prefix("https://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((h, p)) => (h, p.parse().ok()?),
+        None => (authority, 443),
+    };
+    (!host.is_empty()).then(|| (host.to_string(), port, path.to_string()))
+}
+
+/// Whether the last probe found a captive portal
+pub(crate) fn captive_portal() -> bool {
+    CAPTIVE_PORTAL.load(Ordering::SeqCst)
+}
+
+pub(crate) fn set_captive_portal(detected: bool) {
+    CAPTIVE_PORTAL.store(detected, Ordering::SeqCst);
+}
@@ -0,0 +1,103 @@
+//! Separate Tor state for each bitchat identity
+//!
+//! A profile is a data directory under `<root>/profiles/<name>` with its own
+//! guards, state, cache, keys and statistics, so nothing Tor keeps links one
+//! identity to another. Arti runs on one profile at a time; switching
+//! restarts it on the other profile's directory with a new client, so no
+//! circuit or stream is shared either. Deleting a profile, as panic mode does
+//! along with the identity, removes everything Tor kept for it.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+const PROFILES_DIR: &str = "profiles";
+
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug)]
+pub(crate) enum ProfileError {
+    /// Not a valid profile name
+    Invalid,
+    Exists,
+    NotFound,
+    Io(io::Error),
+}
+
+#[derive(Serialize)]
+pub(crate) struct ProfileInfo {
+    name: String,
+    /// Arti is running on this profile
+    active: bool,
+}
+
+/// Data directory of profile `name` under `root`, whether or not it exists.
+///
+/// Names are 1 to 64 ASCII letters, digits, `-` and `_`.
+pub(crate) fn path(root: &Path, name: &str) -> Result<PathBuf, ProfileError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if !valid {
+        return Err(ProfileError::Invalid);
+    }
+    Ok(root.join(PROFILES_DIR).join(name))
+}
+
+/// Data directory of an existing profile
+pub(crate) fn existing(root: &Path, name: &str) -> Result<PathBuf, ProfileError> {
+    let dir = path(root, name)?;
+    if !dir.is_dir() {
+        return Err(ProfileError::NotFound);
+    }
+    Ok(dir)
+}
+
+pub(crate) fn create(root: &Path, name: &str) -> Result<PathBuf, ProfileError> {
+    let dir = path(root, name)?;
+    if dir.exists() {
+        return Err(ProfileError::Exists);
+    }
+    fs::create_dir_all(&dir).map_err(ProfileError::Io)?;
+    Ok(dir)
+}
+
+/// Delete a profile and everything in it; Arti must not be running on it
+pub(crate) fn delete(root: &Path, name: &str) -> Result<(), ProfileError> {
+    let dir = existing(root, name)?;
+    fs::remove_dir_all(&dir).map_err(ProfileError::Io)
+}
+
+/// Profiles under `root` by name, marking the one at `active`
+pub(crate) fn list(root: &Path, active: Option<&Path>) -> Vec<ProfileInfo> {
+    let mut profiles: Vec<ProfileInfo> = dirs(root)
+        .into_iter()
+        .filter_map(|dir| {
+            Some(ProfileInfo {
+                name: dir.file_name()?.to_str()?.to_string(),
+                active: active == Some(dir.as_path()),
+            })
+        })
+        .collect();
+    profiles.sort_by(|a, b| a.name.cmp(&b.name));
+    profiles
+}
+
+/// Data directories of the profiles under `root`
+pub(crate) fn dirs(root: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(root.join(PROFILES_DIR)) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter(|e| e.path().is_dir())
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            path(root, &name).ok()
+        })
+        .collect()
+}
@@ -0,0 +1,341 @@
+//! Bandwidth accounting against daily and monthly quotas
+//!
+//! Bytes carried by SOCKS streams, in both directions, are added to UTC daily
+//! and monthly totals kept in `traffic.json` in the data directory, so they
+//! survive restarts. Once a total reaches its quota (`quota.daily_bytes`,
+//! `quota.monthly_bytes`), new SOCKS connections are refused until the period
+//! rolls over; streams already open are left running.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::events::{self, Event};
+use crate::storage;
+
+const FILE_NAME: &str = "traffic.json";
+
+/// How often totals are written out while running
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+const SECS_PER_DAY: u64 = 86_400;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Period {
+    Daily,
+    Monthly,
+}
+
+/// Totals as saved on disk
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Totals {
+    /// UTC day number `day_bytes` belongs to
+    day: u64,
+    day_bytes: u64,
+    /// Months since year 0 `month_bytes` belongs to
+    month: u64,
+    month_bytes: u64,
+}
+
+#[derive(Default)]
+struct Ledger {
+    /// Where totals are saved; unset while stopped
+    path: Option<PathBuf>,
+    totals: Totals,
+    /// Unsaved additions since the last write
+    dirty: bool,
+    daily_limit: u64,
+    monthly_limit: u64,
+    exceeded: Option<Period>,
+}
+
+static LEDGER: Lazy<Mutex<Ledger>> = Lazy::new(|| Mutex::new(Ledger::default()));
+
+/// Usage report for the status snapshot
+#[derive(Default, Serialize)]
+pub(crate) struct Usage {
+    today_bytes: u64,
+    month_bytes: u64,
+    /// Null when there is no daily quota
+    daily_limit: Option<u64>,
+    monthly_limit: Option<u64>,
+    /// Quota currently refusing new connections
+    exceeded: Option<Period>,
+}
+
+impl Ledger {
+    /// Start new periods when the UTC day or month has changed
+    fn roll(&mut self, day: u64) {
+        if self.totals.day != day {
+            self.totals.day = day;
+            self.totals.day_bytes = 0;
+            self.dirty = true;
+        }
+        let month = month_of(day);
+        if self.totals.month != month {
+            self.totals.month = month;
+            self.totals.month_bytes = 0;
+            self.dirty = true;
+        }
+    }
+
+    /// Recompute which quota is exceeded, returning the event for a change
+    fn check(&mut self) -> Option<Event> {
+        let exceeded = if self.monthly_limit > 0 && self.totals.month_bytes >= self.monthly_limit {
+            Some(Period::Monthly)
+        } else if self.daily_limit > 0 && self.totals.day_bytes >= self.daily_limit {
+            Some(Period::Daily)
+        } else {
+            None
+        };
+        if exceeded == self.exceeded {
+            return None;
+        }
+        self.exceeded = exceeded;
+        Some(match exceeded {
+            Some(Period::Daily) => Event::QuotaExceeded {
+                period: Period::Daily,
+                used: self.totals.day_bytes,
+                limit: self.daily_limit,
+            },
+            Some(Period::Monthly) => Event::QuotaExceeded {
+                period: Period::Monthly,
+                used: self.totals.month_bytes,
+                limit: self.monthly_limit,
+            },
+            None => Event::QuotaCleared,
+        })
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if self.dirty {
+            storage::replace_file(
+                path,
+                &serde_json::to_vec(&self.totals).map_err(io::Error::other)?,
+            )?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+/// Load the saved totals from `data_dir` and take the quotas from `config`.
+///
+/// A missing or unreadable file starts the totals from zero.
+pub(crate) fn open(data_dir: &Path, config: &Config) {
+    let path = data_dir.join(FILE_NAME);
+    let totals = fs::read(&path)
+        .ok()
+        .and_then(|b| serde_json::from_slice(&b).ok())
+        .unwrap_or_default();
+    let event = LEDGER.lock().ok().and_then(|mut ledger| {
+        *ledger = Ledger {
+            path: Some(path),
+            totals,
+            dirty: false,
+            daily_limit: config.quota_daily_bytes,
+            monthly_limit: config.quota_monthly_bytes,
+            exceeded: None,
+        };
+        ledger.roll(today());
+        ledger.check()
+    });
+    if let Some(event) = event {
+        events::emit(event);
+    }
+}
+
+/// Save the totals and stop accounting
+pub(crate) fn close() {
+    if let Ok(mut ledger) = LEDGER.lock() {
+        if let Err(e) = ledger.save() {
+            tracing::warn!("Failed to save traffic totals: {}", e);
+        }
+        *ledger = Ledger::default();
+    }
+}
+
+/// Add bytes carried in either direction
+pub(crate) fn add(n: u64) {
+    let event = LEDGER.lock().ok().and_then(|mut ledger| {
+        ledger.path.as_ref()?;
+        ledger.roll(today());
+        ledger.totals.day_bytes += n;
+        ledger.totals.month_bytes += n;
+        ledger.dirty = true;
+        ledger.check()
+    });
+    if let Some(event) = event {
+        events::emit(event);
+    }
+}
+
+/// Quota that should refuse new connections, if any
+pub(crate) fn exceeded() -> Option<Period> {
+    LEDGER.lock().ok().and_then(|l| l.exceeded)
+}
+
+/// Save the totals periodically, and lift a quota once its period is over
+/// even if no traffic flows to notice it
+pub(crate) async fn autosave() {
+    let mut interval = tokio::time::interval(SAVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let event = LEDGER.lock().ok().and_then(|mut ledger| {
+            ledger.roll(today());
+            if let Err(e) = ledger.save() {
+                tracing::warn!("Failed to save traffic totals: {}", e);
+            }
+            ledger.check()
+        });
+        if let Some(event) = event {
+            events::emit(event);
+        }
+    }
+}
+
+pub(crate) fn usage() -> Usage {
+    let Ok(mut ledger) = LEDGER.lock() else {
+        return Usage::default();
+    };
+    if ledger.path.is_some() {
+        ledger.roll(today());
+    }
+    Usage {
+        today_bytes: ledger.totals.day_bytes,
+        month_bytes: ledger.totals.month_bytes,
+        daily_limit: Some(ledger.daily_limit).filter(|l| *l > 0),
+        monthly_limit: Some(ledger.monthly_limit).filter(|l| *l > 0),
+        exceeded: ledger.exceeded,
+    }
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / SECS_PER_DAY)
+        .unwrap_or(0)
+}
+
+/// Months since year 0 for a UTC day number (proleptic Gregorian calendar)
+fn month_of(day: u64) -> u64 {
+    // Shift to a calendar starting on 0000-03-01 so leap days end each year
+    let z = day + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let (year, month) = if mp < 10 {
+        (era * 400 + yoe, mp + 3)
+    } else {
+        (era * 400 + yoe + 1, mp - 9)
+    };
+    year * 12 + month - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn months() {
+        let month = |year: u64, m: u64| year * 12 + m - 1;
+        assert_eq!(month_of(0), month(1970, 1));
+        assert_eq!(month_of(30), month(1970, 1));
+        assert_eq!(month_of(31), month(1970, 2));
+        assert_eq!(month_of(11_016), month(2000, 2));
+        assert_eq!(month_of(11_017), month(2000, 3));
+        assert_eq!(month_of(20_088), month(2024, 12));
+        assert_eq!(month_of(20_089), month(2025, 1));
+        assert_eq!(month_of(47_541), month(2100, 3));
+    }
+
+    fn ledger(daily_limit: u64, monthly_limit: u64) -> Ledger {
+        Ledger {
+            daily_limit,
+            monthly_limit,
+            ..Ledger::default()
+        }
+    }
+
+    #[test]
+    fn rollover_resets_only_the_period_that_ended() {
+        let mut ledger = ledger(0, 0);
+        ledger.roll(20_087);
+        ledger.totals.day_bytes = 10;
+        ledger.totals.month_bytes = 100;
+        ledger.dirty = false;
+
+        ledger.roll(20_087);
+        assert_eq!(
+            (ledger.totals.day_bytes, ledger.totals.month_bytes),
+            (10, 100)
+        );
+        assert!(!ledger.dirty);
+
+        // Next day, same month
+        ledger.roll(20_088);
+        assert_eq!(
+            (ledger.totals.day_bytes, ledger.totals.month_bytes),
+            (0, 100)
+        );
+        assert!(ledger.dirty);
+
+        // Next day, next month
+        ledger.totals.day_bytes = 10;
+        ledger.roll(20_089);
+        assert_eq!((ledger.totals.day_bytes, ledger.totals.month_bytes), (0, 0));
+    }
+
+    #[test]
+    fn exceeded_quotas() {
+        let mut ledger = ledger(10, 100);
+        ledger.roll(20_087);
+        assert!(ledger.check().is_none());
+
+        ledger.totals.day_bytes = 10;
+        assert!(matches!(
+            ledger.check(),
+            Some(Event::QuotaExceeded {
+                period: Period::Daily,
+                used: 10,
+                limit: 10
+            })
+        ));
+        // Only changes are reported
+        assert!(ledger.check().is_none());
+
+        // The monthly quota outlasts the daily one, so it is the one reported
+        ledger.totals.month_bytes = 100;
+        assert!(matches!(
+            ledger.check(),
+            Some(Event::QuotaExceeded {
+                period: Period::Monthly,
+                ..
+            })
+        ));
+
+        ledger.roll(20_089);
+        assert!(matches!(ledger.check(), Some(Event::QuotaCleared)));
+        assert_eq!(ledger.exceeded, None);
+    }
+
+    #[test]
+    fn zero_means_no_quota() {
+        let mut ledger = ledger(0, 0);
+        ledger.totals.day_bytes = u64::MAX;
+        ledger.totals.month_bytes = u64::MAX;
+        assert!(ledger.check().is_none());
+    }
+}
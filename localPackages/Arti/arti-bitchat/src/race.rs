@@ -0,0 +1,99 @@
+//! Racing a destination's onion and clearnet addresses
+//!
+//! A peer or relay reachable both as an onion service and at a clearnet
+//! host can be listed in `onion.race` as `host=name.onion`. A SOCKS request
+//! for either is then connected to both: the onion first, which keeps
+//! traffic inside Tor, and the clearnet host once the onion has had
+//! `onion.race_head_start_ms` to connect, or at once if it fails. The
+//! first to connect is kept and the other given up, so a flaky descriptor
+//! lookup costs the head start rather than the request. Both use the
+//! stream's circuits and the requested port. The address that won is the
+//! stream's destination from then on, and is reported with `stream_raced`.
+//! Onion connects need the `onion-service-client` feature; without it
+//! nothing is raced.
+
+use std::future::Future;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::policy::{self, Decision};
+
+/// An entry of `onion.race`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RacePair {
+    /// Clearnet host name or address, in lower case
+    pub(crate) clearnet: String,
+    /// `name.onion`, in lower case
+    pub(crate) onion: String,
+}
+
+/// Which of the addresses connected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Route {
+    Onion,
+    Clearnet,
+}
+
+impl Route {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Route::Onion => "onion",
+            Route::Clearnet => "clearnet",
+        }
+    }
+}
+
+impl RacePair {
+    pub(crate) fn host(&self, route: Route) -> &str {
+        match route {
+            Route::Onion => &self.onion,
+            Route::Clearnet => &self.clearnet,
+        }
+    }
+}
+
+/// The pair a request for `host` is raced over, if `onion.race` lists it
+/// and local policy allows both addresses
+pub(crate) fn pair_for(host: &str, config: &Config) -> Option<RacePair> {
+    if !cfg!(feature = "onion-service-client") {
+        return None;
+    }
+    let host = host.to_ascii_lowercase();
+    let pair = config
+        .race_pairs
+        .iter()
+        .find(|pair| pair.clearnet == host || pair.onion == host)?;
+    let allowed = |host: &str| policy::evaluate(host, config) == Decision::Allow(host.to_string());
+    (allowed(&pair.clearnet) && allowed(&pair.onion)).then(|| pair.clone())
+}
+
+/// Run `onion`, and `clearnet` once `onion` has had `head_start` or failed,
+/// until one succeeds. If both fail, the error of the last to fail is returned.
+pub(crate) async fn race<T, E>(
+    onion: impl Future<Output = Result<T, E>>,
+    clearnet: impl Future<Output = Result<T, E>>,
+    head_start: Duration,
+) -> Result<(T, Route), E> {
+    tokio::pin!(onion, clearnet);
+    let head_start = tokio::time::sleep(head_start);
+    tokio::pin!(head_start);
+    let (mut onion_failed, mut clearnet_started, mut clearnet_failed) = (false, false, false);
+    loop {
+        tokio::select! {
+            result = &mut onion, if !onion_failed => match result {
+                Ok(stream) => return Ok((stream, Route::Onion)),
+                Err(e) if clearnet_failed => return Err(e),
+                Err(_) => {
+                    onion_failed = true;
+                    clearnet_started = true;
+                }
+            },
+            _ = &mut head_start, if !clearnet_started => clearnet_started = true,
+            result = &mut clearnet, if clearnet_started && !clearnet_failed => match result {
+                Ok(stream) => return Ok((stream, Route::Clearnet)),
+                Err(e) if onion_failed => return Err(e),
+                Err(_) => clearnet_failed = true,
+            },
+        }
+    }
+}
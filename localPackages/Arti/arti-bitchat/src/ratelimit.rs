@@ -0,0 +1,176 @@
+//! Per-source limiting of failed SOCKS handshakes
+//!
+//! A source address that fails too many handshakes within the window is
+//! refused for a while: its connections are closed as soon as they are
+//! accepted, before any bytes are read.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::config::Config;
+use crate::metrics;
+
+/// Tracked sources beyond which stale entries are pruned
+const PRUNE_THRESHOLD: usize = 1024;
+
+static SOURCES: Lazy<Mutex<HashMap<IpAddr, SourceRecord>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct SourceRecord {
+    /// Start of the current counting window
+    window_start: Instant,
+    /// Failures seen since `window_start`
+    failures: u32,
+    /// Refuse this source until then
+    blocked_until: Option<Instant>,
+}
+
+impl SourceRecord {
+    fn is_stale(&self, now: Instant, window: Duration) -> bool {
+        self.blocked_until.is_none_or(|t| t <= now)
+            && now.duration_since(self.window_start) >= window
+    }
+}
+
+/// Record a failed handshake from `ip`, blocking it once it exceeds the limit
+pub(crate) fn note_failure(ip: IpAddr, config: &Config) {
+    note_failure_at(ip, config, Instant::now());
+}
+
+/// [`note_failure`] as of `now`
+fn note_failure_at(ip: IpAddr, config: &Config, now: Instant) {
+    if config.failure_limit == 0 {
+        return;
+    }
+    let Ok(mut sources) = SOURCES.lock() else {
+        return;
+    };
+    if sources.len() >= PRUNE_THRESHOLD {
+        sources.retain(|_, r| !r.is_stale(now, config.failure_window));
+    }
+
+    let record = sources.entry(ip).or_insert(SourceRecord {
+        window_start: now,
+        failures: 0,
+        blocked_until: None,
+    });
+    if now.duration_since(record.window_start) >= config.failure_window {
+        record.window_start = now;
+        record.failures = 0;
+    }
+    record.failures += 1;
+    if record.failures >= config.failure_limit && record.blocked_until.is_none_or(|t| t <= now) {
+        tracing::warn!(
+            "Refusing SOCKS connections from {} for {:?} after {} failed handshakes",
+            ip,
+            config.failure_block,
+            record.failures
+        );
+        record.blocked_until = Some(now + config.failure_block);
+        record.window_start = now;
+        record.failures = 0;
+    }
+}
+
+/// Whether connections from `ip` should be refused right now.
+///
+/// Counts each refusal in the metrics.
+pub(crate) fn check_blocked(ip: IpAddr) -> bool {
+    let blocked = is_blocked(ip, Instant::now());
+    if blocked {
+        metrics::note_rate_limited();
+    }
+    blocked
+}
+
+fn is_blocked(ip: IpAddr, now: Instant) -> bool {
+    SOURCES
+        .lock()
+        .ok()
+        .and_then(|s| s.get(&ip).and_then(|r| r.blocked_until))
+        .is_some_and(|t| t > now)
+}
+
+/// Number of sources currently being refused
+pub(crate) fn blocked_sources() -> usize {
+    let now = Instant::now();
+    SOURCES
+        .lock()
+        .map(|s| {
+            s.values()
+                .filter(|r| r.blocked_until.is_some_and(|t| t > now))
+                .count()
+        })
+        .unwrap_or(0)
+}
+
+/// Forget all failure history (e.g. on shutdown)
+pub(crate) fn clear() {
+    if let Ok(mut sources) = SOURCES.lock() {
+        sources.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(limit: u32, window: Duration, block: Duration) -> Config {
+        Config {
+            failure_limit: limit,
+            failure_window: window,
+            failure_block: block,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn sources_over_the_limit_are_blocked() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let config = config(3, Duration::from_secs(60), Duration::from_secs(60));
+        note_failure(ip, &config);
+        note_failure(ip, &config);
+        assert!(!check_blocked(ip));
+        note_failure(ip, &config);
+        assert!(check_blocked(ip));
+        assert!(blocked_sources() >= 1);
+        // Others are not
+        assert!(!check_blocked("192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn blocks_end() {
+        let ip: IpAddr = "192.0.2.3".parse().unwrap();
+        let config = config(1, Duration::from_secs(60), Duration::from_secs(300));
+        let start = Instant::now();
+        note_failure_at(ip, &config, start);
+        assert!(is_blocked(ip, start + Duration::from_secs(299)));
+        assert!(!is_blocked(ip, start + Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn failures_outside_the_window_are_forgotten() {
+        let ip: IpAddr = "192.0.2.4".parse().unwrap();
+        let config = config(2, Duration::from_secs(60), Duration::from_secs(300));
+        let start = Instant::now();
+        note_failure_at(ip, &config, start);
+        note_failure_at(ip, &config, start + Duration::from_secs(60));
+        assert!(!is_blocked(ip, start + Duration::from_secs(60)));
+        note_failure_at(ip, &config, start + Duration::from_secs(61));
+        assert!(is_blocked(ip, start + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn zero_disables_the_limit() {
+        let ip: IpAddr = "192.0.2.5".parse().unwrap();
+        let config = config(0, Duration::from_secs(60), Duration::from_secs(60));
+        for _ in 0..100 {
+            note_failure(ip, &config);
+        }
+        assert!(!check_blocked(ip));
+    }
+}
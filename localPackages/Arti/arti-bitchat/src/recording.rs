@@ -0,0 +1,139 @@
+//! Opt-in recordings of SOCKS sessions, for reproducing client bugs
+//!
+//! With `socks.record_path` set, each SOCKS session is appended to that file
+//! as JSON lines: every read of the client's greeting and request and every
+//! reply it got, byte for byte and timed from the session's start, then how
+//! much data moved each way and how the session ended. Payloads are never
+//! stored, only the length of each chunk. `socks-replay` in the
+//! `arti-harness` crate plays a recording back against a proxy and reports
+//! replies that differ.
+//!
+//! The request names the destination, so a recording is as revealing as an
+//! unredacted audit log; it is meant for debugging, not for release builds.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::config::Config;
+
+static RECORDING: Mutex<Option<File>> = Mutex::new(None);
+
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(1);
+
+/// Which side of a session an entry comes from
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Side {
+    Client,
+    /// Our replies during the handshake
+    Proxy,
+    /// The destination, once connected
+    Remote,
+}
+
+/// One line of a recording
+#[derive(Serialize)]
+struct Entry<'a> {
+    session: u64,
+    at_ms: u64,
+    #[serde(flatten)]
+    kind: Kind<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Kind<'a> {
+    /// Handshake bytes, as hex
+    Bytes {
+        from: Side,
+        hex: String,
+    },
+    /// A payload chunk, by length only
+    Data {
+        from: Side,
+        len: u64,
+    },
+    End {
+        outcome: &'a str,
+    },
+}
+
+/// A session being recorded
+pub(crate) struct Session {
+    id: u64,
+    started: Instant,
+}
+
+impl Session {
+    /// Start recording a session, if recording is on
+    pub(crate) fn start() -> Option<Session> {
+        let recording = RECORDING.lock().ok()?.is_some();
+        recording.then(|| Session {
+            id: NEXT_SESSION.fetch_add(1, Ordering::Relaxed),
+            started: Instant::now(),
+        })
+    }
+
+    pub(crate) fn bytes(&self, from: Side, bytes: &[u8]) {
+        let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        self.write(Kind::Bytes { from, hex });
+    }
+
+    pub(crate) fn data(&self, from: Side, len: u64) {
+        self.write(Kind::Data { from, len });
+    }
+
+    pub(crate) fn end(&self, outcome: &str) {
+        self.write(Kind::End { outcome });
+    }
+
+    fn write(&self, kind: Kind<'_>) {
+        let entry = Entry {
+            session: self.id,
+            at_ms: self.started.elapsed().as_millis() as u64,
+            kind,
+        };
+        let Ok(mut line) = serde_json::to_vec(&entry) else {
+            return;
+        };
+        line.push(b'\n');
+        let Ok(mut recording) = RECORDING.lock() else {
+            return;
+        };
+        if let Some(file) = recording.as_mut() {
+            if let Err(e) = file.write_all(&line) {
+                tracing::warn!("Failed to write SOCKS recording: {}", e);
+            }
+        }
+    }
+}
+
+/// Start recording if configured; relative paths are under `data_dir`
+pub(crate) fn open(config: &Config, data_dir: &Path) -> io::Result<()> {
+    let file = match config.record_path.as_ref() {
+        Some(path) => Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(data_dir.join(path))?,
+        ),
+        None => None,
+    };
+    if let Ok(mut recording) = RECORDING.lock() {
+        *recording = file;
+    }
+    Ok(())
+}
+
+/// Stop recording
+pub(crate) fn close() {
+    if let Ok(mut recording) = RECORDING.lock() {
+        *recording = None;
+    }
+}
@@ -0,0 +1,170 @@
+//! Relay loop counters, in debug builds
+//!
+//! Each relayed stream counts, per direction, the reads and writes its copy
+//! made, the bytes they moved and the time spent waiting on either side: on
+//! the reading side for data to arrive, on the writing side for room to put
+//! it. Reads and writes of the client's socket are one syscall each; those
+//! of the Tor stream move data between cells. `arti_streams` reports them,
+//! so buffer sizes and `socks.coalesce_ms` can be tuned against
+//! measurements. Release builds leave the relay loop uninstrumented.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Counters of one stream
+#[derive(Default)]
+pub(crate) struct RelayStats {
+    pub(crate) client_to_tor: Direction,
+    pub(crate) tor_to_client: Direction,
+}
+
+/// Counters of one direction of a stream
+#[derive(Default)]
+pub(crate) struct Direction {
+    reads: AtomicU64,
+    read_bytes: AtomicU64,
+    /// Microseconds spent waiting for data to read
+    read_wait_us: AtomicU64,
+    writes: AtomicU64,
+    write_bytes: AtomicU64,
+    flushes: AtomicU64,
+    /// Microseconds spent waiting to write or flush
+    write_wait_us: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RelayView {
+    client_to_tor: DirectionView,
+    tor_to_client: DirectionView,
+}
+
+#[derive(Serialize)]
+struct DirectionView {
+    reads: u64,
+    writes: u64,
+    flushes: u64,
+    /// Mean bytes per read and per write
+    avg_read_bytes: u64,
+    avg_write_bytes: u64,
+    read_wait_ms: u64,
+    write_wait_ms: u64,
+}
+
+impl RelayStats {
+    pub(crate) fn view(&self) -> RelayView {
+        RelayView {
+            client_to_tor: self.client_to_tor.view(),
+            tor_to_client: self.tor_to_client.view(),
+        }
+    }
+}
+
+impl Direction {
+    fn view(&self) -> DirectionView {
+        let reads = self.reads.load(Ordering::Relaxed);
+        let writes = self.writes.load(Ordering::Relaxed);
+        DirectionView {
+            reads,
+            writes,
+            flushes: self.flushes.load(Ordering::Relaxed),
+            avg_read_bytes: self
+                .read_bytes
+                .load(Ordering::Relaxed)
+                .checked_div(reads)
+                .unwrap_or(0),
+            avg_write_bytes: self
+                .write_bytes
+                .load(Ordering::Relaxed)
+                .checked_div(writes)
+                .unwrap_or(0),
+            read_wait_ms: self.read_wait_us.load(Ordering::Relaxed) / 1000,
+            write_wait_ms: self.write_wait_us.load(Ordering::Relaxed) / 1000,
+        }
+    }
+}
+
+/// A reader or writer counted against a [`Direction`]
+pub(crate) struct Instrumented<'a, T> {
+    inner: T,
+    direction: &'a Direction,
+    /// When the pending read, write or flush first had to wait
+    waiting_since: Option<Instant>,
+}
+
+impl<'a, T> Instrumented<'a, T> {
+    pub(crate) fn new(inner: T, direction: &'a Direction) -> Self {
+        Instrumented {
+            inner,
+            direction,
+            waiting_since: None,
+        }
+    }
+
+    /// Track the wait of a call that returned `poll`, adding it to `wait_us` once ready
+    fn note_wait<R>(&mut self, poll: Poll<R>, wait_us: fn(&Direction) -> &AtomicU64) -> Poll<R> {
+        match &poll {
+            Poll::Pending => {
+                self.waiting_since.get_or_insert_with(Instant::now);
+            }
+            Poll::Ready(_) => {
+                if let Some(since) = self.waiting_since.take() {
+                    wait_us(self.direction)
+                        .fetch_add(since.elapsed().as_micros() as u64, Ordering::Relaxed);
+                }
+            }
+        }
+        poll
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Instrumented<'_, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            self.direction.reads.fetch_add(1, Ordering::Relaxed);
+            let n = (buf.filled().len() - before) as u64;
+            self.direction.read_bytes.fetch_add(n, Ordering::Relaxed);
+        }
+        self.note_wait(poll, |d| &d.read_wait_us)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Instrumented<'_, T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.direction.writes.fetch_add(1, Ordering::Relaxed);
+            self.direction
+                .write_bytes
+                .fetch_add(n as u64, Ordering::Relaxed);
+        }
+        self.note_wait(poll, |d| &d.write_wait_us)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        if let Poll::Ready(Ok(())) = poll {
+            self.direction.flushes.fetch_add(1, Ordering::Relaxed);
+        }
+        self.note_wait(poll, |d| &d.write_wait_us)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,133 @@
+//! The far end of a SOCKS stream
+//!
+//! Normally a Tor stream; in `loopback` builds a TCP connection made
+//! without Tor. The relay loop and its accounting work on either alike.
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use arti_client::{DataReader, DataStream, DataWriter};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+use crate::failure::ConnectFailure;
+
+// Tor streams are boxed, as they are much larger than TCP streams
+
+pub(crate) enum Remote {
+    Tor(Box<DataStream>),
+    /// See [`crate::loopback`]
+    Direct(TcpStream),
+}
+
+pub(crate) enum RemoteReader {
+    Tor(Box<DataReader>),
+    Direct(OwnedReadHalf),
+}
+
+pub(crate) enum RemoteWriter {
+    Tor(Box<DataWriter>),
+    Direct(OwnedWriteHalf),
+}
+
+/// Why a stream could not be connected
+#[derive(Debug)]
+pub(crate) enum ConnectError {
+    Tor(arti_client::Error),
+    Direct(io::Error),
+}
+
+impl Remote {
+    /// The Tor stream, for inspecting its circuit
+    pub(crate) fn tor_stream(&self) -> Option<&DataStream> {
+        match self {
+            Remote::Tor(stream) => Some(stream),
+            Remote::Direct(_) => None,
+        }
+    }
+
+    pub(crate) fn split(self) -> (RemoteReader, RemoteWriter) {
+        match self {
+            Remote::Tor(stream) => {
+                let (read, write) = stream.split();
+                (
+                    RemoteReader::Tor(Box::new(read)),
+                    RemoteWriter::Tor(Box::new(write)),
+                )
+            }
+            Remote::Direct(stream) => {
+                let (read, write) = stream.into_split();
+                (RemoteReader::Direct(read), RemoteWriter::Direct(write))
+            }
+        }
+    }
+}
+
+impl ConnectError {
+    pub(crate) fn failure(&self) -> ConnectFailure {
+        match self {
+            ConnectError::Tor(e) => ConnectFailure::classify(e),
+            ConnectError::Direct(e) => ConnectFailure::classify_io(e),
+        }
+    }
+
+    /// The Tor error, for failure analytics
+    pub(crate) fn tor(&self) -> Option<&arti_client::Error> {
+        match self {
+            ConnectError::Tor(e) => Some(e),
+            ConnectError::Direct(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::Tor(e) => e.fmt(f),
+            ConnectError::Direct(e) => e.fmt(f),
+        }
+    }
+}
+
+impl AsyncRead for RemoteReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RemoteReader::Tor(r) => Pin::new(r).poll_read(cx, buf),
+            RemoteReader::Direct(r) => Pin::new(r).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for RemoteWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            RemoteWriter::Tor(w) => Pin::new(w).poll_write(cx, buf),
+            RemoteWriter::Direct(w) => Pin::new(w).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RemoteWriter::Tor(w) => Pin::new(w).poll_flush(cx),
+            RemoteWriter::Direct(w) => Pin::new(w).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            RemoteWriter::Tor(w) => Pin::new(w).poll_shutdown(cx),
+            RemoteWriter::Direct(w) => Pin::new(w).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! Limits on the clients of hosted onion services
+//!
+//! A hosted service cannot tell its clients apart: restricted discovery
+//! decides who may find it, but an introduction carries no client identity.
+//! What the service does see is each client's rendezvous circuit, so the
+//! limits apply per circuit. A client opening streams faster than
+//! `service.client_streams_per_minute`, or sending more than
+//! `service.client_max_bytes` towards the app over one circuit, has that
+//! circuit closed and `onion_service_client_limited` is emitted.
+//!
+//! A client cut off can come back on a new circuit, which starts afresh.
+//! Nothing longer-lived is banned: with no identity to ban, the only ban
+//! possible would turn away every client of the service, which is the
+//! denial a hostile client was after.
+//!
+//! Nothing is stored here for a service, so there is no storage to give a
+//! quota: a mailbox or file drop keeps what it receives in the app. The
+//! byte limit bounds how much one circuit can hand the app to store; a quota
+//! over what the app keeps across circuits is the app's to enforce.
+//!
+//! Each service also counts what it has handled since it was launched, for
+//! an operator watching for abuse. Under restricted discovery a client
+//! without a key cannot read the descriptor, so it never reaches the service
+//! to be counted; the clients counted as limited are those cut off here.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::Config;
+use crate::events::{self, Event};
+
+/// Window `service.client_streams_per_minute` counts over
+const STREAM_WINDOW: Duration = Duration::from_secs(60);
+
+/// Which limit a client went over
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Limit {
+    Streams,
+    Bytes,
+}
+
+/// Something a service counts, see [`Counts`]
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Count {
+    Introduction,
+    Rendezvous,
+    RendezvousFailed,
+    Stream,
+    StreamRefused,
+}
+
+#[derive(Default)]
+struct Counters {
+    introductions: AtomicU64,
+    rendezvous: AtomicU64,
+    rendezvous_failed: AtomicU64,
+    streams: AtomicU64,
+    streams_refused: AtomicU64,
+    clients_limited: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+/// What a service has handled since it was launched
+#[derive(Debug, Serialize)]
+pub(crate) struct Counts {
+    /// Introductions received, each asking for a rendezvous
+    introductions: u64,
+    /// Rendezvous circuits completed
+    rendezvous: u64,
+    rendezvous_failed: u64,
+    /// Streams forwarded to the app
+    streams: u64,
+    /// Streams for another port, or that the app did not accept
+    streams_refused: u64,
+    /// Clients cut off over a limit
+    clients_limited: u64,
+    /// Bytes sent to clients
+    bytes_sent: u64,
+    /// Bytes clients sent towards the app
+    bytes_received: u64,
+}
+
+/// The limits of one service, and the clients it is serving
+pub(crate) struct Guard {
+    nickname: String,
+    /// Zero for no limit
+    streams_per_minute: u64,
+    max_bytes: u64,
+    /// Rendezvous circuits open
+    clients: AtomicUsize,
+    next_client: AtomicU64,
+    counters: Counters,
+}
+
+/// One client's rendezvous circuit, counted against its service's limits
+pub(crate) struct Client {
+    guard: Arc<Guard>,
+    /// Numbered per service, for telling clients apart in events
+    id: u64,
+    /// Start of the current stream window, and streams opened since
+    window: Mutex<(Instant, u64)>,
+    /// Bytes the client has sent towards the app
+    received: AtomicU64,
+    cut_off: AtomicBool,
+    /// Stops the client's streams once it is cut off
+    pub(crate) cancel: CancellationToken,
+}
+
+impl Guard {
+    pub(crate) fn new(nickname: &str, config: &Config) -> Arc<Self> {
+        Arc::new(Guard {
+            nickname: nickname.to_string(),
+            streams_per_minute: config.service_client_streams_per_minute,
+            max_bytes: config.service_client_max_bytes,
+            clients: AtomicUsize::new(0),
+            next_client: AtomicU64::new(1),
+            counters: Counters::default(),
+        })
+    }
+
+    pub(crate) fn note(&self, count: Count) {
+        let counter = match count {
+            Count::Introduction => &self.counters.introductions,
+            Count::Rendezvous => &self.counters.rendezvous,
+            Count::RendezvousFailed => &self.counters.rendezvous_failed,
+            Count::Stream => &self.counters.streams,
+            Count::StreamRefused => &self.counters.streams_refused,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Streams forwarded to the app
+    pub(crate) fn streams(&self) -> u64 {
+        self.counters.streams.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn counts(&self) -> Counts {
+        let c = &self.counters;
+        Counts {
+            introductions: c.introductions.load(Ordering::Relaxed),
+            rendezvous: c.rendezvous.load(Ordering::Relaxed),
+            rendezvous_failed: c.rendezvous_failed.load(Ordering::Relaxed),
+            streams: c.streams.load(Ordering::Relaxed),
+            streams_refused: c.streams_refused.load(Ordering::Relaxed),
+            clients_limited: c.clients_limited.load(Ordering::Relaxed),
+            bytes_sent: c.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: c.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn clients(&self) -> usize {
+        self.clients.load(Ordering::SeqCst)
+    }
+
+    /// Take on a new client whose streams stop with `stop`
+    pub(crate) fn admit(self: &Arc<Self>, stop: &CancellationToken) -> Arc<Client> {
+        self.clients.fetch_add(1, Ordering::SeqCst);
+        Arc::new(Client {
+            guard: self.clone(),
+            id: self.next_client.fetch_add(1, Ordering::Relaxed),
+            window: Mutex::new((Instant::now(), 0)),
+            received: AtomicU64::new(0),
+            cut_off: AtomicBool::new(false),
+            cancel: stop.child_token(),
+        })
+    }
+}
+
+impl Client {
+    pub(crate) fn note(&self, count: Count) {
+        self.guard.note(count);
+    }
+
+    /// Count `n` bytes sent to the client
+    pub(crate) fn note_sent(&self, n: u64) {
+        self.guard
+            .counters
+            .bytes_sent
+            .fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Count a stream the client asked for; false if it went over the limit
+    pub(crate) fn note_stream(&self) -> bool {
+        let limit = self.guard.streams_per_minute;
+        if limit == 0 {
+            return true;
+        }
+        let over = {
+            let Ok(mut window) = self.window.lock() else {
+                return true;
+            };
+            let now = Instant::now();
+            if now.duration_since(window.0) >= STREAM_WINDOW {
+                *window = (now, 0);
+            }
+            window.1 += 1;
+            window.1 > limit
+        };
+        if over {
+            self.cut_off(Limit::Streams);
+        }
+        !over
+    }
+
+    /// Count `n` bytes the client sent towards the app, cutting it off once
+    /// it is over the limit
+    pub(crate) fn note_received(&self, n: u64) {
+        self.guard
+            .counters
+            .bytes_received
+            .fetch_add(n, Ordering::Relaxed);
+        let total = self.received.fetch_add(n, Ordering::Relaxed) + n;
+        if self.guard.max_bytes > 0 && total > self.guard.max_bytes {
+            self.cut_off(Limit::Bytes);
+        }
+    }
+
+    fn cut_off(&self, limit: Limit) {
+        if self.cut_off.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.cancel.cancel();
+        let guard = &self.guard;
+        guard
+            .counters
+            .clients_limited
+            .fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(
+            "Onion service {} closed the circuit of client {}: over its {:?} limit",
+            guard.nickname,
+            self.id,
+            limit
+        );
+        events::emit(Event::OnionServiceClientLimited {
+            nickname: guard.nickname.clone(),
+            client: self.id,
+            limit,
+        });
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.guard.clients.fetch_sub(1, Ordering::SeqCst);
+    }
+}
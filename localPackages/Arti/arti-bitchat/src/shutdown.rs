@@ -0,0 +1,131 @@
+//! Coordinated shutdown of the proxy
+//!
+//! Every connection handler is spawned through the [`ShutdownController`].
+//! Shutting down cancels the controller's token, which handlers observe to
+//! stop reading new data and close cleanly. Handlers still running when the
+//! drain deadline passes are force-closed. The outcome is kept as a
+//! [`ShutdownReport`] for the status snapshot.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Report from the most recent shutdown
+static LAST_REPORT: Mutex<Option<ShutdownReport>> = Mutex::new(None);
+
+/// How in-flight connections fared during shutdown
+#[derive(Clone, Copy, Debug, Serialize)]
+pub(crate) struct ShutdownReport {
+    /// Connections open when shutdown began
+    pub(crate) in_flight: usize,
+    /// Connections that closed cleanly before the deadline
+    pub(crate) drained: usize,
+    /// Connections force-closed at the deadline
+    pub(crate) aborted: usize,
+}
+
+pub(crate) struct ShutdownController {
+    /// How long handlers get to finish before being force-closed, in ms
+    deadline_ms: AtomicU64,
+    /// Asks handlers to wind down
+    graceful: CancellationToken,
+    /// Closes handlers that did not wind down in time
+    force: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl ShutdownController {
+    pub(crate) fn new(deadline: Duration) -> Self {
+        ShutdownController {
+            deadline_ms: AtomicU64::new(deadline.as_millis() as u64),
+            graceful: CancellationToken::new(),
+            force: CancellationToken::new(),
+            tracker: TaskTracker::new(),
+        }
+    }
+
+    /// Drain deadline handlers will be given
+    pub(crate) fn deadline(&self) -> Duration {
+        Duration::from_millis(self.deadline_ms.load(Ordering::SeqCst))
+    }
+
+    /// Give handlers no more than `max` to finish, e.g. when the app is about
+    /// to be killed. Call before [`cancel`](Self::cancel).
+    pub(crate) fn limit_deadline(&self, max: Duration) {
+        self.deadline_ms
+            .fetch_min(max.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Token handlers watch to learn that shutdown has begun
+    pub(crate) fn token(&self) -> CancellationToken {
+        self.graceful.clone()
+    }
+
+    /// Begin shutting down
+    pub(crate) fn cancel(&self) {
+        self.graceful.cancel();
+    }
+
+    /// Resolves once shutdown has begun
+    pub(crate) async fn cancelled(&self) {
+        self.graceful.cancelled().await
+    }
+
+    /// Spawn a connection handler that is tracked and can be force-closed
+    pub(crate) fn spawn<F>(&self, handler: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let force = self.force.clone();
+        self.tracker.spawn(async move {
+            tokio::select! {
+                _ = handler => {}
+                _ = force.cancelled() => {}
+            }
+        });
+    }
+
+    /// Wait up to the deadline for handlers to finish, then force-close the rest.
+    ///
+    /// Call after [`cancel`](Self::cancel) and after the accept loops have
+    /// stopped, so no new handlers are spawned.
+    pub(crate) async fn drain(&self) -> ShutdownReport {
+        self.tracker.close();
+        let in_flight = self.tracker.len();
+
+        let aborted = match tokio::time::timeout(self.deadline(), self.tracker.wait()).await {
+            Ok(()) => 0,
+            Err(_) => {
+                let remaining = self.tracker.len();
+                self.force.cancel();
+                self.tracker.wait().await;
+                remaining
+            }
+        };
+
+        let report = ShutdownReport {
+            in_flight,
+            drained: in_flight.saturating_sub(aborted),
+            aborted,
+        };
+        tracing::info!(
+            "Shutdown: {} connections drained, {} aborted",
+            report.drained,
+            report.aborted
+        );
+        if let Ok(mut last) = LAST_REPORT.lock() {
+            *last = Some(report);
+        }
+        report
+    }
+}
+
+/// Report from the most recent shutdown, if any
+pub(crate) fn last_report() -> Option<ShutdownReport> {
+    LAST_REPORT.lock().ok().and_then(|r| *r)
+}
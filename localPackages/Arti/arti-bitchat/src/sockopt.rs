@@ -0,0 +1,72 @@
+//! Socket options for SOCKS clients
+//!
+//! Chat messages are small and latency-bound, so Nagle's algorithm only
+//! holds them back, while bulk transfers such as media move more per wakeup
+//! with larger buffers and lose nothing to Nagle. Once a client's request is
+//! read, its socket gets the options of its class: bulk if the destination
+//! port is in `socket.bulk_ports`, interactive otherwise. The other
+//! `socket.*` options override the class defaults.
+
+use std::io;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+
+/// Socket buffer size for bulk connections unless configured
+const BULK_BUFFER_BYTES: usize = 256 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum QosClass {
+    Interactive,
+    Bulk,
+}
+
+impl QosClass {
+    pub(crate) fn of(port: u16, config: &Config) -> QosClass {
+        if config.bulk_ports.contains(&port) {
+            QosClass::Bulk
+        } else {
+            QosClass::Interactive
+        }
+    }
+
+    fn nodelay(self) -> bool {
+        self == QosClass::Interactive
+    }
+
+    /// Buffer size to request, or `None` to keep the system's
+    fn buffer_bytes(self) -> Option<usize> {
+        match self {
+            QosClass::Interactive => None,
+            QosClass::Bulk => Some(BULK_BUFFER_BYTES),
+        }
+    }
+}
+
+/// Apply the options for a connection to `port`
+pub(crate) fn apply(stream: &TcpStream, port: u16, config: &Config) -> io::Result<()> {
+    let class = QosClass::of(port, config);
+    let socket = SockRef::from(stream);
+    socket.set_tcp_nodelay(config.nodelay.unwrap_or(class.nodelay()))?;
+    if let Some(size) = config.send_buffer_bytes.or(class.buffer_bytes()) {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = config.recv_buffer_bytes.or(class.buffer_bytes()) {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if !config.keepalive.is_zero() {
+        socket.set_tcp_keepalive(&keepalive(config.keepalive))?;
+    }
+    Ok(())
+}
+
+fn keepalive(idle: Duration) -> TcpKeepalive {
+    let keepalive = TcpKeepalive::new().with_time(idle);
+    // Probe as often as the idle time, so a dead peer is noticed within a few of them
+    #[cfg(any(target_os = "linux", target_os = "android", target_vendor = "apple"))]
+    let keepalive = keepalive.with_interval(idle);
+    keepalive
+}
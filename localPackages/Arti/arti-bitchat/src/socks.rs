@@ -1,61 +1,1014 @@
 //! SOCKS5 protocol handler for Arti
 //!
 //! Implements a minimal SOCKS5 server that forwards connections through Tor.
+//!
+//! With `socks.require_token` set, clients must authenticate with
+//! username/password (RFC 1929), giving as the username a random token made
+//! at each start and handed to the app by `arti_socks_token`. Other apps on
+//! the device can reach the port but not use it. The password then names the
+//! client's isolation group, see [`groups`](crate::groups).
+//!
+//! Local clients usually send the greeting and the request together, and
+//! some send their first data without waiting for the reply. The handshake
+//! is therefore parsed from a buffer filled by as few reads as the client's
+//! packets allow, and anything read past the request is relayed as the
+//! start of the stream.
+//!
+//! Some client libraries repeat the whole handshake once connected, which
+//! would reach the destination as the start of the stream. A greeting or
+//! request as a stream's first data is reported with `stream_pipelined`,
+//! then relayed as data, which a proxy beyond the exit would expect, or,
+//! with `socks.pipelining=reject`, refused before connecting or closed once
+//! connected. Only well-formed SOCKS5 greetings and requests are caught,
+//! which take a binary protocol to mimic.
 
+use std::future::Future;
 use std::io;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
-use arti_client::{TorClient, IntoTorAddr};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tor_rtcompat::PreferredRuntime;
+use arti_client::{DataStream, IntoTorAddr, IsolationToken, StreamPrefs, TorAddr, TorClient};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_util::sync::CancellationToken;
+
+use crate::audit::{self, AuditRecord, Verdict};
+use crate::budget;
+use crate::client_stream::ClientStream;
+use crate::clock::TorRuntime;
+use crate::config::Config;
+use crate::deadline::{self, Phase};
+use crate::diagnosis;
+use crate::events::{self, Event};
+use crate::failure::ConnectFailure;
+use crate::groups;
+use crate::policy::{self, Decision, Reply};
+use crate::recording::{self, Side};
+#[cfg(debug_assertions)]
+use crate::relay_stats::Instrumented;
+use crate::remote::{ConnectError, Remote};
+use crate::stream_events::{CloseReason, Progress};
+use crate::{
+    circuit_timeout, latency, loopback, metrics, migration, race, ratelimit, stats, tuning,
+};
 
 // SOCKS5 constants
 const SOCKS5_VERSION: u8 = 0x05;
 const SOCKS5_AUTH_NONE: u8 = 0x00;
+const SOCKS5_AUTH_USERPASS: u8 = 0x02;
+const SOCKS5_AUTH_NO_ACCEPTABLE: u8 = 0xFF;
+const USERPASS_VERSION: u8 = 0x01;
+const USERPASS_SUCCESS: u8 = 0x00;
+const USERPASS_FAILURE: u8 = 0x01;
 const SOCKS5_CMD_CONNECT: u8 = 0x01;
+const SOCKS5_CMD_UDP_ASSOCIATE: u8 = 0x03;
 const SOCKS5_ATYP_IPV4: u8 = 0x01;
 const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
 const SOCKS5_ATYP_IPV6: u8 = 0x04;
 const SOCKS5_REP_SUCCESS: u8 = 0x00;
 const SOCKS5_REP_FAILURE: u8 = 0x01;
-const SOCKS5_REP_CONN_REFUSED: u8 = 0x05;
+const SOCKS5_REP_NOT_ALLOWED: u8 = 0x02;
+const SOCKS5_REP_ADDR_NOT_SUPPORTED: u8 = 0x08;
+
+pub(crate) const RELAY_BUF_SIZE: usize = 16 * 1024;
+
+/// Data carried by one relay cell; smaller writes are worth coalescing
+const CELL_DATA_LEN: usize = 498;
+
+/// How a SOCKS handshake a client repeats as stream data is handled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Pipelining {
+    /// Relay it to the destination like any other data
+    Tunnel,
+    /// Refuse the request, or close the stream if already connected
+    Reject,
+}
+
+impl Pipelining {
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        match s {
+            "tunnel" => Some(Pipelining::Tunnel),
+            "reject" => Some(Pipelining::Reject),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Pipelining::Tunnel => "tunnel",
+            Pipelining::Reject => "reject",
+        }
+    }
+}
+
+/// Random bytes in a SOCKS token, which is sent as hex
+const TOKEN_BYTES: usize = 16;
+
+/// The username clients must give while `socks.require_token` is set
+static TOKEN: Mutex<Option<String>> = Mutex::new(None);
+
+/// Make a new token if `required`, or drop the current one
+pub(crate) fn reset_token(required: bool) {
+    let token = required.then(|| {
+        rand::random::<[u8; TOKEN_BYTES]>()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    });
+    if let Ok(mut current) = TOKEN.lock() {
+        *current = token;
+    }
+}
+
+/// The token for this session, if one is required
+pub(crate) fn token() -> Option<String> {
+    TOKEN.lock().ok().and_then(|t| t.clone())
+}
 
 /// Handle a single SOCKS5 connection
-pub async fn handle_socks_connection(
-    mut stream: TcpStream,
+pub async fn handle_socks_connection<S: ClientStream>(
+    stream: S,
     peer_addr: SocketAddr,
-    client: Arc<TorClient<PreferredRuntime>>,
+    client: Arc<TorClient<TorRuntime>>,
+    config: Arc<Config>,
+    cancel: CancellationToken,
 ) -> io::Result<()> {
-    // --- Greeting ---
-    // Client sends: VER | NMETHODS | METHODS
-    let mut greeting = [0u8; 2];
-    stream.read_exact(&mut greeting).await?;
+    let recording = recording::Session::start();
+    let result = relay_socks_connection(
+        stream,
+        peer_addr,
+        client,
+        config,
+        cancel,
+        recording.as_ref(),
+    )
+    .await;
+    if let Some(recording) = &recording {
+        match &result {
+            Ok(outcome) => recording.end(outcome),
+            Err(e) => recording.end(&e.to_string()),
+        }
+    }
+    result.map(|_| ())
+}
 
-    if greeting[0] != SOCKS5_VERSION {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Not SOCKS5",
-        ));
+/// Handshake, connect and relay; returns how the relay ended
+async fn relay_socks_connection<S: ClientStream>(
+    mut stream: S,
+    peer_addr: SocketAddr,
+    client: Arc<TorClient<TorRuntime>>,
+    config: Arc<Config>,
+    cancel: CancellationToken,
+    recording: Option<&recording::Session>,
+) -> io::Result<String> {
+    let mut deadline = deadline::for_peer(peer_addr);
+    let handshake = limited_handshake(&mut stream, &config, recording);
+    let handshake = tokio::select! {
+        result = unless_cancelled(&cancel, handshake) => result,
+        _ = deadline.reached() => {
+            deadline.report(Phase::Handshake, None, None, 0, 0);
+            audit::record(AuditRecord::new(peer_addr, Verdict::Failed, "handshake").outcome("deadline reached"));
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "deadline reached"));
+        }
+    };
+    let Request {
+        host: dest_host,
+        port: dest_port,
+        group,
+        early_data,
+    } = match handshake {
+        Ok(request) => request,
+        Err(e) if cancel.is_cancelled() => return Err(e),
+        Err(e) => {
+            if S::RATE_LIMITED {
+                ratelimit::note_failure(peer_addr.ip(), &config);
+            }
+            audit::record(
+                AuditRecord::new(peer_addr, Verdict::Failed, "handshake").outcome(e.to_string()),
+            );
+            return Err(e);
+        }
+    };
+
+    tracing::debug!(
+        "SOCKS5 CONNECT from {} to {}:{}",
+        peer_addr,
+        dest_host,
+        dest_port
+    );
+    let progress = Progress::new(metrics::new_stream_id(), peer_addr.port(), &config);
+    if let Err(e) = stream.apply_options(dest_port, &config) {
+        tracing::debug!("Failed to set socket options: {}", e);
     }
 
-    let nmethods = greeting[1] as usize;
-    let mut methods = vec![0u8; nmethods];
-    stream.read_exact(&mut methods).await?;
+    // A repeated handshake sent without waiting for the reply is already here
+    if is_socks_handshake(&early_data) {
+        let reject = config.pipelining == Pipelining::Reject;
+        tracing::debug!(
+            "{} repeated its SOCKS handshake to {}:{}",
+            peer_addr,
+            dest_host,
+            dest_port
+        );
+        events::emit(Event::StreamPipelined {
+            stream: progress.stream(),
+            client_port: progress.client_port(),
+            destination: format!("{}:{}", dest_host, dest_port),
+            action: if reject { "refused" } else { "tunneled" },
+        });
+        if reject {
+            audit::record(
+                AuditRecord::new(peer_addr, Verdict::Blocked, "pipelined_request")
+                    .destination(&dest_host, dest_port),
+            );
+            send_reply(&mut stream, SOCKS5_REP_FAILURE, recording).await?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, PipelinedRequest));
+        }
+    }
 
-    // We only support no-auth
-    if !methods.contains(&SOCKS5_AUTH_NONE) {
-        // Send failure: no acceptable methods
-        stream.write_all(&[SOCKS5_VERSION, 0xFF]).await?;
-        return Err(io::Error::new(
-            io::ErrorKind::PermissionDenied,
-            "No acceptable auth methods",
-        ));
+    // The stream is logged under the rule that redirected it, if any
+    let (dest_host, dest_port, audit_rule) = match policy::evaluate(&dest_host, &config) {
+        Decision::Allow(host) => (host, dest_port, "default"),
+        Decision::Reject(rejection) => {
+            let rule = rejection.as_str();
+            let reply = policy::reply_for(rejection, &config);
+            if let Reply::Redirect(host, port) = reply {
+                tracing::debug!(
+                    "Redirected {}:{} to {}:{} under {}",
+                    dest_host,
+                    dest_port,
+                    host,
+                    port,
+                    rule
+                );
+                (host, port, rule)
+            } else {
+                tracing::debug!("Rejected {}:{} under {}", dest_host, dest_port, rule);
+                let record = AuditRecord::new(peer_addr, Verdict::Blocked, rule)
+                    .destination(&dest_host, dest_port);
+                events::emit(Event::StreamRejected {
+                    stream: progress.stream(),
+                    client_port: progress.client_port(),
+                    destination: format!("{}:{}", dest_host, dest_port),
+                    rule,
+                });
+                if reply == Reply::Blackhole {
+                    audit::record(record.outcome("blackholed"));
+                    // Closed without a reply, as a destination that never answers would be
+                    tokio::select! {
+                        _ = tokio::time::sleep(config.blackhole_delay) => {}
+                        _ = cancel.cancelled() => {}
+                        _ = deadline.reached() => {}
+                    }
+                } else {
+                    audit::record(record);
+                    send_reply(&mut stream, rejection.socks_reply(), recording).await?;
+                }
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, rule));
+            }
+        }
+    };
+
+    // Connect through Tor
+    let tor_addr = format!("{}:{}", dest_host, dest_port);
+    let tor_addr = match tor_addr.as_str().into_tor_addr() {
+        Ok(a) => a,
+        Err(e) => {
+            tracing::debug!("Invalid Tor address: {}", e);
+            audit::record(
+                AuditRecord::new(peer_addr, Verdict::Failed, "address")
+                    .destination(&dest_host, dest_port)
+                    .outcome(e.to_string()),
+            );
+            send_reply(&mut stream, SOCKS5_REP_FAILURE, recording).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid Tor address: {}", e),
+            ));
+        }
+    };
+
+    let admission = match groups::admit(&group, &dest_host, dest_port, &config) {
+        Ok(admission) => admission,
+        Err(refusal) => {
+            let rule = refusal.as_str();
+            tracing::debug!("Refused {}:{} under {}", dest_host, dest_port, rule);
+            audit::record(
+                AuditRecord::new(peer_addr, Verdict::Blocked, rule)
+                    .destination(&dest_host, dest_port),
+            );
+            events::emit(Event::StreamRejected {
+                stream: progress.stream(),
+                client_port: progress.client_port(),
+                destination: format!("{}:{}", dest_host, dest_port),
+                rule,
+            });
+            send_reply(&mut stream, SOCKS5_REP_NOT_ALLOWED, recording).await?;
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, rule));
+        }
+    };
+
+    // Both addresses of a raced destination are connected to, if both parse
+    let race = race::pair_for(&dest_host, &config).and_then(|pair| {
+        let onion = (pair.onion.as_str(), dest_port).into_tor_addr().ok()?;
+        let clearnet = (pair.clearnet.as_str(), dest_port).into_tor_addr().ok()?;
+        Some((pair, onion, clearnet))
+    });
+    // Only hostnames are resolved by the exit, so only they are worth retrying
+    let retries = |host: &str| {
+        if is_hostname(host) {
+            config.resolve_retries
+        } else {
+            0
+        }
+    };
+    let circuit_timeout = circuit_timeout::limit(&client, dest_port, &config);
+    let started = Instant::now();
+    budget::note_connect(&config);
+    // Taken before connecting: a circuit built while the interface changes
+    // may be on the old path
+    let path = migration::path();
+    let diagnosis = diagnosis::Connect::new(
+        progress.stream(),
+        progress.client_port(),
+        format!("{}:{}", dest_host, dest_port),
+    );
+    // Not entered, for the same reason as the bootstrap span
+    let span = tracing::info_span!(
+        "tor_connect",
+        port = dest_port,
+        outcome = tracing::field::Empty
+    );
+    let connect = async {
+        let _slot = groups::connect_slot(&group, config.max_connecting).await;
+        if loopback::ENABLED {
+            return loopback::connect(&dest_host, dest_port, &config, &progress)
+                .await
+                .map(|stream| (Remote::Direct(stream), None))
+                .map_err(|e| (ConnectError::Direct(e), 1));
+        }
+        let exit_country = config.exit_country.as_deref();
+        let connected = match &race {
+            Some((pair, onion, clearnet)) => race::race(
+                connect_tor(
+                    &client,
+                    onion.clone(),
+                    admission.isolation,
+                    0,
+                    None,
+                    circuit_timeout,
+                    &progress,
+                ),
+                connect_tor(
+                    &client,
+                    clearnet.clone(),
+                    admission.isolation,
+                    retries(&pair.clearnet),
+                    exit_country,
+                    circuit_timeout,
+                    &progress,
+                ),
+                config.race_head_start,
+            )
+            .await
+            .map(|(stream, route)| (stream, Some(route))),
+            None => {
+                let retries = retries(&dest_host);
+                connect_tor(
+                    &client,
+                    tor_addr,
+                    admission.isolation,
+                    retries,
+                    exit_country,
+                    circuit_timeout,
+                    &progress,
+                )
+                .await
+                .map(|stream| (stream, None))
+            }
+        };
+        connected
+            .map(|(stream, route)| (Remote::Tor(Box::new(stream)), route))
+            .map_err(|(e, attempts)| (ConnectError::Tor(e), attempts))
+    };
+    let connected = tokio::select! {
+        result = diagnosis.run(connect) => Ok(result),
+        _ = cancel.cancelled() => Err(Abandoned::Shutdown),
+        _ = deadline.reached() => Err(Abandoned::Deadline),
+    };
+    span.record(
+        "outcome",
+        match &connected {
+            Ok(Ok(_)) => "connected",
+            Ok(Err((e, _))) => e.failure().as_str(),
+            Err(Abandoned::Shutdown) => "cancelled",
+            Err(Abandoned::Deadline) => "deadline",
+        },
+    );
+    drop(span);
+    let (remote, route) = match connected {
+        Ok(Ok(connected)) => connected,
+        Ok(Err((e, attempts))) => {
+            let failure = e.failure();
+            if let Some(e) = e.tor() {
+                metrics::note_circuit_failures(e);
+                diagnosis.failed(e, attempts, client.bootstrap_status().ready_for_traffic());
+            }
+            stats::note_connect_failed();
+            tuning::note_connect_failed(failure);
+            tracing::debug!(
+                "Tor connect failed after {} attempts ({}): {}",
+                attempts,
+                failure.as_str(),
+                e
+            );
+            audit::record(
+                AuditRecord::new(peer_addr, Verdict::Allowed, audit_rule)
+                    .destination(&dest_host, dest_port)
+                    .outcome(format!("connect failed ({}): {}", failure.as_str(), e))
+                    .traffic(started.elapsed(), 0, 0),
+            );
+            events::emit(Event::StreamFailed {
+                stream: progress.stream(),
+                client_port: progress.client_port(),
+                destination: format!("{}:{}", dest_host, dest_port),
+                reason: failure.as_str(),
+                detail: e.to_string(),
+                attempts,
+            });
+            send_reply(&mut stream, failure.socks_reply(), recording).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                e.to_string(),
+            ));
+        }
+        Err(Abandoned::Shutdown) => {
+            audit::record(
+                AuditRecord::new(peer_addr, Verdict::Allowed, audit_rule)
+                    .destination(&dest_host, dest_port)
+                    .outcome("connect cancelled for shutdown")
+                    .traffic(started.elapsed(), 0, 0),
+            );
+            send_reply(&mut stream, SOCKS5_REP_FAILURE, recording).await?;
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "shutting down"));
+        }
+        Err(Abandoned::Deadline) => {
+            let destination = format!("{}:{}", dest_host, dest_port);
+            deadline.report(
+                Phase::Connect,
+                Some(progress.stream()),
+                Some(destination),
+                0,
+                0,
+            );
+            audit::record(
+                AuditRecord::new(peer_addr, Verdict::Allowed, audit_rule)
+                    .destination(&dest_host, dest_port)
+                    .outcome("connect stopped at its deadline")
+                    .traffic(started.elapsed(), 0, 0),
+            );
+            send_reply(
+                &mut stream,
+                ConnectFailure::Timeout.socks_reply(),
+                recording,
+            )
+            .await?;
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "deadline reached"));
+        }
+    };
+
+    // A raced request goes on as a stream to the address that won
+    let dest_host = match (race, route) {
+        (Some((pair, _, _)), Some(route)) => {
+            tracing::debug!(
+                "Raced {}:{} connected over {}",
+                dest_host,
+                dest_port,
+                route.as_str()
+            );
+            let host = pair.host(route).to_string();
+            events::emit(Event::StreamRaced {
+                stream: progress.stream(),
+                client_port: progress.client_port(),
+                destination: format!("{}:{}", host, dest_port),
+                route: route.as_str(),
+                elapsed_ms: started.elapsed().as_millis() as u64,
+            });
+            host
+        }
+        _ => dest_host,
+    };
+
+    // Send success reply, reporting the local end of the client's socket, if
+    // it has one, as the bound address since the Tor side has none to offer
+    let bound = stream.local_addr();
+    write_reply(
+        &mut stream,
+        &encode_reply(SOCKS5_REP_SUCCESS, bound),
+        recording,
+    )
+    .await?;
+
+    // Keep the stream registered for status reporting until the relay ends
+    let destination = format!("{}:{}", dest_host, dest_port);
+    let handle = metrics::StreamHandle::register(
+        progress.stream(),
+        progress.client_port(),
+        remote.tor_stream(),
+        &client,
+        destination.clone(),
+    );
+    progress.connected(destination, handle.circuit());
+
+    // Bidirectional copy
+    // Shadowed by their instrumented versions in debug builds
+    #[cfg_attr(debug_assertions, allow(unused_mut))]
+    let (client_read, mut client_write) = stream.into_split();
+    #[cfg_attr(debug_assertions, allow(unused_mut))]
+    let (mut tor_read, mut tor_write) = remote.split();
+    #[cfg(debug_assertions)]
+    let (client_read, mut client_write, mut tor_read, mut tor_write) = {
+        let stats = handle.relay_stats();
+        (
+            Instrumented::new(client_read, &stats.client_to_tor),
+            Instrumented::new(client_write, &stats.tor_to_client),
+            Instrumented::new(tor_read, &stats.tor_to_client),
+            Instrumented::new(tor_write, &stats.client_to_tor),
+        )
+    };
+    // Unless the client sent data before the reply, its first data after it
+    // may be a repeated handshake
+    let pipelined = |data: &[u8]| {
+        if !is_socks_handshake(data) {
+            return true;
+        }
+        let reject = config.pipelining == Pipelining::Reject;
+        tracing::debug!(
+            "{} repeated its SOCKS handshake to {}:{}",
+            peer_addr,
+            dest_host,
+            dest_port
+        );
+        events::emit(Event::StreamPipelined {
+            stream: progress.stream(),
+            client_port: progress.client_port(),
+            destination: format!("{}:{}", dest_host, dest_port),
+            action: if reject { "closed" } else { "tunneled" },
+        });
+        !reject
+    };
+    let mut client_read = FirstRead {
+        inner: client_read,
+        check: early_data.is_empty().then_some(pipelined),
+    };
+
+    let (mut sent, mut received) = (0, 0);
+    if !early_data.is_empty() {
+        tor_write.write_all(&early_data).await?;
+        tor_write.flush().await?;
+        sent = early_data.len() as u64;
+        metrics::add_sent(sent);
+        if let Some(recording) = recording {
+            recording.data(Side::Client, sent);
+        }
+    }
+    let client_to_tor = copy_counted(
+        &mut client_read,
+        &mut tor_write,
+        |n| {
+            metrics::add_sent(n);
+            if let Some(recording) = recording {
+                recording.data(Side::Client, n);
+            }
+        },
+        &mut sent,
+        config.coalesce_window,
+        Duration::ZERO,
+        &cancel,
+    );
+    let first_byte = AtomicBool::new(true);
+    let tor_to_client = copy_counted(
+        &mut tor_read,
+        &mut client_write,
+        |n| {
+            if first_byte.swap(false, Ordering::Relaxed) {
+                progress.first_byte();
+            }
+            metrics::add_received(n);
+            if let Some(recording) = recording {
+                recording.data(Side::Remote, n);
+            }
+        },
+        &mut received,
+        Duration::ZERO,
+        config.client_write_timeout,
+        &cancel,
+    );
+    let lifetime = lifetime(&config, &progress, format!("{}:{}", dest_host, dest_port));
+
+    let (reason, outcome) = tokio::select! {
+        result = client_to_tor => match result {
+            Ok(()) if cancel.is_cancelled() => (CloseReason::Shutdown, "closed for shutdown".to_string()),
+            Ok(()) => (CloseReason::Client, "closed by client".to_string()),
+            Err(e) if PipelinedRequest::is(&e) => {
+                (CloseReason::PipelinedRequest, "closed, client repeated its SOCKS handshake".to_string())
+            }
+            Err(e) => {
+                tracing::debug!("Client to Tor copy error: {}", e);
+                (CloseReason::ClientError, format!("client error: {}", e))
+            }
+        },
+        result = tor_to_client => match result {
+            Ok(()) if cancel.is_cancelled() => (CloseReason::Shutdown, "closed for shutdown".to_string()),
+            Ok(()) => (CloseReason::Remote, "closed by remote".to_string()),
+            Err(e) if WriteTimedOut::is(&e) => {
+                tracing::debug!("Closing stream to {}:{}: client stopped reading", dest_host, dest_port);
+                (CloseReason::ClientStalled, "closed, client stopped reading".to_string())
+            }
+            Err(e) => {
+                tracing::debug!("Tor to client copy error: {}", e);
+                (CloseReason::TorError, format!("tor error: {}", e))
+            }
+        },
+        _ = path.cancelled() => (CloseReason::InterfaceChanged, "closed for interface change".to_string()),
+        _ = lifetime, if !config.max_stream_lifetime.is_zero() => {
+            (CloseReason::MaxLifetime, "closed at its maximum lifetime".to_string())
+        }
+        _ = deadline.reached() => (CloseReason::Deadline, "closed at its deadline".to_string()),
+    };
+    let late = matches!(reason, CloseReason::Deadline);
+    if late {
+        let destination = format!("{}:{}", dest_host, dest_port);
+        deadline.report(
+            Phase::Relay,
+            Some(progress.stream()),
+            Some(destination),
+            sent,
+            received,
+        );
+    }
+    if cancel.is_cancelled() || late {
+        let _ = client_write.shutdown().await;
+        let _ = tor_write.shutdown().await;
     }
 
-    // Accept no-auth
-    stream.write_all(&[SOCKS5_VERSION, SOCKS5_AUTH_NONE]).await?;
+    audit::record(
+        AuditRecord::new(peer_addr, Verdict::Allowed, audit_rule)
+            .destination(&dest_host, dest_port)
+            .outcome(outcome.clone())
+            .traffic(started.elapsed(), sent, received),
+    );
+    progress.closed(reason, sent, received);
+    Ok(outcome)
+}
+
+/// Why a connect was given up before it finished
+enum Abandoned {
+    Shutdown,
+    /// The host's deadline for the connection passed
+    Deadline,
+}
+
+/// Complete the handshake, then fail the request without connecting anywhere.
+///
+/// Used while the listener is paused in refusing mode or a traffic quota is
+/// exceeded, so clients get a prompt SOCKS error rather than a hang or a
+/// reset. `reason` is recorded in the audit log.
+pub async fn refuse_socks_connection<S: ClientStream>(
+    mut stream: S,
+    peer_addr: SocketAddr,
+    config: Arc<Config>,
+    reason: &'static str,
+    cancel: CancellationToken,
+) -> io::Result<()> {
+    let Request {
+        host: dest_host,
+        port: dest_port,
+        ..
+    } = unless_cancelled(&cancel, limited_handshake(&mut stream, &config, None)).await?;
+    audit::record(
+        AuditRecord::new(peer_addr, Verdict::Blocked, reason).destination(&dest_host, dest_port),
+    );
+    send_reply(&mut stream, SOCKS5_REP_FAILURE, None).await
+}
+
+/// Connect through Tor, retrying resolution failures up to `retries` times.
+///
+/// Each retry uses a fresh isolation group, so it is built on a new circuit
+/// and normally asks a different exit to resolve the name. So does an
+/// attempt with no circuit within `circuit_timeout`, see
+/// [`circuit_timeout`](crate::circuit_timeout). With `exit_country`, only
+/// exits in that country are used (in `geoip` builds).
+/// Each attempt that gets a circuit is reported to `progress`.
+/// On failure, returns the last error and the number of attempts made.
+async fn connect_tor(
+    client: &TorClient<TorRuntime>,
+    addr: TorAddr,
+    isolation: IsolationToken,
+    retries: u32,
+    exit_country: Option<&str>,
+    circuit_timeout: Option<Duration>,
+    progress: &Progress,
+) -> Result<DataStream, (arti_client::Error, u32)> {
+    let started = Instant::now();
+    let mut prefs = StreamPrefs::new();
+    prefs.set_isolation(isolation);
+    #[cfg(feature = "geoip")]
+    if let Some(country) = exit_country.and_then(|c| c.parse().ok()) {
+        prefs.exit_country(country);
+    }
+    #[cfg(not(feature = "geoip"))]
+    let _ = exit_country;
+    let (mut attempts, mut failovers) = (0, 0);
+    loop {
+        attempts += 1;
+        let attempt_started = Instant::now();
+        let got_circuit = Arc::new(AtomicBool::new(false));
+        let on_attach = {
+            let (progress, got_circuit) = (progress.clone(), got_circuit.clone());
+            move || {
+                got_circuit.store(true, Ordering::SeqCst);
+                progress.attached()
+            }
+        };
+        // Boxed, as Arti's connect future is too large for the stack of a
+        // debug build once the timeout wraps it
+        let attempt = Box::pin(async {
+            if attempts == 1 {
+                latency::watch_attach(client.connect_with_prefs(addr.clone(), &prefs), on_attach)
+                    .await
+            } else {
+                let prefs = prefs.new_isolation_group();
+                latency::watch_attach(client.connect_with_prefs(addr.clone(), prefs), on_attach)
+                    .await
+            }
+        });
+        // The last attempt has as long as Arti gives it
+        let limit = circuit_timeout.filter(|_| failovers < circuit_timeout::FAILOVERS);
+        let Some((result, attached)) = circuit_timeout::within(limit, &got_circuit, attempt).await
+        else {
+            failovers += 1;
+            continue;
+        };
+        match result {
+            Ok(stream) => {
+                latency::record(started, attempt_started, attached);
+                stats::note_connect_succeeded(started.elapsed());
+                let to_circuit =
+                    attached.map_or_else(|| started.elapsed(), |at| at - attempt_started);
+                tuning::note_connect_succeeded(to_circuit);
+                return Ok(stream);
+            }
+            Err(e)
+                if attempts - failovers <= retries
+                    && ConnectFailure::classify(&e).is_resolution_failure() =>
+            {
+                tracing::debug!("Resolution failed ({}), retrying on another exit", e);
+            }
+            Err(e) => return Err((e, attempts)),
+        }
+    }
+}
+
+/// Wait until a stream's `socks.max_lifetime_ms` is over, announcing it
+/// `socks.lifetime_notice_ms` ahead
+async fn lifetime(config: &Config, progress: &Progress, destination: String) {
+    let notice = config.lifetime_notice.min(config.max_stream_lifetime);
+    tokio::time::sleep(config.max_stream_lifetime - notice).await;
+    events::emit(Event::StreamExpiring {
+        stream: progress.stream(),
+        client_port: progress.client_port(),
+        destination,
+        closes_in_ms: notice.as_millis() as u64,
+    });
+    tokio::time::sleep(notice).await;
+}
+
+/// Whether `data` starts with a SOCKS5 greeting offering only known methods,
+/// or with a well-formed request
+fn is_socks_handshake(data: &[u8]) -> bool {
+    if let [SOCKS5_VERSION, n @ 1..=u8::MAX, rest @ ..] = data {
+        if let Some((methods, after)) = rest.split_at_checked(*n as usize) {
+            let known = methods
+                .iter()
+                .all(|m| matches!(m, SOCKS5_AUTH_NONE..=SOCKS5_AUTH_USERPASS | 0x80..=0xFE));
+            if known && (after.is_empty() || is_socks_request(after)) {
+                return true;
+            }
+        }
+    }
+    is_socks_request(data)
+}
+
+/// Whether `data` starts with a whole SOCKS5 request: VER | CMD | RSV | ATYP | DST.ADDR | DST.PORT
+fn is_socks_request(data: &[u8]) -> bool {
+    let [SOCKS5_VERSION, SOCKS5_CMD_CONNECT..=SOCKS5_CMD_UDP_ASSOCIATE, 0x00, atyp, addr @ ..] =
+        data
+    else {
+        return false;
+    };
+    match *atyp {
+        SOCKS5_ATYP_IPV4 => addr.len() >= 4 + 2,
+        SOCKS5_ATYP_IPV6 => addr.len() >= 16 + 2,
+        SOCKS5_ATYP_DOMAIN => addr
+            .first()
+            .is_some_and(|len| *len > 0 && addr.len() > *len as usize + 2),
+        _ => false,
+    }
+}
+
+/// A client reader whose first data is shown to `check`, failing with
+/// [`PipelinedRequest`] if it returns false
+struct FirstRead<R, F> {
+    inner: R,
+    check: Option<F>,
+}
+
+impl<R: AsyncRead + Unpin, F: FnOnce(&[u8]) -> bool + Unpin> AsyncRead for FirstRead<R, F> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            if buf.filled().len() > before {
+                if let Some(check) = self.check.take() {
+                    if !check(&buf.filled()[before..]) {
+                        buf.set_filled(before);
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            PipelinedRequest,
+                        )));
+                    }
+                }
+            }
+        }
+        poll
+    }
+}
+
+/// A client sent its SOCKS handshake again under `socks.pipelining=reject`
+#[derive(Debug)]
+pub(crate) struct PipelinedRequest;
+
+impl std::fmt::Display for PipelinedRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SOCKS handshake repeated as stream data")
+    }
+}
+
+impl std::error::Error for PipelinedRequest {}
+
+impl PipelinedRequest {
+    fn is(error: &io::Error) -> bool {
+        error.get_ref().is_some_and(|e| e.is::<PipelinedRequest>())
+    }
+}
+
+fn is_hostname(host: &str) -> bool {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .is_err()
+}
+
+/// Run `fut` unless shutdown begins first
+async fn unless_cancelled<T>(
+    cancel: &CancellationToken,
+    fut: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    tokio::select! {
+        result = fut => result,
+        _ = cancel.cancelled() => Err(io::Error::new(io::ErrorKind::Interrupted, "shutting down")),
+    }
+}
+
+/// Run the greeting and request phase within the configured time and byte
+/// budget, counting clients that exceed either as dropped.
+async fn limited_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    config: &Config,
+    recording: Option<&recording::Session>,
+) -> io::Result<Request> {
+    let mut hs = HandshakeStream {
+        stream,
+        buffered: Vec::new(),
+        remaining: config.handshake_max_bytes,
+        exceeded: false,
+        recording,
+    };
+    let handshake = handshake(&mut hs, config.require_token);
+    match tokio::time::timeout(config.handshake_timeout, handshake).await {
+        Ok(Err(e)) if hs.exceeded => {
+            metrics::note_handshake_dropped();
+            Err(e)
+        }
+        Ok(Ok((host, port, group))) => Ok(Request {
+            host,
+            port,
+            group,
+            early_data: hs.buffered,
+        }),
+        Ok(Err(e)) => Err(e),
+        Err(_) => {
+            metrics::note_handshake_dropped();
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "SOCKS handshake timed out",
+            ))
+        }
+    }
+}
+
+/// What a client asked for in its handshake
+struct Request {
+    host: String,
+    port: u16,
+    /// Isolation group named by the client's credentials; empty for the default group
+    group: Vec<u8>,
+    /// Data the client sent after the request, without waiting for the reply
+    early_data: Vec<u8>,
+}
+
+/// Client socket during the handshake, with a cap on how much may be read
+struct HandshakeStream<'a, S> {
+    stream: &'a mut S,
+    /// Read from the client but not parsed yet
+    buffered: Vec<u8>,
+    /// Bytes that may still be read from the socket
+    remaining: usize,
+    exceeded: bool,
+    recording: Option<&'a recording::Session>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> HandshakeStream<'_, S> {
+    /// Take the next `buf.len()` bytes, reading only if fewer are buffered
+    async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        while self.buffered.len() < buf.len() {
+            self.fill().await?;
+        }
+        buf.copy_from_slice(&self.buffered[..buf.len()]);
+        self.buffered.drain(..buf.len());
+        if let Some(recording) = self.recording {
+            recording.bytes(Side::Client, buf);
+        }
+        Ok(())
+    }
+
+    /// Buffer whatever the client has sent, up to the byte budget
+    async fn fill(&mut self) -> io::Result<()> {
+        if self.remaining == 0 {
+            self.exceeded = true;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "SOCKS handshake too large",
+            ));
+        }
+        let start = self.buffered.len();
+        self.buffered
+            .resize(start + self.remaining.min(RELAY_BUF_SIZE), 0);
+        let read = self.stream.read(&mut self.buffered[start..]).await;
+        self.buffered.truncate(start + *read.as_ref().unwrap_or(&0));
+        match read? {
+            0 => Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                self.remaining -= n;
+                Ok(())
+            }
+        }
+    }
+
+    /// As `read_exact`, but recorded as zeros
+    async fn read_secret(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let recording = self.recording.take();
+        let result = self.read_exact(buf).await;
+        self.recording = recording;
+        if let (Ok(()), Some(recording)) = (&result, recording) {
+            recording.bytes(Side::Client, &vec![0; buf.len()]);
+        }
+        result
+    }
+
+    async fn send_reply(&mut self, rep: u8) -> io::Result<()> {
+        send_reply(self.stream, rep, self.recording).await
+    }
+
+    async fn write_reply(&mut self, reply: &[u8]) -> io::Result<()> {
+        write_reply(self.stream, reply, self.recording).await
+    }
+}
+
+/// Greeting and CONNECT request; returns the requested destination and isolation group
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut HandshakeStream<'_, S>,
+    require_token: bool,
+) -> io::Result<(String, u16, Vec<u8>)> {
+    let group = negotiate_auth(stream, require_token).await?;
 
     // --- Request ---
     // Client sends: VER | CMD | RSV | ATYP | DST.ADDR | DST.PORT
@@ -74,7 +1027,7 @@ pub async fn handle_socks_connection(
 
     if cmd != SOCKS5_CMD_CONNECT {
         // We only support CONNECT
-        send_reply(&mut stream, SOCKS5_REP_FAILURE).await?;
+        stream.send_reply(SOCKS5_REP_FAILURE).await?;
         return Err(io::Error::new(
             io::ErrorKind::Unsupported,
             "Only CONNECT supported",
@@ -89,20 +1042,27 @@ pub async fn handle_socks_connection(
             let mut port_buf = [0u8; 2];
             stream.read_exact(&mut port_buf).await?;
             let port = u16::from_be_bytes(port_buf);
-            let host = format!("{}.{}.{}.{}", addr[0], addr[1], addr[2], addr[3]);
-            (host, port)
+            (Ipv4Addr::from(addr).to_string(), port)
         }
         SOCKS5_ATYP_DOMAIN => {
             let mut len_buf = [0u8; 1];
             stream.read_exact(&mut len_buf).await?;
             let len = len_buf[0] as usize;
+            if len == 0 {
+                return reject_address(stream, "Empty domain name").await;
+            }
             let mut domain = vec![0u8; len];
             stream.read_exact(&mut domain).await?;
             let mut port_buf = [0u8; 2];
             stream.read_exact(&mut port_buf).await?;
             let port = u16::from_be_bytes(port_buf);
-            let host = String::from_utf8_lossy(&domain).to_string();
-            (host, port)
+            let Ok(host) = String::from_utf8(domain) else {
+                return reject_address(stream, "Domain name is not UTF-8").await;
+            };
+            match normalize_literal(host) {
+                Ok(host) => (host, port),
+                Err(reason) => return reject_address(stream, reason).await,
+            }
         }
         SOCKS5_ATYP_IPV6 => {
             let mut addr = [0u8; 16];
@@ -110,99 +1070,469 @@ pub async fn handle_socks_connection(
             let mut port_buf = [0u8; 2];
             stream.read_exact(&mut port_buf).await?;
             let port = u16::from_be_bytes(port_buf);
-            // Format IPv6 address
-            let segments: Vec<String> = addr
-                .chunks(2)
-                .map(|c| format!("{:02x}{:02x}", c[0], c[1]))
-                .collect();
-            let host = format!("[{}]", segments.join(":"));
-            (host, port)
-        }
-        _ => {
-            send_reply(&mut stream, SOCKS5_REP_FAILURE).await?;
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Unsupported address type",
-            ));
+            (ipv6_host(Ipv6Addr::from(addr)), port)
         }
+        _ => return reject_address(stream, "Unsupported address type").await,
     };
 
-    tracing::debug!("SOCKS5 CONNECT from {} to {}:{}", peer_addr, dest_host, dest_port);
+    Ok((dest_host, dest_port, group))
+}
 
-    // Connect through Tor
-    let tor_addr = format!("{}:{}", dest_host, dest_port);
-    let tor_addr = match tor_addr.as_str().into_tor_addr() {
-        Ok(a) => a,
-        Err(e) => {
-            tracing::debug!("Invalid Tor address: {}", e);
-            send_reply(&mut stream, SOCKS5_REP_FAILURE).await?;
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("Invalid Tor address: {}", e),
-            ));
-        }
-    };
+/// An IPv6 destination as Arti parses it: in canonical compressed form, or
+/// as plain IPv4 if it is IPv4-mapped, since exits may not connect to IPv6
+fn ipv6_host(ip: Ipv6Addr) -> String {
+    match ip.to_ipv4_mapped() {
+        Some(v4) => v4.to_string(),
+        None => format!("[{}]", ip),
+    }
+}
 
-    let tor_stream = match client.connect(tor_addr).await {
-        Ok(s) => s,
-        Err(e) => {
-            tracing::debug!("Tor connect failed: {}", e);
-            send_reply(&mut stream, SOCKS5_REP_CONN_REFUSED).await?;
-            return Err(io::Error::new(
-                io::ErrorKind::ConnectionRefused,
-                e.to_string(),
-            ));
+/// Normalize an IPv6 address sent as a domain name, with or without
+/// brackets, as [`ipv6_host`] does; other names are kept as they are.
+///
+/// Scoped addresses (`fe80::1%en0`) name an interface of this device, which
+/// an exit cannot connect to, so they are refused.
+fn normalize_literal(host: String) -> Result<String, &'static str> {
+    let bare = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(&host);
+    if let Some((addr, _zone)) = bare.split_once('%') {
+        if addr.parse::<Ipv6Addr>().is_ok() {
+            return Err("Scoped IPv6 addresses are not supported");
         }
-    };
+    }
+    match bare.parse::<Ipv6Addr>() {
+        Ok(ip) => Ok(ipv6_host(ip)),
+        Err(_) => Ok(host),
+    }
+}
 
-    // Send success reply
-    // Reply: VER | REP | RSV | ATYP | BND.ADDR | BND.PORT
-    // We use 0.0.0.0:0 as the bound address since we're proxying
-    let reply = [
-        SOCKS5_VERSION,
-        SOCKS5_REP_SUCCESS,
-        0x00, // RSV
-        SOCKS5_ATYP_IPV4,
-        0, 0, 0, 0, // BND.ADDR
-        0, 0, // BND.PORT
-    ];
-    stream.write_all(&reply).await?;
+/// Fail the request with ADDRESS_NOT_SUPPORTED
+async fn reject_address<S: AsyncRead + AsyncWrite + Unpin, T>(
+    stream: &mut HandshakeStream<'_, S>,
+    reason: &'static str,
+) -> io::Result<T> {
+    stream.send_reply(SOCKS5_REP_ADDR_NOT_SUPPORTED).await?;
+    Err(io::Error::new(io::ErrorKind::InvalidData, reason))
+}
 
-    // Bidirectional copy
-    let (mut client_read, mut client_write) = stream.into_split();
-    let (mut tor_read, mut tor_write) = tor_stream.split();
+/// Negotiate the authentication method: username/password if the client
+/// offers it or a token is required, otherwise no-auth. Returns the
+/// isolation group the credentials name.
+async fn negotiate_auth<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut HandshakeStream<'_, S>,
+    require_token: bool,
+) -> io::Result<Vec<u8>> {
+    // --- Greeting ---
+    // Client sends: VER | NMETHODS | METHODS
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting).await?;
+
+    if greeting[0] != SOCKS5_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not SOCKS5"));
+    }
+
+    // NMETHODS is a single byte, so scanning the list is bounded; an empty
+    // list can never be satisfied and is rejected outright
+    let nmethods = greeting[1] as usize;
+    if nmethods == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No auth methods offered",
+        ));
+    }
+    let mut methods = vec![0u8; nmethods];
+    stream.read_exact(&mut methods).await?;
 
-    let client_to_tor = async {
-        tokio::io::copy(&mut client_read, &mut tor_write).await
+    let method = if require_token || methods.contains(&SOCKS5_AUTH_USERPASS) {
+        SOCKS5_AUTH_USERPASS
+    } else {
+        SOCKS5_AUTH_NONE
     };
-    let tor_to_client = async {
-        tokio::io::copy(&mut tor_read, &mut client_write).await
+    if !methods.contains(&method) {
+        // Send failure: no acceptable methods
+        stream
+            .write_reply(&[SOCKS5_VERSION, SOCKS5_AUTH_NO_ACCEPTABLE])
+            .await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "No acceptable auth methods",
+        ));
+    }
+    stream.write_reply(&[SOCKS5_VERSION, method]).await?;
+    if method == SOCKS5_AUTH_USERPASS {
+        authenticate(stream, require_token).await
+    } else {
+        Ok(Vec::new())
+    }
+}
+
+/// Username/password subnegotiation (RFC 1929); with `require_token`, only
+/// the token is accepted as the username. Returns the isolation group.
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut HandshakeStream<'_, S>,
+    require_token: bool,
+) -> io::Result<Vec<u8>> {
+    // Client sends: VER | ULEN | UNAME | PLEN | PASSWD
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != USERPASS_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid username/password version",
+        ));
+    }
+    let mut username = vec![0u8; header[1] as usize];
+    stream.read_secret(&mut username).await?;
+    let mut plen = [0u8; 1];
+    stream.read_exact(&mut plen).await?;
+    let mut password = vec![0u8; plen[0] as usize];
+    stream.read_secret(&mut password).await?;
+
+    let valid =
+        !require_token || token().is_some_and(|token| same_secret(token.as_bytes(), &username));
+    if !valid {
+        stream
+            .write_reply(&[USERPASS_VERSION, USERPASS_FAILURE])
+            .await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Wrong SOCKS token",
+        ));
+    }
+    stream
+        .write_reply(&[USERPASS_VERSION, USERPASS_SUCCESS])
+        .await?;
+
+    // Length-prefixed, so no two credentials make the same group
+    let group = if require_token {
+        password
+    } else {
+        let mut group = vec![username.len() as u8];
+        group.extend_from_slice(&username);
+        group.extend_from_slice(&password);
+        group
     };
+    Ok(group)
+}
 
-    tokio::select! {
-        result = client_to_tor => {
-            if let Err(e) = result {
-                tracing::debug!("Client to Tor copy error: {}", e);
+/// Compare without returning early, so timing does not reveal how much matched
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Copy until EOF, reporting bytes as they move.
+///
+/// Each chunk is flushed as soon as it is written, unless `window` is
+/// non-zero: then less than a cell's worth of data waits up to `window` for
+/// more, so a burst of tiny writes goes out in one cell rather than one cell
+/// each. `total` is kept up to date as data moves, so it is accurate even if
+/// the copy is cancelled. Once shutdown begins no new data is read, but data
+/// already read is still written out.
+///
+/// With a non-zero `write_timeout`, a write or flush that blocks longer
+/// fails with [`WriteTimedOut`].
+pub(crate) async fn copy_counted<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    count: impl Fn(u64),
+    total: &mut u64,
+    window: Duration,
+    write_timeout: Duration,
+    cancel: &CancellationToken,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; RELAY_BUF_SIZE];
+    // Bytes written since the last flush, and when they must go out
+    let mut unflushed = 0;
+    let mut flush_at = tokio::time::Instant::now();
+    loop {
+        let n = tokio::select! {
+            n = reader.read(&mut buf) => n?,
+            _ = cancel.cancelled() => 0,
+            _ = tokio::time::sleep_until(flush_at), if unflushed > 0 => {
+                timed(write_timeout, writer.flush()).await?;
+                unflushed = 0;
+                continue;
             }
+        };
+        if n == 0 {
+            return timed(write_timeout, writer.flush()).await;
         }
-        result = tor_to_client => {
-            if let Err(e) = result {
-                tracing::debug!("Tor to client copy error: {}", e);
-            }
+        timed(write_timeout, writer.write_all(&buf[..n])).await?;
+        *total += n as u64;
+        count(n as u64);
+        if unflushed == 0 {
+            flush_at = tokio::time::Instant::now() + window;
+        }
+        unflushed += n;
+        if window.is_zero() || unflushed >= CELL_DATA_LEN {
+            timed(write_timeout, writer.flush()).await?;
+            unflushed = 0;
         }
     }
+}
+
+/// A write of [`copy_counted`] blocked past its timeout
+#[derive(Debug)]
+pub(crate) struct WriteTimedOut;
+
+impl std::fmt::Display for WriteTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("write timed out")
+    }
+}
+
+impl std::error::Error for WriteTimedOut {}
+
+impl WriteTimedOut {
+    pub(crate) fn is(error: &io::Error) -> bool {
+        error.get_ref().is_some_and(|e| e.is::<WriteTimedOut>())
+    }
+}
 
-    Ok(())
+/// Run `write`, failing with [`WriteTimedOut`] after `timeout` unless it is zero
+async fn timed(timeout: Duration, write: impl Future<Output = io::Result<()>>) -> io::Result<()> {
+    if timeout.is_zero() {
+        return write.await;
+    }
+    tokio::time::timeout(timeout, write)
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, WriteTimedOut)))
+}
+
+async fn send_reply<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    rep: u8,
+    recording: Option<&recording::Session>,
+) -> io::Result<()> {
+    write_reply(stream, &encode_reply(rep, None), recording).await
 }
 
-async fn send_reply(stream: &mut TcpStream, rep: u8) -> io::Result<()> {
-    let reply = [
-        SOCKS5_VERSION,
-        rep,
-        0x00, // RSV
-        SOCKS5_ATYP_IPV4,
-        0, 0, 0, 0, // BND.ADDR
-        0, 0, // BND.PORT
-    ];
-    stream.write_all(&reply).await
+async fn write_reply<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    reply: &[u8],
+    recording: Option<&recording::Session>,
+) -> io::Result<()> {
+    if let Some(recording) = recording {
+        recording.bytes(Side::Proxy, reply);
+    }
+    stream.write_all(reply).await
+}
+
+/// Build a reply: VER | REP | RSV | ATYP | BND.ADDR | BND.PORT.
+///
+/// Without a bound address, 0.0.0.0:0 is sent.
+fn encode_reply(rep: u8, bound: Option<SocketAddr>) -> Vec<u8> {
+    let mut reply = vec![SOCKS5_VERSION, rep, 0x00];
+    match bound.map(|a| (a.ip().to_canonical(), a.port())) {
+        Some((IpAddr::V6(ip), port)) => {
+            reply.push(SOCKS5_ATYP_IPV6);
+            reply.extend_from_slice(&ip.octets());
+            reply.extend_from_slice(&port.to_be_bytes());
+        }
+        Some((IpAddr::V4(ip), port)) => {
+            reply.push(SOCKS5_ATYP_IPV4);
+            reply.extend_from_slice(&ip.octets());
+            reply.extend_from_slice(&port.to_be_bytes());
+        }
+        None => {
+            reply.push(SOCKS5_ATYP_IPV4);
+            reply.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        }
+    }
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Greeting offering no-auth, then CONNECT to `example.com:443`
+    fn connect_request() -> Vec<u8> {
+        let mut bytes = vec![SOCKS5_VERSION, 1, SOCKS5_AUTH_NONE];
+        bytes.extend_from_slice(&[SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0, SOCKS5_ATYP_DOMAIN]);
+        bytes.push(11);
+        bytes.extend_from_slice(b"example.com");
+        bytes.extend_from_slice(&443u16.to_be_bytes());
+        bytes
+    }
+
+    struct Outcome {
+        result: io::Result<(String, u16, Vec<u8>)>,
+        buffered: Vec<u8>,
+        exceeded: bool,
+        /// What the proxy wrote back
+        replies: Vec<u8>,
+    }
+
+    /// Run the handshake on `chunks` sent one write at a time, within `budget` bytes
+    async fn run(chunks: Vec<Vec<u8>>, budget: usize) -> Outcome {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let writer = tokio::spawn(async move {
+            for chunk in chunks {
+                client.write_all(&chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            client
+        });
+        let mut hs = HandshakeStream {
+            stream: &mut server,
+            buffered: Vec::new(),
+            remaining: budget,
+            exceeded: false,
+            recording: None,
+        };
+        let result = handshake(&mut hs, false).await;
+        let (buffered, exceeded) = (std::mem::take(&mut hs.buffered), hs.exceeded);
+        drop(server);
+        let mut replies = Vec::new();
+        writer
+            .await
+            .unwrap()
+            .read_to_end(&mut replies)
+            .await
+            .unwrap();
+        Outcome {
+            result,
+            buffered,
+            exceeded,
+            replies,
+        }
+    }
+
+    #[tokio::test]
+    async fn pipelined_data_stays_buffered() {
+        let mut bytes = connect_request();
+        bytes.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let outcome = run(vec![bytes], 1024).await;
+        assert_eq!(
+            outcome.result.unwrap(),
+            ("example.com".to_string(), 443, Vec::new())
+        );
+        assert_eq!(outcome.buffered, b"GET / HTTP/1.1\r\n");
+        assert_eq!(outcome.replies, [SOCKS5_VERSION, SOCKS5_AUTH_NONE]);
+    }
+
+    #[tokio::test]
+    async fn handshake_split_across_reads() {
+        let chunks = connect_request().into_iter().map(|b| vec![b]).collect();
+        let outcome = run(chunks, 1024).await;
+        assert_eq!(outcome.result.unwrap().0, "example.com");
+        assert!(outcome.buffered.is_empty());
+    }
+
+    #[tokio::test]
+    async fn handshake_over_budget() {
+        let outcome = run(vec![connect_request()], 8).await;
+        assert!(outcome.result.is_err());
+        assert!(outcome.exceeded);
+    }
+
+    #[tokio::test]
+    async fn mapped_ipv6_destinations_become_ipv4() {
+        let mut bytes = vec![SOCKS5_VERSION, 1, SOCKS5_AUTH_NONE];
+        bytes.extend_from_slice(&[SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0, SOCKS5_ATYP_IPV6]);
+        bytes.extend_from_slice(&"::ffff:192.0.2.1".parse::<Ipv6Addr>().unwrap().octets());
+        bytes.extend_from_slice(&80u16.to_be_bytes());
+        let outcome = run(vec![bytes], 1024).await;
+        assert_eq!(outcome.result.unwrap().0, "192.0.2.1");
+    }
+
+    #[tokio::test]
+    async fn scoped_addresses_are_refused() {
+        let mut bytes = vec![SOCKS5_VERSION, 1, SOCKS5_AUTH_NONE];
+        bytes.extend_from_slice(&[SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0, SOCKS5_ATYP_DOMAIN]);
+        bytes.push(11);
+        bytes.extend_from_slice(b"fe80::1%en0");
+        bytes.extend_from_slice(&80u16.to_be_bytes());
+        let outcome = run(vec![bytes], 1024).await;
+        assert!(outcome.result.is_err());
+        assert_eq!(
+            outcome.replies[2..4],
+            [SOCKS5_VERSION, SOCKS5_REP_ADDR_NOT_SUPPORTED]
+        );
+    }
+
+    /// Greeting offering username/password, then the credentials
+    fn credentials(username: &[u8], password: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![SOCKS5_VERSION, 1, SOCKS5_AUTH_USERPASS, USERPASS_VERSION];
+        bytes.push(username.len() as u8);
+        bytes.extend_from_slice(username);
+        bytes.push(password.len() as u8);
+        bytes.extend_from_slice(password);
+        bytes.extend_from_slice(&connect_request()[3..]);
+        bytes
+    }
+
+    #[tokio::test]
+    async fn credentials_never_share_a_group() {
+        let pairs: [(&[u8], &[u8]); 6] = [
+            (b"ab", b"c"),
+            (b"a", b"bc"),
+            (b"abc", b""),
+            (b"", b"abc"),
+            (b"", b""),
+            (b"\x01a", b""),
+        ];
+        let mut groups = Vec::new();
+        for (username, password) in pairs {
+            let outcome = run(vec![credentials(username, password)], 1024).await;
+            let (_, _, group) = outcome.result.unwrap();
+            assert!(!groups.contains(&group), "{:?}", (username, password));
+            groups.push(group);
+        }
+        // Nor with the default group of clients that do not authenticate
+        assert!(!groups.contains(&Vec::new()));
+    }
+
+    #[test]
+    fn socks_handshakes_are_recognised() {
+        let request = connect_request();
+        assert!(is_socks_handshake(&request));
+        assert!(is_socks_handshake(&request[..3]));
+        assert!(is_socks_handshake(&request[3..]));
+        assert!(is_socks_handshake(&[SOCKS5_VERSION, 2, 0x00, 0x80]));
+        for data in [
+            &b"GET / HTTP/1.1\r\n"[..],
+            &[0x16, 0x03, 0x01, 0x00, 0xa5],
+            &[SOCKS5_VERSION, 1, 0x03],
+            &[SOCKS5_VERSION, 0],
+            &request[3..request.len() - 1],
+            &[
+                SOCKS5_VERSION,
+                SOCKS5_CMD_CONNECT,
+                0,
+                SOCKS5_ATYP_DOMAIN,
+                0,
+                0,
+                80,
+            ],
+        ] {
+            assert!(!is_socks_handshake(data), "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn ipv6_literals_are_normalized() {
+        assert_eq!(ipv6_host("2001:db8::1".parse().unwrap()), "[2001:db8::1]");
+        assert_eq!(ipv6_host("::ffff:192.0.2.1".parse().unwrap()), "192.0.2.1");
+        for (host, normalized) in [
+            ("example.com", "example.com"),
+            ("[::1]", "[::1]"),
+            ("0:0:0:0:0:0:0:1", "[::1]"),
+            ("[2001:DB8:0::1]", "[2001:db8::1]"),
+            ("::ffff:192.0.2.1", "192.0.2.1"),
+            ("192.0.2.1", "192.0.2.1"),
+            ("host%en0", "host%en0"),
+        ] {
+            assert_eq!(normalize_literal(host.into()).unwrap(), normalized);
+        }
+        assert!(normalize_literal("fe80::1%en0".into()).is_err());
+        assert!(normalize_literal("[fe80::1%en0]".into()).is_err());
+    }
 }
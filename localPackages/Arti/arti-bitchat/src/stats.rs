@@ -0,0 +1,258 @@
+//! Daily usage statistics kept on disk for the UI
+//!
+//! Per UTC day: bytes each way, connects that succeeded and failed, the total
+//! time successful connects took, and how long Tor was ready for traffic.
+//! Kept in `stats.json` in the data directory for the last [`RETAINED_DAYS`]
+//! days, and readable while stopped, so the app can chart trends without
+//! starting Tor.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage;
+
+const FILE_NAME: &str = "stats.json";
+
+/// Days of history kept, including today
+const RETAINED_DAYS: u64 = 90;
+
+/// How often statistics are written out while running
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+const SECS_PER_DAY: u64 = 86_400;
+
+/// One UTC day's statistics, as saved on disk
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+struct Day {
+    day: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    connects_succeeded: u64,
+    connects_failed: u64,
+    /// Summed time to connect of the successful connects
+    connect_ms_total: u64,
+    connected_secs: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct History {
+    /// Oldest first
+    days: Vec<Day>,
+}
+
+impl History {
+    fn load(path: &Path) -> History {
+        fs::read(path)
+            .ok()
+            .and_then(|b| serde_json::from_slice(&b).ok())
+            .unwrap_or_default()
+    }
+
+    /// Today's entry, started if need be, with days past retention dropped
+    fn today(&mut self) -> &mut Day {
+        let today = today();
+        if self.days.last().is_none_or(|d| d.day != today) {
+            self.days.push(Day {
+                day: today,
+                ..Day::default()
+            });
+            self.days.retain(|d| d.day + RETAINED_DAYS > today);
+        }
+        let last = self.days.len() - 1;
+        &mut self.days[last]
+    }
+}
+
+struct Store {
+    /// Where statistics are saved; unset while stopped
+    path: Option<PathBuf>,
+    history: History,
+    dirty: bool,
+    /// Since when Tor has been ready, counted up to the last save
+    connected_since: Option<Instant>,
+}
+
+static STORE: Mutex<Store> = Mutex::new(Store::new());
+
+impl Store {
+    const fn new() -> Self {
+        Store {
+            path: None,
+            history: History { days: Vec::new() },
+            dirty: false,
+            connected_since: None,
+        }
+    }
+
+    /// Count the time connected so far into today
+    fn accrue(&mut self) {
+        if let Some(since) = self.connected_since {
+            let secs = since.elapsed().as_secs();
+            self.history.today().connected_secs += secs;
+            // Carry the part second over to the next count
+            self.connected_since = Some(since + Duration::from_secs(secs));
+            self.dirty = true;
+        }
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        self.accrue();
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if self.dirty {
+            storage::replace_file(
+                path,
+                &serde_json::to_vec(&self.history).map_err(io::Error::other)?,
+            )?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+/// Day as reported to the app
+#[derive(Serialize)]
+pub(crate) struct DayReport {
+    /// Start of the UTC day, in Unix seconds
+    date: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    connects_succeeded: u64,
+    connects_failed: u64,
+    /// Null on days without connects
+    connect_success_rate: Option<f64>,
+    average_connect_ms: Option<u64>,
+    connected_secs: u64,
+}
+
+impl From<&Day> for DayReport {
+    fn from(d: &Day) -> Self {
+        let attempts = d.connects_succeeded + d.connects_failed;
+        DayReport {
+            date: d.day * SECS_PER_DAY,
+            bytes_sent: d.bytes_sent,
+            bytes_received: d.bytes_received,
+            connects_succeeded: d.connects_succeeded,
+            connects_failed: d.connects_failed,
+            connect_success_rate: (attempts > 0)
+                .then(|| d.connects_succeeded as f64 / attempts as f64),
+            average_connect_ms: (d.connects_succeeded > 0)
+                .then(|| d.connect_ms_total / d.connects_succeeded),
+            connected_secs: d.connected_secs,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct Report {
+    /// Oldest first; days without any activity are left out
+    days: Vec<DayReport>,
+}
+
+/// Load the statistics kept in `data_dir` and start recording
+pub(crate) fn open(data_dir: &Path) {
+    let path = data_dir.join(FILE_NAME);
+    if let Ok(mut store) = STORE.lock() {
+        *store = Store {
+            history: History::load(&path),
+            path: Some(path),
+            ..Store::new()
+        };
+    }
+}
+
+/// Save the statistics and stop recording
+pub(crate) fn close() {
+    if let Ok(mut store) = STORE.lock() {
+        if let Err(e) = store.save() {
+            tracing::warn!("Failed to save statistics: {}", e);
+        }
+        *store = Store::new();
+    }
+}
+
+/// Save the statistics periodically
+pub(crate) async fn autosave() {
+    let mut interval = tokio::time::interval(SAVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Ok(mut store) = STORE.lock() {
+            if let Err(e) = store.save() {
+                tracing::warn!("Failed to save statistics: {}", e);
+            }
+        }
+    }
+}
+
+fn update(f: impl FnOnce(&mut Day)) {
+    if let Ok(mut store) = STORE.lock() {
+        if store.path.is_some() {
+            f(store.history.today());
+            store.dirty = true;
+        }
+    }
+}
+
+pub(crate) fn add_sent(n: u64) {
+    update(|d| d.bytes_sent += n);
+}
+
+pub(crate) fn add_received(n: u64) {
+    update(|d| d.bytes_received += n);
+}
+
+/// Record a SOCKS connect that succeeded after `elapsed`
+pub(crate) fn note_connect_succeeded(elapsed: Duration) {
+    update(|d| {
+        d.connects_succeeded += 1;
+        d.connect_ms_total += elapsed.as_millis() as u64;
+    });
+}
+
+pub(crate) fn note_connect_failed() {
+    update(|d| d.connects_failed += 1);
+}
+
+/// Start or stop counting time connected
+pub(crate) fn set_connected(connected: bool) {
+    if let Ok(mut store) = STORE.lock() {
+        if store.path.is_some() {
+            store.accrue();
+            store.connected_since = connected.then(Instant::now);
+        }
+    }
+}
+
+/// The last `days` days of statistics for `data_dir`, including today
+pub(crate) fn report(data_dir: &Path, days: u64) -> Report {
+    let path = data_dir.join(FILE_NAME);
+    // While running, the store is ahead of the file
+    let running = STORE.lock().ok().and_then(|mut store| {
+        (store.path.as_deref() == Some(path.as_path())).then(|| {
+            store.accrue();
+            store.history.days.clone()
+        })
+    });
+    let history = running.unwrap_or_else(|| History::load(&path).days);
+    let first = today().saturating_sub(days.saturating_sub(1));
+    Report {
+        days: history
+            .iter()
+            .filter(|d| d.day >= first)
+            .map(DayReport::from)
+            .collect(),
+    }
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / SECS_PER_DAY)
+        .unwrap_or(0)
+}
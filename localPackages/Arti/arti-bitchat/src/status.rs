@@ -0,0 +1,148 @@
+//! Status snapshot for the app's Tor status sheet
+//!
+//! Gathers everything the UI shows about the Tor layer into one structure so
+//! it can be fetched in a single FFI round trip.
+
+use std::sync::atomic::Ordering;
+
+use serde::Serialize;
+
+use crate::shutdown::{self, ShutdownReport};
+use crate::{
+    circuit_timeout, config, favorites, latency, listener, loopback, metrics, monitor, padding,
+    probe, quota, ratelimit, storage, tuning, watchdog, ARTI_STATE, BOOTSTRAP_PROGRESS,
+    BOOTSTRAP_SUMMARY, IS_DORMANT, IS_RUNNING,
+};
+
+/// Version of arti-client this crate is built against (keep in sync with Cargo.toml)
+const ARTI_CLIENT_VERSION: &str = "0.38";
+
+#[derive(Serialize)]
+pub(crate) struct StatusSnapshot {
+    running: bool,
+    /// Set in `loopback` builds, whose streams are connected without Tor
+    loopback: bool,
+    bootstrap_percent: i32,
+    ready_for_traffic: bool,
+    summary: String,
+    /// Why bootstrap is stalled, if Arti reports a blockage
+    blocked: Option<String>,
+    dormant: bool,
+    /// "ephemeral" when Tor state is discarded on stop, else "persistent"
+    storage: &'static str,
+    /// Connection padding level, while running
+    padding: Option<&'static str>,
+    /// Set when a wrong device clock is blocking bootstrap
+    clock_skew: Option<String>,
+    /// Set while a captive portal is holding off bootstrap
+    captive_portal: bool,
+    /// State of the last hung bootstrap attempt since start
+    last_bootstrap_stall: Option<watchdog::Diagnostics>,
+    /// Addresses the SOCKS proxy is bound to
+    socks_listeners: Vec<String>,
+    /// "accepting", "paused" or "refusing"
+    socks_accept_state: &'static str,
+    /// Guard used by the most recently attached stream
+    guard: Option<String>,
+    /// Circuits carrying active streams
+    circuits: usize,
+    active_streams: usize,
+    bytes_sent_today: u64,
+    bytes_received_today: u64,
+    /// Traffic counted against quotas, persisted across restarts
+    quota: quota::Usage,
+    /// Clients dropped for slow or oversized handshakes since launch
+    handshakes_dropped: u64,
+    /// Sources currently refused for too many failed handshakes
+    blocked_sources: usize,
+    /// Connections closed because their source was refused
+    connections_rate_limited: u64,
+    /// Failed circuit builds behind failed connects since launch
+    circuit_failures: metrics::CircuitFailures,
+    /// Time to connect successful streams since launch
+    connect_latency: latency::Latency,
+    /// Onion services kept reachable by keepalives
+    favorites: Vec<favorites::Favorite>,
+    /// Circuit settings chosen for the network, with `tuning.adaptive`
+    network_tuning: Option<tuning::Decision>,
+    /// Learned circuit build timeout and the limits connects get, while running
+    circuit_timeouts: Option<circuit_timeout::Timeouts>,
+    /// How connections fared in the last shutdown
+    last_shutdown: Option<ShutdownReport>,
+    version: VersionInfo,
+}
+
+#[derive(Serialize)]
+struct VersionInfo {
+    arti_bitchat: &'static str,
+    arti_client: &'static str,
+}
+
+/// Take a snapshot of the current state.
+///
+/// With `redact_guard` set, the guard is reported only by a short fingerprint prefix.
+pub(crate) fn snapshot(redact_guard: bool) -> StatusSnapshot {
+    let client = ARTI_STATE
+        .get()
+        .and_then(|s| s.lock().ok())
+        .and_then(|g| g.client.clone());
+    let bootstrap = client.as_ref().map(|c| c.bootstrap_status());
+    let (bytes_sent_today, bytes_received_today) = metrics::bytes_today();
+
+    StatusSnapshot {
+        running: IS_RUNNING.load(Ordering::SeqCst),
+        loopback: loopback::ENABLED,
+        bootstrap_percent: BOOTSTRAP_PROGRESS.load(Ordering::SeqCst),
+        ready_for_traffic: bootstrap.as_ref().is_some_and(|b| b.ready_for_traffic()),
+        summary: BOOTSTRAP_SUMMARY
+            .lock()
+            .map(|s| s.clone())
+            .unwrap_or_default(),
+        blocked: bootstrap
+            .as_ref()
+            .and_then(|b| b.blocked())
+            .map(|b| b.to_string()),
+        dormant: IS_DORMANT.load(Ordering::SeqCst),
+        storage: if storage::is_ephemeral() {
+            "ephemeral"
+        } else {
+            "persistent"
+        },
+        padding: padding::current(),
+        clock_skew: monitor::clock_skew(),
+        captive_portal: probe::captive_portal(),
+        last_bootstrap_stall: watchdog::last_stall(),
+        socks_listeners: listener::bound_addrs()
+            .iter()
+            .map(|a| a.to_string())
+            .collect(),
+        socks_accept_state: listener::accept_state().as_str(),
+        guard: metrics::current_guard().map(|g| g.describe(redact_guard)),
+        circuits: metrics::active_circuits(),
+        active_streams: metrics::active_streams(),
+        bytes_sent_today,
+        bytes_received_today,
+        quota: quota::usage(),
+        handshakes_dropped: metrics::handshakes_dropped(),
+        blocked_sources: ratelimit::blocked_sources(),
+        connections_rate_limited: metrics::connections_rate_limited(),
+        circuit_failures: metrics::circuit_failures(),
+        connect_latency: latency::snapshot(),
+        favorites: favorites::snapshot(),
+        network_tuning: tuning::current(),
+        circuit_timeouts: client
+            .as_ref()
+            .map(|c| circuit_timeout::snapshot(c, &config::current())),
+        last_shutdown: shutdown::last_report(),
+        version: VersionInfo {
+            arti_bitchat: env!("CARGO_PKG_VERSION"),
+            arti_client: ARTI_CLIENT_VERSION,
+        },
+    }
+}
+
+impl StatusSnapshot {
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
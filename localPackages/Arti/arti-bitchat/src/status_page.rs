@@ -0,0 +1,267 @@
+//! Status page over HTTP, for debugging
+//!
+//! With `status.http_listen` set, a plain HTTP server on that loopback
+//! address serves a page showing the status snapshot, the active streams and
+//! recent errors, refreshing itself, and the same data as JSON at
+//! `/status.json`, `/streams.json` and `/errors.json`. It runs from start to
+//! stop, so it is there while bootstrap is still going.
+//!
+//! Any app on the device can reach a loopback port, so everything is shown
+//! redacted, as `arti_status` and `arti_streams` do when asked to: guards by
+//! fingerprint prefix, destinations by their kind. Requests naming any host
+//! but a loopback one are refused, so a web page cannot read it by pointing
+//! a domain of its own at 127.0.0.1.
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+use crate::events::Event;
+use crate::{audit, metrics, status};
+
+/// Errors kept for `/errors.json`, oldest dropped first
+const MAX_ERRORS: usize = 50;
+
+/// Longest request head read; only the request line and Host matter
+const MAX_REQUEST: usize = 8192;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Seconds between reloads of the HTML page
+const REFRESH_SECS: u32 = 5;
+
+/// Whether errors are being kept; only while the page is served
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static ERRORS: Mutex<VecDeque<Value>> = Mutex::new(VecDeque::new());
+
+/// Bind `addr` and serve the page on it until the task is aborted.
+///
+/// Must be called within the Tokio runtime context.
+pub(crate) fn start(addr: SocketAddr) -> io::Result<JoinHandle<()>> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    tracing::info!("Status page at http://{}/", listener.local_addr()?);
+    ENABLED.store(true, Ordering::SeqCst);
+    Ok(tokio::spawn(serve(listener)))
+}
+
+/// Stop keeping errors and forget them, once the page is gone
+pub(crate) fn clear() {
+    ENABLED.store(false, Ordering::SeqCst);
+    if let Ok(mut errors) = ERRORS.lock() {
+        errors.clear();
+    }
+}
+
+/// Keep `event` for `/errors.json` if it reports a problem
+pub(crate) fn note_event(event: &Event) {
+    if !ENABLED.load(Ordering::SeqCst) || !event.is_problem() {
+        return;
+    }
+    let Ok(mut value) = serde_json::to_value(event) else {
+        return;
+    };
+    if let Some(fields) = value.as_object_mut() {
+        if let Some(Value::String(dest)) = fields.get_mut("destination") {
+            let (host, port) = dest.rsplit_once(':').unwrap_or((dest.as_str(), ""));
+            *dest = format!("{}:{}", audit::host_kind(host), port);
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        fields.insert("time".into(), time.into());
+    }
+    if let Ok(mut errors) = ERRORS.lock() {
+        if errors.len() == MAX_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(value);
+    }
+}
+
+async fn serve(listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    let _ = tokio::time::timeout(REQUEST_TIMEOUT, respond(stream)).await;
+                });
+            }
+            Err(e) => {
+                tracing::debug!("Status page accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || head.len() + n > MAX_REQUEST {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.lines();
+    let mut request = lines.next().unwrap_or_default().split_whitespace();
+    let (method, path) = (
+        request.next().unwrap_or_default(),
+        request.next().unwrap_or_default(),
+    );
+    let host = lines
+        .filter_map(|l| l.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim().to_string());
+
+    let (status, content_type, body) = if !host.as_deref().is_some_and(is_loopback_host) {
+        ("403 Forbidden", "text/plain", "Forbidden\n".to_string())
+    } else if method != "GET" {
+        (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        )
+    } else {
+        match path {
+            "/" => ("200 OK", "text/html; charset=utf-8", page()),
+            "/status.json" => (
+                "200 OK",
+                "application/json",
+                status::snapshot(true).to_json(),
+            ),
+            "/streams.json" => ("200 OK", "application/json", streams_json()),
+            "/errors.json" => ("200 OK", "application/json", errors_json()),
+            _ => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        }
+    };
+    let reply = format!(
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(reply.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Whether a Host header names this machine's loopback interface
+fn is_loopback_host(host: &str) -> bool {
+    let name = match host.rsplit_once(':') {
+        // A bare IPv6 address has colons but no port
+        Some((name, port)) if !name.contains(':') || name.ends_with(']') => {
+            port.parse::<u16>().is_ok().then_some(name)
+        }
+        _ => Some(host),
+    };
+    let Some(name) = name.map(|n| n.trim_start_matches('[').trim_end_matches(']')) else {
+        return false;
+    };
+    name.eq_ignore_ascii_case("localhost")
+        || name
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+fn streams_json() -> String {
+    serde_json::to_string(&metrics::stream_paths(true)).unwrap_or_default()
+}
+
+fn errors_json() -> String {
+    let errors: Vec<Value> = ERRORS
+        .lock()
+        .map(|e| e.iter().cloned().collect())
+        .unwrap_or_default();
+    serde_json::to_string(&errors).unwrap_or_default()
+}
+
+fn page() -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"{}\">\
+         <title>Tor status</title><style>body{{font-family:sans-serif}}td,th{{padding:2px 8px;text-align:left;\
+         vertical-align:top}}pre{{margin:0;white-space:pre-wrap}}</style></head><body>",
+        REFRESH_SECS
+    );
+
+    html.push_str("<h1>Status</h1><table>");
+    if let Ok(Value::Object(fields)) = serde_json::to_value(status::snapshot(true)) {
+        for (name, value) in &fields {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            let _ = write!(
+                html,
+                "<tr><th>{}</th><td>{}</td></tr>",
+                escape(name),
+                escape(&value)
+            );
+        }
+    }
+    html.push_str("</table>");
+
+    html.push_str("<h1>Streams</h1><table><tr><th>Id</th><th>Destination</th><th>Circuit</th><th>Path</th></tr>");
+    if let Ok(Value::Array(streams)) = serde_json::to_value(metrics::stream_paths(true)) {
+        for stream in &streams {
+            let hops: Vec<String> = stream["hops"]
+                .as_array()
+                .map(|hops| hops.iter().map(|h| h.to_string()).collect())
+                .unwrap_or_default();
+            let _ = write!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td><pre>{}</pre></td></tr>",
+                stream["id"],
+                escape(stream["destination"].as_str().unwrap_or_default()),
+                escape(stream["circuit"].as_str().unwrap_or_default()),
+                escape(&hops.join("\n")),
+            );
+        }
+    }
+    html.push_str("</table>");
+
+    html.push_str("<h1>Recent errors</h1><table><tr><th>Time</th><th>Event</th></tr>");
+    if let Ok(errors) = ERRORS.lock() {
+        for error in errors.iter().rev() {
+            let _ = write!(
+                html,
+                "<tr><td>{}</td><td><pre>{}</pre></td></tr>",
+                error["time"],
+                escape(&error.to_string()),
+            );
+        }
+    }
+    html.push_str("</table></body></html>\n");
+    html
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
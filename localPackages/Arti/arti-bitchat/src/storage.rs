@@ -0,0 +1,98 @@
+//! Where Arti keeps its state and directory cache
+//!
+//! Normally both live under the data directory and carry over between
+//! sessions. In ephemeral mode each session gets an empty directory under
+//! the system temporary directory instead, which is deleted when Arti stops
+//! (and at the next start, should the app have been killed). Nothing from an
+//! earlier session is read back.
+//!
+//! Arti cannot run without a state directory, so ephemeral state still
+//! touches storage while the session runs; on iOS the temporary directory is
+//! excluded from backups and purged by the system.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Session directory to delete when the current session ends
+static EPHEMERAL_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+pub(crate) struct Dirs {
+    pub(crate) state: PathBuf,
+    pub(crate) cache: PathBuf,
+}
+
+/// The state and cache directories for a session rooted at `data_dir`
+pub(crate) fn prepare(data_dir: &Path, ephemeral: bool) -> io::Result<Dirs> {
+    if !ephemeral {
+        return Ok(Dirs {
+            state: data_dir.join("state"),
+            cache: data_dir.join("cache"),
+        });
+    }
+
+    // Named after the data directory so a leftover from a killed session is
+    // found and wiped, without touching other instances' directories
+    let mut hasher = DefaultHasher::new();
+    data_dir.hash(&mut hasher);
+    let root = std::env::temp_dir().join(format!("arti-bitchat-{:016x}", hasher.finish()));
+    remove(&root)?;
+    fs::create_dir_all(&root)?;
+    if let Ok(mut dir) = EPHEMERAL_DIR.lock() {
+        *dir = Some(root.clone());
+    }
+    tracing::info!("Using ephemeral Tor state in {}", root.display());
+    Ok(Dirs {
+        state: root.join("state"),
+        cache: root.join("cache"),
+    })
+}
+
+/// Delete the ephemeral session directory, if there is one
+pub(crate) fn cleanup() {
+    let Some(root) = EPHEMERAL_DIR.lock().ok().and_then(|mut d| d.take()) else {
+        return;
+    };
+    if let Err(e) = remove(&root) {
+        tracing::warn!("Could not remove ephemeral state {}: {}", root.display(), e);
+    }
+}
+
+/// The state and cache directories in use for `data_dir`: the session's if
+/// an ephemeral one is `running` on it, else those kept under `data_dir`
+pub(crate) fn dirs(data_dir: &Path, running: bool) -> Dirs {
+    let session = EPHEMERAL_DIR
+        .lock()
+        .ok()
+        .and_then(|d| d.clone())
+        .filter(|_| running);
+    let root = session.as_deref().unwrap_or(data_dir);
+    Dirs {
+        state: root.join("state"),
+        cache: root.join("cache"),
+    }
+}
+
+/// Whether the running session keeps nothing after it stops
+pub(crate) fn is_ephemeral() -> bool {
+    EPHEMERAL_DIR.lock().is_ok_and(|d| d.is_some())
+}
+
+/// Replace the file at `path` with `contents`, written to a temporary file
+/// beside it first so an app killed mid-write leaves the old file intact
+pub(crate) fn replace_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+fn remove(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
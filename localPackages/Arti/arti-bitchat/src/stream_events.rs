@@ -0,0 +1,137 @@
+//! Progress events for individual SOCKS streams
+//!
+//! With `events.streams` set, each stream reports its steps as events, so the
+//! app can show a message going from sending to connected to delivered: its
+//! connect got a circuit, the remote end accepted it, the first byte came
+//! back, and it closed, with why. Events carry the stream's id, as listed by
+//! `arti_streams`, and the port of the client's end of its SOCKS connection,
+//! which the app can match to its own socket.
+//!
+//! Requests that fail or are refused before connecting end with the
+//! `stream_failed` or `stream_rejected` event instead of `stream_closed`;
+//! those are sent whether or not `events.streams` is set.
+
+use std::time::Instant;
+
+use crate::config::Config;
+use crate::events::{self, Event};
+
+/// Why a relayed stream ended
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum CloseReason {
+    /// The client closed its end
+    Client,
+    /// The remote end closed the stream
+    Remote,
+    ClientError,
+    /// A write towards the client blocked past `socks.write_timeout_ms`
+    ClientStalled,
+    /// The stream reached `socks.max_lifetime_ms`
+    MaxLifetime,
+    /// The host's deadline for the connection passed
+    Deadline,
+    /// The client sent its SOCKS handshake again, under `socks.pipelining=reject`
+    PipelinedRequest,
+    TorError,
+    Shutdown,
+    /// The network interface the stream's path used went away
+    InterfaceChanged,
+}
+
+impl CloseReason {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::Client => "client",
+            CloseReason::Remote => "remote",
+            CloseReason::ClientError => "client_error",
+            CloseReason::ClientStalled => "client_stalled",
+            CloseReason::PipelinedRequest => "pipelined_request",
+            CloseReason::MaxLifetime => "max_lifetime",
+            CloseReason::Deadline => "deadline",
+            CloseReason::TorError => "tor_error",
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::InterfaceChanged => "interface_changed",
+        }
+    }
+}
+
+/// Reports the steps of one stream
+#[derive(Clone)]
+pub(crate) struct Progress {
+    stream: u64,
+    client_port: u16,
+    /// When the request was read, which the reported times count from
+    started: Instant,
+    enabled: bool,
+}
+
+impl Progress {
+    pub(crate) fn new(stream: u64, client_port: u16, config: &Config) -> Self {
+        Progress {
+            stream,
+            client_port,
+            started: Instant::now(),
+            enabled: config.stream_events,
+        }
+    }
+
+    pub(crate) fn stream(&self) -> u64 {
+        self.stream
+    }
+
+    pub(crate) fn client_port(&self) -> u16 {
+        self.client_port
+    }
+
+    /// A connect attempt got its circuit
+    pub(crate) fn attached(&self) {
+        if self.enabled {
+            events::emit(Event::StreamCircuitAttached {
+                stream: self.stream,
+                client_port: self.client_port,
+                elapsed_ms: self.elapsed_ms(),
+            });
+        }
+    }
+
+    /// The remote end accepted the stream
+    pub(crate) fn connected(&self, destination: String, circuit: Option<&str>) {
+        if self.enabled {
+            events::emit(Event::StreamConnected {
+                stream: self.stream,
+                client_port: self.client_port,
+                destination,
+                circuit: circuit.map(str::to_string),
+                elapsed_ms: self.elapsed_ms(),
+            });
+        }
+    }
+
+    /// The first data came back from the remote end
+    pub(crate) fn first_byte(&self) {
+        if self.enabled {
+            events::emit(Event::StreamFirstByte {
+                stream: self.stream,
+                client_port: self.client_port,
+                elapsed_ms: self.elapsed_ms(),
+            });
+        }
+    }
+
+    pub(crate) fn closed(&self, reason: CloseReason, sent: u64, received: u64) {
+        if self.enabled {
+            events::emit(Event::StreamClosed {
+                stream: self.stream,
+                client_port: self.client_port,
+                reason: reason.as_str(),
+                sent,
+                received,
+                elapsed_ms: self.elapsed_ms(),
+            });
+        }
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+}
@@ -0,0 +1,55 @@
+//! Span export to an OpenTelemetry collector
+//!
+//! Built with the `otlp` feature for development and soak tests. Spans for
+//! bootstrap attempts and SOCKS connects are batched and sent over OTLP/HTTP
+//! to `OTEL_EXPORTER_OTLP_ENDPOINT` (a collector on localhost by default), so
+//! latency in the Tor connect path can be tracked. The export filter is fixed
+//! and independent of the log filter.
+
+use once_cell::sync::OnceCell;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::Subscriber;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+const SERVICE_NAME: &str = "arti-bitchat";
+
+/// Spans exported; the exporter's own HTTP stack is left out so exporting
+/// does not trace itself
+const EXPORT_FILTER: &str =
+    "info,hyper=off,hyper_util=off,reqwest=off,opentelemetry_sdk=off,opentelemetry_otlp=off";
+
+static PROVIDER: OnceCell<SdkTracerProvider> = OnceCell::new();
+
+/// Layer exporting spans, or `None` if the exporter could not be created
+pub(crate) fn layer<S>() -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let exporter = SpanExporter::builder().with_http().build().ok()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+        .build();
+    let tracer = provider.tracer(SERVICE_NAME);
+    PROVIDER.set(provider).ok()?;
+    let filter: Targets = EXPORT_FILTER.parse().ok()?;
+    Some(
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(filter),
+    )
+}
+
+/// Send spans still batched, e.g. before the app is suspended
+pub(crate) fn flush() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.force_flush() {
+            tracing::debug!("Failed to flush spans: {}", e);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Private Tor test networks, for integration tests
+//!
+//! Built with the `testnet` feature, `testnet.authorities` and
+//! `testnet.fallbacks` point Arti at a network of its own, such as one
+//! chutney or shadow runs on loopback, instead of the public Tor network.
+//! That network's relays usually share an address, so they are not kept
+//! apart by subnet, and its exits are local, so streams to local addresses
+//! are allowed. Everything else, from bootstrap to SOCKS and onion services,
+//! runs as it would against the public network; `arti-harness`'s `testnet`
+//! binary drives it end to end.
+//!
+//! Without the feature both options are refused, so a release build cannot
+//! be pointed at another network, not even by an environment variable.
+
+use std::net::SocketAddr;
+
+use arti_client::config::dir::FallbackDirBuilder;
+use arti_client::config::TorClientConfigBuilder;
+use tor_linkspec::RelayId;
+
+use crate::config::Config;
+
+/// Whether this build may use a test network
+pub(crate) const ENABLED: bool = cfg!(feature = "testnet");
+
+/// Parse a `DirAuthority` line as tor and chutney write them:
+/// `[DirAuthority] nickname [flags] addr:dirport fingerprint`, where the
+/// flags include `v3ident=`; returns the v3 identity and the address
+pub(crate) fn parse_authority(line: &str) -> Result<(RelayId, SocketAddr), String> {
+    let mut words = line.split_whitespace().peekable();
+    words.next_if(|w| w.eq_ignore_ascii_case("DirAuthority"));
+    words.next().ok_or("missing nickname")?;
+    let (mut v3ident, mut addr) = (None, None);
+    for word in words {
+        if let Some(id) = word.strip_prefix("v3ident=") {
+            v3ident = match id.parse() {
+                Ok(id @ RelayId::Rsa(_)) => Some(id),
+                _ => return Err(format!("bad v3ident {:?}", id)),
+            };
+        } else if let Ok(a) = word.parse::<SocketAddr>() {
+            addr.get_or_insert(a);
+        }
+    }
+    Ok((
+        v3ident.ok_or("missing v3ident=")?,
+        addr.ok_or("missing addr:dirport")?,
+    ))
+}
+
+/// Parse a fallback line, `addr:orport rsa_id ed25519_id`, laid out like a
+/// bridge line; the identities are those in a relay's `fingerprint` and
+/// `fingerprint-ed25519` files
+pub(crate) fn parse_fallback(line: &str) -> Result<FallbackDirBuilder, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let [addr, rsa, ed] = words[..] else {
+        return Err("expected addr:orport rsa_id ed25519_id".into());
+    };
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|_| format!("bad address {:?}", addr))?;
+    let mut fallback = FallbackDirBuilder::new();
+    match rsa.parse() {
+        Ok(RelayId::Rsa(id)) => fallback.rsa_identity(id),
+        _ => return Err(format!("bad RSA identity {:?}", rsa)),
+    };
+    match ed.parse() {
+        Ok(RelayId::Ed25519(id)) => fallback.ed_identity(id),
+        _ => return Err(format!("bad Ed25519 identity {:?}", ed)),
+    };
+    fallback.orports().push(addr);
+    Ok(fallback)
+}
+
+/// Point `tor_config` at the test network, if one is configured
+pub(crate) fn apply(
+    tor_config: &mut TorClientConfigBuilder,
+    config: &Config,
+) -> Result<(), String> {
+    if config.testnet_authorities.is_empty() {
+        return Ok(());
+    }
+    let (mut v3idents, mut addrs) = (Vec::new(), Vec::new());
+    for line in &config.testnet_authorities {
+        let (id, addr) = parse_authority(line)?;
+        if let RelayId::Rsa(id) = id {
+            v3idents.push(id);
+        }
+        addrs.push(vec![addr]);
+    }
+    let network = tor_config.tor_network();
+    let authorities = network.authorities();
+    authorities.set_v3idents(v3idents);
+    authorities.set_uploads(addrs.clone());
+    authorities.set_downloads(addrs.clone());
+    authorities.set_votes(addrs);
+    for line in &config.testnet_fallbacks {
+        network.fallback_caches().push(parse_fallback(line)?);
+    }
+    // Above the address length, so no relays are in a family by subnet
+    tor_config
+        .path_rules()
+        .ipv4_subnet_family_prefix(33)
+        .ipv6_subnet_family_prefix(129);
+    tor_config.address_filter().allow_local_addrs(true);
+    tracing::info!(
+        "Using a test network of {} authorities and {} fallbacks",
+        config.testnet_authorities.len(),
+        config.testnet_fallbacks.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,73 @@
+//! Host-driven runtimes, for hosts that ration background time
+//!
+//! Some hosts, watchOS and app extensions among them, hand a process short
+//! bursts of CPU rather than free rein, and suspend it for keeping threads
+//! busy in between. With `runtime.host_driven` set before the runtime is
+//! created, Arti has no threads of its own: nothing runs, not directory
+//! maintenance, descriptor refreshes, circuit timeouts nor SOCKS data,
+//! except while the host lends a thread through `arti_run_for`, or while a
+//! blocking call such as `arti_prefetch` or `arti_stop` is in progress.
+//! Timers that came due in between fire in the next burst, so work held back
+//! is caught up rather than lost.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, TryRecvError};
+use std::time::{Duration, Instant};
+
+use tokio::runtime::{Builder, Handle, Runtime};
+
+/// How often a host-driven wait checks whether it is over
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+static HOST_DRIVEN: AtomicBool = AtomicBool::new(false);
+
+/// Build the runtime, on the calling thread only if `host_driven`
+pub(crate) fn build(host_driven: bool) -> io::Result<Runtime> {
+    if !host_driven {
+        return Runtime::new();
+    }
+    let runtime = Builder::new_current_thread().enable_all().build()?;
+    HOST_DRIVEN.store(true, Ordering::SeqCst);
+    tracing::info!("Runtime is host-driven; Arti runs only within arti_run_for");
+    Ok(runtime)
+}
+
+/// Whether the runtime only runs when driven
+pub(crate) fn host_driven() -> bool {
+    HOST_DRIVEN.load(Ordering::SeqCst)
+}
+
+/// Whether the calling thread is one of the runtime's, e.g. in an event
+/// callback, and so cannot drive it
+pub(crate) fn on_runtime() -> bool {
+    Handle::try_current().is_ok()
+}
+
+/// Run whatever is due on the calling thread for `budget`
+pub(crate) fn run_for(runtime: &Runtime, budget: Duration) {
+    // Created within the runtime, which its timer needs
+    runtime.block_on(async { tokio::time::sleep(budget).await });
+}
+
+/// Wait up to `timeout` for `rx`, driving a host-driven runtime meanwhile;
+/// returns whether it was signalled
+pub(crate) fn wait(runtime: &Runtime, rx: &mpsc::Receiver<()>, timeout: Duration) -> bool {
+    if !host_driven() {
+        return rx.recv_timeout(timeout).is_ok();
+    }
+    if on_runtime() {
+        return rx.try_recv().is_ok();
+    }
+    let deadline = Instant::now() + timeout;
+    runtime.block_on(async {
+        loop {
+            match rx.try_recv() {
+                Err(TryRecvError::Empty) if Instant::now() < deadline => {
+                    tokio::time::sleep(POLL_INTERVAL).await
+                }
+                result => return result.is_ok(),
+            }
+        }
+    })
+}
@@ -0,0 +1,229 @@
+//! Circuit settings adapted to measured network quality
+//!
+//! With `tuning.adaptive` set, the network is rated from how long bootstrap
+//! took and, once enough traffic has been seen, from the time recent SOCKS
+//! connects took to get a circuit and how many failed for reasons on our
+//! side of the exit. Arti's circuit settings then follow the rating: on a
+//! slow or lossy link, such as weak cellular coverage, circuit requests are
+//! given longer and more attempts, and more exit circuits are built before
+//! they are needed. Each change is announced with a `network_tuned` event.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use arti_client::TorClient;
+use serde::Serialize;
+
+use crate::clock::TorRuntime;
+use crate::events::{self, Event};
+use crate::failure::ConnectFailure;
+
+/// Recent connects the rating is taken from
+const WINDOW: usize = 50;
+
+/// Connects needed before they outweigh the bootstrap time
+const MIN_CONNECTS: usize = 10;
+
+/// How often the network is rated again
+const ASSESS_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Quality {
+    Good,
+    Fair,
+    Poor,
+}
+
+impl Quality {
+    /// Rating for a measurement, given the thresholds for fair and poor
+    fn rate<T: PartialOrd>(value: T, fair: T, poor: T) -> Quality {
+        if value >= poor {
+            Quality::Poor
+        } else if value >= fair {
+            Quality::Fair
+        } else {
+            Quality::Good
+        }
+    }
+
+    /// Circuit request timeout, request attempts and exit circuits to keep
+    /// ready for each predicted port; good uses Arti's defaults
+    fn circuit_settings(self) -> (Duration, u32, usize) {
+        match self {
+            Quality::Good => (Duration::from_secs(60), 16, 2),
+            Quality::Fair => (Duration::from_secs(90), 24, 3),
+            Quality::Poor => (Duration::from_secs(120), 32, 4),
+        }
+    }
+}
+
+/// A tuning decision
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Decision {
+    quality: Quality,
+    /// The measurements behind the rating
+    reason: String,
+    request_timeout_ms: u64,
+    request_max_retries: u32,
+    prebuilt_exit_circuits: usize,
+}
+
+impl Decision {
+    fn new(quality: Quality, reason: String) -> Self {
+        let (timeout, retries, prebuilt) = quality.circuit_settings();
+        Decision {
+            quality,
+            reason,
+            request_timeout_ms: timeout.as_millis() as u64,
+            request_max_retries: retries,
+            prebuilt_exit_circuits: prebuilt,
+        }
+    }
+}
+
+struct Samples {
+    bootstrap: Option<Duration>,
+    /// Time to a circuit of recent connects, `None` for failures, newest last
+    connects: VecDeque<Option<Duration>>,
+}
+
+static SAMPLES: Mutex<Samples> = Mutex::new(Samples {
+    bootstrap: None,
+    connects: VecDeque::new(),
+});
+
+/// The decision in force, while tuning
+static CURRENT: Mutex<Option<Decision>> = Mutex::new(None);
+
+pub(crate) fn note_bootstrap(elapsed: Duration) {
+    if let Ok(mut samples) = SAMPLES.lock() {
+        samples.bootstrap = Some(elapsed);
+    }
+}
+
+/// Record a successful connect that took `elapsed` to get its circuit
+pub(crate) fn note_connect_succeeded(elapsed: Duration) {
+    push(Some(elapsed));
+}
+
+/// Record a failed connect, if the failure says something about our network
+pub(crate) fn note_connect_failed(failure: ConnectFailure) {
+    // The others are decided at or beyond the exit
+    if matches!(failure, ConnectFailure::Timeout | ConnectFailure::Other) {
+        push(None);
+    }
+}
+
+fn push(sample: Option<Duration>) {
+    if let Ok(mut samples) = SAMPLES.lock() {
+        if samples.connects.len() == WINDOW {
+            samples.connects.pop_front();
+        }
+        samples.connects.push_back(sample);
+    }
+}
+
+/// Rate the network and adjust `client` until the task is aborted
+pub(crate) async fn run(client: &TorClient<TorRuntime>) {
+    let mut ticks = tokio::time::interval(ASSESS_INTERVAL);
+    // Seen on the previous assessment, so one noisy one does not flip settings
+    let mut pending = None;
+    loop {
+        ticks.tick().await;
+        let Some((quality, reason)) = assess() else {
+            continue;
+        };
+        let current = CURRENT
+            .lock()
+            .ok()
+            .and_then(|c| c.as_ref().map(|d| d.quality));
+        if current == Some(quality) {
+            pending = None;
+            continue;
+        }
+        // The first rating is applied at once
+        if current.is_some() && pending != Some(quality) {
+            pending = Some(quality);
+            continue;
+        }
+        pending = None;
+        let decision = Decision::new(quality, reason);
+        if let Err(e) = apply(client, quality) {
+            tracing::warn!("Failed to apply network tuning: {}", e);
+            continue;
+        }
+        tracing::info!(
+            "Network rated {:?} ({}); circuit settings adjusted",
+            quality,
+            decision.reason
+        );
+        events::emit(Event::NetworkTuned(decision.clone()));
+        if let Ok(mut current) = CURRENT.lock() {
+            *current = Some(decision);
+        }
+    }
+}
+
+/// The rating and what it was based on, if anything has been measured
+fn assess() -> Option<(Quality, String)> {
+    let samples = SAMPLES.lock().ok()?;
+    if samples.connects.len() < MIN_CONNECTS {
+        let bootstrap = samples.bootstrap?;
+        let quality = Quality::rate(bootstrap, Duration::from_secs(20), Duration::from_secs(60));
+        return Some((quality, format!("bootstrap took {}s", bootstrap.as_secs())));
+    }
+
+    let mut times: Vec<Duration> = samples.connects.iter().flatten().copied().collect();
+    let failure_rate = 1.0 - times.len() as f64 / samples.connects.len() as f64;
+    times.sort();
+    let median = times.get(times.len() / 2).copied().unwrap_or(Duration::MAX);
+    let quality = Quality::rate(failure_rate, 0.1, 0.25).max(Quality::rate(
+        median,
+        Duration::from_secs(2),
+        Duration::from_secs(5),
+    ));
+    Some((
+        quality,
+        format!(
+            "{:.0}% of the last {} connects failed, median time to a circuit {}",
+            failure_rate * 100.0,
+            samples.connects.len(),
+            if times.is_empty() {
+                "unknown".to_string()
+            } else {
+                format!("{}ms", median.as_millis())
+            },
+        ),
+    ))
+}
+
+fn apply(client: &TorClient<TorRuntime>, quality: Quality) -> Result<(), String> {
+    let (timeout, retries, prebuilt) = quality.circuit_settings();
+    crate::padding::update(client, |builder| {
+        builder
+            .circuit_timing()
+            .request_timeout(timeout)
+            .request_max_retries(retries);
+        builder
+            .preemptive_circuits()
+            .min_exit_circs_for_port(prebuilt);
+    })
+}
+
+/// The decision in force, if tuning has decided anything this session
+pub(crate) fn current() -> Option<Decision> {
+    CURRENT.lock().ok().and_then(|c| c.clone())
+}
+
+/// Forget this session's measurements and decision
+pub(crate) fn clear() {
+    if let Ok(mut samples) = SAMPLES.lock() {
+        samples.bootstrap = None;
+        samples.connects.clear();
+    }
+    if let Ok(mut current) = CURRENT.lock() {
+        *current = None;
+    }
+}
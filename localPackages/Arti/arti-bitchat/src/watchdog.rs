@@ -0,0 +1,110 @@
+//! Watchdog for bootstraps that hang
+//!
+//! An attempt can sit at one percentage indefinitely without failing, e.g.
+//! when a directory cache stops answering halfway through a download, and
+//! the supervisor only retries attempts that fail. While an attempt runs,
+//! this watches its progress; once it has not moved for
+//! `bootstrap.stall_timeout_ms`, the state is captured for diagnosis and the
+//! client is replaced by a new one, up to `bootstrap.max_restarts` times per
+//! start; after that the hang is only reported. Time spent dormant or blocked by clock skew does not count, since
+//! a new client would be stuck the same way.
+
+use std::future;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use arti_client::status::BootstrapStatus;
+use arti_client::TorClient;
+use futures::StreamExt;
+use serde::Serialize;
+
+use crate::clock::TorRuntime;
+use crate::{monitor, IS_DORMANT};
+
+/// State of a hung attempt when the watchdog gave up on it
+#[derive(Clone, Serialize)]
+pub(crate) struct Diagnostics {
+    /// Bootstrap attempt that hung, numbered from 1
+    attempt: u32,
+    /// Percentage progress stopped at
+    percent: u8,
+    /// Arti's description of the bootstrap state
+    status: String,
+    /// Why Arti considers bootstrap blocked, if it does
+    blocked: Option<String>,
+    /// How long progress had not moved
+    stalled_ms: u64,
+    /// Time since the attempt started
+    elapsed_ms: u64,
+}
+
+/// Diagnostics of the last hang since start, for the status snapshot
+static LAST_STALL: Mutex<Option<Diagnostics>> = Mutex::new(None);
+
+/// Resolve once the running attempt has made no progress for `timeout`;
+/// never resolves if `timeout` is zero.
+pub(crate) async fn watch(
+    client: &TorClient<TorRuntime>,
+    attempt: u32,
+    timeout: Duration,
+) -> Diagnostics {
+    if timeout.is_zero() {
+        return future::pending().await;
+    }
+    let started = Instant::now();
+    let mut statuses = client.bootstrap_events();
+    let mut status = client.bootstrap_status();
+    let mut since = Instant::now();
+    loop {
+        let deadline = tokio::time::Instant::from_std(since + timeout);
+        match tokio::time::timeout_at(deadline, statuses.next()).await {
+            Ok(Some(next)) => {
+                if percent(&next) != percent(&status) {
+                    since = Instant::now();
+                }
+                status = next;
+            }
+            // The client is going away; nothing left to watch
+            Ok(None) => return future::pending().await,
+            Err(_) if IS_DORMANT.load(Ordering::SeqCst) || monitor::clock_skew().is_some() => {
+                since = Instant::now();
+            }
+            Err(_) => {
+                let diagnostics = Diagnostics {
+                    attempt,
+                    percent: percent(&status),
+                    status: status.to_string(),
+                    blocked: status.blocked().map(|b| b.to_string()),
+                    stalled_ms: since.elapsed().as_millis() as u64,
+                    elapsed_ms: started.elapsed().as_millis() as u64,
+                };
+                tracing::warn!(
+                    "Bootstrap attempt {} stalled at {}",
+                    attempt,
+                    diagnostics.status
+                );
+                if let Ok(mut last) = LAST_STALL.lock() {
+                    *last = Some(diagnostics.clone());
+                }
+                return diagnostics;
+            }
+        }
+    }
+}
+
+fn percent(status: &BootstrapStatus) -> u8 {
+    (status.as_frac() * 100.0).round() as u8
+}
+
+/// Diagnostics of the last hung attempt since Arti started
+pub(crate) fn last_stall() -> Option<Diagnostics> {
+    LAST_STALL.lock().ok().and_then(|l| l.clone())
+}
+
+/// Forget the last hang, when Arti starts afresh
+pub(crate) fn clear() {
+    if let Ok(mut last) = LAST_STALL.lock() {
+        *last = None;
+    }
+}
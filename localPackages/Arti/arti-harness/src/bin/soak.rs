@@ -0,0 +1,307 @@
+//! Long-running soak test of the library through its C API
+//!
+//! Usage: `soak <data dir> [--hours H] [--connections N] [--parallel P]
+//! [--target host:port] [--restart-every C] [--warmup C]
+//! [--max-memory-growth-mb M] [--max-fd-growth F]`
+//!
+//! Each cycle makes `--connections` SOCKS connections to `--target`,
+//! publishes and stops an onion service (when built with
+//! `onion-service-service`), and goes dormant and wakes again; every
+//! `--restart-every` cycles Arti is stopped and bootstrapped afresh. After
+//! `--warmup` cycles the process footprint and open file descriptors are
+//! taken as a baseline, and the test fails as soon as either grows past its
+//! limit, which catches leaks that take days to matter on a phone.
+
+use std::ffi::{c_char, CString};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use arti_bitchat as arti;
+
+const BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(300);
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+const DORMANT_FOR: Duration = Duration::from_secs(5);
+const SERVICE_NICKNAME: &str = "soak";
+
+struct Options {
+    data_dir: String,
+    duration: Duration,
+    connections: usize,
+    parallel: usize,
+    target: String,
+    restart_every: u64,
+    warmup: u64,
+    max_memory_growth: u64,
+    max_fd_growth: usize,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let data_dir = args.next().ok_or("missing data directory")?;
+        let mut options = Options {
+            data_dir,
+            duration: Duration::from_secs(24 * 3600),
+            connections: 1000,
+            parallel: 16,
+            target: "example.com:80".to_string(),
+            restart_every: 10,
+            warmup: 3,
+            max_memory_growth: 64 << 20,
+            max_fd_growth: 16,
+        };
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", flag))?;
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| format!("{} needs a number", flag))
+            };
+            match flag.as_str() {
+                "--hours" => options.duration = Duration::from_secs(number()? * 3600),
+                "--connections" => options.connections = number()? as usize,
+                "--parallel" => options.parallel = number()?.max(1) as usize,
+                "--target" => options.target = value.clone(),
+                "--restart-every" => options.restart_every = number()?.max(1),
+                "--warmup" => options.warmup = number()?,
+                "--max-memory-growth-mb" => options.max_memory_growth = number()? << 20,
+                "--max-fd-growth" => options.max_fd_growth = number()? as usize,
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Resource use at the end of a cycle
+#[derive(Clone, Copy)]
+struct Usage {
+    /// Process footprint, where the platform reports it
+    memory: Option<u64>,
+    fds: usize,
+}
+
+fn main() -> ExitCode {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("usage: soak <data dir> [--hours H] [--connections N] [--parallel P] [--target host:port]");
+            eprintln!("            [--restart-every C] [--warmup C] [--max-memory-growth-mb M] [--max-fd-growth F]");
+            return ExitCode::from(2);
+        }
+    };
+    let result = soak(&options);
+    arti::arti_stop();
+    match result {
+        Ok(cycles) => {
+            println!("passed after {} cycles", cycles);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("FAILED: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Run cycles until the time is up; returns how many ran
+fn soak(options: &Options) -> Result<u64, String> {
+    let started = Instant::now();
+    // Onion service streams are forwarded here and dropped
+    let sink = TcpListener::bind("127.0.0.1:0").map_err(|e| e.to_string())?;
+    let sink_port = sink.local_addr().map_err(|e| e.to_string())?.port();
+    thread::spawn(move || sink.incoming().for_each(drop));
+
+    let mut baseline = None;
+    let mut cycle = 0;
+    while started.elapsed() < options.duration {
+        cycle += 1;
+        if (cycle - 1) % options.restart_every == 0 {
+            arti::arti_stop();
+            start(&options.data_dir)?;
+        }
+
+        let cycle_started = Instant::now();
+        let failed = connect_many(options)?;
+        let published = publish(sink_port)?;
+        if arti::arti_go_dormant() != 0 {
+            return Err("arti_go_dormant failed".into());
+        }
+        thread::sleep(DORMANT_FOR);
+        if arti::arti_wake() != 0 {
+            return Err("arti_wake failed".into());
+        }
+
+        let usage = measure();
+        println!(
+            "cycle {}: {}s, {} of {} connections failed, onion {}, memory {}, {} fds",
+            cycle,
+            cycle_started.elapsed().as_secs(),
+            failed,
+            options.connections,
+            published,
+            usage
+                .memory
+                .map_or("unknown".to_string(), |m| format!("{} MB", m >> 20)),
+            usage.fds,
+        );
+        if cycle == options.warmup.max(1) {
+            baseline = Some(usage);
+        }
+        if let Some(baseline) = baseline {
+            check(options, baseline, usage)?;
+        }
+    }
+    Ok(cycle)
+}
+
+fn start(data_dir: &str) -> Result<(), String> {
+    let dir = CString::new(data_dir).map_err(|e| e.to_string())?;
+    let started = Instant::now();
+    let rc = unsafe { arti::arti_start(dir.as_ptr(), 0) };
+    if rc != 0 {
+        return Err(format!("arti_start returned {}", rc));
+    }
+    while arti::arti_bootstrap_progress() < 100 {
+        if started.elapsed() > BOOTSTRAP_TIMEOUT {
+            return Err(format!(
+                "not bootstrapped after {}s",
+                BOOTSTRAP_TIMEOUT.as_secs()
+            ));
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    println!("bootstrapped in {}s", started.elapsed().as_secs());
+    Ok(())
+}
+
+/// Make the cycle's connections; returns how many failed
+fn connect_many(options: &Options) -> Result<usize, String> {
+    let port = arti::arti_socks_port();
+    if port <= 0 {
+        return Err("no SOCKS port".into());
+    }
+    let (host, target_port) = options
+        .target
+        .rsplit_once(':')
+        .and_then(|(h, p)| Some((h.to_string(), p.parse::<u16>().ok()?)))
+        .ok_or("target must be host:port")?;
+    let remaining = Arc::new(AtomicUsize::new(options.connections));
+    let failed = Arc::new(AtomicUsize::new(0));
+    let workers: Vec<_> = (0..options.parallel)
+        .map(|_| {
+            let (remaining, failed, host) = (remaining.clone(), failed.clone(), host.clone());
+            thread::spawn(move || {
+                while remaining
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok()
+                {
+                    if fetch(port as u16, &host, target_port).is_err() {
+                        failed.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().map_err(|_| "connection worker panicked")?;
+    }
+    Ok(failed.load(Ordering::SeqCst))
+}
+
+/// One SOCKS connection carrying a small HTTP request
+fn fetch(socks_port: u16, host: &str, port: u16) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(("127.0.0.1", socks_port))?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.write_all(&[0x05, 0x01, 0x00])?;
+    let mut method = [0; 2];
+    stream.read_exact(&mut method)?;
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+    let mut reply = [0; 10];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0x00 {
+        return Err(std::io::Error::other(format!(
+            "SOCKS reply {:#04x}",
+            reply[1]
+        )));
+    }
+    write!(
+        stream,
+        "HEAD / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        host
+    )?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(())
+}
+
+/// Publish an onion service and stop it again; "skipped" without service support
+fn publish(sink_port: u16) -> Result<&'static str, String> {
+    let nickname = CString::new(SERVICE_NICKNAME).map_err(|e| e.to_string())?;
+    let mut address = vec![0u8; 128];
+    let rc = unsafe {
+        arti::arti_onion_service_create(
+            nickname.as_ptr(),
+            80,
+            sink_port,
+            std::ptr::null(),
+            address.as_mut_ptr() as *mut c_char,
+            address.len() as i32,
+        )
+    };
+    match rc {
+        -6 => Ok("skipped"),
+        rc if rc < 0 => Err(format!("arti_onion_service_create returned {}", rc)),
+        _ => {
+            let rc = unsafe { arti::arti_onion_service_stop(nickname.as_ptr()) };
+            if rc != 0 {
+                return Err(format!("arti_onion_service_stop returned {}", rc));
+            }
+            Ok("published")
+        }
+    }
+}
+
+fn measure() -> Usage {
+    let mut buf = vec![0u8; 1024];
+    let len = unsafe { arti::arti_memory_usage(buf.as_mut_ptr() as *mut c_char, buf.len() as i32) };
+    let memory = (len > 0)
+        .then(|| serde_json::from_slice::<serde_json::Value>(&buf[..len as usize]).ok())
+        .flatten()
+        .and_then(|report| report["total_bytes"].as_u64());
+    // /dev/fd lists this process's descriptors on Linux and macOS alike,
+    // including the one used to read it
+    let fds = fs::read_dir("/dev/fd").map_or(0, |d| d.count().saturating_sub(1));
+    Usage { memory, fds }
+}
+
+fn check(options: &Options, baseline: Usage, usage: Usage) -> Result<(), String> {
+    if let (Some(base), Some(now)) = (baseline.memory, usage.memory) {
+        if now > base + options.max_memory_growth {
+            return Err(format!(
+                "memory grew from {} MB to {} MB, over the {} MB allowed",
+                base >> 20,
+                now >> 20,
+                options.max_memory_growth >> 20,
+            ));
+        }
+    }
+    if usage.fds > baseline.fds + options.max_fd_growth {
+        return Err(format!(
+            "file descriptors grew from {} to {}, over the {} allowed",
+            baseline.fds, usage.fds, options.max_fd_growth,
+        ));
+    }
+    Ok(())
+}
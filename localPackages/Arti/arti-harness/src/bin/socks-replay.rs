@@ -0,0 +1,226 @@
+//! Replays SOCKS sessions recorded with `socks.record_path` against a proxy
+//!
+//! Usage: `socks-replay <recording> <proxy address> [session]`
+//!
+//! Each session's client bytes are sent with their recorded timing, and the
+//! proxy's replies are compared with the recorded ones. The bound address in
+//! a success reply is not compared, since it is the local end of the socket.
+//! Payload chunks from the client are replayed as zero bytes of the recorded
+//! length; data from the destination is read and discarded. Exits with
+//! status 1 if any session's replies differed.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// How long to wait for each reply; connects through Tor can be slow
+const REPLY_TIMEOUT: Duration = Duration::from_secs(120);
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// One line of a recording
+#[derive(Deserialize)]
+struct Entry {
+    session: u64,
+    at_ms: u64,
+    kind: String,
+    from: Option<String>,
+    hex: Option<String>,
+    len: Option<u64>,
+    outcome: Option<String>,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 || args.len() > 4 {
+        eprintln!("usage: {} <recording> <proxy address> [session]", args[0]);
+        return ExitCode::from(2);
+    }
+    let only = match args.get(3).map(|s| s.parse::<u64>()) {
+        Some(Ok(id)) => Some(id),
+        Some(Err(_)) => {
+            eprintln!("session must be a number");
+            return ExitCode::from(2);
+        }
+        None => None,
+    };
+    let sessions = match load(&args[1]) {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            eprintln!("{}: {}", args[1], e);
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut failed = false;
+    for (id, entries) in sessions
+        .iter()
+        .filter(|(id, _)| only.is_none_or(|o| o == **id))
+    {
+        let recorded = entries
+            .iter()
+            .find_map(|e| e.outcome.as_deref())
+            .unwrap_or("unknown");
+        match replay(&args[2], entries) {
+            Ok(()) => println!(
+                "session {}: replies match (recorded outcome: {})",
+                id, recorded
+            ),
+            Err(e) => {
+                println!("session {}: {} (recorded outcome: {})", id, e, recorded);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Read a recording into its sessions, each in recorded order
+fn load(path: &str) -> Result<BTreeMap<u64, Vec<Entry>>, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut sessions: BTreeMap<u64, Vec<Entry>> = BTreeMap::new();
+    for (n, line) in text
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+    {
+        let entry: Entry =
+            serde_json::from_str(line).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        sessions.entry(entry.session).or_default().push(entry);
+    }
+    Ok(sessions)
+}
+
+fn replay(proxy: &str, entries: &[Entry]) -> Result<(), String> {
+    let mut stream =
+        TcpStream::connect(proxy).map_err(|e| format!("cannot connect to {}: {}", proxy, e))?;
+    stream
+        .set_read_timeout(Some(REPLY_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    let started = Instant::now();
+    let mut draining = false;
+    for (n, entry) in entries.iter().enumerate() {
+        match (entry.kind.as_str(), entry.from.as_deref()) {
+            ("bytes", Some("client")) => {
+                wait_until(started, entry.at_ms);
+                stream
+                    .write_all(&decode(entry)?)
+                    .map_err(|e| format!("entry {}: write failed: {}", n + 1, e))?;
+            }
+            ("bytes", Some("proxy")) => {
+                let expected = decode(entry)?;
+                let got = read_reply(&mut stream, expected.len()).map_err(|e| {
+                    format!(
+                        "entry {}: expected {}, read failed: {}",
+                        n + 1,
+                        hex(&expected),
+                        e
+                    )
+                })?;
+                if !same_reply(&expected, &got) {
+                    return Err(format!(
+                        "entry {}: expected {}, got {}",
+                        n + 1,
+                        hex(&expected),
+                        hex(&got)
+                    ));
+                }
+            }
+            ("data", Some("client")) => {
+                wait_until(started, entry.at_ms);
+                let len = entry.len.unwrap_or(0) as usize;
+                stream
+                    .write_all(&vec![0; len])
+                    .map_err(|e| format!("entry {}: write failed: {}", n + 1, e))?;
+            }
+            ("data", Some("remote")) if !draining => {
+                // The destination's data cannot be reproduced; just keep the socket moving
+                let mut reader = stream.try_clone().map_err(|e| e.to_string())?;
+                thread::spawn(move || io::copy(&mut reader, &mut io::sink()));
+                draining = true;
+            }
+            ("data", _) => {}
+            ("end", _) => {
+                wait_until(started, entry.at_ms);
+                let _ = stream.shutdown(Shutdown::Write);
+            }
+            _ => return Err(format!("entry {}: unknown kind {:?}", n + 1, entry.kind)),
+        }
+    }
+    Ok(())
+}
+
+/// Read a reply shaped like the recorded one: a two-byte method reply, or a
+/// request reply whose length depends on its address type
+fn read_reply(stream: &mut TcpStream, expected_len: usize) -> io::Result<Vec<u8>> {
+    if expected_len <= 2 {
+        let mut reply = vec![0; expected_len];
+        stream.read_exact(&mut reply)?;
+        return Ok(reply);
+    }
+    let mut reply = vec![0; 4];
+    stream.read_exact(&mut reply)?;
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            reply.push(len[0]);
+            len[0] as usize
+        }
+        _ => 0,
+    };
+    let mut rest = vec![0; addr_len + 2];
+    stream.read_exact(&mut rest)?;
+    reply.extend_from_slice(&rest);
+    Ok(reply)
+}
+
+/// Whether `got` matches the recorded reply, ignoring a success reply's bound address
+fn same_reply(expected: &[u8], got: &[u8]) -> bool {
+    let is_success = expected.len() > 2 && expected[1] == 0x00;
+    if is_success {
+        got.len() > 2 && got[..2] == expected[..2]
+    } else {
+        got == expected
+    }
+}
+
+fn wait_until(started: Instant, at_ms: u64) {
+    let at = started + Duration::from_millis(at_ms);
+    let now = Instant::now();
+    if at > now {
+        thread::sleep(at - now);
+    }
+}
+
+fn decode(entry: &Entry) -> Result<Vec<u8>, String> {
+    let hex = entry.hex.as_deref().unwrap_or("");
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(format!("bad hex in session {}", entry.session));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| format!("bad hex in session {}", entry.session))
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
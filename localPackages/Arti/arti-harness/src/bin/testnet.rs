@@ -0,0 +1,335 @@
+//! End-to-end test of the library against a private Tor network
+//!
+//! Usage: `testnet <nodes dir> <data dir> [--connections N] [--bytes B]
+//! [--bootstrap-timeout S]`
+//!
+//! `<nodes dir>` is where chutney keeps the directories of a network it
+//! started (`net/nodes`); shadow's tor hosts are laid out the same way. The
+//! authorities and relays are read from each node's `torrc`, `fingerprint`
+//! and `fingerprint-ed25519`, and Arti is pointed at them with the
+//! `testnet.*` options, so nothing touches the public network. The test
+//! then checks, stopping at the first failure:
+//!
+//! 1. that Arti bootstraps from the network's authorities;
+//! 2. that `--connections` SOCKS streams through its exits to a local echo
+//!    server each carry `--bytes` and get them back intact;
+//! 3. built with `onion-service-service`, that an onion service published
+//!    on the network forwards a stream, made through the same SOCKS port,
+//!    to the echo server.
+//!
+//! Exits must allow streams to 127.0.0.1, as chutney's do. Payloads are
+//! fixed, so a failing run repeats.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, CString};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::Path;
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use arti_bitchat as arti;
+
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a new onion service may take to publish its descriptor
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(180);
+const PUBLISH_RETRY: Duration = Duration::from_secs(5);
+const SERVICE_NICKNAME: &str = "testnet";
+const SERVICE_PORT: u16 = 80;
+
+struct Options {
+    nodes_dir: String,
+    data_dir: String,
+    connections: usize,
+    bytes: usize,
+    bootstrap_timeout: Duration,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let nodes_dir = args.next().ok_or("missing nodes directory")?;
+        let data_dir = args.next().ok_or("missing data directory")?;
+        let mut options = Options {
+            nodes_dir,
+            data_dir,
+            connections: 20,
+            bytes: 64 << 10,
+            bootstrap_timeout: Duration::from_secs(300),
+        };
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", flag))?;
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| format!("{} needs a number", flag))
+            };
+            match flag.as_str() {
+                "--connections" => options.connections = number()?.max(1) as usize,
+                "--bytes" => options.bytes = number()?.max(1) as usize,
+                "--bootstrap-timeout" => options.bootstrap_timeout = Duration::from_secs(number()?),
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// What Arti needs to know of the network, as `testnet.*` option values
+struct Network {
+    authorities: Vec<String>,
+    fallbacks: Vec<String>,
+}
+
+fn main() -> ExitCode {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("usage: testnet <nodes dir> <data dir> [--connections N] [--bytes B] [--bootstrap-timeout S]");
+            return ExitCode::from(2);
+        }
+    };
+    let result = run(&options);
+    arti::arti_stop();
+    match result {
+        Ok(()) => {
+            println!("passed");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("FAILED: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let network = read_network(Path::new(&options.nodes_dir))?;
+    println!(
+        "network: {} authorities, {} relays",
+        network.authorities.len(),
+        network.fallbacks.len()
+    );
+    set_option("testnet.authorities", &network.authorities.join("\n"))?;
+    set_option("testnet.fallbacks", &network.fallbacks.join("\n"))?;
+
+    let echo = TcpListener::bind("127.0.0.1:0").map_err(|e| e.to_string())?;
+    let echo_port = echo.local_addr().map_err(|e| e.to_string())?.port();
+    thread::spawn(move || {
+        for stream in echo.incoming().flatten() {
+            thread::spawn(move || echo_back(stream));
+        }
+    });
+
+    start(&options.data_dir, options.bootstrap_timeout)?;
+    let socks_port = u16::try_from(arti::arti_socks_port()).map_err(|_| "no SOCKS port")?;
+
+    let started = Instant::now();
+    for n in 0..options.connections {
+        round_trip(
+            socks_port,
+            "127.0.0.1",
+            echo_port,
+            &payload(n, options.bytes),
+        )
+        .map_err(|e| format!("stream {} to the echo server: {}", n, e))?;
+    }
+    println!(
+        "exits: {} streams of {} bytes in {}ms",
+        options.connections,
+        options.bytes,
+        started.elapsed().as_millis()
+    );
+
+    onion_service(socks_port, echo_port, options.bytes)
+}
+
+/// Collect the `DirAuthority` lines and the relays of the nodes in `dir`
+fn read_network(dir: &Path) -> Result<Network, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    // By directory name, so options come out the same on every run
+    let mut nodes = BTreeMap::new();
+    for entry in entries.flatten() {
+        if let Ok(torrc) = fs::read_to_string(entry.path().join("torrc")) {
+            nodes.insert(entry.file_name(), (entry.path(), torrc));
+        }
+    }
+    let mut network = Network {
+        authorities: Vec::new(),
+        fallbacks: Vec::new(),
+    };
+    for (path, torrc) in nodes.values() {
+        let mut address = "127.0.0.1".to_string();
+        let mut orport = None;
+        for line in torrc.lines().map(str::trim) {
+            let (keyword, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let value = value.trim();
+            if keyword.eq_ignore_ascii_case("DirAuthority") {
+                if !network.authorities.iter().any(|a| a == line) {
+                    network.authorities.push(line.to_string());
+                }
+            } else if keyword.eq_ignore_ascii_case("Address") {
+                address = value.to_string();
+            } else if keyword.eq_ignore_ascii_case("OrPort") {
+                orport = value.split_whitespace().next().map(str::to_string);
+            }
+        }
+        // Clients have no ORPort and no identity
+        let (Some(orport), Some(rsa), Some(ed)) = (
+            orport,
+            identity(&path.join("fingerprint")),
+            identity(&path.join("fingerprint-ed25519")),
+        ) else {
+            continue;
+        };
+        let addr = if orport.contains(':') {
+            orport
+        } else {
+            format!("{}:{}", address, orport)
+        };
+        network.fallbacks.push(format!("{} {} {}", addr, rsa, ed));
+    }
+    if network.authorities.is_empty() || network.fallbacks.is_empty() {
+        return Err(format!("no authorities or relays in {}", dir.display()));
+    }
+    Ok(network)
+}
+
+/// The identity in a `nickname identity` fingerprint file
+fn identity(path: &Path) -> Option<String> {
+    let contents = fs::read_to_string(path).ok()?;
+    contents.split_whitespace().nth(1).map(str::to_string)
+}
+
+fn set_option(key: &str, value: &str) -> Result<(), String> {
+    let (k, v) = (
+        CString::new(key).map_err(|e| e.to_string())?,
+        CString::new(value).map_err(|e| e.to_string())?,
+    );
+    match unsafe { arti::arti_set_option(k.as_ptr(), v.as_ptr()) } {
+        0 => Ok(()),
+        rc => Err(format!("arti_set_option({}) returned {}", key, rc)),
+    }
+}
+
+fn start(data_dir: &str, timeout: Duration) -> Result<(), String> {
+    let dir = CString::new(data_dir).map_err(|e| e.to_string())?;
+    let started = Instant::now();
+    let rc = unsafe { arti::arti_start(dir.as_ptr(), 0) };
+    if rc != 0 {
+        return Err(format!("arti_start returned {}", rc));
+    }
+    while arti::arti_bootstrap_progress() < 100 {
+        if started.elapsed() > timeout {
+            return Err(format!("not bootstrapped after {}s", timeout.as_secs()));
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    println!("bootstrap: {}ms", started.elapsed().as_millis());
+    Ok(())
+}
+
+fn echo_back(mut stream: TcpStream) {
+    let mut buf = [0; 16 << 10];
+    while let Ok(n @ 1..) = stream.read(&mut buf) {
+        if stream.write_all(&buf[..n]).is_err() {
+            return;
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Write);
+}
+
+/// The bytes stream `n` sends; they differ between streams so crossed
+/// streams are caught too
+fn payload(n: usize, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i.wrapping_mul(31).wrapping_add(n.wrapping_mul(7)) % 251) as u8)
+        .collect()
+}
+
+/// Send `payload` to `host:port` over SOCKS and check it comes back
+fn round_trip(socks_port: u16, host: &str, port: u16, payload: &[u8]) -> io::Result<()> {
+    let mut stream = TcpStream::connect(("127.0.0.1", socks_port))?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.write_all(&[0x05, 0x01, 0x00])?;
+    let mut method = [0; 2];
+    stream.read_exact(&mut method)?;
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+    let mut reply = [0; 10];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0x00 {
+        return Err(io::Error::other(format!("SOCKS reply {:#04x}", reply[1])));
+    }
+    // Read while writing, so a payload larger than the buffers in between
+    // cannot stall both sides
+    let mut reader = stream.try_clone()?;
+    let expected = payload.len();
+    let echoed = thread::spawn(move || {
+        let mut echoed = vec![0; expected];
+        reader.read_exact(&mut echoed).map(|()| echoed)
+    });
+    stream.write_all(payload)?;
+    let echoed = echoed
+        .join()
+        .map_err(|_| io::Error::other("reader panicked"))??;
+    match echoed.iter().zip(payload).position(|(a, b)| a != b) {
+        Some(at) => Err(io::Error::other(format!("echo differs at byte {}", at))),
+        None => Ok(()),
+    }
+}
+
+/// Publish an onion service in front of the echo server and make a stream
+/// to it; skipped without service support
+fn onion_service(socks_port: u16, echo_port: u16, bytes: usize) -> Result<(), String> {
+    let nickname = CString::new(SERVICE_NICKNAME).map_err(|e| e.to_string())?;
+    let mut address = vec![0u8; 128];
+    let rc = unsafe {
+        arti::arti_onion_service_create(
+            nickname.as_ptr(),
+            SERVICE_PORT,
+            echo_port,
+            std::ptr::null(),
+            address.as_mut_ptr() as *mut c_char,
+            address.len() as i32,
+        )
+    };
+    if rc == -6 {
+        println!("onion service: skipped, built without onion-service-service");
+        return Ok(());
+    }
+    if rc < 0 {
+        return Err(format!("arti_onion_service_create returned {}", rc));
+    }
+    let address = String::from_utf8_lossy(&address[..rc as usize]).into_owned();
+
+    // Streams fail until the descriptor is published, which takes a while
+    let started = Instant::now();
+    let payload = payload(usize::MAX, bytes);
+    let result = loop {
+        match round_trip(socks_port, &address, SERVICE_PORT, &payload) {
+            Ok(()) => break Ok(()),
+            Err(e) if started.elapsed() > PUBLISH_TIMEOUT => {
+                break Err(format!("stream to {}: {}", address, e));
+            }
+            Err(_) => thread::sleep(PUBLISH_RETRY),
+        }
+    };
+    let rc = unsafe { arti::arti_onion_service_stop(nickname.as_ptr()) };
+    result?;
+    if rc != 0 {
+        return Err(format!("arti_onion_service_stop returned {}", rc));
+    }
+    println!(
+        "onion service: reached {} after {}ms",
+        address,
+        started.elapsed().as_millis()
+    );
+    Ok(())
+}